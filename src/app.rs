@@ -1,12 +1,42 @@
+use crate::backend::GitBackend;
+use crate::binary;
+use crate::comments::{self, Comment, ReviewComments, Side};
+use crate::expand::{self, Expand};
+use crate::full_file;
 use crate::git;
-use crate::highlight::Highlighter;
-use crate::model::{DiffSource, FileEntry, HighlightedLine, TreeNode};
+use crate::highlight::{self, Highlighter};
+use crate::model::{
+    ApplyTo, CommitInfo, DiffAlgorithm, DiffLayer, DiffLine, DiffOptions, DiffRange, DiffSource,
+    DiffViewMode, FileConflict, FileDiff, FileEntry, FileLayers, FileStatus, HighlightedDiff,
+    HighlightedLine, MergePreview, MergeSource, SplitRow, TreeNode, Whitespace, WorkState,
+};
+use crate::moves::{self, FileMoves};
+use crate::patch;
 use crate::theme::Theme;
 use crate::tree;
+use crate::viewed::{self, ViewedMarks};
 use crate::watcher::{GitWatcher, WatcherMessage};
 use anyhow::Result;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Commit message file in the git dir, kept after a failed commit so the
+/// message isn't lost.
+const COMMIT_MESSAGE_FILE: &str = "PRDIFF_COMMIT_EDITMSG";
+
+/// Review comment file in the git dir, written for the editor.
+const COMMENT_FILE: &str = "PRDIFF_COMMENT_EDITMSG";
+
+/// How long a toast stays up.
+const TOAST_DURATION: Duration = Duration::from_secs(5);
+
+/// Beyond this many files moved blocks aren't detected.
+const MAX_MOVE_FILES: usize = 300;
 
 pub struct BranchModal {
     pub branches: Vec<String>,
@@ -55,6 +85,50 @@ impl BranchModal {
     }
 }
 
+/// Cursor and range anchor of the commit panel. Entry 0 is "Uncommitted
+/// changes" when diffing against the working tree, followed by the commits
+/// newest first.
+#[derive(Default)]
+pub struct CommitPanel {
+    pub cursor: usize,
+    /// Other end of a range selection, set with Space
+    pub anchor: Option<usize>,
+    pub scroll_offset: usize,
+}
+
+/// Lines of the diff pane that `S`, `D` and `i` act on instead of those at
+/// the top, as rows of the unified diff of `path`.
+pub struct LineSelection {
+    pub path: String,
+    pub anchor: usize,
+    pub cursor: usize,
+}
+
+impl LineSelection {
+    /// Selected rows as (first, last), inclusive.
+    pub fn rows(&self) -> (usize, usize) {
+        (self.anchor.min(self.cursor), self.anchor.max(self.cursor))
+    }
+}
+
+/// A discarded change, kept for the session so it can be put back.
+struct Discard {
+    /// Tree path of the file
+    path: String,
+    /// Submodule the file is in; empty for this repository
+    repo: String,
+    /// Patch that was applied in reverse to the working tree
+    patch: String,
+}
+
+impl CommitPanel {
+    /// Selected entries as (newest, oldest) indices, inclusive.
+    pub fn selection(&self) -> (usize, usize) {
+        let anchor = self.anchor.unwrap_or(self.cursor);
+        (anchor.min(self.cursor), anchor.max(self.cursor))
+    }
+}
+
 pub struct App {
     pub files: Vec<FileEntry>,
     pub tree: Vec<TreeNode>,
@@ -63,38 +137,153 @@ pub struct App {
     pub scroll_offset: usize,
     pub diff_scroll: usize,
     pub diff_line_count: usize,
-    diff_cache: HashMap<String, Vec<String>>,
+    /// Rows of comment text under each row of the diff pane
+    pub diff_comment_rows: HashMap<usize, usize>,
+    diff_cache: HashMap<String, FileDiff>,
     diff_source_cache: HashMap<String, DiffSource>,
-    highlighted_cache: HashMap<String, Vec<HighlightedLine>>,
-    pub merge_base: String,
+    highlighted_cache: HashMap<String, HighlightedDiff>,
+    /// What is being compared; `range.from` is the merge-base in branch mode
+    pub range: DiffRange,
     pub base_branch: String,
+    /// The `A..B`, `A...B` or commit the user asked for, if not diffing a branch
+    pub range_spec: Option<String>,
+    /// Commits in the reviewed range, newest first
+    pub commits: Vec<CommitInfo>,
+    pub commit_panel: Option<CommitPanel>,
+    /// Commit panel entries the view is limited to, with the range they cover
+    commit_filter: Option<((usize, usize), DiffRange)>,
+    /// Which part of the branch diff is shown
+    pub layer: DiffLayer,
+    pub show_untracked: bool,
+    /// Range for `layer` and `show_untracked`, unless that is the whole diff
+    layer_range: Option<DiffRange>,
+    /// Layer badges per path; empty when reviewing a fixed range
+    pub file_layers: HashMap<String, FileLayers>,
+    /// How diffs are computed; the watcher gets a copy when it is spawned
+    pub diff_options: DiffOptions,
+    backend: Arc<dyn GitBackend>,
     editor: String,
     highlighter: Highlighter,
     tree_version: u64,
-    watcher: GitWatcher,
+    /// Only runs when diffing against the working tree
+    watcher: Option<GitWatcher>,
     pub theme: Theme,
     /// Percentage of terminal width for the file tree panel (10-90)
     pub split_percent: u16,
+    /// Show the old/new line number gutter in the diff pane
+    pub show_line_numbers: bool,
+    /// Unified or side-by-side diff; kept for the whole session
+    pub diff_view: DiffViewMode,
+    /// Show binary files as a diff of their hex dumps
+    pub show_hex: bool,
     pub branch_modal: Option<BranchModal>,
+    /// Waiting for the second key of a `w` whitespace toggle
+    pub whitespace_menu: bool,
+    /// Draw blocks moved within or between files as moves
+    pub show_moves: bool,
+    /// Moved lines per path, computed from every file's diff on first use
+    moves: Option<HashMap<String, FileMoves>>,
+    /// Show the whole file with changes marked in the gutter instead of the diff
+    pub full_file: bool,
+    /// Show removed lines in place in the full-file view
+    pub show_removed: bool,
+    /// Gather generated and vendored files under a folder of their own
+    pub group_generated: bool,
+    /// Generated and vendored files whose diff was opened anyway
+    revealed: HashSet<String>,
+    /// What the merge preview merges into the base; `None` when reviewing
+    /// a fixed range, which has no base branch to merge into
+    pub merge_source: Option<MergeSource>,
+    /// Files that would conflict when merged into the base, by path
+    pub conflicts: HashMap<String, FileConflict>,
+    /// Show the conflicts of a conflicting file instead of its diff
+    pub conflict_view: bool,
+    /// Tree of the merge preview's result
+    merge_tree: Option<String>,
+    /// Diff the would-be merge result against the base tip, as a PR shows
+    /// once the branch is brought up to date, instead of the branch against
+    /// the merge-base
+    pub rebased_preview: bool,
+    /// Commits on the base branch since the merge-base
+    pub behind: usize,
+    /// Short notice shown over the footer until it expires
+    pub toast: Option<(String, Instant)>,
+    /// Allow staging and unstaging; otherwise prdiff never writes to the repo
+    pub interactive: bool,
+    pub line_selection: Option<LineSelection>,
+    /// Discarded changes, oldest first
+    discarded: Vec<Discard>,
+    /// Title and text of an error that needs more room than a toast, like
+    /// the output of a failed commit hook
+    pub error_pane: Option<(String, String)>,
+    /// Files marked as reviewed on this branch, or in this fixed range
+    pub viewed: ViewedMarks,
+    /// Review comments on this branch, or in this fixed range
+    pub comments: ReviewComments,
+    /// Hash of the two sides of each commented file its comments were last
+    /// anchored in, so unchanged files aren't searched again
+    comment_anchors: HashMap<String, u64>,
+    /// Comment being written in the editor, and the index of the one it
+    /// replaces
+    comment_draft: Option<(Comment, Option<usize>)>,
 }
 
 impl App {
-    pub fn new(base_branch: Option<String>, theme: Theme) -> Result<Self> {
-        let base = git::detect_base_branch(base_branch)?;
-        let merge_base = git::get_merge_base(&base)?;
-        let files = git::get_changed_files(&merge_base)?;
-        let tree = tree::build_tree(&files);
+    pub fn new(
+        backend: Arc<dyn GitBackend>,
+        base_branch: Option<String>,
+        range_spec: Option<String>,
+        theme: Theme,
+        diff_options: DiffOptions,
+    ) -> Result<Self> {
+        let (base, range) = match &range_spec {
+            Some(spec) => (spec.clone(), git::resolve_range(backend.as_ref(), spec)?),
+            None => {
+                let base = git::detect_base_branch(backend.as_ref(), base_branch)?;
+                let merge_base = backend.merge_base("HEAD", &base)?;
+                (base, DiffRange::worktree(merge_base))
+            }
+        };
+        let files = backend.changed_files(&range, &diff_options)?;
+        let commits = load_commits(backend.as_ref(), &range);
+        let file_layers = match range.to {
+            Some(_) => HashMap::new(),
+            None => backend
+                .file_layers(&range.from, &diff_options)
+                .unwrap_or_default(),
+        };
+        let tree = tree::build_tree(&files, true);
         let editor = env::var("PRDIFF_EDITOR")
             .or_else(|_| env::var("EDITOR"))
             .unwrap_or_else(|_| "zed".to_string());
 
-        let mut expanded = HashSet::new();
-        tree::expand_all_dirs(&tree, "", &mut expanded);
+        let expanded = tree::default_expanded(&tree);
+        let merge_source = range_spec.is_none().then_some(MergeSource::Head);
+        let behind = merge_source.map_or(0, |_| count_behind(backend.as_ref(), &range, &base));
+        let viewed = ViewedMarks::load(review_file(
+            backend.as_ref(),
+            range_spec.as_deref(),
+            "viewed",
+        ));
+        let comments = ReviewComments::load(review_file(
+            backend.as_ref(),
+            range_spec.as_deref(),
+            "comments",
+        ));
 
-        // Spawn background watcher for git changes
-        let watcher = GitWatcher::spawn(base.clone(), merge_base.clone(), files.clone());
+        // Spawn background watcher for git changes; a fixed range never changes.
+        let watcher = range.to.is_none().then(|| {
+            GitWatcher::spawn(
+                backend.clone(),
+                base.clone(),
+                range.from.clone(),
+                files.clone(),
+                diff_options.clone(),
+                merge_source,
+            )
+        });
 
-        Ok(Self {
+        let mut app = Self {
             files,
             tree,
             expanded,
@@ -102,34 +291,88 @@ impl App {
             scroll_offset: 0,
             diff_scroll: 0,
             diff_line_count: 0,
+            diff_comment_rows: HashMap::new(),
             diff_cache: HashMap::new(),
             diff_source_cache: HashMap::new(),
             highlighted_cache: HashMap::new(),
-            merge_base,
+            range,
             base_branch: base,
+            range_spec,
+            commits,
+            commit_panel: None,
+            commit_filter: None,
+            layer: DiffLayer::All,
+            show_untracked: true,
+            layer_range: None,
+            file_layers,
+            diff_options,
+            backend,
             editor,
             highlighter: Highlighter::new(theme),
             tree_version: 1,
             watcher,
             theme,
             split_percent: 30,
+            show_line_numbers: true,
+            diff_view: DiffViewMode::Unified,
+            show_hex: false,
             branch_modal: None,
-        })
+            whitespace_menu: false,
+            show_moves: false,
+            moves: None,
+            full_file: false,
+            show_removed: false,
+            group_generated: true,
+            revealed: HashSet::new(),
+            merge_source,
+            conflicts: HashMap::new(),
+            conflict_view: false,
+            merge_tree: None,
+            rebased_preview: false,
+            behind,
+            toast: None,
+            interactive: false,
+            line_selection: None,
+            discarded: Vec::new(),
+            error_pane: None,
+            viewed,
+            comments,
+            comment_anchors: HashMap::new(),
+            comment_draft: None,
+        };
+        let files = app.files.clone();
+        app.check_viewed(&files, None);
+        app.reanchor_comments(&files, None);
+        Ok(app)
     }
 
     /// Check for updates from the background watcher (non-blocking)
     pub fn check_for_changes(&mut self) {
         // Receive any updates from the background watcher (non-blocking)
-        while let Some(msg) = self.watcher.try_recv() {
+        while let Some(msg) = self.watcher.as_ref().and_then(GitWatcher::try_recv) {
             match msg {
                 WatcherMessage::FilesChanged {
                     files,
+                    file_layers,
                     merge_base,
                     invalidate_all,
                     invalidate_paths,
                 } => {
+                    self.file_layers = file_layers;
                     self.apply_file_changes(files, merge_base, invalidate_all, invalidate_paths);
                 }
+                WatcherMessage::MergeChecked { preview } => {
+                    self.set_merge_preview(Some(preview));
+                }
+                WatcherMessage::BaseMoved { from, to } => {
+                    let count = self.backend.commits(&from, &to).map_or(0, |c| c.len());
+                    let commits = if count == 1 { "commit" } else { "commits" };
+                    let mut text = format!("{} moved: {count} new {commits}", self.base_branch);
+                    if !self.rebased_preview {
+                        text.push_str(" (R: rebased preview)");
+                    }
+                    self.show_toast(&text);
+                }
             }
         }
     }
@@ -143,25 +386,82 @@ impl App {
     ) {
         // Invalidate caches
         if invalidate_all {
-            self.diff_cache.clear();
-            self.diff_source_cache.clear();
-            self.highlighted_cache.clear();
+            self.clear_diff_caches();
         } else {
+            if self
+                .line_selection
+                .as_ref()
+                .is_some_and(|s| invalidate_paths.contains(&s.path))
+            {
+                self.line_selection = None;
+            }
             for path in &invalidate_paths {
                 self.diff_cache.remove(path);
                 self.diff_source_cache.remove(path);
                 self.highlighted_cache.remove(path);
             }
+            self.invalidate_moves();
+        }
+
+        // The branch diff only changes with the merge-base or the files'
+        // contents, not when something is staged or committed.
+        let merge_base_moved = merge_base != self.range.from;
+        self.range = DiffRange::worktree(merge_base);
+        let (mut viewed_reloaded, mut comments_reloaded) = (false, false);
+        if invalidate_all {
+            // HEAD may be on another branch now, with marks and comments of its own.
+            let spec = self.range_spec.as_deref();
+            let viewed_file = review_file(self.backend.as_ref(), spec, "viewed");
+            if viewed_file.as_deref() != self.viewed.file() {
+                self.viewed = ViewedMarks::load(viewed_file);
+                viewed_reloaded = true;
+            }
+            let comments_file = review_file(self.backend.as_ref(), spec, "comments");
+            if comments_file.as_deref() != self.comments.file() {
+                self.comments = ReviewComments::load(comments_file);
+                self.comment_anchors.clear();
+                comments_reloaded = true;
+            }
+        }
+        let recheck_all = merge_base_moved || viewed_reloaded;
+        self.check_viewed(&files, (!recheck_all).then_some(&invalidate_paths));
+        let reanchor_all = merge_base_moved || comments_reloaded;
+        self.reanchor_comments(&files, (!reanchor_all).then_some(&invalidate_paths));
+        if invalidate_all {
+            // A selected slice only stays meaningful while the commit list is unchanged.
+            let commits = load_commits(self.backend.as_ref(), &self.range);
+            if commits != self.commits {
+                self.commits = commits;
+                self.commit_filter = None;
+                self.commit_panel = None;
+            }
+            self.behind = count_behind(self.backend.as_ref(), &self.range, &self.base_branch);
+            self.layer_range = self.compute_layer_range();
         }
+        let mut files = files;
+        if self.commit_filter.is_some() || self.layer_range.is_some() {
+            match self
+                .backend
+                .changed_files(self.view_range(), &self.diff_options)
+            {
+                Ok(sliced) => files = sliced,
+                Err(_) => return,
+            }
+        }
+        self.set_files(files);
+    }
 
+    /// Replace the file list, keeping expanded directories and the selected
+    /// path where they still exist.
+    fn set_files(&mut self, files: Vec<FileEntry>) {
         let old_selected = self.selected_path();
         let mut old_dirs = HashSet::new();
         tree::expand_all_dirs(&self.tree, "", &mut old_dirs);
 
-        self.merge_base = merge_base;
         self.files = files;
-        self.tree = tree::build_tree(&self.files);
+        self.tree = tree::build_tree(&self.files, self.group_generated);
         self.tree_version = self.tree_version.wrapping_add(1);
+        self.invalidate_moves();
 
         // Preserve user expand/collapse state for existing directories, but default-expand
         // any newly introduced directory nodes.
@@ -172,7 +472,10 @@ impl App {
             .intersection(&new_dirs)
             .cloned()
             .collect::<HashSet<_>>();
-        for dir in new_dirs.difference(&old_dirs) {
+        for dir in new_dirs
+            .difference(&old_dirs)
+            .filter(|d| *d != tree::GENERATED_DIR)
+        {
             self.expanded.insert(dir.clone());
         }
 
@@ -207,137 +510,2092 @@ impl App {
         }
     }
 
-    pub fn tree_version(&self) -> u64 {
-        self.tree_version
+    fn clear_diff_caches(&mut self) {
+        self.line_selection = None;
+        self.diff_cache.clear();
+        self.diff_source_cache.clear();
+        self.highlighted_cache.clear();
+        self.moves = None;
     }
 
-    pub fn visible_items(&self) -> Vec<(usize, String, &TreeNode)> {
-        let mut items = Vec::new();
-        tree::collect_visible(&self.tree, "", 0, &self.expanded, &mut items);
-        items
+    /// Drop detected moves, and the highlighting they went into when they
+    /// are shown, since a change to one file can move lines in any other.
+    fn invalidate_moves(&mut self) {
+        if self.moves.take().is_some() && self.show_moves {
+            self.highlighted_cache.clear();
+        }
     }
 
-    pub fn selected_path(&self) -> Option<String> {
-        let visible = self.visible_items();
-        visible.get(self.cursor).map(|(_, path, _)| path.clone())
+    /// The range diffs are currently computed over: a commit slice if one is
+    /// selected, then the chosen layer, otherwise the whole review range.
+    fn view_range(&self) -> &DiffRange {
+        self.commit_filter
+            .as_ref()
+            .map(|(_, range)| range)
+            .or(self.layer_range.as_ref())
+            .unwrap_or(&self.range)
     }
 
-    pub fn toggle_expand(&mut self) {
-        let dir_path = {
-            let visible = self.visible_items();
-            match visible.get(self.cursor) {
-                Some((_, path, TreeNode::Directory { .. })) => Some(path.clone()),
-                _ => None,
+    /// Range for the selected layer and untracked toggle, or `None` when that
+    /// is the whole branch diff.
+    fn compute_layer_range(&self) -> Option<DiffRange> {
+        if self.rebased_preview {
+            if let (Some(tree), Ok(tip)) =
+                (&self.merge_tree, self.backend.rev_parse(&self.base_branch))
+            {
+                return Some(DiffRange::commits(tip, tree.as_str()));
             }
+        }
+        if self.range.to.is_some() || (self.layer == DiffLayer::All && self.show_untracked) {
+            return None;
+        }
+        let head = || self.backend.rev_parse("HEAD").ok();
+        Some(match self.layer {
+            DiffLayer::All => DiffRange {
+                untracked: false,
+                ..self.range.clone()
+            },
+            DiffLayer::Committed => DiffRange::commits(self.range.from.as_str(), head()?),
+            DiffLayer::Staged => DiffRange {
+                work: WorkState::Index,
+                untracked: false,
+                ..DiffRange::worktree(head()?)
+            },
+            DiffLayer::Unstaged => DiffRange {
+                work: WorkState::Unstaged,
+                untracked: self.show_untracked,
+                ..DiffRange::worktree(head()?)
+            },
+        })
+    }
+
+    /// Show the next layer of the branch diff. Not available for fixed ranges.
+    pub fn cycle_layer(&mut self) {
+        if self.range.to.is_some() {
+            return;
+        }
+        self.layer = self.layer.next();
+        self.rebased_preview = false;
+        self.reload_view();
+    }
+
+    pub fn toggle_untracked(&mut self) {
+        if self.range.to.is_some() {
+            return;
+        }
+        self.show_untracked = !self.show_untracked;
+        self.reload_view();
+    }
+
+    /// Recompute the file list after the layer selection changed. A commit
+    /// slice is dropped since it can't be combined with a layer.
+    fn reload_view(&mut self) {
+        self.commit_filter = None;
+        self.commit_panel = None;
+        self.layer_range = self.compute_layer_range();
+        let Ok(files) = self
+            .backend
+            .changed_files(self.view_range(), &self.diff_options)
+        else {
+            return;
         };
-        if let Some(path) = dir_path {
-            if self.expanded.contains(&path) {
-                self.expanded.remove(&path);
+        self.clear_diff_caches();
+        self.set_files(files);
+        self.diff_scroll = 0;
+    }
+
+    /// Recompute stats and diffs with different whitespace handling.
+    pub fn set_whitespace(&mut self, whitespace: Whitespace) {
+        self.diff_options.whitespace = whitespace;
+        self.reload_diff_options();
+    }
+
+    /// Show `lines` of context around changes, at least one.
+    pub fn set_context_lines(&mut self, lines: u32) {
+        self.diff_options.context_lines = lines.max(1);
+        self.reload_diff_options();
+    }
+
+    /// Switch to the next diff algorithm.
+    pub fn cycle_algorithm(&mut self) {
+        self.diff_options.algorithm = self.diff_options.algorithm.next();
+        self.reload_diff_options();
+    }
+
+    /// Recompute the file list and diffs after `diff_options` changed.
+    fn reload_diff_options(&mut self) {
+        let Ok(files) = self
+            .backend
+            .changed_files(self.view_range(), &self.diff_options)
+        else {
+            return;
+        };
+        // The watcher lists files with its own copy of the options.
+        if self.range.to.is_none() {
+            let whole = if self.view_range() == &self.range {
+                Ok(files.clone())
             } else {
-                self.expanded.insert(path);
+                self.backend.changed_files(&self.range, &self.diff_options)
+            };
+            if let Ok(whole) = whole {
+                self.spawn_watcher(whole);
+            }
+        }
+        self.clear_diff_caches();
+        self.set_files(files);
+        self.diff_scroll = 0;
+    }
+
+    /// Restart the watcher from `files`, the whole branch diff.
+    fn spawn_watcher(&mut self, files: Vec<FileEntry>) {
+        self.watcher = Some(GitWatcher::spawn(
+            self.backend.clone(),
+            self.base_branch.clone(),
+            self.range.from.clone(),
+            files,
+            self.diff_options.clone(),
+            self.merge_source,
+        ));
+    }
+
+    /// Take the conflicts and merged tree of a merge preview; `None` when
+    /// it couldn't run.
+    fn set_merge_preview(&mut self, preview: Option<MergePreview>) {
+        let (tree, conflicts) = match preview {
+            Some(preview) => (Some(preview.tree), conflicts_by_path(preview.conflicts)),
+            None => (None, HashMap::new()),
+        };
+        if conflicts != self.conflicts {
+            if self.conflict_view {
+                self.highlighted_cache.clear();
             }
+            self.conflicts = conflicts;
+            // The tree shows a badge on conflicting files.
             self.tree_version = self.tree_version.wrapping_add(1);
         }
+        if tree != self.merge_tree {
+            self.merge_tree = tree;
+            if self.rebased_preview {
+                self.reload_view();
+            }
+        }
     }
 
-    pub fn collapse_selected(&mut self) {
-        let path = {
+    /// Include uncommitted changes in the merge preview, or merge HEAD only.
+    pub fn toggle_merge_worktree(&mut self) {
+        let Some(source) = self.merge_source else {
+            return;
+        };
+        let source = match source {
+            MergeSource::Head => MergeSource::Worktree,
+            MergeSource::Worktree => MergeSource::Head,
+        };
+        self.merge_source = Some(source);
+        // The watcher reruns the preview as it starts.
+        if self.watcher.is_some() {
+            if let Ok(files) = self.backend.changed_files(&self.range, &self.diff_options) {
+                self.spawn_watcher(files);
+            }
+        }
+    }
+
+    /// Show the would-be merge result against the base tip, or go back to
+    /// the branch against the merge-base.
+    pub fn toggle_rebased_preview(&mut self) {
+        if self.merge_tree.is_none() && !self.rebased_preview {
+            return;
+        }
+        self.rebased_preview = !self.rebased_preview;
+        self.reload_view();
+    }
+
+    /// Drop the toast once it has been up long enough; `true` if it went.
+    pub fn expire_toast(&mut self) -> bool {
+        let expired = self
+            .toast
+            .as_ref()
+            .is_some_and(|(_, shown)| shown.elapsed() >= TOAST_DURATION);
+        if expired {
+            self.toast = None;
+        }
+        expired
+    }
+
+    /// Mark the selected file, or every file under the selected directory,
+    /// as viewed; unmark them when they all already are.
+    pub fn toggle_viewed(&mut self) {
+        let paths: Vec<String> = {
             let visible = self.visible_items();
-            visible.get(self.cursor).map(|(_, path, _)| path.clone())
+            let Some((_, _, node)) = visible.get(self.cursor) else {
+                return;
+            };
+            let mut files = Vec::new();
+            tree::files_under(node, &mut files);
+            files.into_iter().map(|f| f.path.clone()).collect()
         };
-        if let Some(path) = path {
-            if self.expanded.remove(&path) {
-                self.tree_version = self.tree_version.wrapping_add(1);
+        if paths.is_empty() {
+            return;
+        }
+        // Viewed files collapse, and unmarked ones open again.
+        for path in &paths {
+            self.revealed.remove(path);
+            self.highlighted_cache.remove(path);
+        }
+        if paths.iter().all(|path| self.viewed.contains(path)) {
+            for path in &paths {
+                self.viewed.unmark(path);
+            }
+        } else {
+            // Marks are for the whole range, whichever part of it is shown.
+            let files = if self.view_range() == &self.range {
+                self.files.clone()
+            } else {
+                self.backend
+                    .changed_files(&self.range, &self.diff_options)
+                    .unwrap_or_default()
+            };
+            for file in files.iter().filter(|f| paths.contains(&f.path)) {
+                let hash = self.viewed_hash(file);
+                self.viewed.mark(&file.path, hash);
             }
         }
+        self.save_viewed();
     }
 
-    pub fn ensure_highlighted(&mut self, path: &str) {
-        if self.highlighted_cache.contains_key(path) {
+    /// Drop the viewed marks of files whose diff changed since they were
+    /// marked, checking those in `paths` or every one. `files` are the
+    /// files of the whole range.
+    fn check_viewed(&mut self, files: &[FileEntry], paths: Option<&HashSet<String>>) {
+        let mut changed = false;
+        for path in self.viewed.paths() {
+            if paths.is_some_and(|paths| !paths.contains(&path)) {
+                continue;
+            }
+            let hash = files
+                .iter()
+                .find(|f| f.path == path)
+                .map(|f| self.viewed_hash(f));
+            if hash != self.viewed.hash(&path) {
+                self.viewed.unmark(&path);
+                self.highlighted_cache.remove(&path);
+                changed = true;
+            }
+        }
+        if changed {
+            self.save_viewed();
+        }
+    }
+
+    /// Hash of `file`'s diff across the whole range, computed the same way
+    /// whatever whitespace, context and algorithm the view uses.
+    fn viewed_hash(&self, file: &FileEntry) -> u64 {
+        let defaults = DiffOptions::default();
+        let options = DiffOptions {
+            whitespace: defaults.whitespace,
+            algorithm: defaults.algorithm,
+            context_lines: defaults.context_lines,
+            ..self.diff_options.clone()
+        };
+        viewed::diff_hash(&self.backend.file_diff(&self.range, file, &options).1)
+    }
+
+    fn save_viewed(&mut self) {
+        self.tree_version = self.tree_version.wrapping_add(1);
+        if let Err(err) = self.viewed.save() {
+            self.show_toast(&format!("Could not save viewed files: {err:#}"));
+        }
+    }
+
+    /// Stage the selected file, or every file under the selected directory;
+    /// unstage them when `stage` is unset. The view is refreshed right away
+    /// rather than when the watcher notices.
+    pub fn stage_selected(&mut self, stage: bool) {
+        if !self.interactive {
+            self.show_toast("Read-only: start prdiff with --interactive to stage files");
+            return;
+        }
+        if self.range.to.is_some() {
+            self.show_toast("Nothing to stage when reviewing a fixed range");
             return;
         }
+        // Paths to pass to git by the repository they are in: "" for this
+        // one, otherwise the submodule's path.
+        let mut paths: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut invalidate = HashSet::new();
+        {
+            let visible = self.visible_items();
+            let Some((_, _, node)) = visible.get(self.cursor) else {
+                return;
+            };
+            let mut files = Vec::new();
+            tree::files_under(node, &mut files);
+            for file in files {
+                invalidate.insert(file.path.clone());
+                let (repo, file) = match &file.submodule {
+                    Some(scope) => (scope.path.clone(), &scope.file),
+                    None => (String::new(), file),
+                };
+                let repo_paths = paths.entry(repo).or_default();
+                if matches!(file.status, FileStatus::Renamed) {
+                    repo_paths.extend(file.old_path.clone());
+                }
+                repo_paths.push(file.path.clone());
+            }
+        }
+
+        let result = paths.iter().try_for_each(|(repo, paths)| {
+            self.in_repo(repo, |backend| {
+                if stage {
+                    backend.stage(paths)
+                } else {
+                    backend.unstage(paths)
+                }
+            })
+        });
+        if let Err(err) = result {
+            self.show_toast(&format!("{err:#}"));
+        }
+        self.refresh_index_state(invalidate);
+    }
+
+    /// Run `f` with the backend of `repo`: this repository when empty,
+    /// otherwise the submodule at that path.
+    fn in_repo<T>(&self, repo: &str, f: impl FnOnce(&dyn GitBackend) -> Result<T>) -> Result<T> {
+        if repo.is_empty() {
+            f(self.backend.as_ref())
+        } else {
+            f(self.backend.open_submodule(repo)?.as_ref())
+        }
+    }
 
-        if !self.diff_cache.contains_key(path) {
-            let (source, diff) = git::get_file_diff(&self.merge_base, path);
-            self.diff_cache.insert(path.to_string(), diff);
-            self.diff_source_cache.insert(path.to_string(), source);
+    /// Pick up a change prdiff made to the index or working tree right away,
+    /// rather than when the watcher notices.
+    fn refresh_index_state(&mut self, invalidate: HashSet<String>) {
+        if let Ok(layers) = self
+            .backend
+            .file_layers(&self.range.from, &self.diff_options)
+        {
+            self.file_layers = layers;
+        }
+        if let Ok(files) = self.backend.changed_files(&self.range, &self.diff_options) {
+            let merge_base = self.range.from.clone();
+            self.apply_file_changes(files, merge_base, false, invalidate);
         }
+    }
 
-        let Some(diff_lines) = self.diff_cache.get(path) else {
+    /// Start picking lines of the selected file's diff, from the first change
+    /// at or below the top of the diff pane.
+    pub fn start_line_selection(&mut self) {
+        let Some(path) = self.selected_path() else {
             return;
         };
+        if self.view_mode(&path) != DiffViewMode::Unified
+            || self.showing_full_file(&path)
+            || self.showing_conflict(&path).is_some()
+        {
+            self.show_toast("Lines are picked in the unified diff (v)");
+            return;
+        }
+        match self.first_change_row(&path) {
+            Some(row) => {
+                self.line_selection = Some(LineSelection {
+                    path,
+                    anchor: row,
+                    cursor: row,
+                })
+            }
+            None => self.show_toast("No changes below the top of the diff pane"),
+        }
+    }
 
-        let highlighted = self.highlighter.highlight_diff(diff_lines, path);
-        self.highlighted_cache.insert(path.to_string(), highlighted);
+    /// The first added or removed row of `path`'s unified diff from the
+    /// top of the diff pane down.
+    fn first_change_row(&self, path: &str) -> Option<usize> {
+        let diff = self.diff_cache.get(path)?;
+        (self.diff_scroll..patch::row_count(diff)).find(|&row| patch::is_change_row(diff, row))
     }
 
-    pub fn get_highlighted(&self, path: &str) -> &[HighlightedLine] {
-        self.highlighted_cache
-            .get(path)
-            .map(|v| v.as_slice())
-            .unwrap_or(&[])
+    /// Move the moving end of the line selection one row.
+    pub fn move_line_cursor(&mut self, down: bool) {
+        let Some(selection) = &mut self.line_selection else {
+            return;
+        };
+        let Some(diff) = self.diff_cache.get(&selection.path) else {
+            return;
+        };
+        let rows = patch::row_count(diff);
+        if down {
+            selection.cursor = (selection.cursor + 1).min(rows.saturating_sub(1));
+        } else {
+            selection.cursor = selection.cursor.saturating_sub(1);
+        }
     }
 
-    pub fn get_diff_source(&self, path: &str) -> Option<DiffSource> {
-        self.diff_source_cache.get(path).copied()
+    /// Patch of the picked lines of the selected file, or else of the hunk
+    /// at the top of the diff pane, with the file's tree path and the
+    /// repository the patch applies in. Errors are hints for the toast.
+    fn picked_patch(
+        &self,
+        reverse: bool,
+    ) -> std::result::Result<(String, String, String), &'static str> {
+        if !self.interactive {
+            return Err("Read-only: start prdiff with --interactive to stage hunks");
+        }
+        let layer_view =
+            self.range.to.is_none() && self.commit_filter.is_none() && !self.rebased_preview;
+        if !layer_view || !matches!(self.layer, DiffLayer::Staged | DiffLayer::Unstaged) {
+            return Err("Switch to the staged or unstaged layer (L) to stage hunks");
+        }
+        if self.diff_options.whitespace != Whitespace::default() {
+            return Err("Stop ignoring whitespace (w) to stage hunks");
+        }
+        let path = self.selected_path().ok_or("Select a file to stage hunks")?;
+        let file = self
+            .files
+            .iter()
+            .find(|f| f.path == path)
+            .ok_or("Select a file to stage hunks")?;
+        let diff = self.diff_cache.get(&path);
+        if !matches!(file.status, FileStatus::Modified) || diff.is_none_or(|d| d.binary) {
+            return Err("New, deleted, renamed and binary files are staged whole (s/u)");
+        }
+        if self.showing_full_file(&path) || self.showing_conflict(&path).is_some() {
+            return Err("Hunks are staged from the diff view (f, X)");
+        }
+        let diff = diff.ok_or("Select a file to stage hunks")?;
+        let (repo, target) = match &file.submodule {
+            Some(scope) => (scope.path.clone(), scope.file.path.as_str()),
+            None => (String::new(), path.as_str()),
+        };
+        let patch = match self.line_selection.as_ref().filter(|s| s.path == path) {
+            Some(selection) => {
+                let (first, last) = selection.rows();
+                let picked: HashSet<(usize, usize)> = (first..=last)
+                    .filter_map(|row| patch::row_line(diff, row))
+                    .collect();
+                patch::build_patch(target, diff, |h, i| picked.contains(&(h, i)), reverse)
+            }
+            None => {
+                let hunk = self.current_hunk(&path).ok_or("No hunk to stage")?;
+                patch::build_patch(target, diff, |h, _| h == hunk, reverse)
+            }
+        };
+        let patch = patch.ok_or("No changed lines picked")?;
+        Ok((path, repo, patch))
     }
 
-    /// Returns the (editor, path) to open, if a file is selected.
-    /// The caller is responsible for terminal restore/re-enter around spawning.
-    pub fn editor_command(&self) -> Option<(String, String)> {
-        let visible = self.visible_items();
-        match visible.get(self.cursor) {
-            Some((_, _, TreeNode::File(f))) => Some((self.editor.clone(), f.path.clone())),
-            _ => None,
+    /// Stage the picked lines or the hunk at the top of the diff pane in the
+    /// unstaged layer; unstage them in the staged layer.
+    pub fn stage_lines(&mut self) {
+        let reverse = self.layer == DiffLayer::Staged;
+        let (path, repo, patch) = match self.picked_patch(reverse) {
+            Ok(picked) => picked,
+            Err(hint) => return self.show_toast(hint),
+        };
+        match self.in_repo(&repo, |backend| {
+            backend.apply_patch(&patch, ApplyTo::Index, reverse)
+        }) {
+            Ok(()) => self.refresh_index_state(HashSet::from([path])),
+            Err(err) => self.show_toast(&format!("{err:#}")),
         }
     }
 
-    pub fn open_branch_modal(&mut self) {
-        let branches = git::list_branches().unwrap_or_default();
-        if !branches.is_empty() {
-            self.branch_modal = Some(BranchModal::new(branches));
+    /// Throw away the picked unstaged lines or the unstaged hunk at the top
+    /// of the diff pane. `undo_discard` puts them back.
+    pub fn discard_lines(&mut self) {
+        if self.layer == DiffLayer::Staged && self.interactive {
+            return self
+                .show_toast("Only unstaged changes can be discarded; unstage them first (S)");
+        }
+        let (path, repo, patch) = match self.picked_patch(true) {
+            Ok(picked) => picked,
+            Err(hint) => return self.show_toast(hint),
+        };
+        match self.in_repo(&repo, |backend| {
+            backend.apply_patch(&patch, ApplyTo::Worktree, true)
+        }) {
+            Ok(()) => {
+                self.discarded.push(Discard {
+                    path: path.clone(),
+                    repo,
+                    patch,
+                });
+                self.show_toast("Discarded (Z: undo)");
+                self.refresh_index_state(HashSet::from([path]));
+            }
+            Err(err) => self.show_toast(&format!("{err:#}")),
         }
     }
 
-    pub fn switch_base_branch(&mut self, branch: &str) {
-        let resolved = match git::resolve_base_ref(branch) {
-            Ok(r) => r,
-            Err(_) => branch.to_string(),
+    /// Put the last discarded change back into the working tree.
+    pub fn undo_discard(&mut self) {
+        let Some(discard) = self.discarded.pop() else {
+            return self.show_toast("Nothing discarded to undo");
         };
-        let merge_base = match git::get_merge_base(&resolved) {
-            Ok(mb) => mb,
-            Err(_) => return,
+        match self.in_repo(&discard.repo, |backend| {
+            backend.apply_patch(&discard.patch, ApplyTo::Worktree, false)
+        }) {
+            Ok(()) => {
+                self.show_toast(&format!(
+                    "Restored the discarded change to {}",
+                    discard.path
+                ));
+                self.refresh_index_state(HashSet::from([discard.path]));
+            }
+            Err(err) => {
+                self.show_toast(&format!("{err:#}"));
+                self.discarded.push(discard);
+            }
+        }
+    }
+
+    pub fn show_toast(&mut self, text: &str) {
+        self.toast = Some((text.to_string(), Instant::now()));
+    }
+
+    /// Write the commit message file for the editor: the message to start
+    /// from, then the files the commit will contain as comments. When
+    /// amending it starts from HEAD's message, unless a failed commit or
+    /// amend left one. Returns its path, or `None`
+    /// with a toast saying why there is nothing to commit.
+    pub fn prepare_commit(&mut self, amend: bool) -> Option<PathBuf> {
+        if !self.interactive {
+            self.show_toast("Read-only: start prdiff with --interactive to commit");
+            return None;
+        }
+        if self.range.to.is_some() {
+            self.show_toast("Nothing to commit when reviewing a fixed range");
+            return None;
+        }
+        if amend && self.commits.is_empty() {
+            self.show_toast("No commit on this branch to amend");
+            return None;
+        }
+        let parent = if amend { "HEAD^" } else { "HEAD" };
+        let staged = DiffRange {
+            work: WorkState::Index,
+            untracked: false,
+            ..DiffRange::worktree(parent)
         };
-        let files = match git::get_changed_files(&merge_base) {
-            Ok(f) => f,
-            Err(_) => return,
+        let files = self
+            .backend
+            .changed_files(&staged, &self.diff_options)
+            .unwrap_or_default();
+        if files.is_empty() && !amend {
+            self.show_toast("Nothing staged to commit (s, S)");
+            return None;
+        }
+
+        let path = match self.backend.git_dir() {
+            Ok(dir) => dir.join(COMMIT_MESSAGE_FILE),
+            Err(err) => {
+                self.show_toast(&format!("{err:#}"));
+                return None;
+            }
+        };
+        // A message left by a failed commit or amend is offered again.
+        let left = fs::read_to_string(&path)
+            .ok()
+            .map(|text| strip_comments(&text))
+            .unwrap_or_default();
+        let message = if amend && left.is_empty() {
+            self.backend.commit_message("HEAD").unwrap_or_default()
+        } else {
+            left
+        };
+        let mut text = format!("{message}\n\n");
+        text.push_str("# Write the commit message above. Lines starting with '#' are left out,\n");
+        text.push_str("# and an empty message aborts the commit.\n#\n");
+        let action = if amend {
+            "Amending HEAD with"
+        } else {
+            "Committing"
         };
+        let count = if files.len() == 1 {
+            "1 file".to_string()
+        } else {
+            format!("{} files", files.len())
+        };
+        text.push_str(&format!("# {action} {count}:\n"));
+        for file in &files {
+            text.push_str(&format!(
+                "#   {} {} +{}/-{}\n",
+                file.status.symbol(),
+                file.display_path(),
+                file.additions,
+                file.deletions
+            ));
+        }
+        if let Err(err) = fs::write(&path, text) {
+            self.show_toast(&format!("Failed to write {}: {err}", path.display()));
+            return None;
+        }
+        Some(path)
+    }
 
-        self.base_branch = resolved;
-        self.merge_base = merge_base.clone();
-        self.diff_cache.clear();
-        self.diff_source_cache.clear();
-        self.highlighted_cache.clear();
-        self.tree = tree::build_tree(&files);
-        self.tree_version = self.tree_version.wrapping_add(1);
+    /// Commit with the message written to `message_file`. A failed commit
+    /// opens the error pane with what git and its hooks printed; the
+    /// watcher picks up the new HEAD of a successful one.
+    pub fn finish_commit(&mut self, message_file: &Path, amend: bool) {
+        let message = fs::read_to_string(message_file)
+            .map(|text| strip_comments(&text))
+            .unwrap_or_default();
+        if message.is_empty() {
+            let _ = fs::remove_file(message_file);
+            return self.show_toast("Commit aborted: empty message");
+        }
+        match self.backend.commit(message_file, amend) {
+            Ok(()) => {
+                let _ = fs::remove_file(message_file);
+                let subject = message.lines().next().unwrap_or_default();
+                let action = if amend { "Amended" } else { "Committed" };
+                self.show_toast(&format!("{action}: {subject}"));
+            }
+            Err(err) => {
+                let title = if amend {
+                    "Amend failed"
+                } else {
+                    "Commit failed"
+                };
+                self.error_pane = Some((title.to_string(), format!("{err:#}")));
+            }
+        }
+    }
+
+    /// The shell command of the editor for commit messages and comments.
+    pub fn commit_editor(&self) -> String {
+        git::commit_editor()
+    }
 
-        let mut new_expanded = HashSet::new();
-        tree::expand_all_dirs(&self.tree, "", &mut new_expanded);
-        self.expanded = new_expanded;
+    /// Comments are anchored in the diff of the whole range, so they only
+    /// show, and can only be written, when that is what the view shows.
+    fn comments_shown(&self) -> bool {
+        self.view_range() == &self.range
+    }
 
-        self.files = files.clone();
-        self.cursor = 0;
-        self.scroll_offset = 0;
-        self.diff_scroll = 0;
+    /// Comments to show on `path`'s diff.
+    pub fn file_comments(&self, path: &str) -> Vec<&Comment> {
+        if !self.comments_shown() || self.showing_conflict(path).is_some() {
+            return Vec::new();
+        }
+        self.comments
+            .comments
+            .iter()
+            .filter(|c| c.path == path)
+            .collect()
+    }
 
-        self.respawn_watcher();
+    /// The comments shown under each row of `path`'s diff pane: under the
+    /// last row at or before their last line.
+    pub fn placed_comments(&self, path: &str) -> HashMap<usize, Vec<&Comment>> {
+        let comments = self.file_comments(path);
+        if comments.is_empty() {
+            return HashMap::new();
+        }
+        let lines = self.get_highlighted(path);
+        let numbers: Vec<(Option<u32>, Option<u32>)> = match self.view_mode(path) {
+            DiffViewMode::Unified => lines
+                .iter()
+                .map(|hl| (hl.old_lineno, hl.new_lineno))
+                .collect(),
+            DiffViewMode::Split => {
+                let line = |idx: Option<usize>| idx.and_then(|i| lines.get(i));
+                self.get_split_rows(path)
+                    .iter()
+                    .map(|row| match *row {
+                        SplitRow::Full(_) => (None, None),
+                        SplitRow::Pair(old, new) => (
+                            line(old).and_then(|hl| hl.old_lineno),
+                            line(new).and_then(|hl| hl.new_lineno),
+                        ),
+                    })
+                    .collect()
+            }
+        };
+        let mut placed: HashMap<usize, Vec<&Comment>> = HashMap::new();
+        for comment in comments {
+            let row = numbers
+                .iter()
+                .rposition(|&(old, new)| {
+                    comment
+                        .side
+                        .lineno(old, new)
+                        .is_some_and(|n| n <= comment.end)
+                })
+                .unwrap_or(0);
+            placed.entry(row).or_default().push(comment);
+        }
+        placed
     }
 
-    fn respawn_watcher(&mut self) {
-        self.watcher = GitWatcher::spawn(
-            self.base_branch.clone(),
-            self.merge_base.clone(),
-            self.files.clone(),
-        );
+    /// Rows of comment text under each row of `path`'s diff pane.
+    pub fn comment_rows(&self, path: &str) -> HashMap<usize, usize> {
+        self.placed_comments(path)
+            .into_iter()
+            .map(|(row, comments)| (row, comments.iter().map(|c| c.text.lines().count()).sum()))
+            .collect()
+    }
+
+    /// Write the file for the editor to write a comment in, on the picked
+    /// lines of the selected file or else the first change at the top of
+    /// the diff pane. A comment ending on those lines is edited instead.
+    /// Returns its path, or `None` with a toast saying why not.
+    pub fn prepare_comment(&mut self) -> Option<PathBuf> {
+        if !self.comments_shown() {
+            self.show_toast("Comments are on the whole diff: show all layers and commits (L, c)");
+            return None;
+        }
+        let path = self.selected_path()?;
+        if self.view_mode(&path) != DiffViewMode::Unified
+            || self.showing_full_file(&path)
+            || self.showing_conflict(&path).is_some()
+        {
+            self.show_toast("Comments are written in the unified diff (v)");
+            return None;
+        }
+        let picked = self
+            .line_selection
+            .as_ref()
+            .filter(|s| s.path == path)
+            .map(LineSelection::rows);
+        let Some((first, last)) =
+            picked.or_else(|| self.first_change_row(&path).map(|row| (row, row)))
+        else {
+            self.show_toast("No changes below the top of the diff pane");
+            return None;
+        };
+        let diff = self.diff_cache.get(&path)?;
+        let rows: Vec<(usize, &DiffLine)> = (first..=last)
+            .filter_map(|row| patch::row_line(diff, row))
+            .map(|(h, i)| (h, &diff.hunks[h].lines[i]))
+            .collect();
+        if rows.first().map(|(h, _)| h) != rows.last().map(|(h, _)| h) {
+            self.show_toast("Comment on lines of a single hunk");
+            return None;
+        }
+        // The comment goes under the last line, on the side that has it.
+        let side = match rows.last() {
+            Some((_, line)) if line.new_lineno.is_some() => Side::New,
+            Some(_) => Side::Old,
+            None => {
+                self.show_toast("Pick diff lines to comment on (V)");
+                return None;
+            }
+        };
+        let lines: Vec<(u32, &str)> = rows
+            .iter()
+            .filter_map(|(_, l)| {
+                Some((side.lineno(l.old_lineno, l.new_lineno)?, l.content.as_str()))
+            })
+            .collect();
+        let (start, end) = (lines.first()?.0, lines.last()?.0);
+        let existing = self
+            .comments
+            .comments
+            .iter()
+            .position(|c| c.path == path && c.side == side && (start..=end).contains(&c.end));
+        let comment = match existing {
+            Some(i) => self.comments.comments[i].clone(),
+            None => Comment {
+                path: path.clone(),
+                side,
+                start,
+                end,
+                lines: lines
+                    .iter()
+                    .map(|(_, content)| content.to_string())
+                    .collect(),
+                text: String::new(),
+                outdated: false,
+            },
+        };
+
+        let file = match self.backend.git_dir() {
+            Ok(dir) => dir.join(COMMENT_FILE),
+            Err(err) => {
+                self.show_toast(&format!("{err:#}"));
+                return None;
+            }
+        };
+        let range = if comment.start == comment.end {
+            format!("line {}", comment.start)
+        } else {
+            format!("lines {}-{}", comment.start, comment.end)
+        };
+        let mut text = format!("{}\n\n", comment.text);
+        text.push_str("# Write the comment above. Lines starting with '#' are left out,\n");
+        text.push_str("# and an empty comment removes it.\n#\n");
+        text.push_str(&format!(
+            "# On {path}, {range} of the {} version:\n",
+            comment.side.name()
+        ));
+        for line in &comment.lines {
+            text.push_str(&format!("#   {line}\n"));
+        }
+        if let Err(err) = fs::write(&file, text) {
+            self.show_toast(&format!("Failed to write {}: {err}", file.display()));
+            return None;
+        }
+        self.comment_draft = Some((comment, existing));
+        Some(file)
+    }
+
+    /// Save the comment written to `file`, or remove the one edited when
+    /// it was emptied.
+    pub fn finish_comment(&mut self, file: &Path) {
+        let text = fs::read_to_string(file)
+            .map(|text| strip_comments(&text))
+            .unwrap_or_default();
+        let _ = fs::remove_file(file);
+        let Some((mut comment, existing)) = self.comment_draft.take() else {
+            return;
+        };
+        self.line_selection = None;
+        match (existing, text.is_empty()) {
+            (Some(i), true) => {
+                self.comments.comments.remove(i);
+                self.show_toast("Comment removed");
+            }
+            (None, true) => return self.show_toast("Comment discarded: empty"),
+            (Some(i), false) => self.comments.comments[i].text = text,
+            (None, false) => {
+                comment.text = text;
+                self.comments.comments.push(comment);
+            }
+        }
+        self.save_comments();
+    }
+
+    /// Move the comments on files in `paths`, or on every file, to where
+    /// their lines are now, marking those whose lines changed as outdated.
+    /// `files` are the files of the whole range.
+    fn reanchor_comments(&mut self, files: &[FileEntry], paths: Option<&HashSet<String>>) {
+        let mut changed = false;
+        for path in self.comments.paths() {
+            if paths.is_some_and(|paths| !paths.contains(&path)) {
+                continue;
+            }
+            // A file that dropped out of the diff has nothing to anchor to.
+            let sides = files.iter().find(|f| f.path == path).and_then(|file| {
+                let cached = self
+                    .diff_source_cache
+                    .get(&path)
+                    .filter(|_| self.comments_shown());
+                let source = match cached {
+                    Some(&source) => source,
+                    None => {
+                        self.backend
+                            .file_diff(&self.range, file, &self.diff_options)
+                            .0
+                    }
+                };
+                self.backend.file_sides(&self.range, file, source).ok()
+            });
+            let (old, new) = sides.unwrap_or_default();
+            let mut hasher = DefaultHasher::new();
+            (&old, &new).hash(&mut hasher);
+            let hash = hasher.finish();
+            if self.comment_anchors.insert(path.clone(), hash) == Some(hash) {
+                continue;
+            }
+            let text = |side: Option<Vec<u8>>| {
+                String::from_utf8_lossy(&side.unwrap_or_default()).into_owned()
+            };
+            let (old, new) = (text(old), text(new));
+            let (old, new): (Vec<&str>, Vec<&str>) = (old.lines().collect(), new.lines().collect());
+            for comment in self.comments.comments.iter_mut().filter(|c| c.path == path) {
+                let lines = match comment.side {
+                    Side::Old => &old,
+                    Side::New => &new,
+                };
+                changed |= comments::reanchor(comment, lines);
+            }
+        }
+        if changed {
+            self.save_comments();
+        }
+    }
+
+    fn save_comments(&mut self) {
+        self.tree_version = self.tree_version.wrapping_add(1);
+        if let Err(err) = self.comments.save() {
+            self.show_toast(&format!("Could not save comments: {err:#}"));
+        }
+    }
+
+    /// Switch conflicting files between their diff and their conflicts.
+    pub fn toggle_conflict_view(&mut self) {
+        self.conflict_view = !self.conflict_view;
+        self.highlighted_cache
+            .retain(|path, _| !self.conflicts.contains_key(path));
+        self.diff_scroll = 0;
+    }
+
+    /// The conflict `path` is shown as, in the conflict view.
+    pub fn showing_conflict(&self, path: &str) -> Option<&FileConflict> {
+        self.conflicts.get(path).filter(|_| self.conflict_view)
+    }
+
+    pub fn tree_version(&self) -> u64 {
+        self.tree_version
+    }
+
+    pub fn visible_items(&self) -> Vec<(usize, String, &TreeNode)> {
+        let mut items = Vec::new();
+        tree::collect_visible(&self.tree, "", 0, &self.expanded, &mut items);
+        items
+    }
+
+    pub fn selected_path(&self) -> Option<String> {
+        let visible = self.visible_items();
+        visible.get(self.cursor).map(|(_, path, _)| path.clone())
+    }
+
+    pub fn toggle_expand(&mut self) {
+        let dir_path = {
+            let visible = self.visible_items();
+            match visible.get(self.cursor) {
+                Some((_, path, TreeNode::Directory { .. })) => Some(path.clone()),
+                _ => None,
+            }
+        };
+        if let Some(path) = dir_path {
+            if self.expanded.contains(&path) {
+                self.expanded.remove(&path);
+            } else {
+                self.expanded.insert(path);
+            }
+            self.tree_version = self.tree_version.wrapping_add(1);
+        }
+    }
+
+    pub fn collapse_selected(&mut self) {
+        let path = {
+            let visible = self.visible_items();
+            visible.get(self.cursor).map(|(_, path, _)| path.clone())
+        };
+        if let Some(path) = path {
+            if self.expanded.remove(&path) {
+                self.tree_version = self.tree_version.wrapping_add(1);
+            }
+        }
+    }
+
+    pub fn ensure_highlighted(&mut self, path: &str) {
+        if self.highlighted_cache.contains_key(path) {
+            return;
+        }
+        if let Some(conflict) = self.showing_conflict(path) {
+            let highlighted = self
+                .highlighter
+                .highlight_conflict(conflict, &self.base_branch);
+            self.highlighted_cache.insert(path.to_string(), highlighted);
+            return;
+        }
+        if let Some(file) = self.collapsed_file(path) {
+            let highlighted = highlight::collapsed(file, self.viewed.contains(path));
+            self.highlighted_cache.insert(path.to_string(), highlighted);
+            return;
+        }
+        self.load_diff(path);
+        let hex_view = self.show_hex
+            && self
+                .diff_cache
+                .get(path)
+                .is_some_and(|d| d.binary_info.is_some());
+        if self.show_moves && !hex_view {
+            self.ensure_moves();
+        }
+
+        let Some(diff) = self.diff_cache.get(path) else {
+            return;
+        };
+        let hex;
+        let diff = if hex_view {
+            hex = binary::hex_diff(diff, &self.diff_options);
+            &hex
+        } else {
+            diff
+        };
+
+        let full_file = (self.full_file && !hex_view)
+            .then(|| self.highlight_full_file(path, diff))
+            .flatten();
+        let moves = self.moves.as_ref().filter(|_| self.show_moves && !hex_view);
+        let highlighted = full_file.unwrap_or_else(|| HighlightedDiff {
+            lines: self
+                .highlighter
+                .highlight_diff(diff, path, moves.and_then(|m| m.get(path))),
+            split_rows: highlight::split_rows(diff),
+            changes: highlight::change_starts(diff),
+            full_file: false,
+        });
+        self.highlighted_cache.insert(path.to_string(), highlighted);
+    }
+
+    /// The full-file view of `path`: its new version, or the old one shown
+    /// as removed if it was deleted. `None` for files that have no text to
+    /// show, which keep their diff.
+    fn highlight_full_file(&self, path: &str, diff: &FileDiff) -> Option<HighlightedDiff> {
+        let file = self.files.iter().find(|f| f.path == path)?;
+        if diff.binary || file.is_submodule() {
+            return None;
+        }
+        let source = self.get_diff_source(path).unwrap_or(DiffSource::Worktree);
+        let (old, new) = self
+            .backend
+            .file_sides(self.view_range(), file, source)
+            .ok()?;
+        let deleted = new.is_none();
+        let text = new.or(old.map(|_| Vec::new()))?;
+        let text = String::from_utf8_lossy(&text);
+        let lines: Vec<&str> = text.split_inclusive('\n').collect();
+        let rows = full_file::file_rows(diff, lines.len(), self.show_removed || deleted);
+        Some(HighlightedDiff {
+            lines: self.highlighter.highlight_file(&lines, diff, &rows, path),
+            split_rows: Vec::new(),
+            changes: full_file::change_starts(&rows),
+            full_file: true,
+        })
+    }
+
+    fn load_diff(&mut self, path: &str) {
+        if self.diff_cache.contains_key(path) {
+            return;
+        }
+        let file = self
+            .files
+            .iter()
+            .find(|f| f.path == path)
+            .cloned()
+            .unwrap_or_else(|| FileEntry::new(path, FileStatus::Unknown, 0, 0));
+        let (source, diff) = self
+            .backend
+            .file_diff(self.view_range(), &file, &self.diff_options);
+        self.diff_cache.insert(path.to_string(), diff);
+        self.diff_source_cache.insert(path.to_string(), source);
+    }
+
+    /// Detect moved blocks across all listed files, loading their diffs.
+    /// Skipped for very large file lists, where loading every diff up front
+    /// would stall the UI.
+    fn ensure_moves(&mut self) {
+        if self.moves.is_some() {
+            return;
+        }
+        if self.files.len() > MAX_MOVE_FILES {
+            self.moves = Some(HashMap::new());
+            return;
+        }
+        // Code moved into generated files is the tool's doing, not a move to review.
+        let paths: Vec<String> = self
+            .files
+            .iter()
+            .filter(|f| !f.is_generated())
+            .map(|f| f.path.clone())
+            .collect();
+        for path in &paths {
+            self.load_diff(path);
+        }
+        let diffs: Vec<(&str, &FileDiff)> = paths
+            .iter()
+            .filter_map(|path| Some((path.as_str(), self.diff_cache.get(path)?)))
+            .filter(|(_, diff)| !diff.binary)
+            .collect();
+        self.moves = Some(moves::detect_moves(&diffs));
+    }
+
+    /// The hunk at the top of the diff pane for `path`; the file header
+    /// counts as part of the first hunk.
+    fn current_hunk(&self, path: &str) -> Option<usize> {
+        let diff = self.diff_cache.get(path)?;
+        let row = self.diff_scroll;
+        let line = match self.diff_view {
+            DiffViewMode::Unified => row,
+            DiffViewMode::Split => match self.get_split_rows(path).get(row)? {
+                SplitRow::Full(i) => *i,
+                SplitRow::Pair(old, new) => old.or(*new)?,
+            },
+        };
+        let mut end = diff.header.len();
+        for (i, hunk) in diff.hunks.iter().enumerate() {
+            end += 1 + hunk.lines.len();
+            if line < end {
+                return Some(i);
+            }
+        }
+        diff.hunks.len().checked_sub(1)
+    }
+
+    /// Reveal hidden lines around the hunk at the top of the diff pane,
+    /// reading them from the file itself.
+    pub fn expand_context(&mut self, expand: Expand) {
+        let Some(path) = self.selected_path() else {
+            return;
+        };
+        if self.showing_full_file(&path) {
+            return;
+        }
+        let Some(index) = self.current_hunk(&path) else {
+            return;
+        };
+        let Some(file) = self.files.iter().find(|f| f.path == path) else {
+            return;
+        };
+        let source = self.get_diff_source(&path).unwrap_or(DiffSource::Worktree);
+        // Context comes from the new side; a deleted file has none.
+        let Ok((_, Some(text))) = self.backend.file_sides(self.view_range(), file, source) else {
+            return;
+        };
+        let Some(diff) = self.diff_cache.get_mut(&path).filter(|d| !d.binary) else {
+            return;
+        };
+        if expand::expand_hunk(diff, index, expand, &text) {
+            self.highlighted_cache.remove(&path);
+            self.invalidate_moves();
+        }
+    }
+
+    /// A generated, vendored or viewed file at `path` whose diff stays hidden.
+    fn collapsed_file(&self, path: &str) -> Option<&FileEntry> {
+        self.files
+            .iter()
+            .find(|f| f.path == path)
+            .filter(|f| self.collapses(f) && !self.revealed.contains(path))
+    }
+
+    fn collapses(&self, file: &FileEntry) -> bool {
+        file.is_generated() || self.viewed.contains(&file.path)
+    }
+
+    /// Show or hide the diff of the selected generated, vendored or viewed
+    /// file.
+    pub fn toggle_revealed(&mut self) {
+        let Some(path) = self.selected_path() else {
+            return;
+        };
+        if !self
+            .files
+            .iter()
+            .any(|f| f.path == path && self.collapses(f))
+        {
+            return;
+        }
+        if !self.revealed.remove(&path) {
+            self.revealed.insert(path.clone());
+        }
+        self.highlighted_cache.remove(&path);
+        self.diff_scroll = 0;
+    }
+
+    /// Gather generated and vendored files in their own folder, or show
+    /// them in place.
+    pub fn toggle_group_generated(&mut self) {
+        self.group_generated = !self.group_generated;
+        self.set_files(self.files.clone());
+    }
+
+    /// Switch between the diff and the whole file with its changes marked.
+    pub fn toggle_full_file(&mut self) {
+        self.full_file = !self.full_file;
+        self.highlighted_cache.clear();
+        self.diff_scroll = 0;
+    }
+
+    /// Show or hide removed lines in the full-file view.
+    pub fn toggle_removed(&mut self) {
+        self.show_removed = !self.show_removed;
+        if self.full_file {
+            self.highlighted_cache.retain(|_, h| !h.full_file);
+        }
+    }
+
+    /// Whether `path` is shown as a whole file rather than as a diff.
+    pub fn showing_full_file(&self, path: &str) -> bool {
+        self.highlighted_cache
+            .get(path)
+            .is_some_and(|h| h.full_file)
+    }
+
+    /// Scroll the diff pane to the start of the next or previous change.
+    pub fn jump_to_change(&mut self, forward: bool) {
+        let Some(path) = self.selected_path() else {
+            return;
+        };
+        let Some(highlighted) = self.highlighted_cache.get(&path) else {
+            return;
+        };
+        let rows: Vec<usize> = match self.view_mode(&path) {
+            DiffViewMode::Unified => highlighted.changes.clone(),
+            DiffViewMode::Split => highlighted
+                .changes
+                .iter()
+                .filter_map(|&line| {
+                    highlighted.split_rows.iter().position(|row| match *row {
+                        SplitRow::Full(i) => i == line,
+                        SplitRow::Pair(old, new) => old == Some(line) || new == Some(line),
+                    })
+                })
+                .collect(),
+        };
+        let target = if forward {
+            rows.into_iter().find(|&row| row > self.diff_scroll)
+        } else {
+            rows.into_iter().rev().find(|&row| row < self.diff_scroll)
+        };
+        if let Some(row) = target {
+            self.diff_scroll = row;
+        }
+    }
+
+    /// Show moved blocks as moves, or as plain additions and deletions.
+    pub fn toggle_moves(&mut self) {
+        self.show_moves = !self.show_moves;
+        self.highlighted_cache.clear();
+    }
+
+    pub fn get_highlighted(&self, path: &str) -> &[HighlightedLine] {
+        self.highlighted_cache
+            .get(path)
+            .map(|v| v.lines.as_slice())
+            .unwrap_or(&[])
+    }
+
+    pub fn get_split_rows(&self, path: &str) -> &[SplitRow] {
+        self.highlighted_cache
+            .get(path)
+            .map(|v| v.split_rows.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// How `path` is laid out: the whole-file view is always unified.
+    pub fn view_mode(&self, path: &str) -> DiffViewMode {
+        if self.showing_full_file(path) {
+            DiffViewMode::Unified
+        } else {
+            self.diff_view
+        }
+    }
+
+    /// Number of diff rows in the current view mode, for scrolling.
+    pub fn diff_row_count(&self, path: &str) -> usize {
+        match self.view_mode(path) {
+            DiffViewMode::Unified => self.get_highlighted(path).len(),
+            DiffViewMode::Split => self.get_split_rows(path).len(),
+        }
+    }
+
+    pub fn toggle_diff_view(&mut self) {
+        self.diff_view = match self.diff_view {
+            DiffViewMode::Unified => DiffViewMode::Split,
+            DiffViewMode::Split => DiffViewMode::Unified,
+        };
+        self.diff_scroll = 0;
+    }
+
+    /// Switch binary files between their summary and a hex dump diff.
+    pub fn toggle_hex(&mut self) {
+        self.show_hex = !self.show_hex;
+        self.highlighted_cache
+            .retain(|path, _| !self.diff_cache.get(path).is_some_and(|d| d.binary));
+        self.diff_scroll = 0;
+    }
+
+    pub fn get_diff(&self, path: &str) -> Option<&FileDiff> {
+        self.diff_cache.get(path)
+    }
+
+    pub fn get_diff_source(&self, path: &str) -> Option<DiffSource> {
+        self.diff_source_cache.get(path).copied()
+    }
+
+    /// Returns the (editor, path) to open, if a file is selected.
+    /// The caller is responsible for terminal restore/re-enter around spawning.
+    pub fn editor_command(&self) -> Option<(String, String)> {
+        let visible = self.visible_items();
+        match visible.get(self.cursor) {
+            Some((_, _, TreeNode::File(f))) => Some((self.editor.clone(), f.path.clone())),
+            _ => None,
+        }
+    }
+
+    /// Title of the file tree panel, describing what is being compared.
+    pub fn tree_title(&self) -> String {
+        let short = |oid: &str| oid.chars().take(7).collect::<String>();
+        let title = match (&self.range_spec, &self.range.to) {
+            (Some(spec), Some(to)) => {
+                format!(
+                    " prdiff {spec} ({}..{})",
+                    short(&self.range.from),
+                    short(to)
+                )
+            }
+            _ if self.rebased_preview => format!(
+                " prdiff vs {} (rebased preview on {})",
+                self.base_branch,
+                short(&self.view_range().from)
+            ),
+            (Some(_), None) => format!(
+                " prdiff vs {} (merge-base {})",
+                self.base_branch,
+                short(&self.range.from)
+            ),
+            (None, _) => format!(
+                " prdiff vs {} (merge-base {}, ↑{} ↓{})",
+                self.base_branch,
+                short(&self.range.from),
+                self.commits.len(),
+                self.behind
+            ),
+        };
+        // Generated and vendored files would drown out the changes to review.
+        let (additions, deletions) = self
+            .files
+            .iter()
+            .filter(|f| !f.is_generated())
+            .fold((0, 0), |(a, d), f| (a + f.additions, d + f.deletions));
+        let title = format!("{title} +{additions}/-{deletions}");
+        let mut labels: Vec<String> = Vec::new();
+        if let Some(label) = self.commit_filter_label() {
+            labels.push(label);
+        } else if self.layer != DiffLayer::All {
+            labels.push(self.layer.label().to_string());
+        }
+        if self.range.to.is_none() && !self.show_untracked && self.layer != DiffLayer::Staged {
+            labels.push("no untracked".to_string());
+        }
+        let generated = self.files.iter().filter(|f| f.is_generated()).count();
+        if generated > 0 {
+            labels.push(format!("{generated} generated"));
+        }
+        if !self.viewed.is_empty() {
+            let viewed = self
+                .files
+                .iter()
+                .filter(|f| self.viewed.contains(&f.path))
+                .count();
+            labels.push(format!("{viewed}/{} files viewed", self.files.len()));
+        }
+        let outdated = self.comments.comments.iter().filter(|c| c.outdated).count();
+        match (self.comments.comments.len(), outdated) {
+            (0, _) => {}
+            (1, 0) => labels.push("1 comment".to_string()),
+            (n, 0) => labels.push(format!("{n} comments")),
+            (1, _) => labels.push("1 comment, outdated".to_string()),
+            (n, outdated) => labels.push(format!("{n} comments, {outdated} outdated")),
+        }
+        let conflicts = match self.conflicts.len() {
+            0 => None,
+            1 => Some("1 conflict".to_string()),
+            n => Some(format!("{n} conflicts")),
+        };
+        match (conflicts, self.merge_source) {
+            (Some(conflicts), Some(MergeSource::Worktree)) => {
+                labels.push(format!("{conflicts} incl. uncommitted"))
+            }
+            (None, Some(MergeSource::Worktree)) => {
+                labels.push("no conflicts incl. uncommitted".to_string())
+            }
+            (Some(conflicts), _) => labels.push(conflicts),
+            (None, _) => {}
+        }
+        labels.extend(self.diff_options.whitespace.label());
+        if self.diff_options.context_lines != DiffOptions::default().context_lines {
+            labels.push(format!(
+                "{} lines of context",
+                self.diff_options.context_lines
+            ));
+        }
+        if self.diff_options.algorithm != DiffAlgorithm::Myers {
+            labels.push(self.diff_options.algorithm.name().to_string());
+        }
+        if labels.is_empty() {
+            format!("{title} ")
+        } else {
+            format!("{title} [{}] ", labels.join(", "))
+        }
+    }
+
+    /// Whether the commit panel starts with the "Uncommitted changes" entry.
+    pub fn has_uncommitted_entry(&self) -> bool {
+        self.range.to.is_none()
+    }
+
+    pub fn commit_entry_count(&self) -> usize {
+        self.commits.len() + usize::from(self.has_uncommitted_entry())
+    }
+
+    /// The commit behind a commit panel entry, `None` for "Uncommitted changes".
+    pub fn commit_entry(&self, entry: usize) -> Option<&CommitInfo> {
+        let offset = usize::from(self.has_uncommitted_entry());
+        entry.checked_sub(offset).and_then(|i| self.commits.get(i))
+    }
+
+    /// Entries the view is currently limited to, as (newest, oldest).
+    pub fn commit_filter_entries(&self) -> Option<(usize, usize)> {
+        self.commit_filter.as_ref().map(|(entries, _)| *entries)
+    }
+
+    fn commit_filter_label(&self) -> Option<String> {
+        let (newest, oldest) = self.commit_filter_entries()?;
+        let short = |entry: usize| {
+            self.commit_entry(entry)
+                .map(|c| c.id.chars().take(7).collect::<String>())
+        };
+        Some(match (short(newest), short(oldest)) {
+            (None, None) => "uncommitted".to_string(),
+            (Some(c), _) if newest == oldest => c,
+            (None, Some(c)) => format!("{c}^..worktree"),
+            (Some(n), Some(o)) => format!("{o}^..{n}"),
+            (Some(_), None) => return None,
+        })
+    }
+
+    pub fn toggle_commit_panel(&mut self) {
+        if self.commit_panel.take().is_some() {
+            return;
+        }
+        let mut panel = CommitPanel::default();
+        if let Some((newest, oldest)) = self.commit_filter_entries() {
+            panel.cursor = newest;
+            panel.anchor = (oldest != newest).then_some(oldest);
+        }
+        self.commit_panel = Some(panel);
+    }
+
+    /// Limit the tree and diffs to the entries selected in the commit panel.
+    /// Selecting every entry goes back to the whole range.
+    pub fn apply_commit_selection(&mut self) {
+        let Some(panel) = self.commit_panel.take() else {
+            return;
+        };
+        let (newest, oldest) = panel.selection();
+        if newest == 0 && oldest + 1 >= self.commit_entry_count() {
+            self.clear_commit_filter();
+            return;
+        }
+
+        let to = self.commit_entry(newest).map(|commit| commit.id.clone());
+        let untracked = to.is_none() && self.show_untracked;
+        let from = match self.commit_entry(oldest) {
+            Some(commit) => self
+                .backend
+                .rev_parse(&format!("{}^", commit.id))
+                .unwrap_or_else(|_| git::EMPTY_TREE.to_string()),
+            None => match self.backend.rev_parse("HEAD") {
+                Ok(head) => head,
+                Err(_) => return,
+            },
+        };
+        let range = DiffRange {
+            from,
+            to,
+            work: WorkState::Worktree,
+            untracked,
+        };
+        let Ok(files) = self.backend.changed_files(&range, &self.diff_options) else {
+            return;
+        };
+
+        // A commit slice replaces any layer selection.
+        self.layer = DiffLayer::All;
+        self.rebased_preview = false;
+        self.layer_range = self.compute_layer_range();
+        self.commit_filter = Some(((newest, oldest), range));
+        self.clear_diff_caches();
+        self.set_files(files);
+        self.diff_scroll = 0;
+    }
+
+    pub fn clear_commit_filter(&mut self) {
+        self.commit_panel = None;
+        if self.commit_filter.take().is_none() {
+            return;
+        }
+        let Ok(files) = self
+            .backend
+            .changed_files(self.view_range(), &self.diff_options)
+        else {
+            return;
+        };
+        self.clear_diff_caches();
+        self.set_files(files);
+        self.diff_scroll = 0;
+    }
+
+    pub fn open_branch_modal(&mut self) {
+        if self.range_spec.is_some() {
+            return;
+        }
+        let branches = self.backend.list_branches().unwrap_or_default();
+        if !branches.is_empty() {
+            self.branch_modal = Some(BranchModal::new(branches));
+        }
+    }
+
+    pub fn switch_base_branch(&mut self, branch: &str) {
+        let resolved = match git::resolve_base_ref(self.backend.as_ref(), branch) {
+            Ok(r) => r,
+            Err(_) => branch.to_string(),
+        };
+        let range = match self.backend.merge_base("HEAD", &resolved) {
+            Ok(mb) => DiffRange::worktree(mb),
+            Err(_) => return,
+        };
+        let files = match self.backend.changed_files(&range, &self.diff_options) {
+            Ok(f) => f,
+            Err(_) => return,
+        };
+
+        self.base_branch = resolved;
+        self.behind = count_behind(self.backend.as_ref(), &range, &self.base_branch);
+        self.commits = load_commits(self.backend.as_ref(), &range);
+        self.file_layers = self
+            .backend
+            .file_layers(&range.from, &self.diff_options)
+            .unwrap_or_default();
+        self.commit_filter = None;
+        self.commit_panel = None;
+        self.range = range;
+        self.layer_range = self.compute_layer_range();
+        self.clear_diff_caches();
+        // Every diff changed with the base; the watcher won't see it move.
+        self.check_viewed(&files, None);
+        self.comment_anchors.clear();
+        self.reanchor_comments(&files, None);
+
+        // Start the watcher from the whole branch diff, even if a layer narrows
+        // the view. It reruns the merge preview against the new base.
+        self.spawn_watcher(files.clone());
+        let files = match &self.layer_range {
+            Some(range) => self
+                .backend
+                .changed_files(range, &self.diff_options)
+                .unwrap_or(files),
+            None => files,
+        };
+        self.tree = tree::build_tree(&files, self.group_generated);
+        self.tree_version = self.tree_version.wrapping_add(1);
+        self.expanded = tree::default_expanded(&self.tree);
+
+        self.files = files;
+        self.cursor = 0;
+        self.scroll_offset = 0;
+        self.diff_scroll = 0;
+    }
+}
+
+/// A commit message without `#` comment lines and surrounding blank lines.
+fn strip_comments(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().filter(|line| !line.starts_with('#')).collect();
+    lines.join("\n").trim().to_string()
+}
+
+/// Where review state of the `kind` given is kept for the fixed range
+/// being reviewed, or else for the branch HEAD is on.
+fn review_file(backend: &dyn GitBackend, range_spec: Option<&str>, kind: &str) -> Option<PathBuf> {
+    let key = match range_spec {
+        Some(spec) => spec.to_string(),
+        None => backend.head_branch().unwrap_or_else(|| "HEAD".to_string()),
+    };
+    let name = key.replace('%', "%25").replace('/', "%2F");
+    Some(backend.git_dir().ok()?.join("prdiff").join(kind).join(name))
+}
+
+/// Commits in `range`, or up to `HEAD` when diffing against the working tree.
+fn load_commits(backend: &dyn GitBackend, range: &DiffRange) -> Vec<CommitInfo> {
+    let to = range.to.as_deref().unwrap_or("HEAD");
+    backend.commits(&range.from, to).unwrap_or_default()
+}
+
+/// Commits on `base` that the merge-base `range.from` doesn't have.
+fn count_behind(backend: &dyn GitBackend, range: &DiffRange, base: &str) -> usize {
+    backend.commits(&range.from, base).map_or(0, |c| c.len())
+}
+
+fn conflicts_by_path(conflicts: Vec<FileConflict>) -> HashMap<String, FileConflict> {
+    conflicts.into_iter().map(|c| (c.path.clone(), c)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::App;
+    use crate::backend::fake::FakeBackend;
+    use crate::conflict;
+    use crate::git;
+    use crate::model::{
+        ApplyTo, CommitInfo, DiffLayer, DiffOptions, DiffRange, FileEntry, FileStatus,
+    };
+    use crate::theme::Theme;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+
+    fn file(path: &str) -> FileEntry {
+        FileEntry::new(path, FileStatus::Modified, 1, 1)
+    }
+
+    /// A backend with `main` to diff against, to fill in with `..fake()`.
+    fn fake() -> FakeBackend {
+        FakeBackend {
+            branches: vec!["main".into()],
+            ..FakeBackend::default()
+        }
+    }
+
+    fn app(backend: impl Into<Arc<FakeBackend>>) -> App {
+        App::new(
+            backend.into(),
+            Some("main".into()),
+            None,
+            Theme::dark(),
+            DiffOptions::default(),
+        )
+        .unwrap()
+    }
+
+    fn commit(id: &str) -> CommitInfo {
+        CommitInfo {
+            id: id.to_string(),
+            subject: format!("subject of {id}"),
+            author: "dev".to_string(),
+            time: 0,
+        }
+    }
+
+    /// Wait for the watcher's first merge preview to come in.
+    fn wait_for_merge_preview(app: &mut App) {
+        for _ in 0..500 {
+            app.check_for_changes();
+            if app.merge_tree.is_some() {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        panic!("no merge preview");
+    }
+
+    #[test]
+    fn selecting_a_commit_limits_the_file_list() {
+        let mut backend = FakeBackend {
+            files: vec![file("a.rs"), file("b.rs")],
+            commits: vec![commit("c2"), commit("c1")],
+            ..fake()
+        };
+        backend.commit_files.insert("c1".into(), vec![file("a.rs")]);
+        let mut app = app(backend);
+        assert_eq!(app.commit_entry_count(), 3);
+        assert!(app.commit_entry(0).is_none());
+
+        app.toggle_commit_panel();
+        app.commit_panel.as_mut().unwrap().cursor = 2;
+        app.apply_commit_selection();
+        assert!(app.commit_panel.is_none());
+        assert_eq!(app.files.len(), 1);
+        assert_eq!(app.commit_filter_entries(), Some((2, 2)));
+        assert!(app.tree_title().contains("[c1]"));
+
+        app.clear_commit_filter();
+        assert_eq!(app.files.len(), 2);
+        assert_eq!(app.commit_filter_entries(), None);
+    }
+
+    #[test]
+    fn selecting_every_entry_shows_the_whole_range() {
+        let backend = FakeBackend {
+            files: vec![file("a.rs")],
+            commits: vec![commit("c1")],
+            ..fake()
+        };
+        let mut app = app(backend);
+        app.toggle_commit_panel();
+        let panel = app.commit_panel.as_mut().unwrap();
+        panel.anchor = Some(0);
+        panel.cursor = 1;
+        app.apply_commit_selection();
+        assert_eq!(app.commit_filter_entries(), None);
+    }
+
+    #[test]
+    fn generated_files_are_collapsed_and_left_out_of_totals() {
+        let mut generated = FileEntry::new("api.pb.rs", FileStatus::Modified, 900, 40);
+        generated.attributes.generated = true;
+        let backend = FakeBackend {
+            files: vec![file("a.rs"), generated],
+            ..fake()
+        };
+        let mut app = app(backend);
+        assert!(app.tree_title().contains(" +1/-1 [1 generated] "));
+
+        // The generated folder starts collapsed; its file shows only stats until revealed.
+        assert_eq!(app.visible_items().len(), 2);
+        app.cursor = 1;
+        app.toggle_expand();
+        app.cursor = 2;
+        assert_eq!(app.selected_path().as_deref(), Some("api.pb.rs"));
+        app.ensure_highlighted("api.pb.rs");
+        assert_eq!(app.get_highlighted("api.pb.rs").len(), 1);
+        app.toggle_revealed();
+        app.ensure_highlighted("api.pb.rs");
+        assert!(app.get_diff("api.pb.rs").is_some());
+    }
+
+    #[test]
+    fn conflicts_show_in_the_title_and_the_conflict_view() {
+        let conflict = conflict::file_conflict(
+            "a.rs".into(),
+            [true, true, true],
+            Some(b"<<<<<<< main\nold\n=======\nnew\nnewer\n>>>>>>> HEAD\n"),
+        );
+        let backend = FakeBackend {
+            files: vec![file("a.rs"), file("b.rs")],
+            conflicts: vec![conflict],
+            ..fake()
+        };
+        let mut app = app(backend);
+        wait_for_merge_preview(&mut app);
+        assert!(app.tree_title().ends_with(" [1 conflict] "));
+
+        app.toggle_conflict_view();
+        app.ensure_highlighted("a.rs");
+        app.ensure_highlighted("b.rs");
+        // Summary, region header, base label and line, branch label and lines.
+        assert_eq!(app.get_highlighted("a.rs").len(), 7);
+        assert_eq!(app.get_split_rows("a.rs").len(), 5);
+        assert!(app.showing_conflict("b.rs").is_none());
+
+        app.toggle_merge_worktree();
+        assert!(app
+            .tree_title()
+            .ends_with(" [1 conflict incl. uncommitted] "));
+    }
+
+    #[test]
+    fn rebased_preview_diffs_the_merge_result_against_the_base_tip() {
+        let mut backend = FakeBackend {
+            files: vec![file("a.rs")],
+            commits: vec![commit("c2"), commit("c1")],
+            ..fake()
+        };
+        backend
+            .commit_files
+            .insert("main-merge-tree".into(), vec![file("a.rs"), file("b.rs")]);
+        let mut app = app(backend);
+        wait_for_merge_preview(&mut app);
+        // The fake lists the same commits on both sides of the merge-base.
+        assert!(app
+            .tree_title()
+            .starts_with(" prdiff vs main (merge-base HEAD-ma, ↑2 ↓2) "));
+
+        app.toggle_rebased_preview();
+        assert_eq!(
+            app.view_range(),
+            &DiffRange::commits("main-oid", "main-merge-tree")
+        );
+        assert_eq!(app.files.len(), 2);
+        assert!(app
+            .tree_title()
+            .starts_with(" prdiff vs main (rebased preview on main-oi) "));
+
+        app.cycle_layer();
+        assert!(!app.rebased_preview);
+        assert_eq!(app.files.len(), 1);
+    }
+
+    #[test]
+    fn staging_takes_the_selected_file_or_directory() {
+        let mut renamed = FileEntry::new("src/new.rs", FileStatus::Renamed, 0, 0);
+        renamed.old_path = Some("src/old.rs".into());
+        let backend = Arc::new(FakeBackend {
+            files: vec![file("README.md"), file("src/lib.rs"), renamed],
+            ..fake()
+        });
+        let mut app = app(backend.clone());
+
+        // Read-only unless asked otherwise.
+        app.stage_selected(true);
+        assert!(backend.staged.lock().unwrap().is_empty());
+        assert!(app.toast.as_ref().unwrap().0.contains("--interactive"));
+
+        app.interactive = true;
+        assert_eq!(app.selected_path().as_deref(), Some("src"));
+        app.stage_selected(true);
+        assert_eq!(
+            *backend.staged.lock().unwrap(),
+            ["src/lib.rs", "src/old.rs", "src/new.rs"]
+        );
+
+        app.cursor = app.visible_items().len() - 1;
+        app.stage_selected(false);
+        assert_eq!(*backend.unstaged.lock().unwrap(), ["README.md"]);
+        assert_eq!(app.file_layers["README.md"].stage_marker(), "◐");
+    }
+
+    #[test]
+    fn hunks_and_picked_lines_are_staged_and_discarded_as_patches() {
+        let diff =
+            git::parse_diff("--- a/a.rs\n+++ b/a.rs\n@@ -1,2 +1,3 @@\n one\n-two\n+TWO\n+three\n");
+        let backend = Arc::new(FakeBackend {
+            files: vec![file("a.rs")],
+            diffs: HashMap::from([("a.rs".to_string(), diff)]),
+            ..fake()
+        });
+        let mut app = app(backend.clone());
+        app.interactive = true;
+        app.ensure_highlighted("a.rs");
+
+        // The branch diff doesn't apply to the index; a layer does.
+        app.stage_lines();
+        assert!(backend.patches.lock().unwrap().is_empty());
+        while app.layer != DiffLayer::Unstaged {
+            app.cycle_layer();
+        }
+        app.ensure_highlighted("a.rs");
+        app.stage_lines();
+        let (patch, to, reverse) = backend.patches.lock().unwrap().pop().unwrap();
+        assert!(
+            patch.ends_with("@@ -1,2 +1,3 @@\n one\n-two\n+TWO\n+three\n"),
+            "{patch}"
+        );
+        assert_eq!((to, reverse), (ApplyTo::Index, false));
+
+        // Pick the changed line, leaving `+three` as context; discard it and
+        // put it back.
+        app.ensure_highlighted("a.rs");
+        app.start_line_selection();
+        app.move_line_cursor(true);
+        app.discard_lines();
+        app.undo_discard();
+        let patches = backend.patches.lock().unwrap();
+        assert!(
+            patches[0]
+                .0
+                .ends_with("@@ -1,3 +1,3 @@\n one\n-two\n+TWO\n three\n"),
+            "{}",
+            patches[0].0
+        );
+        assert_eq!(patches[0].0, patches[1].0);
+        assert_eq!((patches[0].1, patches[0].2), (ApplyTo::Worktree, true));
+        assert_eq!((patches[1].1, patches[1].2), (ApplyTo::Worktree, false));
+    }
+
+    #[test]
+    fn comments_are_written_on_picked_lines_and_follow_them() {
+        let git_dir =
+            std::env::temp_dir().join(format!("prdiff-comments-app-{}", std::process::id()));
+        std::fs::create_dir_all(&git_dir).unwrap();
+        let open = |new_side: &str| {
+            let backend = FakeBackend {
+                files: vec![file("a.rs")],
+                diffs: HashMap::from([(
+                    "a.rs".to_string(),
+                    git::parse_diff(
+                        "--- a/a.rs\n+++ b/a.rs\n@@ -1,2 +1,3 @@\n one\n-two\n+TWO\n+three\n",
+                    ),
+                )]),
+                blobs: HashMap::from([("a.rs".to_string(), new_side.as_bytes().to_vec())]),
+                git_dir: Some(git_dir.clone()),
+                ..fake()
+            };
+            let mut app = app(backend);
+            app.ensure_highlighted("a.rs");
+            app
+        };
+        let mut app = open("one\nTWO\nthree\n");
+
+        // `-two` through `+three`: the comment is on the new side's lines.
+        app.start_line_selection();
+        app.move_line_cursor(true);
+        app.move_line_cursor(true);
+        let path = app.prepare_comment().unwrap();
+        let template = std::fs::read_to_string(&path).unwrap();
+        assert!(
+            template.contains("# On a.rs, lines 2-3 of the new version:\n#   TWO\n#   three\n"),
+            "{template}"
+        );
+        std::fs::write(&path, format!("Needs a test\n{template}")).unwrap();
+        app.finish_comment(&path);
+        assert!(app.line_selection.is_none() && !path.exists());
+        assert_eq!(app.comment_rows("a.rs").values().sum::<usize>(), 1);
+        assert!(
+            app.tree_title().ends_with("[1 comment] "),
+            "{}",
+            app.tree_title()
+        );
+
+        // Without picked lines it goes on the first change; empty is dropped.
+        let path = app.prepare_comment().unwrap();
+        app.finish_comment(&path);
+        assert_eq!(app.toast.as_ref().unwrap().0, "Comment discarded: empty");
+
+        // A line added above moves the comment; an edit makes it outdated.
+        let app = open("zero\none\nTWO\nthree\n");
+        let comment = &app.comments.comments[0];
+        assert_eq!(
+            (comment.start, comment.end, comment.outdated),
+            (3, 4, false)
+        );
+        let mut app = open("one\nTWO2\nthree\n");
+        let comment = &app.comments.comments[0];
+        assert_eq!((comment.start, comment.end, comment.outdated), (2, 3, true));
+        assert!(app.tree_title().ends_with("[1 comment, outdated] "));
+
+        // Emptying it in the editor removes it.
+        app.start_line_selection();
+        app.move_line_cursor(true);
+        app.move_line_cursor(true);
+        let path = app.prepare_comment().unwrap();
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .starts_with("Needs a test\n"));
+        std::fs::write(&path, "").unwrap();
+        app.finish_comment(&path);
+        assert!(app.comments.comments.is_empty());
+        assert!(!git_dir.join("prdiff/comments/HEAD").exists());
+        std::fs::remove_dir_all(&git_dir).unwrap();
+    }
+
+    #[test]
+    fn commit_messages_are_written_with_the_staged_files_and_committed() {
+        let git_dir = std::env::temp_dir().join(format!("prdiff-commit-{}", std::process::id()));
+        std::fs::create_dir_all(&git_dir).unwrap();
+        let backend = Arc::new(FakeBackend {
+            files: vec![file("a.rs")],
+            git_dir: Some(git_dir.clone()),
+            ..fake()
+        });
+        let mut app = app(backend.clone());
+        app.interactive = true;
+        assert!(
+            app.prepare_commit(true).is_none(),
+            "no branch commit to amend"
+        );
+
+        let path = app.prepare_commit(false).unwrap();
+        let template = std::fs::read_to_string(&path).unwrap();
+        assert!(
+            template.contains("# Committing 1 file:\n#   ~ a.rs +1/-1\n"),
+            "{template}"
+        );
+        std::fs::write(&path, format!("Fix a\n\nBecause.\n{template}")).unwrap();
+        app.finish_commit(&path, false);
+        assert_eq!(app.toast.as_ref().unwrap().0, "Committed: Fix a");
+        assert!(!path.exists());
+
+        // Nothing but comments aborts.
+        let path = app.prepare_commit(false).unwrap();
+        app.finish_commit(&path, false);
+        assert_eq!(
+            app.toast.as_ref().unwrap().0,
+            "Commit aborted: empty message"
+        );
+
+        // Amending starts from HEAD's message, or from what a failed amend left.
+        app.commits = vec![commit("c1")];
+        let path = app.prepare_commit(true).unwrap();
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .starts_with("subject of HEAD\n\nbody of HEAD\n"));
+        std::fs::write(&path, "Reworded\n").unwrap();
+        let path = app.prepare_commit(true).unwrap();
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .starts_with("Reworded\n"));
+        std::fs::remove_dir_all(&git_dir).unwrap();
+        let made = backend.made_commits.lock().unwrap();
+        assert_eq!(made.len(), 1);
+        assert!(made[0].0.starts_with("Fix a\n\nBecause.\n") && !made[0].1);
+    }
+
+    #[test]
+    fn viewed_marks_persist_per_branch_until_the_diff_changes() {
+        let git_dir =
+            std::env::temp_dir().join(format!("prdiff-viewed-app-{}", std::process::id()));
+        let backend = |b: &str| FakeBackend {
+            files: vec![file("src/a.rs"), file("src/b.rs"), file("c.rs")],
+            diffs: HashMap::from([
+                (
+                    "src/a.rs".to_string(),
+                    git::parse_diff(&format!("@@ -1,1 +1,1 @@\n-a\n+{b}\n")),
+                ),
+                (
+                    "src/b.rs".to_string(),
+                    git::parse_diff("@@ -1,1 +1,1 @@\n-b\n+B\n"),
+                ),
+            ]),
+            git_dir: Some(git_dir.clone()),
+            head_branch: Some("feature/x".into()),
+            ..fake()
+        };
+        let open = |b: &str| app(backend(b));
+        let mut app = open("A");
+        app.cursor = app
+            .visible_items()
+            .iter()
+            .position(|(_, path, _)| path == "src")
+            .unwrap();
+        app.toggle_viewed();
+        assert!(
+            app.tree_title().ends_with("[2/3 files viewed] "),
+            "{}",
+            app.tree_title()
+        );
+        assert!(git_dir.join("prdiff/viewed/feature%2Fx").exists());
+
+        // A refresh that leaves the diff alone keeps the mark.
+        let files = app.files.clone();
+        app.apply_file_changes(
+            files,
+            app.range.from.clone(),
+            false,
+            HashSet::from(["src/a.rs".to_string()]),
+        );
+        assert!(app.viewed.contains("src/a.rs"));
+
+        // Viewed files collapse to their stats until revealed.
+        app.ensure_highlighted("src/b.rs");
+        assert_eq!(app.get_highlighted("src/b.rs").len(), 1);
+        app.cursor = app
+            .visible_items()
+            .iter()
+            .position(|(_, path, _)| path == "src/b.rs")
+            .unwrap();
+        app.toggle_revealed();
+        app.ensure_highlighted("src/b.rs");
+        assert!(app.get_highlighted("src/b.rs").len() > 1);
+
+        // The next session finds `src/a.rs` changed since it was viewed.
+        let mut app = open("A2");
+        assert!(!app.viewed.contains("src/a.rs") && app.viewed.contains("src/b.rs"));
+        app.toggle_viewed();
+        assert!(app.tree_title().ends_with("[2/3 files viewed] "));
+        app.toggle_viewed();
+        assert!(!app.tree_title().contains("viewed"));
+        assert!(!git_dir.join("prdiff/viewed/feature%2Fx").exists());
+        std::fs::remove_dir_all(&git_dir).unwrap();
+    }
+
+    #[test]
+    fn layers_narrow_the_view_and_show_in_the_title() {
+        let backend = FakeBackend {
+            files: vec![file("a.rs")],
+            commits: vec![commit("c1")],
+            ..fake()
+        };
+        let mut app = app(backend);
+        assert_eq!(app.file_layers["a.rs"].badge(), "csu");
+
+        app.cycle_layer();
+        assert_eq!(app.layer, DiffLayer::Committed);
+        assert!(app.tree_title().ends_with("[committed] "));
+
+        app.toggle_untracked();
+        assert!(app.tree_title().ends_with("[committed, no untracked] "));
+
+        // Picking a commit replaces the layer selection.
+        app.toggle_commit_panel();
+        app.commit_panel.as_mut().unwrap().cursor = 1;
+        app.apply_commit_selection();
+        assert_eq!(app.layer, DiffLayer::All);
+        assert!(app.tree_title().ends_with("[c1, no untracked] "));
     }
 }
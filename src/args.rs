@@ -1,32 +1,80 @@
+use crate::backend::BackendKind;
+use crate::model::{DiffAlgorithm, DiffOptions};
 use crate::theme::ThemeMode;
 use anyhow::Result;
 use std::env;
 
 pub struct Args {
     pub base_branch: Option<String>,
+    /// `A..B`, `A...B` or a single commit to review instead of the branch diff
+    pub range: Option<String>,
     pub theme: Option<ThemeMode>,
+    pub backend: Option<BackendKind>,
+    /// Similarity percentage for rename and copy detection
+    pub rename_threshold: Option<u8>,
+    /// Expand changed submodules into the files changed inside them
+    pub recurse_submodules: bool,
+    pub diff_algorithm: Option<DiffAlgorithm>,
+    /// Lines of context around each change
+    pub context_lines: Option<u32>,
+    /// Allow staging and unstaging from the file tree
+    pub interactive: bool,
+}
+
+impl Args {
+    /// Whether staging is allowed.
+    /// Priority: PRDIFF_INTERACTIVE env var > --interactive > off (read-only)
+    pub fn interactive_from_config(&self) -> bool {
+        env::var("PRDIFF_INTERACTIVE")
+            .ok()
+            .and_then(|s| DiffOptions::parse_switch(&s))
+            .unwrap_or(self.interactive)
+    }
 }
 
 fn print_usage() {
     eprintln!("prdiff - Terminal PR diff viewer");
     eprintln!();
-    eprintln!("Usage: prdiff [OPTIONS] [BASE_BRANCH]");
+    eprintln!("Usage: prdiff [OPTIONS] [BASE_BRANCH | RANGE]");
     eprintln!();
     eprintln!("Options:");
     eprintln!("  -b, --base <BRANCH>    Base branch to diff against");
+    eprintln!("  -r, --range <RANGE>    Review A..B, A...B or a single commit instead");
     eprintln!("  -t, --theme <THEME>    Color theme: light or dark (default: dark)");
+    eprintln!("  -B, --backend <NAME>   Git backend: cli or gix (default: cli)");
+    eprintln!(
+        "  -M, --find-renames <N> Rename/copy similarity in percent, 0 disables (default: 50)"
+    );
+    eprintln!("  --recurse-submodules   List files changed inside submodules");
+    eprintln!("  --diff-algorithm <ALG> myers, minimal, patience or histogram (default: myers)");
+    eprintln!("  -U, --unified <N>      Lines of context around changes (default: 3)");
+    eprintln!("  -i, --interactive      Allow staging and unstaging files (s/u keys)");
     eprintln!("  -h, --help             Show this help message");
     eprintln!();
     eprintln!("Environment:");
     eprintln!("  PRDIFF_THEME           Color theme (overrides --theme flag)");
+    eprintln!("  PRDIFF_BACKEND         Git backend (overrides --backend flag)");
+    eprintln!("  PRDIFF_RENAME_THRESHOLD  Rename threshold (overrides --find-renames flag)");
+    eprintln!("  PRDIFF_RECURSE_SUBMODULES  1 or 0 (overrides --recurse-submodules flag)");
+    eprintln!("  PRDIFF_DIFF_ALGORITHM  Diff algorithm (overrides --diff-algorithm flag)");
+    eprintln!("  PRDIFF_CONTEXT         Lines of context (overrides --unified flag)");
+    eprintln!("  PRDIFF_INTERACTIVE     1 or 0 (overrides --interactive flag)");
     eprintln!();
     eprintln!("If no base branch specified, auto-detects upstream/develop/main/master");
+    eprintln!("A positional argument containing '..' is treated as a range");
 }
 
 pub fn parse_args() -> Result<Args> {
     let args: Vec<String> = env::args().skip(1).collect();
     let mut base_branch = None;
+    let mut range = None;
     let mut theme = None;
+    let mut backend = None;
+    let mut rename_threshold = None;
+    let mut recurse_submodules = false;
+    let mut diff_algorithm = None;
+    let mut context_lines = None;
+    let mut interactive = false;
     let mut i = 0;
 
     while i < args.len() {
@@ -42,6 +90,13 @@ pub fn parse_args() -> Result<Args> {
                 }
                 base_branch = Some(args[i].clone());
             }
+            "-r" | "--range" => {
+                i += 1;
+                if i >= args.len() {
+                    anyhow::bail!("--range requires a range or commit");
+                }
+                range = Some(args[i].clone());
+            }
             "-t" | "--theme" => {
                 i += 1;
                 if i >= args.len() {
@@ -52,9 +107,62 @@ pub fn parse_args() -> Result<Args> {
                     None => anyhow::bail!("Invalid theme '{}': must be 'light' or 'dark'", args[i]),
                 }
             }
+            "-B" | "--backend" => {
+                i += 1;
+                if i >= args.len() {
+                    anyhow::bail!("--backend requires a value: cli or gix");
+                }
+                match BackendKind::from_str(&args[i]) {
+                    Some(kind) => backend = Some(kind),
+                    None => anyhow::bail!("Invalid backend '{}': must be 'cli' or 'gix'", args[i]),
+                }
+            }
+            "-M" | "--find-renames" => {
+                i += 1;
+                if i >= args.len() {
+                    anyhow::bail!("--find-renames requires a percentage");
+                }
+                match DiffOptions::parse_threshold(&args[i]) {
+                    Some(threshold) => rename_threshold = Some(threshold),
+                    None => anyhow::bail!("Invalid rename threshold '{}': must be 0-100", args[i]),
+                }
+            }
+            "--recurse-submodules" => recurse_submodules = true,
+            "-i" | "--interactive" => interactive = true,
+            "--diff-algorithm" => {
+                i += 1;
+                if i >= args.len() {
+                    anyhow::bail!(
+                        "--diff-algorithm requires a value: myers, minimal, patience or histogram"
+                    );
+                }
+                match DiffAlgorithm::from_str(&args[i]) {
+                    Some(algorithm) => diff_algorithm = Some(algorithm),
+                    None => anyhow::bail!(
+                        "Invalid diff algorithm '{}': must be myers, minimal, patience or histogram",
+                        args[i]
+                    ),
+                }
+            }
+            "-U" | "--unified" => {
+                i += 1;
+                if i >= args.len() {
+                    anyhow::bail!("--unified requires a number of lines");
+                }
+                match args[i].parse() {
+                    Ok(lines) => context_lines = Some(lines),
+                    Err(_) => anyhow::bail!(
+                        "Invalid context size '{}': must be a number of lines",
+                        args[i]
+                    ),
+                }
+            }
             arg if arg.starts_with('-') => {
                 anyhow::bail!("Unknown option: {arg}");
             }
+            arg if arg.contains("..") && range.is_none() => {
+                range = Some(arg.to_string());
+            }
             arg => {
                 if base_branch.is_none() {
                     base_branch = Some(arg.to_string());
@@ -66,5 +174,19 @@ pub fn parse_args() -> Result<Args> {
         i += 1;
     }
 
-    Ok(Args { base_branch, theme })
+    if range.is_some() && base_branch.is_some() {
+        anyhow::bail!("A base branch can't be combined with a range");
+    }
+
+    Ok(Args {
+        base_branch,
+        range,
+        theme,
+        backend,
+        rename_threshold,
+        recurse_submodules,
+        diff_algorithm,
+        context_lines,
+        interactive,
+    })
 }
@@ -0,0 +1,333 @@
+use crate::git::CliBackend;
+use crate::gix_backend::GixBackend;
+use crate::model::{
+    ApplyTo, CommitInfo, DiffOptions, DiffRange, DiffSource, FileDiff, FileEntry, FileLayers,
+    FileSides, FileStatus, MergePreview, MergeSource, WorkState,
+};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The git operations prdiff needs to build and refresh its view.
+///
+/// The app and the background watcher share one backend, so implementations
+/// must be usable from both threads.
+pub trait GitBackend: Send + Sync {
+    /// Best common ancestor of two revisions.
+    fn merge_base(&self, one: &str, two: &str) -> Result<String>;
+
+    /// Files that differ across `range`. Against the working tree this
+    /// includes index-only and untracked changes.
+    fn changed_files(&self, range: &DiffRange, options: &DiffOptions) -> Result<Vec<FileEntry>>;
+
+    /// Parsed diff of one file, as listed by `changed_files`, across `range`.
+    fn file_diff(
+        &self,
+        range: &DiffRange,
+        file: &FileEntry,
+        options: &DiffOptions,
+    ) -> (DiffSource, FileDiff);
+
+    /// Contents of the old and new side of `file` across `range`, as
+    /// `file_diff` compared them from `source`; `None` for a missing side.
+    fn file_sides(
+        &self,
+        range: &DiffRange,
+        file: &FileEntry,
+        source: DiffSource,
+    ) -> Result<FileSides>;
+
+    /// Which layers (committed, staged, unstaged, untracked) touch each file
+    /// changed since `merge_base`.
+    fn file_layers(
+        &self,
+        merge_base: &str,
+        options: &DiffOptions,
+    ) -> Result<HashMap<String, FileLayers>> {
+        let head = self.rev_parse("HEAD")?;
+        let mut layers: HashMap<String, FileLayers> = HashMap::new();
+
+        let committed = DiffRange::commits(merge_base, head.as_str());
+        for file in self.changed_files(&committed, options)? {
+            layers.entry(file.path).or_default().committed = true;
+        }
+        let staged = DiffRange {
+            work: WorkState::Index,
+            untracked: false,
+            ..DiffRange::worktree(head.as_str())
+        };
+        for file in self.changed_files(&staged, options)? {
+            layers.entry(file.path).or_default().staged = true;
+        }
+        // Unstaged and untracked files in one listing: only untracked ones
+        // show up as added relative to the index.
+        let unstaged = DiffRange {
+            work: WorkState::Unstaged,
+            ..DiffRange::worktree(head.as_str())
+        };
+        for file in self.changed_files(&unstaged, options)? {
+            let entry = layers.entry(file.path).or_default();
+            if matches!(file.status, FileStatus::Added) {
+                entry.untracked = true;
+            } else {
+                entry.unstaged = true;
+            }
+        }
+        Ok(layers)
+    }
+
+    /// Commits reachable from `to` but not from `from`, newest first.
+    fn commits(&self, from: &str, to: &str) -> Result<Vec<CommitInfo>>;
+
+    /// Merge `source` into `base` without touching the working tree, index
+    /// or refs, to see which files would conflict.
+    fn merge_preview(&self, base: &str, source: MergeSource) -> Result<MergePreview>;
+
+    /// Resolve a revision to a full object id.
+    fn rev_parse(&self, rev: &str) -> Result<String>;
+
+    /// Hash of the working tree and index status, used to detect changes cheaply.
+    fn status_hash(&self) -> Result<u64>;
+
+    /// Local and remote branch names, sorted.
+    fn list_branches(&self) -> Result<Vec<String>>;
+
+    /// Names of the configured remotes.
+    fn remotes(&self) -> Result<Vec<String>>;
+
+    /// Backend of the same kind for the submodule checked out at `path`.
+    fn open_submodule(&self, path: &str) -> Result<Box<dyn GitBackend>>;
+
+    /// Add the working tree state of `paths` to the index. Interactive mode only.
+    fn stage(&self, paths: &[String]) -> Result<()>;
+
+    /// Reset `paths` in the index to HEAD. Interactive mode only.
+    fn unstage(&self, paths: &[String]) -> Result<()>;
+
+    /// Apply a patch to the index or the working tree, in `reverse` to
+    /// undo it. Interactive mode only.
+    fn apply_patch(&self, patch: &str, to: ApplyTo, reverse: bool) -> Result<()>;
+
+    /// The repository's git dir, where prdiff keeps its own files.
+    fn git_dir(&self) -> Result<PathBuf>;
+
+    /// Where git keeps `name`, like `index`, `HEAD` or a ref, as `git
+    /// rev-parse --git-path` finds it. The watcher polls these files.
+    fn git_path(&self, name: &str) -> Result<PathBuf>;
+
+    /// Full message of a commit.
+    fn commit_message(&self, rev: &str) -> Result<String>;
+
+    /// Short name of the branch HEAD points at; `None` when detached.
+    fn head_branch(&self) -> Option<String>;
+
+    /// Commit the index with the message in `message_file`, `#` lines
+    /// dropped, or amend HEAD with it. Interactive mode only.
+    fn commit(&self, message_file: &Path, amend: bool) -> Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackendKind {
+    /// Shell out to the `git` executable
+    Cli,
+    /// In-process implementation based on gitoxide
+    Gix,
+}
+
+impl BackendKind {
+    /// Parse backend kind from string (case-insensitive)
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "cli" | "git" => Some(Self::Cli),
+            "gix" | "gitoxide" => Some(Self::Gix),
+            _ => None,
+        }
+    }
+
+    /// Pick the backend from environment variable and/or CLI argument
+    /// Priority: PRDIFF_BACKEND env var > CLI arg > default (cli)
+    pub fn from_config(cli_backend: Option<BackendKind>) -> Self {
+        if let Ok(env_backend) = env::var("PRDIFF_BACKEND") {
+            if let Some(kind) = Self::from_str(&env_backend) {
+                return kind;
+            }
+        }
+        cli_backend.unwrap_or(Self::Cli)
+    }
+}
+
+pub fn open(kind: BackendKind) -> Result<Arc<dyn GitBackend>> {
+    Ok(match kind {
+        BackendKind::Cli => Arc::new(CliBackend::default()),
+        BackendKind::Gix => Arc::new(GixBackend::discover()?),
+    })
+}
+
+/// In-memory backend for tests.
+#[cfg(test)]
+pub mod fake {
+    use super::GitBackend;
+    use crate::model::{
+        ApplyTo, CommitInfo, DiffOptions, DiffRange, DiffSource, FileConflict, FileDiff, FileEntry,
+        FileSides, MergePreview, MergeSource,
+    };
+    use anyhow::Result;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    pub struct FakeBackend {
+        pub branches: Vec<String>,
+        pub remotes: Vec<String>,
+        pub files: Vec<FileEntry>,
+        pub diffs: HashMap<String, FileDiff>,
+        pub commits: Vec<CommitInfo>,
+        /// Files returned for a range ending in this commit instead of `files`
+        pub commit_files: HashMap<String, Vec<FileEntry>>,
+        /// Files changed inside the submodule at each path
+        pub submodules: HashMap<String, Vec<FileEntry>>,
+        /// New side of each file
+        pub blobs: HashMap<String, Vec<u8>>,
+        /// Files that conflict when merging into any base
+        pub conflicts: Vec<FileConflict>,
+        /// Paths passed to `stage` and `unstage`, in order
+        pub staged: Mutex<Vec<String>>,
+        pub unstaged: Mutex<Vec<String>>,
+        /// Patches passed to `apply_patch`
+        pub patches: Mutex<Vec<(String, ApplyTo, bool)>>,
+        pub git_dir: Option<PathBuf>,
+        pub head_branch: Option<String>,
+        /// Messages passed to `commit`, and whether they amended
+        pub made_commits: Mutex<Vec<(String, bool)>>,
+    }
+
+    impl GitBackend for FakeBackend {
+        fn merge_base(&self, one: &str, two: &str) -> Result<String> {
+            Ok(format!("{one}-{two}-merge-base"))
+        }
+
+        fn changed_files(
+            &self,
+            range: &DiffRange,
+            _options: &DiffOptions,
+        ) -> Result<Vec<FileEntry>> {
+            let files = range.to.as_ref().and_then(|to| self.commit_files.get(to));
+            Ok(files.unwrap_or(&self.files).clone())
+        }
+
+        fn commits(&self, _from: &str, _to: &str) -> Result<Vec<CommitInfo>> {
+            Ok(self.commits.clone())
+        }
+
+        fn merge_preview(&self, base: &str, _source: MergeSource) -> Result<MergePreview> {
+            Ok(MergePreview {
+                tree: format!("{base}-merge-tree"),
+                conflicts: self.conflicts.clone(),
+            })
+        }
+
+        fn file_diff(
+            &self,
+            range: &DiffRange,
+            file: &FileEntry,
+            _options: &DiffOptions,
+        ) -> (DiffSource, FileDiff) {
+            let diff = self.diffs.get(&file.path).cloned().unwrap_or_default();
+            let source = match range.to {
+                Some(_) => DiffSource::Commit,
+                None => DiffSource::Worktree,
+            };
+            (source, diff)
+        }
+
+        fn file_sides(
+            &self,
+            _range: &DiffRange,
+            file: &FileEntry,
+            _source: DiffSource,
+        ) -> Result<FileSides> {
+            Ok((None, self.blobs.get(&file.path).cloned()))
+        }
+
+        fn rev_parse(&self, rev: &str) -> Result<String> {
+            let name = rev.strip_suffix('^').unwrap_or(rev);
+            let name = name.split_once(':').map_or(name, |(commit, _)| commit);
+            let known = self
+                .branches
+                .iter()
+                .chain(self.commits.iter().map(|c| &c.id));
+            if name == "HEAD" || known.into_iter().any(|b| b == name) {
+                Ok(format!("{rev}-oid"))
+            } else {
+                anyhow::bail!("unknown revision '{rev}'")
+            }
+        }
+
+        fn status_hash(&self) -> Result<u64> {
+            Ok(0)
+        }
+
+        fn list_branches(&self) -> Result<Vec<String>> {
+            Ok(self.branches.clone())
+        }
+
+        fn remotes(&self) -> Result<Vec<String>> {
+            Ok(self.remotes.clone())
+        }
+
+        fn open_submodule(&self, path: &str) -> Result<Box<dyn GitBackend>> {
+            match self.submodules.get(path) {
+                Some(files) => Ok(Box::new(FakeBackend {
+                    files: files.clone(),
+                    ..FakeBackend::default()
+                })),
+                None => anyhow::bail!("no submodule at '{path}'"),
+            }
+        }
+
+        fn stage(&self, paths: &[String]) -> Result<()> {
+            self.staged.lock().unwrap().extend_from_slice(paths);
+            Ok(())
+        }
+
+        fn unstage(&self, paths: &[String]) -> Result<()> {
+            self.unstaged.lock().unwrap().extend_from_slice(paths);
+            Ok(())
+        }
+
+        fn apply_patch(&self, patch: &str, to: ApplyTo, reverse: bool) -> Result<()> {
+            self.patches
+                .lock()
+                .unwrap()
+                .push((patch.to_string(), to, reverse));
+            Ok(())
+        }
+
+        fn git_dir(&self) -> Result<PathBuf> {
+            self.git_dir
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("no git dir"))
+        }
+
+        fn git_path(&self, name: &str) -> Result<PathBuf> {
+            Ok(self.git_dir()?.join(name))
+        }
+
+        fn commit_message(&self, rev: &str) -> Result<String> {
+            Ok(format!("subject of {rev}\n\nbody of {rev}"))
+        }
+
+        fn head_branch(&self) -> Option<String> {
+            self.head_branch.clone()
+        }
+
+        fn commit(&self, message_file: &Path, amend: bool) -> Result<()> {
+            let message = std::fs::read_to_string(message_file)?;
+            self.made_commits.lock().unwrap().push((message, amend));
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use crate::line_diff;
+use crate::model::{BinaryInfo, BinarySide, DiffOptions, FileDiff, Whitespace};
+
+/// Bytes shown per hex dump row.
+const HEX_ROW: usize = 16;
+
+pub fn format_size(bytes: usize) -> String {
+    if bytes < 1024 {
+        format!("{bytes} bytes")
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
+
+pub fn format_delta(old: usize, new: usize) -> String {
+    match new.cmp(&old) {
+        std::cmp::Ordering::Equal => "same size".to_string(),
+        std::cmp::Ordering::Greater => format!("+{}", format_size(new - old)),
+        std::cmp::Ordering::Less => format!("-{}", format_size(old - new)),
+    }
+}
+
+/// Guess the file format from its magic bytes, as `(description, MIME type)`.
+pub fn sniff_format(bytes: &[u8]) -> (&'static str, &'static str) {
+    let starts = |magic: &[u8]| bytes.starts_with(magic);
+    if bytes.is_empty() {
+        ("empty file", "application/x-empty")
+    } else if starts(b"\x89PNG\r\n\x1a\n") {
+        ("PNG image", "image/png")
+    } else if starts(b"\xff\xd8\xff") {
+        ("JPEG image", "image/jpeg")
+    } else if starts(b"GIF87a") || starts(b"GIF89a") {
+        ("GIF image", "image/gif")
+    } else if starts(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        ("WebP image", "image/webp")
+    } else if starts(b"BM") && bytes.len() > 26 {
+        ("BMP image", "image/bmp")
+    } else if starts(b"\0\0\x01\0") {
+        ("ICO icon", "image/x-icon")
+    } else if starts(b"%PDF-") {
+        ("PDF document", "application/pdf")
+    } else if starts(b"PK\x03\x04") || starts(b"PK\x05\x06") {
+        ("ZIP archive", "application/zip")
+    } else if starts(b"\x1f\x8b") {
+        ("gzip archive", "application/gzip")
+    } else if starts(b"\xfd7zXZ\0") {
+        ("xz archive", "application/x-xz")
+    } else if starts(b"\x28\xb5\x2f\xfd") {
+        ("zstd archive", "application/zstd")
+    } else if starts(b"7z\xbc\xaf\x27\x1c") {
+        ("7-Zip archive", "application/x-7z-compressed")
+    } else if bytes.get(257..262) == Some(b"ustar") {
+        ("tar archive", "application/x-tar")
+    } else if starts(b"\x7fELF") {
+        ("ELF executable", "application/x-executable")
+    } else if starts(b"\0asm") {
+        ("WebAssembly module", "application/wasm")
+    } else if starts(b"SQLite format 3\0") {
+        ("SQLite database", "application/vnd.sqlite3")
+    } else if starts(b"wOFF") {
+        ("WOFF font", "font/woff")
+    } else if starts(b"wOF2") {
+        ("WOFF2 font", "font/woff2")
+    } else if starts(b"OTTO") {
+        ("OpenType font", "font/otf")
+    } else if starts(b"\0\x01\0\0") {
+        ("TrueType font", "font/ttf")
+    } else {
+        ("binary data", "application/octet-stream")
+    }
+}
+
+fn be16(bytes: &[u8], at: usize) -> Option<u32> {
+    let b = bytes.get(at..at + 2)?;
+    Some(u32::from(u16::from_be_bytes([b[0], b[1]])))
+}
+
+fn le16(bytes: &[u8], at: usize) -> Option<u32> {
+    let b = bytes.get(at..at + 2)?;
+    Some(u32::from(u16::from_le_bytes([b[0], b[1]])))
+}
+
+fn be32(bytes: &[u8], at: usize) -> Option<u32> {
+    let b = bytes.get(at..at + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn le32(bytes: &[u8], at: usize) -> Option<u32> {
+    let b = bytes.get(at..at + 4)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn le24(bytes: &[u8], at: usize) -> Option<u32> {
+    let b = bytes.get(at..at + 3)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], 0]))
+}
+
+/// Width and height of a PNG, JPEG, GIF, WebP, BMP or ICO image.
+pub fn image_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    match sniff_format(bytes).1 {
+        "image/png" => Some((be32(bytes, 16)?, be32(bytes, 20)?)),
+        "image/gif" => Some((le16(bytes, 6)?, le16(bytes, 8)?)),
+        "image/bmp" => {
+            let width = le32(bytes, 18)? as i32;
+            let height = le32(bytes, 22)? as i32;
+            Some((width.unsigned_abs(), height.unsigned_abs()))
+        }
+        "image/x-icon" => {
+            // Directory entries store 0 for 256 pixels.
+            let size = |b: &u8| if *b == 0 { 256 } else { u32::from(*b) };
+            Some((size(bytes.get(6)?), size(bytes.get(7)?)))
+        }
+        "image/jpeg" => jpeg_dimensions(bytes),
+        "image/webp" => match bytes.get(12..16)? {
+            b"VP8 " => Some((le16(bytes, 26)? & 0x3fff, le16(bytes, 28)? & 0x3fff)),
+            b"VP8L" => {
+                let bits = le32(bytes, 21)?;
+                Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+            }
+            b"VP8X" => Some((le24(bytes, 24)? + 1, le24(bytes, 27)? + 1)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Walk the JPEG segments up to the first start-of-frame marker.
+fn jpeg_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let mut i = 2;
+    loop {
+        while *bytes.get(i)? == 0xff && *bytes.get(i + 1)? == 0xff {
+            i += 1;
+        }
+        if *bytes.get(i)? != 0xff {
+            return None;
+        }
+        let marker = *bytes.get(i + 1)?;
+        let is_frame = (0xc0..=0xcf).contains(&marker) && !matches!(marker, 0xc4 | 0xc8 | 0xcc);
+        if is_frame {
+            return Some((be16(bytes, i + 7)?, be16(bytes, i + 5)?));
+        }
+        i += 2 + be16(bytes, i + 2)? as usize;
+    }
+}
+
+fn side(bytes: &[u8], options: &DiffOptions) -> BinarySide {
+    let (format, mime) = sniff_format(bytes);
+    let head_len = bytes.len().min(options.hex_dump_kb * 1024);
+    BinarySide {
+        size: bytes.len(),
+        format,
+        mime,
+        dimensions: image_dimensions(bytes),
+        head: bytes[..head_len].to_vec(),
+    }
+}
+
+/// Mark `diff` as binary and describe both sides in its header: size, size
+/// change, format and image dimensions.
+pub fn attach(diff: &mut FileDiff, old: Option<&[u8]>, new: Option<&[u8]>, options: &DiffOptions) {
+    let info = BinaryInfo {
+        old: old.map(|bytes| side(bytes, options)),
+        new: new.map(|bytes| side(bytes, options)),
+    };
+
+    let change = match (&info.old, &info.new) {
+        (None, Some(_)) => "added",
+        (Some(_), None) => "deleted",
+        _ => "changed",
+    };
+    let format = match (&info.old, &info.new) {
+        (Some(old), Some(new)) if old.mime != new.mime => {
+            format!(
+                "{} ({}) → {} ({})",
+                old.format, old.mime, new.format, new.mime
+            )
+        }
+        (_, Some(side)) | (Some(side), None) => format!("{} ({})", side.format, side.mime),
+        (None, None) => String::new(),
+    };
+    diff.header.push(format!("Binary file {change}: {format}"));
+
+    let describe = |label: &str, side: &BinarySide| {
+        let mut line = format!("  {label}: {}", format_size(side.size));
+        if let Some((width, height)) = side.dimensions {
+            line.push_str(&format!(", {width}×{height}"));
+        }
+        line
+    };
+    if let Some(old) = &info.old {
+        diff.header.push(describe("old", old));
+    }
+    if let Some(new) = &info.new {
+        let mut line = describe("new", new);
+        if let Some(old) = &info.old {
+            line.push_str(&format!(" ({})", format_delta(old.size, new.size)));
+        }
+        diff.header.push(line);
+    }
+
+    diff.binary = true;
+    diff.binary_info = Some(info);
+}
+
+/// One `xxd`-style row: offset, hex bytes in two groups of eight, ASCII.
+fn hex_row(offset: usize, chunk: &[u8]) -> String {
+    let mut hex = String::new();
+    for i in 0..HEX_ROW {
+        if i == HEX_ROW / 2 {
+            hex.push(' ');
+        }
+        match chunk.get(i) {
+            Some(b) => hex.push_str(&format!("{b:02x} ")),
+            None => hex.push_str("   "),
+        }
+    }
+    let ascii: String = chunk
+        .iter()
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        })
+        .collect();
+    format!("{offset:08x}  {hex} |{ascii}|")
+}
+
+fn hex_dump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, chunk) in bytes.chunks(HEX_ROW).enumerate() {
+        out.push_str(&hex_row(i * HEX_ROW, chunk));
+        out.push('\n');
+    }
+    out
+}
+
+/// A copy of a binary `diff` whose hunks compare hex dumps of the first
+/// bytes of each side, so the usual diff views can show what changed.
+pub fn hex_diff(diff: &FileDiff, options: &DiffOptions) -> FileDiff {
+    let mut hex = diff.clone();
+    let Some(info) = &diff.binary_info else {
+        return hex;
+    };
+    let dump =
+        |side: &Option<BinarySide>| side.as_ref().map(|s| hex_dump(&s.head)).unwrap_or_default();
+    hex.header.push(format!(
+        "  hex dump of the first {} KB",
+        options.hex_dump_kb
+    ));
+    // Spaces in a dump separate bytes, so never ignore them.
+    let exact = DiffOptions {
+        whitespace: Whitespace::default(),
+        ..options.clone()
+    };
+    hex.hunks = line_diff::diff_hunks(
+        dump(&info.old).as_bytes(),
+        dump(&info.new).as_bytes(),
+        &exact,
+    );
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{attach, hex_row, image_dimensions, sniff_format};
+    use crate::model::{DiffOptions, FileDiff};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        bytes.extend_from_slice(&width.to_be_bytes());
+        bytes.extend_from_slice(&height.to_be_bytes());
+        bytes.extend_from_slice(&[8, 6, 0, 0, 0]);
+        bytes
+    }
+
+    #[test]
+    fn image_dimensions_reads_png_and_gif_headers() {
+        assert_eq!(sniff_format(&png(64, 32)).1, "image/png");
+        assert_eq!(image_dimensions(&png(64, 32)), Some((64, 32)));
+        assert_eq!(image_dimensions(b"GIF89a\x10\0\x20\0\0\0"), Some((16, 32)));
+        assert_eq!(image_dimensions(b"\0\x01\x02"), None);
+    }
+
+    #[test]
+    fn attach_describes_size_change_and_dimensions() {
+        let mut diff = FileDiff::default();
+        let old = png(16, 16);
+        let mut new = png(32, 32);
+        new.extend_from_slice(&[0; 100]);
+        attach(&mut diff, Some(&old), Some(&new), &DiffOptions::default());
+
+        assert!(diff.binary);
+        assert_eq!(diff.header[0], "Binary file changed: PNG image (image/png)");
+        assert_eq!(diff.header[1], "  old: 29 bytes, 16×16");
+        assert_eq!(diff.header[2], "  new: 129 bytes, 32×32 (+100 bytes)");
+    }
+
+    #[test]
+    fn hex_row_pads_short_rows() {
+        assert_eq!(
+            hex_row(16, b"AB\0"),
+            "00000010  41 42 00                                          |AB.|"
+        );
+    }
+}
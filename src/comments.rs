@@ -0,0 +1,330 @@
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// How alike the commented lines and a stretch of the file must be, from 0
+/// to 1, for the comment to follow them there once they were edited.
+const MIN_SIMILARITY: f64 = 0.6;
+
+/// How many lines from where they were edited lines are looked for.
+const SIMILAR_WITHIN: usize = 200;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Old,
+    New,
+}
+
+impl Side {
+    pub fn name(self) -> &'static str {
+        match self {
+            Side::Old => "old",
+            Side::New => "new",
+        }
+    }
+
+    /// The line number on this side, of a line with `old` and `new` ones.
+    pub fn lineno(self, old: Option<u32>, new: Option<u32>) -> Option<u32> {
+        match self {
+            Side::Old => old,
+            Side::New => new,
+        }
+    }
+}
+
+/// A review note on a range of lines of one side of a file's diff.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comment {
+    pub path: String,
+    pub side: Side,
+    /// First and last commented line on `side`, from 1
+    pub start: u32,
+    pub end: u32,
+    /// The commented lines as they read when the comment was written
+    pub lines: Vec<String>,
+    pub text: String,
+    /// The commented lines were edited or removed since
+    pub outdated: bool,
+}
+
+impl Comment {
+    pub fn covers(&self, side: Side, lineno: u32) -> bool {
+        self.side == side && (self.start..=self.end).contains(&lineno)
+    }
+}
+
+/// Review comments, kept in `file`. Each comment is a `@ side start end
+/// outdated path` line, then its lines as `> ` lines and its text as `| `
+/// lines.
+#[derive(Default)]
+pub struct ReviewComments {
+    /// `None` when there is nowhere to keep them, like outside a repository
+    file: Option<PathBuf>,
+    pub comments: Vec<Comment>,
+}
+
+impl ReviewComments {
+    /// The comments saved in `file`; none when it is missing or unreadable.
+    pub fn load(file: Option<PathBuf>) -> Self {
+        let text = file
+            .as_ref()
+            .and_then(|f| std::fs::read_to_string(f).ok())
+            .unwrap_or_default();
+        let mut comments: Vec<Comment> = Vec::new();
+        for line in text.lines() {
+            if let Some(anchor) = line.strip_prefix("@ ") {
+                comments.extend(parse_anchor(anchor));
+                continue;
+            }
+            let Some(comment) = comments.last_mut() else {
+                continue;
+            };
+            if let Some(content) = line.strip_prefix("> ") {
+                comment.lines.push(content.to_string());
+            } else if let Some(text) = line.strip_prefix("| ") {
+                if !comment.text.is_empty() {
+                    comment.text.push('\n');
+                }
+                comment.text.push_str(text);
+            }
+        }
+        Self { file, comments }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        if self.comments.is_empty() {
+            return match std::fs::remove_file(file) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut text = String::new();
+        for c in &self.comments {
+            let state = if c.outdated { "outdated" } else { "current" };
+            text.push_str(&format!(
+                "@ {} {} {} {state} {}\n",
+                c.side.name(),
+                c.start,
+                c.end,
+                c.path
+            ));
+            for line in &c.lines {
+                text.push_str(&format!("> {line}\n"));
+            }
+            for line in c.text.lines() {
+                text.push_str(&format!("| {line}\n"));
+            }
+        }
+        std::fs::write(file, text).with_context(|| format!("Could not write {}", file.display()))
+    }
+
+    /// Where the comments are kept.
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    pub fn for_path<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a Comment> + 'a {
+        self.comments.iter().filter(move |c| c.path == path)
+    }
+
+    pub fn paths(&self) -> HashSet<String> {
+        self.comments.iter().map(|c| c.path.clone()).collect()
+    }
+}
+
+fn parse_anchor(anchor: &str) -> Option<Comment> {
+    let mut fields = anchor.splitn(5, ' ');
+    let side = match fields.next()? {
+        "old" => Side::Old,
+        "new" => Side::New,
+        _ => return None,
+    };
+    let start = fields.next()?.parse().ok()?;
+    let end = fields.next()?.parse().ok()?;
+    let outdated = fields.next()? == "outdated";
+    let path = fields.next()?.to_string();
+    Some(Comment {
+        path,
+        side,
+        start,
+        end,
+        lines: Vec::new(),
+        text: String::new(),
+        outdated,
+    })
+}
+
+/// Move `comment` to where its lines are in `text`, the file on its side
+/// now: the nearest exact copy, or else the most similar stretch within
+/// `SIMILAR_WITHIN` lines, which marks it outdated. Returns whether the
+/// comment changed.
+pub fn reanchor(comment: &mut Comment, text: &[&str]) -> bool {
+    let before = (comment.start, comment.end, comment.outdated);
+    let len = comment.lines.len();
+    if len == 0 || text.len() < len {
+        comment.outdated = true;
+        return before != (comment.start, comment.end, comment.outdated);
+    }
+    let old_start = (comment.start as usize).saturating_sub(1);
+    // Windows closest to where the comment was come first, so they win ties.
+    let mut starts: Vec<usize> = (0..=text.len() - len).collect();
+    starts.sort_by_key(|&s| s.abs_diff(old_start));
+    let same = |a: &str, b: &str| a.trim_end() == b.trim_end();
+    let exact = starts.iter().find(|&&s| {
+        comment
+            .lines
+            .iter()
+            .zip(&text[s..])
+            .all(|(a, b)| same(a, b))
+    });
+    let found = match exact {
+        Some(&s) => Some((s, false)),
+        None => {
+            let last = text.len() - len;
+            let first = old_start.saturating_sub(SIMILAR_WITHIN).min(last);
+            let end = (old_start + SIMILAR_WITHIN).min(last) + len;
+            let wanted: Vec<_> = comment.lines.iter().map(|l| Pairs::new(l)).collect();
+            let near: Vec<_> = text[first..end].iter().map(|l| Pairs::new(l)).collect();
+            let mut best: Option<(f64, usize)> = None;
+            for &s in starts
+                .iter()
+                .take_while(|s| s.abs_diff(old_start) <= SIMILAR_WITHIN)
+            {
+                let score = wanted
+                    .iter()
+                    .zip(&near[s - first..])
+                    .map(|(a, b)| a.similarity(b))
+                    .sum::<f64>()
+                    / len as f64;
+                if score >= MIN_SIMILARITY && best.is_none_or(|(b, _)| score > b) {
+                    best = Some((score, s));
+                }
+            }
+            best.map(|(_, s)| (s, true))
+        }
+    };
+    match found {
+        Some((s, outdated)) => {
+            comment.start = s as u32 + 1;
+            comment.end = (s + len) as u32;
+            comment.outdated = outdated;
+        }
+        None => comment.outdated = true,
+    }
+    before != (comment.start, comment.end, comment.outdated)
+}
+
+/// A line without its indentation and its sorted character pairs, to
+/// compare with others.
+struct Pairs<'a> {
+    text: &'a str,
+    pairs: Vec<(char, char)>,
+}
+
+impl<'a> Pairs<'a> {
+    fn new(line: &'a str) -> Self {
+        let text = line.trim();
+        let chars: Vec<char> = text.chars().collect();
+        let mut pairs: Vec<(char, char)> = chars.windows(2).map(|w| (w[0], w[1])).collect();
+        pairs.sort_unstable();
+        Self { text, pairs }
+    }
+
+    /// Dice coefficient of the character pairs of two lines: 1 for the
+    /// same text, 0 for nothing in common.
+    fn similarity(&self, other: &Pairs) -> f64 {
+        if self.text == other.text {
+            return 1.0;
+        }
+        let total = self.pairs.len() + other.pairs.len();
+        if total == 0 {
+            return 0.0;
+        }
+        let (mut a, mut b) = (self.pairs.iter().peekable(), other.pairs.iter().peekable());
+        let mut shared = 0;
+        while let (Some(x), Some(y)) = (a.peek(), b.peek()) {
+            match x.cmp(y) {
+                std::cmp::Ordering::Less => {
+                    a.next();
+                }
+                std::cmp::Ordering::Greater => {
+                    b.next();
+                }
+                std::cmp::Ordering::Equal => {
+                    shared += 1;
+                    a.next();
+                    b.next();
+                }
+            }
+        }
+        2.0 * shared as f64 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reanchor, Comment, ReviewComments, Side};
+
+    fn comment(start: u32, lines: &[&str]) -> Comment {
+        Comment {
+            path: "src/a b.rs".into(),
+            side: Side::New,
+            start,
+            end: start + lines.len() as u32 - 1,
+            lines: lines.iter().map(|l| l.to_string()).collect(),
+            text: "Why?\nThis needs a test.".into(),
+            outdated: false,
+        }
+    }
+
+    #[test]
+    fn comments_are_saved_and_follow_their_lines() {
+        let dir = std::env::temp_dir().join(format!("prdiff-comments-{}", std::process::id()));
+        let file = dir.join("prdiff").join("comments").join("topic");
+        let mut comments = ReviewComments::load(Some(file.clone()));
+        comments.comments.push(comment(2, &["let x = 1;", "x + 1"]));
+        comments.save().unwrap();
+        assert_eq!(
+            ReviewComments::load(Some(file.clone())).comments,
+            comments.comments
+        );
+        comments.comments.clear();
+        comments.save().unwrap();
+        assert!(!file.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Lines moved down by an insertion above.
+        let mut c = comment(2, &["let x = 1;", "x + 1"]);
+        assert!(reanchor(
+            &mut c,
+            &["fn f() {", "// new", "let x = 1;", "x + 1", "}"]
+        ));
+        assert_eq!((c.start, c.end, c.outdated), (3, 4, false));
+        assert!(!reanchor(
+            &mut c,
+            &["fn f() {", "// new", "let x = 1;", "x + 1", "}"]
+        ));
+
+        // Edited lines are followed, but the comment is outdated.
+        assert!(reanchor(&mut c, &["fn f() {", "let x = 10;", "x + 1", "}"]));
+        assert_eq!((c.start, c.end, c.outdated), (2, 3, true));
+
+        // Edited lines too far away are not looked for.
+        let mut c = comment(1, &["let x = 1;"]);
+        let mut far = vec!["fn f() {"; 300];
+        far.push("let x = 10;");
+        assert!(reanchor(&mut c, &far));
+        assert_eq!((c.start, c.outdated), (1, true));
+
+        // Gone entirely: it stays where it was.
+        let mut c = comment(2, &["let x = 1;"]);
+        assert!(reanchor(&mut c, &["fn f() {", "}", "", ""]));
+        assert_eq!((c.start, c.outdated), (2, true));
+    }
+}
@@ -0,0 +1,119 @@
+use crate::model::{ConflictKind, ConflictRegion, FileConflict};
+
+/// A conflicted path as `git merge-tree` lists it: which of the index
+/// stages (1 ancestor, 2 base, 3 branch) it has, and the merge result with
+/// its conflict markers.
+pub fn file_conflict(path: String, stages: [bool; 3], merged: Option<&[u8]>) -> FileConflict {
+    let kind = match stages {
+        [true, true, true] => ConflictKind::Content,
+        [false, true, true] => ConflictKind::AddedOnBoth,
+        [_, false, _] => ConflictKind::DeletedOnBase,
+        [_, true, false] => ConflictKind::DeletedOnBranch,
+    };
+    let regions = match kind {
+        ConflictKind::Content | ConflictKind::AddedOnBoth => {
+            merged.map_or_else(Vec::new, |text| regions(&String::from_utf8_lossy(text)))
+        }
+        _ => Vec::new(),
+    };
+    FileConflict {
+        path,
+        kind,
+        regions,
+    }
+}
+
+/// Where a line of the merge result falls relative to the conflict markers.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Outside,
+    Base,
+    /// The common ancestor, with `merge.conflictStyle=diff3`
+    Ancestor,
+    Branch,
+}
+
+fn is_marker(line: &str, marker: &str) -> bool {
+    line.strip_prefix(marker)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+}
+
+/// The conflicts in a merge result, base side first as `git merge-tree`
+/// writes them when the base is the first branch.
+pub fn regions(text: &str) -> Vec<ConflictRegion> {
+    let mut regions = Vec::new();
+    let mut region = ConflictRegion::default();
+    let mut section = Section::Outside;
+    let (mut base_line, mut branch_line) = (0, 0);
+    for line in text.lines() {
+        match section {
+            Section::Outside if is_marker(line, "<<<<<<<") => {
+                region = ConflictRegion {
+                    base_start: base_line + 1,
+                    branch_start: branch_line + 1,
+                    ..ConflictRegion::default()
+                };
+                section = Section::Base;
+            }
+            Section::Outside => {
+                base_line += 1;
+                branch_line += 1;
+            }
+            Section::Base if is_marker(line, "|||||||") => section = Section::Ancestor,
+            Section::Base | Section::Ancestor if line == "=======" => section = Section::Branch,
+            Section::Base => {
+                region.base.push(line.to_string());
+                base_line += 1;
+            }
+            Section::Ancestor => {}
+            Section::Branch if is_marker(line, ">>>>>>>") => {
+                regions.push(std::mem::take(&mut region));
+                section = Section::Outside;
+            }
+            Section::Branch => {
+                region.branch.push(line.to_string());
+                branch_line += 1;
+            }
+        }
+    }
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::file_conflict;
+    use crate::model::{ConflictKind, ConflictRegion};
+
+    #[test]
+    fn file_conflict_reads_kind_and_regions() {
+        let merged = "1\n<<<<<<< main\ntwo\n=======\nTWO\n2b\n>>>>>>> HEAD\n3\n\
+<<<<<<< main\nnine\n||||||| ancestor\n9\n=======\n>>>>>>> HEAD\n";
+        let conflict = file_conflict("a.txt".into(), [true, true, true], Some(merged.as_bytes()));
+        assert_eq!(conflict.kind, ConflictKind::Content);
+        assert_eq!(
+            conflict.regions,
+            [
+                ConflictRegion {
+                    base_start: 2,
+                    base: vec!["two".into()],
+                    branch_start: 2,
+                    branch: vec!["TWO".into(), "2b".into()],
+                },
+                ConflictRegion {
+                    base_start: 4,
+                    base: vec!["nine".into()],
+                    branch_start: 5,
+                    branch: vec![],
+                },
+            ]
+        );
+
+        let deleted = file_conflict("gone.txt".into(), [true, false, true], Some(b"kept\n"));
+        assert_eq!(deleted.kind, ConflictKind::DeletedOnBase);
+        assert!(deleted.regions.is_empty());
+        assert_eq!(
+            file_conflict("new".into(), [false, true, true], None).kind,
+            ConflictKind::AddedOnBoth
+        );
+    }
+}
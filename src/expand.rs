@@ -0,0 +1,161 @@
+use crate::model::{DiffLine, DiffLineKind, FileDiff, Hunk};
+use std::ops::Range;
+
+/// Hidden lines one expand step reveals.
+pub const EXPAND_STEP: u32 = 20;
+
+/// Where to reveal lines hidden around a hunk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expand {
+    /// Up to [`EXPAND_STEP`] lines above the hunk
+    Up,
+    /// Up to [`EXPAND_STEP`] lines below the hunk
+    Down,
+    /// Everything between the hunk and the previous one, or the next one
+    /// when nothing is hidden above
+    Gap,
+}
+
+/// 0-based lines a hunk covers on one side. git reports an empty range as
+/// starting at the line before it.
+pub fn covered(start: u32, count: u32) -> Range<u32> {
+    let begin = if count == 0 { start } else { start - 1 };
+    begin..begin + count
+}
+
+/// Recompute a hunk's header from the line numbers of its lines.
+fn recount(hunk: &mut Hunk) {
+    let old: Vec<u32> = hunk.lines.iter().filter_map(|l| l.old_lineno).collect();
+    let new: Vec<u32> = hunk.lines.iter().filter_map(|l| l.new_lineno).collect();
+    hunk.old_count = old.len() as u32;
+    hunk.new_count = new.len() as u32;
+    if let Some(&first) = old.first() {
+        hunk.old_start = first;
+    }
+    if let Some(&first) = new.first() {
+        hunk.new_start = first;
+    }
+}
+
+/// Context line for 0-based new line `new` and old line `old`, plus a
+/// marker if it is the last line and lacks a newline.
+fn context_line(text: &str, old: u32, new: u32) -> Vec<DiffLine> {
+    let content = text.strip_suffix('\n').unwrap_or(text);
+    let content = content.strip_suffix('\r').unwrap_or(content);
+    let mut lines = vec![DiffLine {
+        kind: DiffLineKind::Context,
+        content: content.to_string(),
+        old_lineno: Some(old + 1),
+        new_lineno: Some(new + 1),
+    }];
+    if !text.ends_with('\n') {
+        lines.push(DiffLine {
+            kind: DiffLineKind::NoNewline,
+            content: " No newline at end of file".to_string(),
+            old_lineno: None,
+            new_lineno: None,
+        });
+    }
+    lines
+}
+
+/// Reveal hidden lines next to hunk `index`, taken from `text`, the new
+/// side of the file. Hunks that end up touching are merged. Returns
+/// `false` when nothing was hidden there, or the file has no new side.
+pub fn expand_hunk(diff: &mut FileDiff, index: usize, expand: Expand, text: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(text);
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let Some(hunk) = diff.hunks.get(index) else {
+        return false;
+    };
+    // A deleted file's hunk already covers all of it.
+    if hunk.new_start == 0 && hunk.new_count == 0 {
+        return false;
+    }
+    let new = covered(hunk.new_start, hunk.new_count);
+    let old = covered(hunk.old_start, hunk.old_count);
+    if new.end > lines.len() as u32 {
+        // The file changed since the diff was taken.
+        return false;
+    }
+    let above = index.checked_sub(1).map_or(0, |i| {
+        covered(diff.hunks[i].new_start, diff.hunks[i].new_count).end
+    });
+    let below = diff.hunks.get(index + 1).map_or(lines.len() as u32, |h| {
+        covered(h.new_start, h.new_count).start
+    });
+    let (up, down) = match expand {
+        Expand::Up => (EXPAND_STEP, 0),
+        Expand::Down => (0, EXPAND_STEP),
+        Expand::Gap if new.start > above => (u32::MAX, 0),
+        Expand::Gap => (0, u32::MAX),
+    };
+    let top = new.start.saturating_sub(up).max(above);
+    let bottom = new.end.saturating_add(down).min(below);
+    if top == new.start && bottom == new.end {
+        return false;
+    }
+
+    // Old line numbers follow from the edge of the hunk being extended.
+    let mut prefix = Vec::new();
+    for n in top..new.start {
+        let old_line = old.start.saturating_sub(new.start - n);
+        prefix.extend(context_line(lines[n as usize], old_line, n));
+    }
+    let mut suffix = Vec::new();
+    for n in new.end..bottom {
+        suffix.extend(context_line(lines[n as usize], old.end + (n - new.end), n));
+    }
+    let hunk = &mut diff.hunks[index];
+    hunk.lines.splice(0..0, prefix);
+    hunk.lines.extend(suffix);
+    recount(hunk);
+
+    if bottom == below && index + 1 < diff.hunks.len() {
+        let next = diff.hunks.remove(index + 1);
+        diff.hunks[index].lines.extend(next.lines);
+        recount(&mut diff.hunks[index]);
+    }
+    if top == above && index > 0 {
+        let hunk = diff.hunks.remove(index);
+        diff.hunks[index - 1].lines.extend(hunk.lines);
+        recount(&mut diff.hunks[index - 1]);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand_hunk, Expand};
+    use crate::git::parse_diff;
+
+    #[test]
+    fn expanding_fills_gaps_and_merges_hunks() {
+        let text: String = (1..=12).map(|n| format!("line {n}\n")).collect();
+        let text = text
+            .replace("line 2\n", "two\n")
+            .replace("line 10\n", "ten\n");
+        let mut diff = parse_diff(
+            "--- a/f\n+++ b/f\n@@ -1,3 +1,3 @@\n line 1\n-line 2\n+two\n line 3\n\
+@@ -9,3 +9,3 @@\n line 9\n-line 10\n+ten\n line 11\n",
+        );
+
+        assert!(expand_hunk(&mut diff, 1, Expand::Down, text.as_bytes()));
+        assert_eq!(diff.hunks[1].header(), "@@ -9,4 +9,4 @@");
+        assert!(!expand_hunk(&mut diff, 1, Expand::Down, text.as_bytes()));
+
+        assert!(expand_hunk(&mut diff, 1, Expand::Gap, text.as_bytes()));
+        assert_eq!(diff.hunks.len(), 1);
+        assert_eq!(diff.hunks[0].header(), "@@ -1,12 +1,12 @@");
+        let line = &diff.hunks[0].lines[5];
+        assert_eq!(
+            (line.content.as_str(), line.old_lineno, line.new_lineno),
+            ("line 5", Some(5), Some(5))
+        );
+
+        // A deleted file has no new side to take context from.
+        let mut deleted = parse_diff("--- a/f\n+++ /dev/null\n@@ -1,2 +0,0 @@\n-line 1\n-two\n");
+        assert!(!expand_hunk(&mut deleted, 0, Expand::Down, text.as_bytes()));
+        assert_eq!(deleted.hunks[0].lines.len(), 2);
+    }
+}
@@ -0,0 +1,225 @@
+use crate::expand::covered;
+use crate::model::{DiffLineKind, FileDiff};
+use std::collections::BTreeMap;
+
+/// Gutter marker of a line in the full-file view.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Marker {
+    Added,
+    /// Added in place of removed lines
+    Modified,
+    /// Unchanged, with lines removed just above it
+    DeletedAbove,
+    /// The last line, with lines removed after it
+    DeletedBelow,
+}
+
+impl Marker {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Marker::Added => "+",
+            Marker::Modified => "~",
+            Marker::DeletedAbove => "‾",
+            Marker::DeletedBelow => "_",
+        }
+    }
+}
+
+/// One row of the full-file view.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileRow {
+    /// 0-based line of the file, with its old line number when unchanged
+    Line {
+        index: usize,
+        old_lineno: Option<u32>,
+        marker: Option<Marker>,
+    },
+    /// Removed line shown inline, by hunk index and line index in the hunk
+    Removed { hunk: usize, line: usize },
+}
+
+impl FileRow {
+    fn is_change(&self) -> bool {
+        matches!(
+            self,
+            FileRow::Removed { .. }
+                | FileRow::Line {
+                    marker: Some(_),
+                    ..
+                }
+        )
+    }
+}
+
+/// Removed and added lines between two context lines, waiting to be marked.
+#[derive(Default)]
+struct Run {
+    removed: Vec<(usize, usize)>,
+    added: Vec<usize>,
+}
+
+/// Lay out a file of `line_count` lines with the changes of `diff` marked,
+/// showing its removed lines in place when `show_removed` is set.
+pub fn file_rows(diff: &FileDiff, line_count: usize, show_removed: bool) -> Vec<FileRow> {
+    let mut markers = vec![None; line_count];
+    let mut old_linenos = vec![None; line_count];
+    // Removed lines by the line they are shown above; `line_count` is the end.
+    let mut removed: BTreeMap<usize, Vec<(usize, usize)>> = BTreeMap::new();
+
+    let mut flush = |run: &mut Run, after: usize, markers: &mut Vec<Option<Marker>>| {
+        let anchor = match run.added.first() {
+            Some(&first) => {
+                let marker = if run.removed.is_empty() {
+                    Marker::Added
+                } else {
+                    Marker::Modified
+                };
+                for &i in &run.added {
+                    markers[i] = Some(marker);
+                }
+                first
+            }
+            None if run.removed.is_empty() => return,
+            None if after < line_count => {
+                markers[after].get_or_insert(Marker::DeletedAbove);
+                after
+            }
+            None => {
+                if let Some(last) = markers.last_mut() {
+                    last.get_or_insert(Marker::DeletedBelow);
+                }
+                after
+            }
+        };
+        removed.entry(anchor).or_default().append(&mut run.removed);
+        run.added.clear();
+    };
+
+    // Unchanged lines outside hunks are offset by the changes above them.
+    let mut next = 0;
+    let mut delta = 0i64;
+    for (h, hunk) in diff.hunks.iter().enumerate() {
+        let new = covered(hunk.new_start, hunk.new_count);
+        let old = covered(hunk.old_start, hunk.old_count);
+        if new.end as usize > line_count {
+            // The file changed since the diff was taken.
+            break;
+        }
+        for (i, lineno) in old_linenos
+            .iter_mut()
+            .enumerate()
+            .take(new.start as usize)
+            .skip(next)
+        {
+            *lineno = Some((i as i64 + 1 + delta) as u32);
+        }
+
+        let mut run = Run::default();
+        let mut after = new.start as usize;
+        for (i, line) in hunk.lines.iter().enumerate() {
+            let index = line.new_lineno.map(|n| n as usize - 1);
+            match (line.kind, index) {
+                (DiffLineKind::Removed, _) => {
+                    if !run.added.is_empty() {
+                        flush(&mut run, after, &mut markers);
+                    }
+                    run.removed.push((h, i));
+                }
+                (DiffLineKind::Added, Some(index)) => {
+                    run.added.push(index);
+                    after = index + 1;
+                }
+                (DiffLineKind::Context, Some(index)) => {
+                    flush(&mut run, after, &mut markers);
+                    old_linenos[index] = line.old_lineno;
+                    after = index + 1;
+                }
+                _ => {}
+            }
+        }
+        flush(&mut run, after, &mut markers);
+        next = new.end as usize;
+        delta = old.end as i64 - new.end as i64;
+    }
+    for (i, lineno) in old_linenos.iter_mut().enumerate().skip(next) {
+        *lineno = Some((i as i64 + 1 + delta) as u32);
+    }
+
+    let mut rows = Vec::with_capacity(line_count);
+    let push_removed = |rows: &mut Vec<FileRow>, anchor: usize| {
+        if show_removed {
+            for &(hunk, line) in removed.get(&anchor).into_iter().flatten() {
+                rows.push(FileRow::Removed { hunk, line });
+            }
+        }
+    };
+    for (index, (old_lineno, marker)) in old_linenos.into_iter().zip(markers).enumerate() {
+        push_removed(&mut rows, index);
+        rows.push(FileRow::Line {
+            index,
+            old_lineno,
+            marker,
+        });
+    }
+    push_removed(&mut rows, line_count);
+    rows
+}
+
+/// Rows where a block of changed rows begins, for jumping between changes.
+pub fn change_starts(rows: &[FileRow]) -> Vec<usize> {
+    (0..rows.len())
+        .filter(|&i| rows[i].is_change() && (i == 0 || !rows[i - 1].is_change()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{change_starts, file_rows, FileRow, Marker};
+    use crate::git::parse_diff;
+
+    #[test]
+    fn file_rows_mark_changes_and_place_removed_lines() {
+        // Old: a b c d e f; new: a B c x d f (b changed, x added, e removed).
+        let diff =
+            parse_diff("--- a/f\n+++ b/f\n@@ -1,6 +1,6 @@\n a\n-b\n+B\n c\n+x\n d\n-e\n f\n");
+        let markers: Vec<_> = file_rows(&diff, 6, false)
+            .into_iter()
+            .map(|row| match row {
+                FileRow::Line {
+                    old_lineno, marker, ..
+                } => (old_lineno, marker),
+                FileRow::Removed { .. } => panic!("removed lines are hidden"),
+            })
+            .collect();
+        assert_eq!(
+            markers,
+            [
+                (Some(1), None),
+                (None, Some(Marker::Modified)),
+                (Some(3), None),
+                (None, Some(Marker::Added)),
+                (Some(4), None),
+                (Some(6), Some(Marker::DeletedAbove)),
+            ]
+        );
+
+        let rows = file_rows(&diff, 6, true);
+        assert_eq!(rows.len(), 8);
+        assert_eq!(rows[1], FileRow::Removed { hunk: 0, line: 1 });
+        assert_eq!(rows[6], FileRow::Removed { hunk: 0, line: 6 });
+        assert_eq!(change_starts(&rows), [1, 4, 6]);
+
+        // Lines past the last hunk keep their offset, and a deletion at the
+        // end marks the last line.
+        let diff = parse_diff("--- a/f\n+++ b/f\n@@ -0,0 +1,1 @@\n+new\n@@ -4,1 +4,0 @@\n-gone\n");
+        let rows = file_rows(&diff, 4, false);
+        assert_eq!(
+            rows[3],
+            FileRow::Line {
+                index: 3,
+                old_lineno: Some(3),
+                marker: Some(Marker::DeletedBelow)
+            }
+        );
+    }
+}
@@ -1,87 +1,470 @@
-use crate::model::{DiffSource, FileEntry, FileStatus};
+use crate::backend::GitBackend;
+use crate::binary;
+use crate::conflict;
+use crate::lfs;
+use crate::logging;
+use crate::model::{
+    ApplyTo, Attributes, CommitInfo, DiffLine, DiffLineKind, DiffOptions, DiffRange, DiffSource,
+    FileDiff, FileEntry, FileSides, FileStatus, Hunk, MergePreview, MergeSource, WorkState,
+    MODE_GITLINK,
+};
+use crate::submodule;
 use anyhow::{Context, Result};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::hash::{Hash, Hasher};
-use std::process::Command;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
 
 /// Create a git Command with GIT_OPTIONAL_LOCKS=0 to avoid creating index.lock.
 /// prdiff is read-only and should never lock the index, which would conflict
-/// with user git operations in the same repo.
+/// with user git operations in the same repo. Only staging in interactive
+/// mode writes it.
 fn git_cmd() -> Command {
     let mut cmd = Command::new("git");
     cmd.env("GIT_OPTIONAL_LOCKS", "0");
     cmd
 }
 
-pub fn detect_base_branch(specified: Option<String>) -> Result<String> {
+/// `git` running in the repository at `dir`, e.g. a submodule.
+fn git_cmd_in(dir: &Path) -> Command {
+    let mut cmd = git_cmd();
+    cmd.current_dir(dir);
+    cmd
+}
+
+pub fn detect_base_branch(backend: &dyn GitBackend, specified: Option<String>) -> Result<String> {
     if let Some(b) = specified {
-        return resolve_base_ref(&b);
+        return resolve_base_ref(backend, &b);
     }
 
     // Try common base branch names. The upstream tracking branch isn't useful here
     // since feature branches typically track origin/feature-branch, not the base.
     for branch in ["develop", "main", "master"] {
-        if let Ok(resolved) = resolve_base_ref(branch) {
+        if let Ok(resolved) = resolve_base_ref(backend, branch) {
             return Ok(resolved);
         }
     }
-    anyhow::bail!(
-        "No base branch found (develop/main/master). Specify one with --base <BRANCH>."
-    )
+    anyhow::bail!("No base branch found (develop/main/master). Specify one with --base <BRANCH>.")
+}
+
+/// Object id of the empty tree, used as the old side of a root commit.
+pub const EMPTY_TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+/// Turn `A..B`, `A...B` or a single commit into the range to diff.
+/// Both ends are resolved to object ids so the view doesn't move if refs do.
+pub fn resolve_range(backend: &dyn GitBackend, spec: &str) -> Result<DiffRange> {
+    let or_head = |rev: &str| {
+        if rev.is_empty() {
+            "HEAD".to_string()
+        } else {
+            rev.to_string()
+        }
+    };
+
+    if let Some((from, to)) = spec.split_once("...") {
+        let (from, to) = (or_head(from), or_head(to));
+        let to = backend.rev_parse(&to)?;
+        let from = backend.merge_base(&from, &to)?;
+        return Ok(DiffRange::commits(from, to));
+    }
+    if let Some((from, to)) = spec.split_once("..") {
+        let from = backend.rev_parse(&or_head(from))?;
+        let to = backend.rev_parse(&or_head(to))?;
+        return Ok(DiffRange::commits(from, to));
+    }
+
+    let to = backend.rev_parse(spec)?;
+    let from = backend
+        .rev_parse(&format!("{spec}^"))
+        .unwrap_or_else(|_| EMPTY_TREE.to_string());
+    Ok(DiffRange::commits(from, to))
 }
 
-pub fn get_merge_base(base: &str) -> Result<String> {
-    let out = git_cmd()
-        .args(["merge-base", "HEAD", base])
+fn get_merge_base(dir: &Path, one: &str, two: &str) -> Result<String> {
+    let out = git_cmd_in(dir)
+        .args(["merge-base", one, two])
         .output()
         .context("Failed to run git merge-base")?;
     if !out.status.success() {
-        anyhow::bail!("Could not find merge-base with '{base}'");
+        anyhow::bail!("Could not find merge-base of '{one}' and '{two}'");
     }
     Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
 }
 
 /// Check if file content appears to be binary by looking for NUL bytes in the first 8KB.
-fn is_binary(bytes: &[u8]) -> bool {
+pub fn is_binary(bytes: &[u8]) -> bool {
     let check_len = bytes.len().min(8192);
     bytes[..check_len].contains(&0)
 }
 
-fn format_size(bytes: usize) -> String {
-    if bytes < 1024 {
-        format!("{bytes} bytes")
-    } else if bytes < 1024 * 1024 {
-        format!("{:.1} KB", bytes as f64 / 1024.0)
-    } else {
-        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+/// `git diff` flags for rename and copy detection at the configured threshold.
+fn rename_args(options: &DiffOptions) -> Vec<String> {
+    match options.rename_threshold {
+        0 => vec!["--no-renames".to_string()],
+        n => vec![format!("-M{n}%"), format!("-C{n}%")],
+    }
+}
+
+fn get_changed_files(
+    dir: &Path,
+    range: &DiffRange,
+    options: &DiffOptions,
+) -> Result<Vec<FileEntry>> {
+    let merge_base = range.from.as_str();
+    if let Some(to) = &range.to {
+        return git_diff_status_and_stats(dir, &[merge_base, to], options);
+    }
+
+    let mut files = match range.work {
+        WorkState::Index => {
+            return git_diff_status_and_stats(dir, &["--cached", merge_base], options);
+        }
+        WorkState::Unstaged => git_diff_status_and_stats(dir, &[], options)?,
+        WorkState::Worktree => {
+            // Effective PR diff is merge_base..(worktree) with a fallback to index-only
+            // changes in the rare case the working tree no longer contains them.
+            let mut files = git_diff_status_and_stats(dir, &[merge_base], options)?;
+            let index_files = git_diff_status_and_stats(dir, &["--cached", merge_base], options)?;
+            let seen_paths: HashSet<String> = files.iter().map(|f| f.path.clone()).collect();
+
+            // Add index-only files that aren't represented in the working tree diff.
+            files.extend(
+                index_files
+                    .into_iter()
+                    .filter(|entry| !seen_paths.contains(&entry.path)),
+            );
+            files
+        }
+    };
+
+    if range.untracked {
+        push_untracked_files(dir, &mut files)?;
+    }
+    Ok(files)
+}
+
+/// Paths per `git check-attr` call, to stay well below the argument limit.
+const CHECK_ATTR_BATCH: usize = 1000;
+
+/// Set the [`Attributes`] of `files` from `git check-attr`, which reads the
+/// working tree's `.gitattributes` and falls back to the index.
+fn apply_attributes(dir: &Path, files: &mut [FileEntry]) -> Result<()> {
+    let paths: Vec<String> = files.iter().map(|f| f.path.clone()).collect();
+    let mut attributes = HashMap::new();
+    for batch in paths.chunks(CHECK_ATTR_BATCH) {
+        let out = git_cmd_in(dir)
+            .args(["check-attr", "-z"])
+            .args(Attributes::NAMES)
+            .arg("--")
+            .args(batch)
+            .output()
+            .context("Failed to run git check-attr")?;
+        if !out.status.success() {
+            anyhow::bail!(
+                "git check-attr failed: {}",
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+        }
+        attributes.extend(parse_check_attr(&String::from_utf8_lossy(&out.stdout)));
+    }
+    for file in files {
+        if let Some(&found) = attributes.get(&file.path) {
+            file.set_attributes(found);
+        }
+    }
+    Ok(())
+}
+
+/// Parse `git check-attr -z` output: `path NUL attribute NUL value NUL` records.
+fn parse_check_attr(text: &str) -> HashMap<String, Attributes> {
+    let fields: Vec<&str> = text.split('\0').collect();
+    let mut attributes: HashMap<String, Attributes> = HashMap::new();
+    for record in fields.chunks_exact(3) {
+        attributes
+            .entry(record[0].to_string())
+            .or_default()
+            .assign(record[1], record[2]);
+    }
+    attributes
+}
+
+/// Set [`FileEntry::lfs`] on files with a Git LFS pointer on either side.
+/// One `git cat-file --batch-check` sizes the blobs, and only those small
+/// enough to be pointers are read, by one `git cat-file --batch`.
+fn mark_lfs_pointers(dir: &Path, range: &DiffRange, files: &mut [FileEntry]) -> Result<()> {
+    let from = range.from.as_str();
+    let mut specs: Vec<(usize, String)> = Vec::new();
+    for (i, file) in files.iter().enumerate() {
+        let path = file.path.as_str();
+        let old_path = file.old_path.as_deref().unwrap_or(path);
+        // `cat-file` reads one object name per line.
+        if file.is_submodule() || path.contains('\n') || old_path.contains('\n') {
+            continue;
+        }
+        let old = match (&range.to, range.work) {
+            (None, WorkState::Unstaged) => format!(":{old_path}"),
+            _ => format!("{from}:{old_path}"),
+        };
+        specs.push((i, old));
+        match (&range.to, range.work) {
+            (Some(to), _) => specs.push((i, format!("{to}:{path}"))),
+            (None, WorkState::Index) => specs.push((i, format!(":{path}"))),
+            // The new side is the working tree, read below.
+            (None, _) => {}
+        }
+    }
+
+    let mut pointers = HashSet::new();
+    if !specs.is_empty() {
+        let names: Vec<&str> = specs.iter().map(|(_, spec)| spec.as_str()).collect();
+        let sizes = cat_file_batch(dir, "--batch-check", &names)?;
+        let sizes = String::from_utf8_lossy(&sizes);
+        let mut small: Vec<(usize, &str)> = Vec::new();
+        for ((i, _), line) in specs.iter().zip(sizes.lines()) {
+            let fields: Vec<&str> = line.split(' ').collect();
+            if let [oid, "blob", size] = fields[..] {
+                if size
+                    .parse()
+                    .is_ok_and(|n: usize| n <= lfs::MAX_POINTER_SIZE)
+                {
+                    small.push((*i, oid));
+                }
+            }
+        }
+        if !small.is_empty() {
+            let oids: Vec<&str> = small.iter().map(|(_, oid)| *oid).collect();
+            let contents = cat_file_batch(dir, "--batch", &oids)?;
+            let found: HashSet<&str> = parse_cat_file_batch(&contents)
+                .into_iter()
+                .filter(|(_, bytes)| lfs::parse_pointer(bytes).is_some())
+                .map(|(oid, _)| oid)
+                .collect();
+            pointers.extend(
+                small
+                    .iter()
+                    .filter(|(_, oid)| found.contains(oid))
+                    .map(|(i, _)| *i),
+            );
+        }
+    }
+
+    let on_disk = |path: &str| {
+        let full = dir.join(path);
+        let small = std::fs::symlink_metadata(&full)
+            .is_ok_and(|meta| meta.is_file() && meta.len() <= lfs::MAX_POINTER_SIZE as u64);
+        small && std::fs::read(&full).is_ok_and(|bytes| lfs::parse_pointer(&bytes).is_some())
+    };
+    let worktree = range.to.is_none() && range.work != WorkState::Index;
+    for (i, file) in files.iter_mut().enumerate() {
+        if pointers.contains(&i) || (worktree && !file.is_submodule() && on_disk(&file.path)) {
+            file.mark_lfs();
+        }
+    }
+    Ok(())
+}
+
+/// Run `git cat-file` in a batch `mode`, with one object name per line.
+fn cat_file_batch(dir: &Path, mode: &str, names: &[&str]) -> Result<Vec<u8>> {
+    let mut child = git_cmd_in(dir)
+        .args(["cat-file", mode])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("Failed to run git cat-file")?;
+    let input: String = names.iter().map(|name| format!("{name}\n")).collect();
+    let mut stdin = child.stdin.take().context("git cat-file has no stdin")?;
+    // Written from another thread so a full stdout pipe can't block it.
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let out = child
+        .wait_with_output()
+        .context("Failed to run git cat-file")?;
+    writer
+        .join()
+        .ok()
+        .transpose()
+        .context("Failed to write to git cat-file")?;
+    if !out.status.success() {
+        anyhow::bail!("git cat-file {mode} failed");
+    }
+    Ok(out.stdout)
+}
+
+/// Parse `git cat-file --batch` output into `(oid, contents)` pairs,
+/// skipping names that were missing.
+fn parse_cat_file_batch(out: &[u8]) -> Vec<(&str, &[u8])> {
+    let mut objects = Vec::new();
+    let mut rest = out;
+    while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+        let header = std::str::from_utf8(&rest[..end]).unwrap_or_default();
+        rest = &rest[end + 1..];
+        let fields: Vec<&str> = header.split(' ').collect();
+        let [oid, _, size] = fields[..] else {
+            continue;
+        };
+        let Some(contents) = size.parse().ok().and_then(|size: usize| rest.get(..size)) else {
+            break;
+        };
+        objects.push((oid, contents));
+        rest = rest.get(contents.len() + 1..).unwrap_or_default();
     }
+    objects
 }
 
-pub fn get_changed_files(merge_base: &str) -> Result<Vec<FileEntry>> {
-    // Effective PR diff is merge_base..(worktree) with a fallback to index-only changes
-    // in the rare case the working tree no longer contains them.
-    let work_files = git_diff_status_and_stats(merge_base, false)?;
-    let index_files = git_diff_status_and_stats(merge_base, true)?;
+/// Merge the branch into `base` with `git merge-tree`, which writes the
+/// result's objects but leaves the working tree, index and refs alone.
+/// Both backends use it: gitoxide is built without its merge support.
+pub fn merge_preview(dir: &Path, base: &str, source: MergeSource) -> Result<MergePreview> {
+    let head = match source {
+        MergeSource::Head => "HEAD".to_string(),
+        MergeSource::Worktree => worktree_commit(dir)?,
+    };
+    let out = git_cmd_in(dir)
+        .args([
+            "merge-tree",
+            "--write-tree",
+            "-z",
+            "--no-messages",
+            base,
+            &head,
+        ])
+        .output()
+        .context("Failed to run git merge-tree")?;
+    // Exit status 1 means the merge has conflicts.
+    if !matches!(out.status.code(), Some(0 | 1)) {
+        anyhow::bail!(
+            "git merge-tree failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    let (tree, stages) = parse_merge_tree(&String::from_utf8_lossy(&out.stdout));
+    let conflicts = stages
+        .into_iter()
+        .map(|(path, stages)| {
+            let merged = git_blob(dir, &format!("{tree}:{path}"));
+            conflict::file_conflict(path, stages, merged.as_deref())
+        })
+        .collect();
+    Ok(MergePreview { tree, conflicts })
+}
 
-    let mut files: Vec<FileEntry> = Vec::new();
-    let mut seen_paths: HashSet<String> = HashSet::new();
+/// The temp index the last worktree commit left on disk: the repository,
+/// the file, and the modification time of the real index it came from.
+type MergeIndex = (PathBuf, PathBuf, Option<SystemTime>);
 
-    for entry in &work_files {
-        seen_paths.insert(entry.path.clone());
-        files.push(entry.clone());
+static LAST_MERGE_INDEX: Mutex<Option<MergeIndex>> = Mutex::new(None);
+static MERGE_INDEX_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Commit the working tree, untracked files included, on top of HEAD. It
+/// goes through a copy of the index in a file of its own, so the real one
+/// is never locked and concurrent runs don't share it. While the real
+/// index is unchanged the last run's copy is reused, so files hashed then,
+/// untracked ones included, aren't hashed and written again.
+fn worktree_commit(dir: &Path) -> Result<String> {
+    let index = git_path(dir, "index")?;
+    let index_mtime = std::fs::metadata(&index).and_then(|m| m.modified()).ok();
+    let last = LAST_MERGE_INDEX
+        .lock()
+        .ok()
+        .and_then(|mut last| last.take());
+    let temp_index = match last {
+        Some((last_dir, file, mtime))
+            if last_dir == dir && mtime == index_mtime && file.exists() =>
+        {
+            file
+        }
+        last => {
+            if let Some((_, file, _)) = last {
+                let _ = std::fs::remove_file(file);
+            }
+            let count = MERGE_INDEX_COUNT.fetch_add(1, Ordering::Relaxed);
+            let file = git_path(
+                dir,
+                &format!("prdiff-merge-index-{}-{count}", std::process::id()),
+            )?;
+            if index.exists() {
+                std::fs::copy(&index, &file).context("Failed to copy the index")?;
+            }
+            file
+        }
+    };
+    let run = |args: &[&str]| -> Result<String> {
+        let out = git_cmd_in(dir)
+            .env("GIT_INDEX_FILE", &temp_index)
+            .envs(["GIT_AUTHOR", "GIT_COMMITTER"].iter().flat_map(|who| {
+                [
+                    (format!("{who}_NAME"), "prdiff"),
+                    (format!("{who}_EMAIL"), "prdiff@localhost"),
+                ]
+            }))
+            .args(args)
+            .output()
+            .with_context(|| format!("Failed to run git {}", args[0]))?;
+        if !out.status.success() {
+            anyhow::bail!(
+                "git {} failed: {}",
+                args[0],
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+    };
+    let commit = run(&["add", "-A"])
+        .and_then(|_| run(&["write-tree"]))
+        .and_then(|tree| {
+            run(&[
+                "commit-tree",
+                &tree,
+                "-p",
+                "HEAD",
+                "-m",
+                "prdiff merge preview",
+            ])
+        });
+    // Keep the copy for the next run, or drop it if another run's is kept.
+    let kept = commit.is_ok()
+        && LAST_MERGE_INDEX.lock().is_ok_and(|mut last| {
+            last.get_or_insert_with(|| (dir.to_path_buf(), temp_index.clone(), index_mtime))
+                .1
+                == temp_index
+        });
+    if !kept {
+        let _ = std::fs::remove_file(&temp_index);
     }
+    commit
+}
 
-    // Add index-only files that aren't represented in the working tree diff.
-    for entry in &index_files {
-        if seen_paths.contains(&entry.path) {
+/// Parse `git merge-tree --write-tree -z` output: the merged tree, then a
+/// `<mode> <oid> <stage> TAB <path>` record per conflicted stage, ending at
+/// an empty record. Returns the stages (1 to 3) present for each path.
+fn parse_merge_tree(text: &str) -> (String, BTreeMap<String, [bool; 3]>) {
+    let mut records = text.split('\0');
+    let tree = records.next().unwrap_or_default().trim().to_string();
+    let mut stages: BTreeMap<String, [bool; 3]> = BTreeMap::new();
+    for record in records.take_while(|r| !r.is_empty()) {
+        let Some((info, path)) = record.split_once('\t') else {
             continue;
+        };
+        let stage = info
+            .rsplit(' ')
+            .next()
+            .and_then(|s| s.parse::<usize>().ok());
+        if let Some(stage @ 1..=3) = stage {
+            stages.entry(path.to_string()).or_default()[stage - 1] = true;
         }
-        seen_paths.insert(entry.path.clone());
-        files.push(entry.clone());
     }
+    (tree, stages)
+}
+
+fn push_untracked_files(dir: &Path, files: &mut Vec<FileEntry>) -> Result<()> {
+    let seen_paths: HashSet<String> = files.iter().map(|f| f.path.clone()).collect();
 
     // Include untracked files (use -z for NUL-delimited output)
-    let untracked_out = git_cmd()
+    let untracked_out = git_cmd_in(dir)
         .args(["ls-files", "-z", "--others", "--exclude-standard"])
         .output()?;
     for part in String::from_utf8_lossy(&untracked_out.stdout).split('\0') {
@@ -91,7 +474,7 @@ pub fn get_changed_files(merge_base: &str) -> Result<Vec<FileEntry>> {
         }
 
         // Count lines for untracked files (skip binary)
-        let line_count = std::fs::read(&path)
+        let line_count = std::fs::read(dir.join(&path))
             .map(|bytes| {
                 if bytes.is_empty() || is_binary(&bytes) {
                     return 0;
@@ -106,87 +489,458 @@ pub fn get_changed_files(merge_base: &str) -> Result<Vec<FileEntry>> {
             })
             .unwrap_or(0);
 
-        files.push(FileEntry {
-            path,
-            status: FileStatus::Added,
-            additions: line_count,
-            deletions: 0,
-        });
+        files.push(FileEntry::new(path, FileStatus::Added, line_count, 0));
     }
+    Ok(())
+}
 
-    Ok(files)
+/// Run `git diff --no-color <args> -- <path>`, returning `None` when it prints nothing.
+/// Renames and copies also pass their source path so git can pair the two.
+fn diff_path(
+    dir: &Path,
+    args: &[&str],
+    file: &FileEntry,
+    options: &DiffOptions,
+) -> Option<FileDiff> {
+    let out = git_cmd_in(dir)
+        .args(["diff", "--no-color"])
+        .args(rename_args(options))
+        .args(options.whitespace.git_args())
+        .arg(options.algorithm.git_arg())
+        .arg(format!("-U{}", options.context_lines))
+        .args(args)
+        .arg("--")
+        .args(file.old_path.iter())
+        .arg(&file.path)
+        .output()
+        .ok()?;
+    if !out.status.success() || out.stdout.is_empty() {
+        return None;
+    }
+    let text = String::from_utf8_lossy(&out.stdout);
+    Some(parse_diff(file_section(&text, &file.path)))
 }
 
-pub fn get_file_diff(merge_base: &str, path: &str) -> (DiffSource, Vec<String>) {
-    // Diff merge_base against working tree (not HEAD) to include uncommitted changes.
-    // Fall back to index-only diff if the working tree doesn't currently contain the change.
-    let worktree = git_cmd()
-        .args(["diff", merge_base, "--", path])
-        .output();
+/// The part of a multi-file diff that belongs to `path`. Asking for a copy
+/// also prints the diff of its source when that was modified too, while a
+/// type change is printed as a deletion followed by an addition of `path`.
+fn file_section<'a>(text: &'a str, path: &str) -> &'a str {
+    let suffix = format!(" b/{path}");
+    let mut starts: Vec<usize> = text
+        .match_indices("diff --git ")
+        .map(|(i, _)| i)
+        .filter(|&i| i == 0 || text.as_bytes()[i - 1] == b'\n')
+        .collect();
+    starts.push(text.len());
+    let matching: Vec<(usize, usize)> = starts
+        .windows(2)
+        .map(|w| (w[0], w[1]))
+        .filter(|&(start, end)| {
+            text[start..end]
+                .lines()
+                .next()
+                .is_some_and(|l| l.ends_with(&suffix))
+        })
+        .collect();
+    match (matching.first(), matching.last()) {
+        (Some(first), Some(last)) => &text[first.0..last.1],
+        _ => text,
+    }
+}
 
-    if let Ok(o) = worktree {
-        let lines: Vec<String> = String::from_utf8_lossy(&o.stdout)
-            .lines()
-            .map(|s| s.to_string())
-            .collect();
-        if !lines.is_empty() {
-            return (DiffSource::Worktree, lines);
+fn get_file_diff(
+    dir: &Path,
+    range: &DiffRange,
+    file: &FileEntry,
+    lfs_store: &Path,
+    options: &DiffOptions,
+) -> (DiffSource, FileDiff) {
+    let merge_base = range.from.as_str();
+    let path = file.path.as_str();
+    if file.whitespace_only {
+        // `git diff -w` prints nothing at all for these files.
+        let source = match (&range.to, range.work) {
+            (Some(_), _) => DiffSource::Commit,
+            (None, WorkState::Index) => DiffSource::Index,
+            (None, _) => DiffSource::Worktree,
+        };
+        return (source, whitespace_only_diff(file));
+    }
+    let diff = |args: &[&str]| diff_path(dir, args, file, options);
+    let found = match (&range.to, range.work) {
+        (Some(to), _) => diff(&[merge_base, to]).map(|d| (DiffSource::Commit, d)),
+        (None, WorkState::Index) => diff(&["--cached", merge_base]).map(|d| (DiffSource::Index, d)),
+        (None, WorkState::Unstaged) => diff(&[]).map(|d| (DiffSource::Worktree, d)),
+        // Diff merge_base against working tree (not HEAD) to include uncommitted changes.
+        // Fall back to index-only diff if the working tree doesn't currently contain the change.
+        (None, WorkState::Worktree) => diff(&[merge_base])
+            .map(|d| (DiffSource::Worktree, d))
+            .or_else(|| diff(&["--cached", merge_base]).map(|d| (DiffSource::Index, d))),
+    };
+    if let Some((source, mut diff)) = found {
+        if file.is_submodule() {
+            return (source, cli_submodule_diff(dir, path, &diff));
+        }
+        if diff.binary || file.lfs || lfs::changes_pointer(&diff) {
+            let (old, new) = read_sides(dir, range, source, file);
+            let new_in_worktree = range.to.is_none() && matches!(source, DiffSource::Worktree);
+            if !lfs::attach(
+                &mut diff,
+                old.as_deref(),
+                new.as_deref(),
+                new_in_worktree,
+                lfs_store,
+                options,
+            ) && diff.binary
+            {
+                binary::attach(&mut diff, old.as_deref(), new.as_deref(), options);
+            }
         }
+        return (source, diff);
     }
 
-    let index = git_cmd()
-        .args(["diff", "--cached", merge_base, "--", path])
-        .output();
-    if let Ok(o) = index {
-        let lines: Vec<String> = String::from_utf8_lossy(&o.stdout)
-            .lines()
-            .map(|s| s.to_string())
-            .collect();
-        if !lines.is_empty() {
-            return (DiffSource::Index, lines);
+    // If git diff returns empty, file might be untracked - show as new file.
+    if range.to.is_none() && range.untracked && range.work != WorkState::Index {
+        if let Ok(bytes) = std::fs::read(dir.join(path)) {
+            return (
+                DiffSource::Untracked,
+                untracked_file_diff(file, &bytes, options),
+            );
         }
     }
 
-    // If git diff returns empty, file might be untracked - show as new file.
-    if let Ok(bytes) = std::fs::read(path) {
-        let mut result = vec![
+    let diff = FileDiff {
+        header: vec!["Error getting diff".to_string()],
+        ..FileDiff::default()
+    };
+    (DiffSource::Worktree, diff)
+}
+
+/// Contents of both sides of a change, for binary files that `git diff`
+/// doesn't print and for context beyond its hunks.
+fn read_sides(dir: &Path, range: &DiffRange, source: DiffSource, file: &FileEntry) -> FileSides {
+    let path = file.path.as_str();
+    let old_path = file.old_path.as_deref().unwrap_or(path);
+    let from = range.from.as_str();
+    let old = match (source, range.work) {
+        (DiffSource::Worktree, WorkState::Unstaged) => git_blob(dir, &format!(":{old_path}")),
+        _ => git_blob(dir, &format!("{from}:{old_path}")),
+    };
+    let new = match (&range.to, source) {
+        (Some(to), _) => git_blob(dir, &format!("{to}:{path}")),
+        (None, DiffSource::Index) => git_blob(dir, &format!(":{path}")),
+        (None, _) => std::fs::read(dir.join(path)).ok(),
+    };
+    (old, new)
+}
+
+/// Rebuild git's `Subproject commit` diff of a submodule as a summary with
+/// the commits from the submodule's own history.
+fn cli_submodule_diff(dir: &Path, path: &str, diff: &FileDiff) -> FileDiff {
+    let commit = |kind: DiffLineKind| {
+        diff.hunks
+            .iter()
+            .flat_map(|h| &h.lines)
+            .filter(|l| l.kind == kind)
+            .find_map(|l| l.content.strip_prefix("Subproject commit "))
+            .map(|id| id.trim_end_matches("-dirty").to_string())
+    };
+    let old = commit(DiffLineKind::Removed);
+    let new = commit(DiffLineKind::Added);
+    let log = match (&old, &new) {
+        (Some(old), Some(new)) => git_log_in(&dir.join(path), old, new)
+            .and_then(|gained| Ok((gained, git_log_in(&dir.join(path), new, old)?)))
+            .ok(),
+        _ => None,
+    };
+    submodule_diff(path, old.as_deref(), new.as_deref(), log)
+}
+
+/// Summary of a submodule pointer change: `Submodule X: abc → def (N commits)`
+/// followed by the subjects of the commits gained (`>`) and lost (`<`).
+/// `log` is `None` when the submodule isn't checked out or lacks the commits.
+pub fn submodule_diff(
+    path: &str,
+    old: Option<&str>,
+    new: Option<&str>,
+    log: Option<(Vec<CommitInfo>, Vec<CommitInfo>)>,
+) -> FileDiff {
+    let short = |id: &str| id.chars().take(7).collect::<String>();
+    let plural = |n: usize| if n == 1 { "commit" } else { "commits" };
+    let mut header = vec![match (old, new) {
+        (None, Some(new)) => format!("Submodule {path} added at {}", short(new)),
+        (Some(old), None) => format!("Submodule {path} removed, was at {}", short(old)),
+        (old, new) => {
+            let counts = match &log {
+                None => "commits not available".to_string(),
+                Some((gained, lost)) if lost.is_empty() => {
+                    format!("{} {}", gained.len(), plural(gained.len()))
+                }
+                Some((gained, lost)) if gained.is_empty() => {
+                    format!("{} {} back", lost.len(), plural(lost.len()))
+                }
+                Some((gained, lost)) => {
+                    format!("{} new, {} dropped commits", gained.len(), lost.len())
+                }
+            };
+            format!(
+                "Submodule {path}: {} → {} ({counts})",
+                short(old.unwrap_or_default()),
+                short(new.unwrap_or_default())
+            )
+        }
+    }];
+    if let Some((gained, lost)) = &log {
+        let line = |mark: char, c: &CommitInfo| format!("  {mark} {} {}", short(&c.id), c.subject);
+        header.extend(gained.iter().map(|c| line('>', c)));
+        header.extend(lost.iter().map(|c| line('<', c)));
+    }
+
+    FileDiff {
+        header,
+        old_path: old.map(|_| path.to_string()),
+        new_path: new.map(|_| path.to_string()),
+        old_mode: old.map(|_| "160000".to_string()),
+        new_mode: new.map(|_| "160000".to_string()),
+        ..FileDiff::default()
+    }
+}
+
+/// Read a blob by `rev:path` (or `:path` for the index).
+fn git_blob(dir: &Path, spec: &str) -> Option<Vec<u8>> {
+    let out = git_cmd_in(dir)
+        .args(["cat-file", "blob", spec])
+        .output()
+        .ok()?;
+    out.status.success().then_some(out.stdout)
+}
+
+/// Placeholder diff for a file whose changes are all ignored whitespace.
+pub fn whitespace_only_diff(file: &FileEntry) -> FileDiff {
+    let path = file.path.as_str();
+    FileDiff {
+        header: vec![
+            format!("diff --git a/{path} b/{path}"),
+            "Only whitespace changes, hidden by the whitespace mode".to_string(),
+        ],
+        old_path: Some(path.to_string()),
+        new_path: Some(path.to_string()),
+        ..FileDiff::default()
+    }
+}
+
+/// Build the diff of an untracked file against nothing, as `git diff --no-index` would.
+pub fn untracked_file_diff(file: &FileEntry, bytes: &[u8], options: &DiffOptions) -> FileDiff {
+    let path = file.path.as_str();
+    let mut diff = FileDiff {
+        header: vec![
             format!("diff --git a/{path} b/{path}"),
             "new file mode 100644".to_string(),
             "--- /dev/null".to_string(),
             format!("+++ b/{path}"),
-        ];
-        if bytes.is_empty() {
-            result.push("@@ -0,0 +0,0 @@".to_string());
-        } else if is_binary(&bytes) {
-            result.push(format!("Binary file {path} ({})", format_size(bytes.len())));
-        } else {
-            let content = String::from_utf8_lossy(&bytes);
-            let file_lines: Vec<&str> = content.lines().collect();
-            result.push(format!("@@ -0,0 +1,{} @@", file_lines.len()));
-            for line in file_lines {
-                result.push(format!("+{line}"));
+        ],
+        new_path: Some(path.to_string()),
+        new_mode: Some("100644".to_string()),
+        ..FileDiff::default()
+    };
+
+    if file.attributes.no_diff || is_binary(bytes) {
+        binary::attach(&mut diff, None, Some(bytes), options);
+    } else if !bytes.is_empty() {
+        let content = String::from_utf8_lossy(bytes);
+        let lines: Vec<DiffLine> = content
+            .lines()
+            .enumerate()
+            .map(|(i, line)| DiffLine {
+                kind: DiffLineKind::Added,
+                content: line.to_string(),
+                old_lineno: None,
+                new_lineno: Some(i as u32 + 1),
+            })
+            .collect();
+        diff.hunks.push(Hunk {
+            old_start: 0,
+            old_count: 0,
+            new_start: 1,
+            new_count: lines.len() as u32,
+            section: String::new(),
+            lines,
+        });
+    }
+    diff
+}
+
+/// Parse `@@ -a[,b] +c[,d] @@ section` into a hunk with no lines yet.
+fn parse_hunk_header(line: &str) -> Option<Hunk> {
+    let rest = line.strip_prefix("@@ -")?;
+    let (ranges, section) = rest.split_once(" @@")?;
+    let (old, new) = ranges.split_once(" +")?;
+
+    fn parse_range(range: &str) -> Option<(u32, u32)> {
+        match range.split_once(',') {
+            Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+            None => Some((range.parse().ok()?, 1)),
+        }
+    }
+
+    let (old_start, old_count) = parse_range(old)?;
+    let (new_start, new_count) = parse_range(new)?;
+    Some(Hunk {
+        old_start,
+        old_count,
+        new_start,
+        new_count,
+        section: section.trim_start().to_string(),
+        lines: Vec::new(),
+    })
+}
+
+fn strip_diff_path(path: &str) -> Option<String> {
+    if path == "/dev/null" {
+        return None;
+    }
+    let path = path
+        .strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path);
+    Some(path.to_string())
+}
+
+/// Parse the unified diff of a single file. Hunk bodies are consumed by their
+/// line counts, so content such as `+++ counter` is never mistaken for a header.
+pub fn parse_diff(text: &str) -> FileDiff {
+    let mut diff = FileDiff::default();
+    let mut old_remaining = 0u32;
+    let mut new_remaining = 0u32;
+    let mut old_lineno = 0u32;
+    let mut new_lineno = 0u32;
+
+    for line in text.lines() {
+        if old_remaining > 0 || new_remaining > 0 {
+            let Some(hunk) = diff.hunks.last_mut() else {
+                break;
+            };
+            // Some tools strip the trailing space from empty context lines.
+            let (marker, content) = match line.chars().next() {
+                Some(c) => (c, &line[c.len_utf8()..]),
+                None => (' ', ""),
+            };
+            let kind = match marker {
+                '+' => DiffLineKind::Added,
+                '-' => DiffLineKind::Removed,
+                '\\' => DiffLineKind::NoNewline,
+                _ => DiffLineKind::Context,
+            };
+            let (old, new) = match kind {
+                DiffLineKind::Added => {
+                    new_lineno += 1;
+                    new_remaining = new_remaining.saturating_sub(1);
+                    (None, Some(new_lineno))
+                }
+                DiffLineKind::Removed => {
+                    old_lineno += 1;
+                    old_remaining = old_remaining.saturating_sub(1);
+                    (Some(old_lineno), None)
+                }
+                DiffLineKind::Context => {
+                    old_lineno += 1;
+                    new_lineno += 1;
+                    old_remaining = old_remaining.saturating_sub(1);
+                    new_remaining = new_remaining.saturating_sub(1);
+                    (Some(old_lineno), Some(new_lineno))
+                }
+                DiffLineKind::NoNewline => (None, None),
+            };
+            hunk.lines.push(DiffLine {
+                kind,
+                content: content.to_string(),
+                old_lineno: old,
+                new_lineno: new,
+            });
+            continue;
+        }
+
+        if line.starts_with("@@") {
+            if let Some(hunk) = parse_hunk_header(line) {
+                old_remaining = hunk.old_count;
+                new_remaining = hunk.new_count;
+                old_lineno = hunk.old_start.saturating_sub(1);
+                new_lineno = hunk.new_start.saturating_sub(1);
+                diff.hunks.push(hunk);
+                continue;
             }
         }
-        return (DiffSource::Untracked, result);
+
+        // A trailing "no newline" marker belongs to the last line of the hunk.
+        if let Some(marker) = line.strip_prefix('\\') {
+            if let Some(hunk) = diff.hunks.last_mut() {
+                hunk.lines.push(DiffLine {
+                    kind: DiffLineKind::NoNewline,
+                    content: marker.to_string(),
+                    old_lineno: None,
+                    new_lineno: None,
+                });
+                continue;
+            }
+        }
+
+        if let Some(path) = line.strip_prefix("--- ") {
+            diff.old_path = strip_diff_path(path);
+        } else if let Some(path) = line.strip_prefix("+++ ") {
+            diff.new_path = strip_diff_path(path);
+        } else if let Some(path) = line.strip_prefix("rename from ") {
+            diff.rename_from = Some(path.to_string());
+        } else if let Some(path) = line.strip_prefix("rename to ") {
+            diff.rename_to = Some(path.to_string());
+        } else if let Some(mode) = line
+            .strip_prefix("old mode ")
+            .or_else(|| line.strip_prefix("deleted file mode "))
+        {
+            diff.old_mode = Some(mode.to_string());
+        } else if let Some(mode) = line
+            .strip_prefix("new mode ")
+            .or_else(|| line.strip_prefix("new file mode "))
+        {
+            diff.new_mode = Some(mode.to_string());
+        } else if let Some(rest) = line.strip_prefix("index ") {
+            // `index abc..def 100644` carries the mode when it didn't change.
+            if let Some((_, mode)) = rest.split_once(' ') {
+                diff.old_mode.get_or_insert_with(|| mode.to_string());
+                diff.new_mode.get_or_insert_with(|| mode.to_string());
+            }
+        } else if line.starts_with("Binary files ") || line == "GIT binary patch" {
+            diff.binary = true;
+        }
+        diff.header.push(line.to_string());
     }
 
-    (DiffSource::Worktree, vec!["Error getting diff".to_string()])
+    diff
 }
 
-pub fn git_git_path(name: &str) -> Result<String> {
-    let out = git_cmd()
+fn git_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let out = git_cmd_in(dir)
         .args(["rev-parse", "--git-path", name])
         .output()
         .with_context(|| format!("Failed to run git rev-parse --git-path {name}"))?;
     if !out.status.success() {
         anyhow::bail!("git rev-parse --git-path {name} failed");
     }
-    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+    Ok(dir.join(String::from_utf8_lossy(&out.stdout).trim()))
 }
 
-pub fn git_rev_parse(rev: &str) -> Result<String> {
-    let out = git_cmd()
-        .args(["rev-parse", rev])
+/// Short name of the branch checked out at `dir`; `None` when detached.
+fn head_branch(dir: &Path) -> Option<String> {
+    let out = git_cmd_in(dir)
+        .args(["symbolic-ref", "-q", "--short", "HEAD"])
+        .output()
+        .ok()?;
+    let name = String::from_utf8_lossy(&out.stdout).trim().to_string();
+    (out.status.success() && !name.is_empty()).then_some(name)
+}
+
+fn git_rev_parse(dir: &Path, rev: &str) -> Result<String> {
+    let out = git_cmd_in(dir)
+        .args(["rev-parse", "--verify", "--quiet", rev])
         .output()
         .with_context(|| format!("Failed to run git rev-parse {rev}"))?;
     if !out.status.success() {
@@ -195,8 +949,32 @@ pub fn git_rev_parse(rev: &str) -> Result<String> {
     Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
 }
 
-pub fn git_status_hash() -> Result<u64> {
-    let out = git_cmd()
+/// The git dir of the repository at `dir`; of the worktree, in a linked one.
+fn git_dir(dir: &Path) -> Result<PathBuf> {
+    let out = git_cmd_in(dir)
+        .args(["rev-parse", "--absolute-git-dir"])
+        .output()
+        .context("Failed to run git rev-parse --absolute-git-dir")?;
+    if !out.status.success() {
+        anyhow::bail!("git rev-parse --absolute-git-dir failed");
+    }
+    Ok(PathBuf::from(String::from_utf8_lossy(&out.stdout).trim()))
+}
+
+/// The git dir shared by all worktrees of the repository at `dir`, which
+/// holds the LFS object store.
+fn git_common_dir(dir: &Path) -> PathBuf {
+    let out = git_cmd_in(dir)
+        .args(["rev-parse", "--git-common-dir"])
+        .output();
+    match out {
+        Ok(out) if out.status.success() => dir.join(String::from_utf8_lossy(&out.stdout).trim()),
+        _ => dir.join(".git"),
+    }
+}
+
+fn git_status_hash(dir: &Path) -> Result<u64> {
+    let out = git_cmd_in(dir)
         .args(["status", "--porcelain=v1", "-z"])
         .output()
         .context("Failed to run git status")?;
@@ -212,23 +990,30 @@ fn hash_bytes(bytes: &[u8]) -> u64 {
     hasher.finish()
 }
 
-pub fn file_mtime_ns(path: &str) -> Option<u128> {
+pub fn file_mtime_ns(path: &Path) -> Option<u128> {
     let metadata = std::fs::metadata(path).ok()?;
     let modified = metadata.modified().ok()?;
     let duration = modified.duration_since(std::time::UNIX_EPOCH).ok()?;
     Some(duration.as_nanos())
 }
 
-fn git_default_remote() -> Option<String> {
-    let out = git_cmd().args(["remote"]).output().ok()?;
+fn git_remotes(dir: &Path) -> Result<Vec<String>> {
+    let out = git_cmd_in(dir)
+        .args(["remote"])
+        .output()
+        .context("Failed to run git remote")?;
     if !out.status.success() {
-        return None;
+        anyhow::bail!("git remote failed");
     }
-    let remotes: Vec<String> = String::from_utf8_lossy(&out.stdout)
+    Ok(String::from_utf8_lossy(&out.stdout)
         .lines()
         .map(|s| s.trim().to_string())
         .filter(|s| !s.is_empty())
-        .collect();
+        .collect())
+}
+
+fn default_remote(backend: &dyn GitBackend) -> Option<String> {
+    let remotes = backend.remotes().ok()?;
     if remotes.iter().any(|r| r == "origin") {
         return Some("origin".to_string());
     }
@@ -238,37 +1023,52 @@ fn git_default_remote() -> Option<String> {
     None
 }
 
-pub fn resolve_base_ref(specified: &str) -> Result<String> {
+pub fn resolve_base_ref(backend: &dyn GitBackend, specified: &str) -> Result<String> {
     // Prefer remote tracking ref (e.g. origin/develop) over local branch.
     // PR diffs compare against the remote, and local branches are often stale.
     if !specified.contains('/') {
-        if let Some(remote) = git_default_remote() {
+        if let Some(remote) = default_remote(backend) {
             let candidate = format!("{remote}/{specified}");
-            if git_cmd()
-                .args(["rev-parse", "--verify", "--quiet", &candidate])
-                .output()
-                .map(|o| o.status.success())
-                .unwrap_or(false)
-            {
+            if backend.rev_parse(&candidate).is_ok() {
                 return Ok(candidate);
             }
         }
     }
 
-    if git_cmd()
-        .args(["rev-parse", "--verify", "--quiet", specified])
-        .output()
-        .map(|o| o.status.success())
-        .unwrap_or(false)
-    {
+    if backend.rev_parse(specified).is_ok() {
         return Ok(specified.to_string());
     }
 
     anyhow::bail!("Could not resolve base branch '{specified}'")
 }
 
-pub fn list_branches() -> Result<Vec<String>> {
-    let out = git_cmd()
+/// `git log from..to` in the repository at `dir`, e.g. a submodule.
+fn git_log_in(dir: &Path, from: &str, to: &str) -> Result<Vec<CommitInfo>> {
+    let out = git_cmd_in(dir)
+        .args(["log", "-z", "--no-color", "--format=%H%x1f%s%x1f%an%x1f%ct"])
+        .arg(format!("{from}..{to}"))
+        .output()
+        .context("Failed to run git log")?;
+    if !out.status.success() {
+        anyhow::bail!("git log {from}..{to} failed");
+    }
+    let text = String::from_utf8_lossy(&out.stdout);
+    Ok(text
+        .split('\0')
+        .filter_map(|record| {
+            let mut fields = record.trim_start_matches('\n').split('\x1f');
+            Some(CommitInfo {
+                id: fields.next().filter(|id| !id.is_empty())?.to_string(),
+                subject: fields.next()?.to_string(),
+                author: fields.next()?.to_string(),
+                time: fields.next()?.trim().parse().ok()?,
+            })
+        })
+        .collect())
+}
+
+fn list_branches(dir: &Path) -> Result<Vec<String>> {
+    let out = git_cmd_in(dir)
         .args(["branch", "-a", "--format=%(refname:short)"])
         .output()
         .context("Failed to run git branch -a")?;
@@ -285,6 +1085,255 @@ pub fn list_branches() -> Result<Vec<String>> {
     Ok(branches)
 }
 
+/// Stage `paths` as they are in the working tree, deletions and untracked
+/// files included. Unlike everything else this writes the index, so it
+/// only runs in interactive mode. `update-index`, unlike `git add`, accepts
+/// paths already gone from both, like the old side of a staged rename.
+pub fn stage_paths(dir: &Path, paths: &[String]) -> Result<()> {
+    update_index(dir, &["update-index", "--add", "--remove"], paths)
+}
+
+/// Reset `paths` in the index to HEAD, keeping their working tree changes.
+/// Works before the first commit too, where it removes them from the index.
+/// The paths are taken literally, so `[id].tsx` doesn't also match `i.tsx`.
+pub fn unstage_paths(dir: &Path, paths: &[String]) -> Result<()> {
+    update_index(dir, &["reset", "-q"], paths)
+}
+
+fn update_index(dir: &Path, args: &[&str], paths: &[String]) -> Result<()> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let out = git_cmd_in(dir)
+        .env("GIT_LITERAL_PATHSPECS", "1")
+        .args(args)
+        .arg("--")
+        .args(paths)
+        .output()
+        .with_context(|| format!("Failed to run git {}", args[0]))?;
+    if !out.status.success() {
+        anyhow::bail!(
+            "git {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Full message of commit `rev`.
+fn commit_message(dir: &Path, rev: &str) -> Result<String> {
+    let out = git_cmd_in(dir)
+        .args(["show", "-s", "--format=%B", rev])
+        .output()
+        .context("Failed to run git show")?;
+    if !out.status.success() {
+        anyhow::bail!("git show {rev} failed");
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim_end().to_string())
+}
+
+/// Commit the index with the message in `message_file`, dropping `#` lines,
+/// or amend HEAD with it. Hooks run as usual; when one fails, the error is
+/// what it printed.
+pub fn commit(dir: &Path, message_file: &Path, amend: bool) -> Result<()> {
+    let mut cmd = git_cmd_in(dir);
+    cmd.args(["commit", "--cleanup=strip", "-F"])
+        .arg(message_file);
+    if amend {
+        cmd.arg("--amend");
+    }
+    let out = cmd
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed to run git commit")?;
+    if !out.status.success() {
+        let output = [out.stdout, out.stderr].concat();
+        anyhow::bail!("{}", String::from_utf8_lossy(&output).trim());
+    }
+    Ok(())
+}
+
+/// The editor git opens for commit messages: `GIT_EDITOR`, `core.editor`,
+/// `VISUAL` or `EDITOR`, and `vi` when none is set. It is a shell snippet.
+pub fn commit_editor() -> String {
+    let out = git_cmd().args(["var", "GIT_EDITOR"]).output();
+    match out {
+        Ok(out) if out.status.success() => String::from_utf8_lossy(&out.stdout).trim().to_string(),
+        _ => "vi".to_string(),
+    }
+}
+
+/// `git apply` a patch built by [`crate::patch::build_patch`] to the index or the
+/// working tree, checking first that all of it applies.
+pub fn apply_patch(dir: &Path, patch: &str, to: ApplyTo, reverse: bool) -> Result<()> {
+    let mut cmd = git_cmd_in(dir);
+    cmd.args(["apply", "--unidiff-zero", "--whitespace=nowarn"]);
+    if to == ApplyTo::Index {
+        cmd.arg("--cached");
+    }
+    if reverse {
+        cmd.arg("--reverse");
+    }
+    let mut child = cmd
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run git apply")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(patch.as_bytes())
+            .context("Failed to write the patch")?;
+    }
+    let out = child
+        .wait_with_output()
+        .context("Failed to run git apply")?;
+    if !out.status.success() {
+        anyhow::bail!(
+            "git apply failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Backend that shells out to the `git` executable for every operation.
+pub struct CliBackend {
+    /// Repository the commands run in
+    dir: PathBuf,
+    /// The LFS object store, looked up on first use
+    lfs_store: OnceLock<PathBuf>,
+}
+
+impl CliBackend {
+    fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            lfs_store: OnceLock::new(),
+        }
+    }
+
+    fn lfs_store(&self) -> &Path {
+        self.lfs_store
+            .get_or_init(|| lfs::store(&git_common_dir(&self.dir)))
+    }
+}
+
+impl Default for CliBackend {
+    /// Backend for the repository containing the current directory.
+    fn default() -> Self {
+        Self::new(PathBuf::from("."))
+    }
+}
+
+impl GitBackend for CliBackend {
+    fn merge_base(&self, one: &str, two: &str) -> Result<String> {
+        get_merge_base(&self.dir, one, two)
+    }
+
+    fn changed_files(&self, range: &DiffRange, options: &DiffOptions) -> Result<Vec<FileEntry>> {
+        let mut files = get_changed_files(&self.dir, range, options)?;
+        // Without attributes files only miss their badges and grouping.
+        if let Err(err) = apply_attributes(&self.dir, &mut files) {
+            logging::log_error(&err);
+        }
+        if let Err(err) = mark_lfs_pointers(&self.dir, range, &mut files) {
+            logging::log_error(&err);
+        }
+        Ok(submodule::expand(self, range, files, options))
+    }
+
+    fn file_diff(
+        &self,
+        range: &DiffRange,
+        file: &FileEntry,
+        options: &DiffOptions,
+    ) -> (DiffSource, FileDiff) {
+        if let Some(scope) = &file.submodule {
+            return submodule::file_diff(self, scope, options);
+        }
+        get_file_diff(&self.dir, range, file, self.lfs_store(), options)
+    }
+
+    fn file_sides(
+        &self,
+        range: &DiffRange,
+        file: &FileEntry,
+        source: DiffSource,
+    ) -> Result<FileSides> {
+        if let Some(scope) = &file.submodule {
+            return submodule::file_sides(self, scope, source);
+        }
+        let (old, new) = read_sides(&self.dir, range, source, file);
+        Ok((
+            lfs::smudge(old, self.lfs_store()),
+            lfs::smudge(new, self.lfs_store()),
+        ))
+    }
+
+    fn commits(&self, from: &str, to: &str) -> Result<Vec<CommitInfo>> {
+        git_log_in(&self.dir, from, to)
+    }
+
+    fn merge_preview(&self, base: &str, source: MergeSource) -> Result<MergePreview> {
+        merge_preview(&self.dir, base, source)
+    }
+
+    fn rev_parse(&self, rev: &str) -> Result<String> {
+        git_rev_parse(&self.dir, rev)
+    }
+
+    fn status_hash(&self) -> Result<u64> {
+        git_status_hash(&self.dir)
+    }
+
+    fn list_branches(&self) -> Result<Vec<String>> {
+        list_branches(&self.dir)
+    }
+
+    fn remotes(&self) -> Result<Vec<String>> {
+        git_remotes(&self.dir)
+    }
+
+    fn open_submodule(&self, path: &str) -> Result<Box<dyn GitBackend>> {
+        Ok(Box::new(Self::new(self.dir.join(path))))
+    }
+
+    fn stage(&self, paths: &[String]) -> Result<()> {
+        stage_paths(&self.dir, paths)
+    }
+
+    fn unstage(&self, paths: &[String]) -> Result<()> {
+        unstage_paths(&self.dir, paths)
+    }
+
+    fn apply_patch(&self, patch: &str, to: ApplyTo, reverse: bool) -> Result<()> {
+        apply_patch(&self.dir, patch, to, reverse)
+    }
+
+    fn git_dir(&self) -> Result<PathBuf> {
+        git_dir(&self.dir)
+    }
+
+    fn git_path(&self, name: &str) -> Result<PathBuf> {
+        git_path(&self.dir, name)
+    }
+
+    fn commit_message(&self, rev: &str) -> Result<String> {
+        commit_message(&self.dir, rev)
+    }
+
+    fn head_branch(&self) -> Option<String> {
+        head_branch(&self.dir)
+    }
+
+    fn commit(&self, message_file: &Path, amend: bool) -> Result<()> {
+        commit(&self.dir, message_file, amend)
+    }
+}
+
 fn normalize_numstat_path(field: &str) -> String {
     // git --numstat for renames can emit either:
     // - "old\tnew" (extra tab-separated field)
@@ -307,70 +1356,102 @@ fn normalize_numstat_path(field: &str) -> String {
 }
 
 /// Run a single `git diff -z --raw --numstat` to get both status codes and line counts.
-/// With -z, fields are NUL-delimited for safe handling of paths with special characters.
-/// --raw gives `:oldmode newmode oldhash newhash status\0path[\0path]` records.
-/// --numstat gives `add\tdel\tpath\0` records (tabs within, NUL between).
-fn git_diff_status_and_stats(merge_base: &str, cached: bool) -> Result<Vec<FileEntry>> {
-    let mut args = vec!["diff", "-z", "--raw", "--numstat"];
-    if cached {
-        args.push("--cached");
-    }
-    args.push(merge_base);
-
-    let out = git_cmd()
-        .args(args)
+/// `revs` are the trailing `git diff` arguments, e.g. `[base]`, `["--cached", base]`
+/// or `[from, to]`.
+fn git_diff_status_and_stats(
+    dir: &Path,
+    revs: &[&str],
+    options: &DiffOptions,
+) -> Result<Vec<FileEntry>> {
+    let out = git_cmd_in(dir)
+        .args(["diff", "-z", "--raw", "--numstat"])
+        .args(rename_args(options))
+        .args(options.whitespace.git_args())
+        .arg(options.algorithm.git_arg())
+        .arg(format!("-U{}", options.context_lines))
+        .args(revs)
         .output()
         .context("Failed to run git diff -z --raw --numstat")?;
     if !out.status.success() {
         anyhow::bail!("git diff -z --raw --numstat failed");
     }
+    Ok(parse_status_and_stats(&String::from_utf8_lossy(
+        &out.stdout,
+    )))
+}
 
-    let text = String::from_utf8_lossy(&out.stdout);
+/// Parse the output of `git diff -z --raw --numstat`.
+/// With -z, fields are NUL-delimited for safe handling of paths with special characters.
+/// --raw gives `:oldmode newmode oldhash newhash status\0path[\0path]` records.
+/// --numstat gives `add\tdel\tpath\0` records (tabs within, NUL between).
+fn parse_status_and_stats(text: &str) -> Vec<FileEntry> {
     let parts: Vec<&str> = text.split('\0').collect();
 
-    let mut status_map: HashMap<String, FileStatus> = HashMap::new();
+    let mut entries_map: HashMap<String, FileEntry> = HashMap::new();
     let mut stats_map: HashMap<String, (i32, i32)> = HashMap::new();
+    let mut unhashed_mode_changes: HashSet<String> = HashSet::new();
+    let mut binary_paths: HashSet<String> = HashSet::new();
     let mut paths_ordered: Vec<String> = Vec::new();
 
     let mut i = 0;
     while i < parts.len() {
         let part = parts[i];
-        if part.starts_with(':') {
+        if let Some(raw) = part.strip_prefix(':') {
             // --raw format with -z: `:oldmode newmode oldhash newhash status\0path[\0path]`
-            // Status token is the last space-separated field (e.g. "M", "R100", "C085").
-            // Extract the first character as the status letter.
-            let status_token = part.split_whitespace().last().unwrap_or("?");
+            // Status token is the last space-separated field (e.g. "M", "R100", "C085"):
+            // a letter followed, for renames and copies, by the similarity score.
+            let fields: Vec<&str> = raw.split_whitespace().collect();
+            let status_token = fields.last().copied().unwrap_or("?");
             let status_char = status_token.chars().next().unwrap_or('?');
+            // A zero mode marks a side that doesn't exist.
+            let mode = |i: usize| {
+                let mode = u32::from_str_radix(fields.get(i)?, 8).ok()?;
+                (mode != 0).then_some(mode)
+            };
+            let (old_mode, new_mode) = (mode(0), mode(1));
+            let same_content = fields.len() >= 4 && fields[2] == fields[3];
+            // Working tree files aren't hashed; numstat tells whether the content changed.
+            let unhashed = fields
+                .get(3)
+                .is_some_and(|hash| hash.bytes().all(|b| b == b'0'));
             let status = match status_char {
                 'A' => FileStatus::Added,
-                'M' | 'T' => FileStatus::Modified,
-                'D' => FileStatus::Deleted,
-                'R' | 'C' => {
-                    // Renames/copies have two paths: old\0new
-                    // Skip old path, use new path
-                    i += 1; // skip old path
-                    if i < parts.len() {
-                        i += 1; // move to new path
-                    }
-                    let path = parts.get(i).unwrap_or(&"").to_string();
-                    if !path.is_empty() && !status_map.contains_key(&path) {
-                        paths_ordered.push(path.clone());
-                    }
-                    let s = if status_char == 'R' { FileStatus::Renamed } else { FileStatus::Added };
-                    status_map.insert(path, s);
-                    i += 1;
-                    continue;
+                'M' if old_mode == Some(MODE_GITLINK) || new_mode == Some(MODE_GITLINK) => {
+                    FileStatus::Submodule
                 }
+                'M' if old_mode != new_mode && same_content => FileStatus::ModeChanged,
+                'M' => FileStatus::Modified,
+                'T' => FileStatus::TypeChanged,
+                'D' => FileStatus::Deleted,
+                'R' => FileStatus::Renamed,
+                'C' => FileStatus::Copied,
                 _ => FileStatus::Unknown,
             };
 
+            // Renames/copies have two paths: old\0new
+            let old_path = if matches!(status_char, 'R' | 'C') {
+                i += 1;
+                parts.get(i).map(|p| p.to_string())
+            } else {
+                None
+            };
             i += 1;
             let path = parts.get(i).unwrap_or(&"").to_string();
             if !path.is_empty() {
-                if !status_map.contains_key(&path) {
+                if !entries_map.contains_key(&path) {
                     paths_ordered.push(path.clone());
                 }
-                status_map.insert(path, status);
+                let mut entry = FileEntry::new(path.clone(), status, 0, 0);
+                entry.old_mode = old_mode;
+                entry.new_mode = new_mode;
+                if matches!(status, FileStatus::Modified) && unhashed && old_mode != new_mode {
+                    unhashed_mode_changes.insert(path.clone());
+                }
+                if old_path.is_some() {
+                    entry.old_path = old_path;
+                    entry.similarity = status_token[1..].parse().ok();
+                }
+                entries_map.insert(path, entry);
             }
         } else if !part.is_empty() && (part.as_bytes()[0].is_ascii_digit() || part.starts_with('-')) {
             // numstat format with -z: `add\tdel\tpath` (tabs within the NUL-delimited field)
@@ -382,16 +1463,18 @@ fn git_diff_status_and_stats(merge_base: &str, cached: bool) -> Result<Vec<FileE
                 let add = fields[0].parse::<i32>().unwrap_or(0);
                 let del = fields[1].parse::<i32>().unwrap_or(0);
                 let raw_path = fields[2];
-                if raw_path.is_empty() {
+                let path = if raw_path.is_empty() {
                     // Rename/copy: consume old\0new from subsequent NUL-delimited parts
                     i += 1; // skip old path
                     i += 1; // move to new path
-                    let path = parts.get(i).unwrap_or(&"").to_string();
-                    if !path.is_empty() {
-                        stats_map.insert(path, (add, del));
-                    }
+                    parts.get(i).unwrap_or(&"").to_string()
                 } else {
-                    let path = normalize_numstat_path(raw_path);
+                    normalize_numstat_path(raw_path)
+                };
+                if !path.is_empty() {
+                    if fields[0] == "-" {
+                        binary_paths.insert(path.clone());
+                    }
                     stats_map.insert(path, (add, del));
                 }
             }
@@ -399,23 +1482,40 @@ fn git_diff_status_and_stats(merge_base: &str, cached: bool) -> Result<Vec<FileE
         i += 1;
     }
 
-    let mut entries = Vec::new();
-    for path in &paths_ordered {
-        let status = status_map.get(path).copied().unwrap_or(FileStatus::Unknown);
-        let (additions, deletions) = stats_map.get(path).copied().unwrap_or((0, 0));
-        entries.push(FileEntry {
-            path: path.clone(),
-            status,
-            additions,
-            deletions,
-        });
-    }
-    Ok(entries)
+    paths_ordered
+        .iter()
+        .filter_map(|path| {
+            let mut entry = entries_map.remove(path)?;
+            // numstat counts the `Subproject commit` line of a submodule; that's noise.
+            let stats = stats_map
+                .get(path)
+                .copied()
+                .filter(|_| !entry.is_submodule());
+            let (additions, deletions) = stats.unwrap_or((0, 0));
+            if unhashed_mode_changes.contains(path) && (additions, deletions) == (0, 0) {
+                entry.status = FileStatus::ModeChanged;
+            }
+            // A changed text file without counted lines only differs in
+            // whitespace ignored by -w/-b/...; git leaves out its numstat.
+            entry.whitespace_only = matches!(entry.status, FileStatus::Modified)
+                && entry.old_mode == entry.new_mode
+                && !binary_paths.contains(path)
+                && (additions, deletions) == (0, 0);
+            entry.additions = additions;
+            entry.deletions = deletions;
+            Some(entry)
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
-    use super::normalize_numstat_path;
+    use super::{
+        detect_base_branch, normalize_numstat_path, parse_cat_file_batch, parse_check_attr,
+        parse_diff, parse_merge_tree, parse_status_and_stats, resolve_range,
+    };
+    use crate::backend::fake::FakeBackend;
+    use crate::model::{DiffLineKind, FileStatus};
 
     #[test]
     fn normalize_numstat_path_handles_brace_expansion() {
@@ -429,4 +1529,212 @@ mod tests {
     fn normalize_numstat_path_handles_simple_arrow() {
         assert_eq!(normalize_numstat_path("old => new"), "new");
     }
+
+    #[test]
+    fn parse_status_and_stats_keeps_rename_and_copy_sources() {
+        let text = ":100644 100644 aaaaaaa bbbbbbb R087\0old/name.rs\0new/name.rs\0\
+:100644 100644 aaaaaaa ccccccc C100\0lib.rs\0lib_copy.rs\0\
+:100644 100644 ddddddd eeeeeee M\0main.rs\0\
+3\t1\t\0old/name.rs\0new/name.rs\0\
+0\t0\t\0lib.rs\0lib_copy.rs\0\
+2\t2\tmain.rs\0";
+        let files = parse_status_and_stats(text);
+        assert_eq!(files.len(), 3);
+
+        assert_eq!(files[0].path, "new/name.rs");
+        assert!(matches!(files[0].status, FileStatus::Renamed));
+        assert_eq!(files[0].old_path.as_deref(), Some("old/name.rs"));
+        assert_eq!(files[0].similarity, Some(87));
+        assert_eq!((files[0].additions, files[0].deletions), (3, 1));
+
+        assert!(matches!(files[1].status, FileStatus::Copied));
+        assert_eq!(files[1].old_path.as_deref(), Some("lib.rs"));
+        assert_eq!(files[1].similarity, Some(100));
+
+        assert_eq!(files[2].path, "main.rs");
+        assert!(files[2].old_path.is_none());
+        assert_eq!((files[2].additions, files[2].deletions), (2, 2));
+    }
+
+    #[test]
+    fn parse_status_and_stats_classifies_mode_type_and_submodule_changes() {
+        let text = ":100644 100755 aaaaaaa aaaaaaa M\0run.sh\0\
+:100644 120000 bbbbbbb ccccccc T\0link\0\
+:160000 160000 ddddddd eeeeeee M\0vendor/lib\0\
+0\t0\trun.sh\0\
+1\t1\tlink\0\
+1\t1\tvendor/lib\0";
+        let files = parse_status_and_stats(text);
+        assert_eq!(files.len(), 3);
+
+        assert!(matches!(files[0].status, FileStatus::ModeChanged));
+        assert_eq!(files[0].mode_badge().as_deref(), Some("+x"));
+
+        assert!(matches!(files[1].status, FileStatus::TypeChanged));
+        assert_eq!(files[1].mode_badge().as_deref(), Some("file→symlink"));
+
+        assert!(matches!(files[2].status, FileStatus::Submodule));
+        assert_eq!(files[2].mode_badge().as_deref(), Some("submodule"));
+        assert_eq!((files[2].additions, files[2].deletions), (0, 0));
+    }
+
+    #[test]
+    fn parse_status_and_stats_flags_files_with_only_ignored_whitespace() {
+        // With -w, git lists a re-indented file but leaves out its numstat.
+        let text = ":100644 100644 aaaaaaa bbbbbbb M\0indent.rs\0\
+:100644 100644 ccccccc ddddddd M\0logo.png\0\
+:100644 100644 eeeeeee fffffff M\0main.rs\0\
+-\t-\tlogo.png\0\
+1\t0\tmain.rs\0";
+        let files = parse_status_and_stats(text);
+        assert_eq!(files.len(), 3);
+        assert!(files[0].whitespace_only);
+        assert!(!files[1].whitespace_only);
+        assert!(!files[2].whitespace_only);
+    }
+
+    #[test]
+    fn parse_merge_tree_collects_stages_per_path() {
+        let records = [
+            "4b82",
+            "100644 aaa 1\ta.txt",
+            "100644 bbb 2\ta.txt",
+            "100644 ccc 3\ta.txt",
+            "100644 ddd 1\tgone.txt",
+            "100644 eee 3\tgone.txt",
+            "",
+            "",
+        ];
+        let text = records.join("\0");
+        let (tree, stages) = parse_merge_tree(&text);
+        assert_eq!(tree, "4b82");
+        assert_eq!(stages["a.txt"], [true, true, true]);
+        assert_eq!(stages["gone.txt"], [true, false, true]);
+        assert_eq!(parse_merge_tree("4b82\0").1.len(), 0);
+    }
+
+    #[test]
+    fn parse_check_attr_reads_generated_vendored_and_binary_files() {
+        let text = "api.pb.rs\0linguist-generated\0true\0api.pb.rs\0diff\0unspecified\0\
+vendor/lib.js\0linguist-vendored\0set\0\
+logo.svg\0diff\0unset\0logo.svg\0binary\0set\0\
+main.rs\0linguist-generated\0false\0main.rs\0diff\0rust\0\
+model.bin\0diff\0lfs\0";
+        let attributes = parse_check_attr(text);
+        assert_eq!(attributes["api.pb.rs"].badge(), Some("generated"));
+        assert_eq!(attributes["vendor/lib.js"].badge(), Some("vendored"));
+        assert!(attributes["logo.svg"].no_diff);
+        assert_eq!(attributes["logo.svg"].badge(), None);
+        assert_eq!(attributes["main.rs"], Default::default());
+        assert!(!attributes["model.bin"].no_diff);
+    }
+
+    #[test]
+    fn parse_cat_file_batch_splits_objects_and_skips_missing_names() {
+        let out = b"1111 blob 4\nab\nc\nHEAD:gone missing\n2222 blob 0\n\n";
+        let objects = parse_cat_file_batch(out);
+        assert_eq!(objects, [("1111", &b"ab\nc"[..]), ("2222", &b""[..])]);
+        assert!(parse_cat_file_batch(b"3333 blob 10\nshort\n").is_empty());
+    }
+
+    #[test]
+    fn parse_diff_counts_hunk_lines_instead_of_guessing_prefixes() {
+        let text = "diff --git a/f.txt b/f.txt\n\
+index 1111111..2222222 100644\n\
+--- a/f.txt\n\
++++ b/f.txt\n\
+@@ -10,3 +10,3 @@ fn main()\n\
+ keep\n\
+---- counter\n\
+++++ counter\n\
+ tail\n";
+        let diff = parse_diff(text);
+        assert_eq!(diff.header.len(), 4);
+        assert_eq!(diff.old_path.as_deref(), Some("f.txt"));
+        assert_eq!(diff.new_mode.as_deref(), Some("100644"));
+        assert_eq!(diff.hunks.len(), 1);
+
+        let hunk = &diff.hunks[0];
+        assert_eq!(hunk.section, "fn main()");
+        let kinds: Vec<DiffLineKind> = hunk.lines.iter().map(|l| l.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                DiffLineKind::Context,
+                DiffLineKind::Removed,
+                DiffLineKind::Added,
+                DiffLineKind::Context
+            ]
+        );
+        assert_eq!(hunk.lines[1].content, "--- counter");
+        assert_eq!(hunk.lines[1].old_lineno, Some(11));
+        assert_eq!(hunk.lines[2].new_lineno, Some(11));
+        assert_eq!(hunk.lines[3].old_lineno, Some(12));
+        assert_eq!(hunk.lines[3].new_lineno, Some(12));
+    }
+
+    #[test]
+    fn parse_diff_reads_renames_and_no_newline_markers() {
+        let text = "diff --git a/old.rs b/new.rs\n\
+similarity index 90%\n\
+rename from old.rs\n\
+rename to new.rs\n\
+--- a/old.rs\n\
++++ b/new.rs\n\
+@@ -1 +1 @@\n\
+-a\n\
+\\ No newline at end of file\n\
++b\n\
+\\ No newline at end of file\n";
+        let diff = parse_diff(text);
+        assert_eq!(diff.rename_from.as_deref(), Some("old.rs"));
+        assert_eq!(diff.rename_to.as_deref(), Some("new.rs"));
+        let kinds: Vec<DiffLineKind> = diff.hunks[0].lines.iter().map(|l| l.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                DiffLineKind::Removed,
+                DiffLineKind::NoNewline,
+                DiffLineKind::Added,
+                DiffLineKind::NoNewline
+            ]
+        );
+    }
+
+    #[test]
+    fn detect_base_branch_prefers_remote_tracking_refs() {
+        let backend = FakeBackend {
+            branches: vec!["main".into(), "origin/main".into()],
+            remotes: vec!["upstream".into(), "origin".into()],
+            ..FakeBackend::default()
+        };
+        assert_eq!(detect_base_branch(&backend, None).unwrap(), "origin/main");
+        assert_eq!(
+            detect_base_branch(&backend, Some("origin/main".into())).unwrap(),
+            "origin/main"
+        );
+        assert!(detect_base_branch(&backend, Some("develop".into())).is_err());
+    }
+
+    #[test]
+    fn resolve_range_handles_two_and_three_dot_forms() {
+        let backend = FakeBackend {
+            branches: vec!["main".into(), "feature".into()],
+            ..FakeBackend::default()
+        };
+
+        let range = resolve_range(&backend, "main..feature").unwrap();
+        assert_eq!(range.from, "main-oid");
+        assert_eq!(range.to.as_deref(), Some("feature-oid"));
+
+        let range = resolve_range(&backend, "main...").unwrap();
+        assert_eq!(range.from, "main-HEAD-oid-merge-base");
+        assert_eq!(range.to.as_deref(), Some("HEAD-oid"));
+
+        let range = resolve_range(&backend, "feature").unwrap();
+        assert_eq!(range.from, "feature^-oid");
+        assert_eq!(range.to.as_deref(), Some("feature-oid"));
+
+        assert!(resolve_range(&backend, "main..nope").is_err());
+    }
 }
@@ -0,0 +1,1299 @@
+use crate::backend::GitBackend;
+use crate::binary;
+use crate::git;
+use crate::lfs;
+use crate::line_diff::{self, diff_hunks};
+use crate::logging;
+use crate::model::{
+    ApplyTo, Attributes, CommitInfo, DiffOptions, DiffRange, DiffSource, FileDiff, FileEntry,
+    FileSides, FileStatus, MergePreview, MergeSource, WorkState, MODE_EXECUTABLE, MODE_FILE,
+    MODE_GITLINK, MODE_SYMLINK,
+};
+use crate::submodule;
+use anyhow::{Context, Result};
+use gix::attrs::StateRef;
+use gix::bstr::{BStr, ByteSlice};
+use gix::index::entry::Mode;
+use gix::worktree::stack::state::attributes::Source as AttributeSource;
+use gix::ObjectId;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+/// In-process backend built on gitoxide. Refreshes read the object database,
+/// index and working tree directly instead of spawning `git` processes.
+///
+/// Without a commit to compare with, renames and copies are detected
+/// between the merge-base and the index, so a file moved in the working
+/// tree alone shows up as a deletion plus an untracked file, like in `git diff`.
+pub struct GixBackend {
+    repo: gix::ThreadSafeRepository,
+}
+
+/// How a tracked path in the working tree relates to its index entry.
+#[derive(Clone, Copy, PartialEq)]
+enum WorktreeState {
+    Unchanged,
+    Modified,
+    Removed,
+}
+
+/// Where a path stands relative to the merge-base, collected from one status run.
+struct PathState {
+    /// Blob and mode at the merge-base, `None` if the path didn't exist there
+    base: Option<(ObjectId, u32)>,
+    /// Blob and mode in the index, `None` if the path isn't staged
+    index: Option<(ObjectId, u32)>,
+    worktree: WorktreeState,
+    /// Path `base` was read from when the file was staged as a rename or
+    /// copy of it, and whether it was a copy
+    source: Option<(String, bool)>,
+}
+
+/// One side of a file diff.
+struct Side {
+    bytes: Vec<u8>,
+    id: ObjectId,
+    mode: u32,
+    /// Read from the working tree rather than from an object
+    worktree: bool,
+}
+
+impl GixBackend {
+    pub fn discover() -> Result<Self> {
+        let repo =
+            gix::ThreadSafeRepository::discover(".").context("Failed to open git repository")?;
+        Ok(Self { repo })
+    }
+
+    fn repo(&self) -> gix::Repository {
+        self.repo.to_thread_local()
+    }
+}
+
+fn workdir(repo: &gix::Repository) -> Result<PathBuf> {
+    repo.workdir()
+        .map(Path::to_path_buf)
+        .context("Repository has no working tree")
+}
+
+fn tree_id(repo: &gix::Repository, rev: &str) -> Result<ObjectId> {
+    if rev == git::EMPTY_TREE {
+        return Ok(repo.empty_tree().id);
+    }
+    let spec = format!("{rev}^{{tree}}");
+    Ok(repo
+        .rev_parse_single(spec.as_str())
+        .with_context(|| format!("Could not resolve tree of '{rev}'"))?
+        .detach())
+}
+
+fn mode_bits(mode: Mode) -> u32 {
+    if mode == Mode::FILE_EXECUTABLE {
+        MODE_EXECUTABLE
+    } else if mode == Mode::SYMLINK {
+        MODE_SYMLINK
+    } else if mode == Mode::COMMIT {
+        MODE_GITLINK
+    } else {
+        MODE_FILE
+    }
+}
+
+fn entry_mode_bits(mode: gix::object::tree::EntryMode) -> u32 {
+    match mode.kind() {
+        gix::object::tree::EntryKind::BlobExecutable => MODE_EXECUTABLE,
+        gix::object::tree::EntryKind::Link => MODE_SYMLINK,
+        gix::object::tree::EntryKind::Commit => MODE_GITLINK,
+        _ => MODE_FILE,
+    }
+}
+
+/// Mode git would record for a working tree file.
+fn worktree_mode(root: &Path, path: &str) -> u32 {
+    let Ok(meta) = std::fs::symlink_metadata(root.join(path)) else {
+        return MODE_FILE;
+    };
+    if meta.file_type().is_symlink() {
+        return MODE_SYMLINK;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if meta.permissions().mode() & 0o111 != 0 {
+            return MODE_EXECUTABLE;
+        }
+    }
+    MODE_FILE
+}
+
+/// The blob at `path` in `tree`, skipping directories and submodules.
+fn tree_side(repo: &gix::Repository, tree: &gix::Tree<'_>, path: &str) -> Result<Option<Side>> {
+    match tree.lookup_entry_by_path(path)? {
+        Some(e) if e.mode().is_no_tree() && !e.mode().is_commit() => {
+            let id = e.object_id();
+            Ok(Some(Side {
+                bytes: read_blob(repo, id)?,
+                id,
+                mode: entry_mode_bits(e.mode()),
+                worktree: false,
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The commit a submodule entry at `path` in `tree` points to.
+fn gitlink_id(tree: &gix::Tree<'_>, path: &str) -> Result<Option<ObjectId>> {
+    Ok(tree
+        .lookup_entry_by_path(path)?
+        .filter(|e| e.mode().is_commit())
+        .map(|e| e.object_id()))
+}
+
+/// The commit checked out in the submodule at `dir`, if it is checked out.
+fn submodule_head(dir: &Path) -> Option<ObjectId> {
+    Some(gix::open(dir).ok()?.head_id().ok()?.detach())
+}
+
+/// Whether the submodule at `dir` has modified or untracked files.
+fn submodule_dirty(dir: &Path) -> Result<bool> {
+    let sub = gix::open(dir)?;
+    let mut status = sub
+        .status(gix::progress::Discard)?
+        .untracked_files(gix::status::UntrackedFiles::Files)
+        .into_iter(None)?;
+    Ok(status.next().is_some())
+}
+
+/// Pointer change of the submodule at `path`, with the commits in between
+/// when the submodule is checked out.
+fn submodule_summary(
+    repo: &gix::Repository,
+    path: &str,
+    old: Option<ObjectId>,
+    new: Option<ObjectId>,
+) -> FileDiff {
+    let (old, new) = (old.map(|id| id.to_string()), new.map(|id| id.to_string()));
+    let log = match (&old, &new, repo.workdir()) {
+        (Some(old), Some(new), Some(root)) => gix::open(root.join(path))
+            .map_err(anyhow::Error::from)
+            .and_then(|sub| Ok((commit_log(&sub, old, new)?, commit_log(&sub, new, old)?)))
+            .ok(),
+        _ => None,
+    };
+    git::submodule_diff(path, old.as_deref(), new.as_deref(), log)
+}
+
+/// Commits reachable from `to` but not from `from`, newest first.
+fn commit_log(repo: &gix::Repository, from: &str, to: &str) -> Result<Vec<CommitInfo>> {
+    use gix::revision::walk::Sorting;
+    use gix::traverse::commit::simple::CommitTimeOrder;
+
+    let resolve = |rev: &str| -> Result<ObjectId> {
+        Ok(repo
+            .rev_parse_single(rev)
+            .with_context(|| format!("Could not resolve '{rev}'"))?
+            .detach())
+    };
+    let walk = repo
+        .rev_walk([resolve(to)?])
+        .with_hidden([resolve(from)?])
+        .sorting(Sorting::ByCommitTime(CommitTimeOrder::NewestFirst))
+        .all()?;
+
+    let mut commits = Vec::new();
+    for info in walk {
+        let commit = repo.find_commit(info?.id)?;
+        let message = commit.message()?;
+        commits.push(CommitInfo {
+            id: commit.id.to_string(),
+            subject: message.summary().to_string(),
+            author: commit.author()?.name.to_string(),
+            time: commit.time()?.seconds,
+        });
+    }
+    Ok(commits)
+}
+
+fn read_blob(repo: &gix::Repository, id: ObjectId) -> Result<Vec<u8>> {
+    Ok(repo.find_blob(id)?.detach().data)
+}
+
+/// Read a working tree file the way git stores it: symlinks as their target.
+fn read_worktree(root: &Path, path: &str) -> Option<Vec<u8>> {
+    let full = root.join(path);
+    let meta = std::fs::symlink_metadata(&full).ok()?;
+    if meta.file_type().is_symlink() {
+        let target = std::fs::read_link(&full).ok()?;
+        return Some(target.to_string_lossy().into_owned().into_bytes());
+    }
+    if meta.is_dir() {
+        return None;
+    }
+    std::fs::read(&full).ok()
+}
+
+fn blob_hash(repo: &gix::Repository, bytes: &[u8]) -> ObjectId {
+    gix::objs::compute_hash(repo.object_hash(), gix::objs::Kind::Blob, bytes)
+        .unwrap_or_else(|_| ObjectId::null(repo.object_hash()))
+}
+
+fn line_stats(old: &[u8], new: &[u8], options: &DiffOptions) -> (i32, i32) {
+    if git::is_binary(old) || git::is_binary(new) {
+        return (0, 0);
+    }
+    let (old, new) = (line_diff::split_lines(old), line_diff::split_lines(new));
+    line_diff::line_changes(&old, &new, options).iter().fold(
+        (0, 0),
+        |(additions, deletions), (removed, added)| {
+            (
+                additions + added.len() as i32,
+                deletions + removed.len() as i32,
+            )
+        },
+    )
+}
+
+/// File entry for a change between two optional `(content, mode)` sides.
+fn entry(
+    path: &str,
+    old: Option<(&[u8], u32)>,
+    new: Option<(&[u8], u32)>,
+    options: &DiffOptions,
+) -> FileEntry {
+    let status = match (old, new) {
+        (None, _) => FileStatus::Added,
+        (_, None) => FileStatus::Deleted,
+        (Some((_, old_mode)), Some((_, new_mode)))
+            if (old_mode == MODE_SYMLINK) != (new_mode == MODE_SYMLINK) =>
+        {
+            FileStatus::TypeChanged
+        }
+        (Some((old_bytes, old_mode)), Some((new_bytes, new_mode)))
+            if old_bytes == new_bytes && old_mode != new_mode =>
+        {
+            FileStatus::ModeChanged
+        }
+        _ => FileStatus::Modified,
+    };
+    let old_bytes = old.map(|(bytes, _)| bytes).unwrap_or_default();
+    let new_bytes = new.map(|(bytes, _)| bytes).unwrap_or_default();
+    let (additions, deletions) = line_stats(old_bytes, new_bytes, options);
+    let mut file = FileEntry::new(path, status, additions, deletions);
+    file.old_mode = old.map(|(_, mode)| mode);
+    file.new_mode = new.map(|(_, mode)| mode);
+    file.whitespace_only = matches!(status, FileStatus::Modified)
+        && file.old_mode == file.new_mode
+        && (additions, deletions) == (0, 0)
+        && old_bytes != new_bytes
+        && !git::is_binary(old_bytes)
+        && !git::is_binary(new_bytes);
+    if lfs::parse_pointer(old_bytes).is_some() || lfs::parse_pointer(new_bytes).is_some() {
+        file.mark_lfs();
+    }
+    file
+}
+
+/// Borrow an owned `(bytes, mode)` side in the shape [`entry`] takes.
+fn as_ref(side: &Option<(Vec<u8>, u32)>) -> Option<(&[u8], u32)> {
+    side.as_ref().map(|(bytes, mode)| (bytes.as_slice(), *mode))
+}
+
+/// File entry for a file staged as a rename or copy of `source`, whose
+/// content `old` is. Once the file is gone again a rename is listed as the
+/// deletion of its source, and a copy not at all.
+fn rewrite_entry(
+    path: &str,
+    (source, copy): &(String, bool),
+    old: &Option<(Vec<u8>, u32)>,
+    new: &Option<(Vec<u8>, u32)>,
+    options: &DiffOptions,
+) -> Option<FileEntry> {
+    let (Some((old_bytes, _)), Some((new_bytes, _))) = (old, new) else {
+        return (!copy).then(|| entry(source, as_ref(old), None, options));
+    };
+    let mut file = entry(path, as_ref(old), as_ref(new), options);
+    file.status = if *copy {
+        FileStatus::Copied
+    } else {
+        FileStatus::Renamed
+    };
+    file.old_path = Some(source.clone());
+    file.similarity = Some((similarity(old_bytes, new_bytes) * 100.0).floor() as u8);
+    Some(file)
+}
+
+/// How alike two blobs are, from 0 to 1, measured like gitoxide's rename
+/// tracking: the share of the larger one that the unchanged old lines make up.
+fn similarity(old: &[u8], new: &[u8]) -> f32 {
+    if old == new {
+        return 1.0;
+    }
+    let (old_lines, new_lines) = (line_diff::split_lines(old), line_diff::split_lines(new));
+    let removed: usize = line_diff::line_changes(&old_lines, &new_lines, &DiffOptions::default())
+        .iter()
+        .flat_map(|(removed, _)| removed.clone())
+        .map(|i| old_lines[i as usize].len())
+        .sum();
+    (old.len() - removed) as f32 / old.len().max(new.len()) as f32
+}
+
+/// File entry for a change where either side is a submodule commit.
+fn submodule_entry(path: &str, old_mode: Option<u32>, new_mode: Option<u32>) -> FileEntry {
+    let status = match (old_mode, new_mode) {
+        (None, _) => FileStatus::Added,
+        (_, None) => FileStatus::Deleted,
+        (Some(MODE_GITLINK), Some(MODE_GITLINK)) => FileStatus::Submodule,
+        _ => FileStatus::TypeChanged,
+    };
+    let mut file = FileEntry::new(path, status, 0, 0);
+    file.old_mode = old_mode;
+    file.new_mode = new_mode;
+    file
+}
+
+/// Set the [`Attributes`] of `files` from `.gitattributes`, read from the
+/// working tree with the index as fallback like `git check-attr`.
+fn apply_attributes(repo: &gix::Repository, files: &mut [FileEntry]) -> Result<()> {
+    let index = repo.index_or_empty()?;
+    let mut stack = repo.attributes_only(&index, AttributeSource::WorktreeThenIdMapping)?;
+    let mut outcome = stack.selected_attribute_matches(Attributes::NAMES);
+    for file in files {
+        outcome.reset();
+        stack
+            .at_entry(file.path.as_str(), None)?
+            .matching_attributes(&mut outcome);
+        let mut attributes = Attributes::default();
+        for found in outcome.iter_selected() {
+            let value = match found.assignment.state {
+                StateRef::Set => "set".into(),
+                StateRef::Unset => "unset".into(),
+                StateRef::Value(value) => value.as_bstr().to_str_lossy(),
+                StateRef::Unspecified => continue,
+            };
+            attributes.assign(found.assignment.name.as_str(), &value);
+        }
+        file.set_attributes(attributes);
+    }
+    Ok(())
+}
+
+/// Rename and copy tracking matching `git diff -M<n>% -C<n>%`.
+fn rewrites(options: &DiffOptions) -> Option<gix::diff::Rewrites> {
+    if options.rename_threshold == 0 {
+        return None;
+    }
+    let percentage = Some(f32::from(options.rename_threshold) / 100.0);
+    Some(gix::diff::Rewrites {
+        copies: Some(gix::diff::rewrites::Copies {
+            percentage,
+            ..Default::default()
+        }),
+        percentage,
+        ..Default::default()
+    })
+}
+
+/// Build the same diff `git diff` would print for `file` between two sides.
+/// For renames and copies the old side is read from `file.old_path`.
+fn build_file_diff(
+    file: &FileEntry,
+    old: Option<Side>,
+    new: Option<Side>,
+    lfs_store: &Path,
+    options: &DiffOptions,
+) -> FileDiff {
+    let null = ObjectId::null(gix::hash::Kind::Sha1);
+    let short = |id: &ObjectId| id.to_hex_with_len(7).to_string();
+    let old_id = old.as_ref().map(|s| s.id).unwrap_or(null);
+    let new_id = new.as_ref().map(|s| s.id).unwrap_or(null);
+    let path = file.path.as_str();
+    let old_path = file.old_path.as_deref().unwrap_or(path);
+    if file.whitespace_only {
+        return git::whitespace_only_diff(file);
+    }
+
+    // Like git, show a change between a file and a symlink as a deletion
+    // followed by an addition.
+    if let (Some(o), Some(n)) = (&old, &new) {
+        if (o.mode == MODE_SYMLINK) != (n.mode == MODE_SYMLINK) {
+            let mut diff = build_file_diff(file, old, None, lfs_store, options);
+            let added = build_file_diff(file, None, new, lfs_store, options);
+            diff.header.extend(added.header);
+            diff.hunks.extend(added.hunks);
+            diff.new_path = added.new_path;
+            diff.new_mode = added.new_mode;
+            return diff;
+        }
+    }
+
+    let mut diff = FileDiff {
+        header: vec![format!("diff --git a/{old_path} b/{path}")],
+        old_path: old.as_ref().map(|_| old_path.to_string()),
+        new_path: new.as_ref().map(|_| path.to_string()),
+        old_mode: old.as_ref().map(|s| format!("{:o}", s.mode)),
+        new_mode: new.as_ref().map(|s| format!("{:o}", s.mode)),
+        ..FileDiff::default()
+    };
+
+    match (&old, &new) {
+        (None, Some(new)) => diff.header.push(format!("new file mode {:o}", new.mode)),
+        (Some(old), None) => diff
+            .header
+            .push(format!("deleted file mode {:o}", old.mode)),
+        (Some(old), Some(new)) if old.mode != new.mode => {
+            diff.header.push(format!("old mode {:o}", old.mode));
+            diff.header.push(format!("new mode {:o}", new.mode));
+        }
+        _ => {}
+    }
+    if let (Some(source), Some(similarity)) = (&file.old_path, file.similarity) {
+        let verb = match file.status {
+            FileStatus::Copied => "copy",
+            _ => "rename",
+        };
+        diff.header.push(format!("similarity index {similarity}%"));
+        diff.header.push(format!("{verb} from {source}"));
+        diff.header.push(format!("{verb} to {path}"));
+        diff.rename_from = Some(source.clone());
+        diff.rename_to = Some(path.to_string());
+    }
+    // Pure renames and mode changes have no content to show.
+    if old.is_some() && new.is_some() && old_id == new_id {
+        return diff;
+    }
+    let same_mode = match (&old, &new) {
+        (Some(old), Some(new)) if old.mode == new.mode => format!(" {:o}", old.mode),
+        _ => String::new(),
+    };
+    diff.header.push(format!(
+        "index {}..{}{same_mode}",
+        short(&old_id),
+        short(&new_id)
+    ));
+
+    let old_bytes = old.as_ref().map(|s| s.bytes.as_slice()).unwrap_or_default();
+    let new_bytes = new.as_ref().map(|s| s.bytes.as_slice()).unwrap_or_default();
+    let old_name = if old.is_some() {
+        format!("a/{old_path}")
+    } else {
+        "/dev/null".to_string()
+    };
+    let new_name = if new.is_some() {
+        format!("b/{path}")
+    } else {
+        "/dev/null".to_string()
+    };
+
+    let old_side = old.as_ref().map(|s| s.bytes.as_slice());
+    let new_side = new.as_ref().map(|s| s.bytes.as_slice());
+    if lfs::parse_pointer(old_bytes).is_some() || lfs::parse_pointer(new_bytes).is_some() {
+        diff.header.push(format!("--- {old_name}"));
+        diff.header.push(format!("+++ {new_name}"));
+        let new_in_worktree = new.as_ref().is_some_and(|s| s.worktree);
+        lfs::attach(
+            &mut diff,
+            old_side,
+            new_side,
+            new_in_worktree,
+            lfs_store,
+            options,
+        );
+        return diff;
+    }
+    if file.attributes.no_diff || git::is_binary(old_bytes) || git::is_binary(new_bytes) {
+        diff.header
+            .push(format!("Binary files {old_name} and {new_name} differ"));
+        binary::attach(&mut diff, old_side, new_side, options);
+        return diff;
+    }
+
+    diff.header.push(format!("--- {old_name}"));
+    diff.header.push(format!("+++ {new_name}"));
+    diff.hunks = diff_hunks(old_bytes, new_bytes, options);
+    let old_text = String::from_utf8_lossy(old_bytes);
+    let old_lines: Vec<&str> = old_text.lines().collect();
+    for hunk in &mut diff.hunks {
+        hunk.section = section_heading(&old_lines, hunk.old_start);
+    }
+    diff
+}
+
+/// git's default hunk heading: the closest line above the hunk that starts
+/// with a letter, `_` or `$`, cut to 80 bytes.
+fn section_heading(old_lines: &[&str], old_start: u32) -> String {
+    let above = (old_start as usize).saturating_sub(1).min(old_lines.len());
+    old_lines[..above]
+        .iter()
+        .rev()
+        .find(|line| line.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '$'))
+        .map(|line| {
+            let mut end = line.len().min(80);
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line[..end].trim_end().to_string()
+        })
+        .unwrap_or_default()
+}
+
+/// Submodules whose recorded or checked-out commit differs across the
+/// uncommitted range, or whose working tree has changes, as `git diff`
+/// lists them. Submodules removed from the index are not reported.
+fn worktree_submodules(
+    repo: &gix::Repository,
+    index: &gix::index::File,
+    merge_base: &str,
+    root: &Path,
+    work: WorkState,
+) -> Result<Vec<FileEntry>> {
+    let base_tree = repo.find_tree(tree_id(repo, merge_base)?)?;
+    let mut files = Vec::new();
+    for e in index.entries().iter().filter(|e| e.mode == Mode::COMMIT) {
+        let path = e.path(index).to_string();
+        let base = gitlink_id(&base_tree, &path)?;
+        let staged = Some(e.id);
+        let dir = root.join(&path);
+        let head = submodule_head(&dir).or(staged);
+        let changed = match work {
+            WorkState::Index => staged != base,
+            WorkState::Unstaged => head != staged || submodule_dirty(&dir).unwrap_or(false),
+            WorkState::Worktree => {
+                head != base || staged != base || submodule_dirty(&dir).unwrap_or(false)
+            }
+        };
+        if changed {
+            files.push(submodule_entry(
+                &path,
+                base.map(|_| MODE_GITLINK),
+                Some(MODE_GITLINK),
+            ));
+        }
+    }
+    Ok(files)
+}
+
+/// Changed files between two trees, in path order.
+fn tree_changes(
+    repo: &gix::Repository,
+    from: &str,
+    to: &str,
+    options: &DiffOptions,
+) -> Result<Vec<FileEntry>> {
+    use gix::object::tree::diff::ChangeDetached;
+
+    let old_tree = repo.find_tree(tree_id(repo, from)?)?;
+    let new_tree = repo.find_tree(tree_id(repo, to)?)?;
+    let diff_options = gix::diff::Options::default().with_rewrites(rewrites(options));
+    let changes = repo.diff_tree_to_tree(&old_tree, &new_tree, diff_options)?;
+
+    type TreeSide = Option<(gix::object::tree::EntryMode, ObjectId)>;
+    let blob = |side: TreeSide| -> Result<Option<(Vec<u8>, u32)>> {
+        match side {
+            Some((mode, id)) if mode.is_blob_or_symlink() => {
+                Ok(Some((read_blob(repo, id)?, entry_mode_bits(mode))))
+            }
+            _ => Ok(None),
+        }
+    };
+
+    let mut files = Vec::new();
+    let mut copy_sources = HashSet::new();
+    for change in &changes {
+        let (path, old, new): (_, TreeSide, TreeSide) = match change {
+            ChangeDetached::Addition {
+                location,
+                entry_mode,
+                id,
+                ..
+            } => (location, None, Some((*entry_mode, *id))),
+            ChangeDetached::Deletion {
+                location,
+                entry_mode,
+                id,
+                ..
+            } => (location, Some((*entry_mode, *id)), None),
+            ChangeDetached::Modification {
+                location,
+                previous_entry_mode,
+                previous_id,
+                entry_mode,
+                id,
+            } => (
+                location,
+                Some((*previous_entry_mode, *previous_id)),
+                Some((*entry_mode, *id)),
+            ),
+            ChangeDetached::Rewrite {
+                source_location,
+                source_entry_mode,
+                source_id,
+                diff,
+                entry_mode,
+                id,
+                location,
+                copy,
+                ..
+            } => {
+                let source_path = source_location.to_string();
+                let Some(new) = blob(Some((*entry_mode, *id)))? else {
+                    continue;
+                };
+                // A copy from a file that was itself modified points at the
+                // source's new blob and swallows that modification; git copies
+                // from the old blob and still lists the source.
+                let mut similarity = diff.map(|d| d.similarity).unwrap_or(1.0);
+                let mut old = blob(Some((*source_entry_mode, *source_id)))?;
+                if *copy {
+                    if let Some(pre) = tree_side(repo, &old_tree, &source_path)? {
+                        if pre.id != *source_id {
+                            if !copy_sources.contains(&source_path) {
+                                let pre_side = Some((pre.bytes.as_slice(), pre.mode));
+                                files.push(entry(&source_path, pre_side, as_ref(&old), options));
+                                copy_sources.insert(source_path.clone());
+                            }
+                            if pre.id == *id {
+                                similarity = 1.0;
+                            }
+                            old = Some((pre.bytes, pre.mode));
+                        }
+                    }
+                }
+                if old.is_none() {
+                    continue;
+                }
+                let mut file = entry(
+                    &location.to_string(),
+                    as_ref(&old),
+                    as_ref(&Some(new)),
+                    options,
+                );
+                file.status = if *copy {
+                    FileStatus::Copied
+                } else {
+                    FileStatus::Renamed
+                };
+                file.old_path = Some(source_path);
+                // No line diff was needed when the content is identical.
+                file.similarity = Some((similarity * 100.0).floor() as u8);
+                files.push(file);
+                continue;
+            }
+        };
+
+        let gitlink = |side: TreeSide| side.is_some_and(|(mode, _)| mode.is_commit());
+        if gitlink(old) || gitlink(new) {
+            files.push(submodule_entry(
+                &path.to_string(),
+                old.map(|(mode, _)| entry_mode_bits(mode)),
+                new.map(|(mode, _)| entry_mode_bits(mode)),
+            ));
+            continue;
+        }
+        let (old, new) = (blob(old)?, blob(new)?);
+        if old.is_none() && new.is_none() {
+            continue;
+        }
+        files.push(entry(
+            &path.to_string(),
+            as_ref(&old),
+            as_ref(&new),
+            options,
+        ));
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+impl GixBackend {
+    /// Run one status pass comparing the merge-base tree with the index, and
+    /// the index with the working tree. Returns tracked path states keyed by
+    /// path, plus the untracked paths.
+    fn collect_states(
+        &self,
+        repo: &gix::Repository,
+        index: &gix::index::File,
+        merge_base: &str,
+        options: &DiffOptions,
+    ) -> Result<(BTreeMap<String, PathState>, BTreeSet<String>)> {
+        let base_tree = tree_id(repo, merge_base)?;
+        let mut states: BTreeMap<String, PathState> = BTreeMap::new();
+        let mut untracked = BTreeSet::new();
+
+        // Unchanged paths have the same blob at the merge-base and in the index.
+        let index_id = |path: &BStr| {
+            index
+                .entry_by_path(path)
+                .filter(|e| e.mode != Mode::COMMIT)
+                .map(|e| (e.id, mode_bits(e.mode)))
+        };
+
+        let status = repo
+            .status(gix::progress::Discard)?
+            .head_tree(base_tree)
+            .tree_index_track_renames(match rewrites(options) {
+                Some(rewrites) => gix::status::tree_index::TrackRenames::Given(rewrites),
+                None => gix::status::tree_index::TrackRenames::Disabled,
+            })
+            .untracked_files(gix::status::UntrackedFiles::Files)
+            .index_worktree_rewrites(None)
+            .index_worktree_submodules(gix::status::Submodule::Given {
+                ignore: gix::submodule::config::Ignore::All,
+                check_dirty: false,
+            })
+            .into_iter(None)?;
+
+        for item in status {
+            match item? {
+                gix::status::Item::TreeIndex(change) => {
+                    use gix::diff::index::ChangeRef;
+                    let (path, base, staged, source) = match &change {
+                        ChangeRef::Addition {
+                            location,
+                            entry_mode,
+                            id,
+                            ..
+                        } => {
+                            if *entry_mode == Mode::COMMIT {
+                                continue;
+                            }
+                            let staged = (id.clone().into_owned(), mode_bits(*entry_mode));
+                            (location.to_string(), None, Some(staged), None)
+                        }
+                        ChangeRef::Deletion {
+                            location,
+                            entry_mode,
+                            id,
+                            ..
+                        } => {
+                            if *entry_mode == Mode::COMMIT {
+                                continue;
+                            }
+                            let base = (id.clone().into_owned(), mode_bits(*entry_mode));
+                            (location.to_string(), Some(base), None, None)
+                        }
+                        ChangeRef::Modification {
+                            location,
+                            previous_entry_mode,
+                            previous_id,
+                            entry_mode,
+                            id,
+                            ..
+                        } => {
+                            if *entry_mode == Mode::COMMIT {
+                                continue;
+                            }
+                            (
+                                location.to_string(),
+                                Some((
+                                    previous_id.clone().into_owned(),
+                                    mode_bits(*previous_entry_mode),
+                                )),
+                                Some((id.clone().into_owned(), mode_bits(*entry_mode))),
+                                None,
+                            )
+                        }
+                        ChangeRef::Rewrite {
+                            source_location,
+                            source_entry_mode,
+                            source_id,
+                            location,
+                            entry_mode,
+                            id,
+                            copy,
+                            ..
+                        } => {
+                            if *entry_mode == Mode::COMMIT || *source_entry_mode == Mode::COMMIT {
+                                continue;
+                            }
+                            (
+                                location.to_string(),
+                                Some((
+                                    source_id.clone().into_owned(),
+                                    mode_bits(*source_entry_mode),
+                                )),
+                                Some((id.clone().into_owned(), mode_bits(*entry_mode))),
+                                Some((source_location.to_string(), *copy)),
+                            )
+                        }
+                    };
+                    let state = states.entry(path).or_insert(PathState {
+                        base: None,
+                        index: None,
+                        worktree: WorktreeState::Unchanged,
+                        source: None,
+                    });
+                    state.base = base;
+                    state.index = staged;
+                    state.source = source;
+                }
+                gix::status::Item::IndexWorktree(item) => {
+                    use gix::status::index_worktree::iter::Summary;
+                    let Some(summary) = item.summary() else {
+                        continue;
+                    };
+                    let path = item.rela_path().to_string();
+                    let worktree = match summary {
+                        Summary::Added => {
+                            untracked.insert(path);
+                            continue;
+                        }
+                        Summary::Removed => WorktreeState::Removed,
+                        _ => WorktreeState::Modified,
+                    };
+                    if let gix::status::index_worktree::Item::Modification { entry, .. } = &item {
+                        if entry.mode == Mode::COMMIT {
+                            continue;
+                        }
+                    }
+                    let id = index_id(path.as_str().into());
+                    let state = states.entry(path).or_insert(PathState {
+                        base: id,
+                        index: id,
+                        worktree: WorktreeState::Unchanged,
+                        source: None,
+                    });
+                    state.worktree = worktree;
+                }
+            }
+        }
+
+        Ok((states, untracked))
+    }
+
+    /// Files that differ between `range.from` and the index or working tree.
+    fn worktree_changes(
+        &self,
+        repo: &gix::Repository,
+        range: &DiffRange,
+        options: &DiffOptions,
+    ) -> Result<Vec<FileEntry>> {
+        let root = workdir(repo)?;
+        let index = repo.index_or_empty()?;
+        let (states, untracked) = self.collect_states(repo, &index, &range.from, options)?;
+
+        let mut files = Vec::new();
+        let mut index_only = Vec::new();
+        for (path, state) in &states {
+            let base = match state.base {
+                Some((id, mode)) => Some((read_blob(repo, id)?, mode)),
+                None => None,
+            };
+            let staged = match state.index {
+                Some((id, mode)) => Some((read_blob(repo, id)?, mode)),
+                None => None,
+            };
+            let worktree = match state.worktree {
+                WorktreeState::Unchanged => staged.clone(),
+                WorktreeState::Modified => {
+                    read_worktree(&root, path).map(|bytes| (bytes, worktree_mode(&root, path)))
+                }
+                WorktreeState::Removed => None,
+            };
+
+            let from_base = |new: &Option<(Vec<u8>, u32)>| match &state.source {
+                Some(source) => rewrite_entry(path, source, &base, new, options),
+                None => Some(entry(path, as_ref(&base), as_ref(new), options)),
+            };
+            // A file moved unchanged still differs from its merge-base self.
+            let moved = state.source.is_some();
+            match range.work {
+                WorkState::Index if moved || staged != base => files.extend(from_base(&staged)),
+                WorkState::Unstaged if worktree != staged => {
+                    files.push(entry(path, as_ref(&staged), as_ref(&worktree), options));
+                }
+                WorkState::Worktree if moved || worktree != base => {
+                    files.extend(from_base(&worktree))
+                }
+                // Mirror the CLI backend's index-only fallback.
+                WorkState::Worktree if staged != base => index_only.extend(from_base(&staged)),
+                _ => {}
+            }
+        }
+        files.extend(index_only);
+        files.extend(worktree_submodules(
+            repo,
+            &index,
+            &range.from,
+            &root,
+            range.work,
+        )?);
+        if !range.untracked || range.work == WorkState::Index {
+            return Ok(files);
+        }
+
+        let seen: HashSet<&String> = states.keys().collect();
+        for path in untracked.iter().filter(|p| !seen.contains(p)) {
+            let side = read_worktree(&root, path).map(|bytes| (bytes, worktree_mode(&root, path)));
+            files.push(entry(path, None, as_ref(&side), options));
+        }
+        Ok(files)
+    }
+}
+
+impl GitBackend for GixBackend {
+    fn merge_base(&self, one: &str, two: &str) -> Result<String> {
+        let repo = self.repo();
+        let resolve = |rev: &str| -> Result<ObjectId> {
+            Ok(repo
+                .rev_parse_single(rev)
+                .with_context(|| format!("Could not resolve '{rev}'"))?
+                .detach())
+        };
+        let merge_base = repo
+            .merge_base(resolve(one)?, resolve(two)?)
+            .with_context(|| format!("Could not find merge-base of '{one}' and '{two}'"))?;
+        Ok(merge_base.to_string())
+    }
+
+    fn changed_files(&self, range: &DiffRange, options: &DiffOptions) -> Result<Vec<FileEntry>> {
+        let repo = self.repo();
+        let mut files = match &range.to {
+            Some(to) => tree_changes(&repo, &range.from, to, options)?,
+            None => self.worktree_changes(&repo, range, options)?,
+        };
+        // Without attributes files only miss their badges and grouping.
+        if let Err(err) = apply_attributes(&repo, &mut files) {
+            logging::log_error(&err);
+        }
+        Ok(submodule::expand(self, range, files, options))
+    }
+
+    fn file_diff(
+        &self,
+        range: &DiffRange,
+        file: &FileEntry,
+        options: &DiffOptions,
+    ) -> (DiffSource, FileDiff) {
+        if let Some(scope) = &file.submodule {
+            return submodule::file_diff(self, scope, options);
+        }
+        let path = file.path.as_str();
+        let load = || -> Result<(DiffSource, FileDiff)> {
+            let repo = self.repo();
+            let lfs_store = lfs::store(repo.common_dir());
+            let base_tree = repo.find_tree(tree_id(&repo, &range.from)?)?;
+            if let Some(to) = &range.to {
+                let old_path = file.old_path.as_deref().unwrap_or(path);
+                let base = tree_side(&repo, &base_tree, old_path)?;
+                let new_tree = repo.find_tree(tree_id(&repo, to)?)?;
+                if file.is_submodule() {
+                    let old = gitlink_id(&base_tree, old_path)?;
+                    let new = gitlink_id(&new_tree, path)?;
+                    return Ok((DiffSource::Commit, submodule_summary(&repo, path, old, new)));
+                }
+                let new = tree_side(&repo, &new_tree, path)?;
+                return Ok((
+                    DiffSource::Commit,
+                    build_file_diff(file, base, new, &lfs_store, options),
+                ));
+            }
+            let root = workdir(&repo)?;
+            let index = repo.index_or_empty()?;
+            if file.is_submodule() {
+                let staged = index
+                    .entry_by_path(path.into())
+                    .filter(|e| e.mode == Mode::COMMIT)
+                    .map(|e| e.id);
+                let head = submodule_head(&root.join(path)).or(staged);
+                let base = gitlink_id(&base_tree, path)?;
+                let (source, old, new) = match range.work {
+                    WorkState::Index => (DiffSource::Index, base, staged),
+                    WorkState::Unstaged => (DiffSource::Worktree, staged, head),
+                    WorkState::Worktree => (DiffSource::Worktree, base, head),
+                };
+                return Ok((source, submodule_summary(&repo, path, old, new)));
+            }
+            let base = tree_side(&repo, &base_tree, file.old_path.as_deref().unwrap_or(path))?;
+
+            let index_entry = index
+                .entry_by_path(path.into())
+                .filter(|e| e.mode != Mode::COMMIT);
+            let staged = match index_entry {
+                Some(e) => Some(Side {
+                    bytes: read_blob(&repo, e.id)?,
+                    id: e.id,
+                    mode: mode_bits(e.mode),
+                    worktree: false,
+                }),
+                None => None,
+            };
+            let worktree = read_worktree(&root, path).map(|bytes| Side {
+                id: blob_hash(&repo, &bytes),
+                mode: worktree_mode(&root, path),
+                bytes,
+                worktree: true,
+            });
+
+            let id = |side: &Option<Side>| side.as_ref().map(|s| (s.id, s.mode));
+            match range.work {
+                WorkState::Index => {
+                    return Ok((
+                        DiffSource::Index,
+                        build_file_diff(file, base, staged, &lfs_store, options),
+                    ));
+                }
+                WorkState::Unstaged if staged.is_some() => {
+                    return Ok((
+                        DiffSource::Worktree,
+                        build_file_diff(file, staged, worktree, &lfs_store, options),
+                    ));
+                }
+                _ => {}
+            }
+
+            if staged.is_none() && (base.is_none() || range.work == WorkState::Unstaged) {
+                if let Some(side) = worktree.filter(|_| range.untracked) {
+                    return Ok((
+                        DiffSource::Untracked,
+                        git::untracked_file_diff(file, &side.bytes, options),
+                    ));
+                }
+                return Ok((DiffSource::Worktree, FileDiff::default()));
+            }
+
+            // A file moved unchanged still has its rename to show.
+            if file.old_path.is_some() || id(&worktree) != id(&base) {
+                Ok((
+                    DiffSource::Worktree,
+                    build_file_diff(file, base, worktree, &lfs_store, options),
+                ))
+            } else if id(&staged) != id(&base) {
+                Ok((
+                    DiffSource::Index,
+                    build_file_diff(file, base, staged, &lfs_store, options),
+                ))
+            } else {
+                Ok((DiffSource::Worktree, FileDiff::default()))
+            }
+        };
+
+        load().unwrap_or_else(|err| {
+            let diff = FileDiff {
+                header: vec![format!("Error getting diff: {err}")],
+                ..FileDiff::default()
+            };
+            (DiffSource::Worktree, diff)
+        })
+    }
+
+    fn file_sides(
+        &self,
+        range: &DiffRange,
+        file: &FileEntry,
+        source: DiffSource,
+    ) -> Result<FileSides> {
+        if let Some(scope) = &file.submodule {
+            return submodule::file_sides(self, scope, source);
+        }
+        let repo = self.repo();
+        let path = file.path.as_str();
+        let old_path = file.old_path.as_deref().unwrap_or(path);
+        let staged = |path: &str| -> Result<Option<Vec<u8>>> {
+            let index = repo.index_or_empty()?;
+            match index
+                .entry_by_path(path.into())
+                .filter(|e| e.mode != Mode::COMMIT)
+            {
+                Some(e) => Ok(Some(read_blob(&repo, e.id)?)),
+                None => Ok(None),
+            }
+        };
+        let committed = |rev: &str, path: &str| -> Result<Option<Vec<u8>>> {
+            let tree = repo.find_tree(tree_id(&repo, rev)?)?;
+            Ok(tree_side(&repo, &tree, path)?.map(|side| side.bytes))
+        };
+        let old = match (source, range.work) {
+            (DiffSource::Worktree, WorkState::Unstaged) => staged(old_path)?,
+            _ => committed(&range.from, old_path)?,
+        };
+        let new = match (&range.to, source) {
+            (Some(to), _) => committed(to, path)?,
+            (None, DiffSource::Index) => staged(path)?,
+            (None, _) => read_worktree(&workdir(&repo)?, path),
+        };
+        let store = lfs::store(repo.common_dir());
+        Ok((lfs::smudge(old, &store), lfs::smudge(new, &store)))
+    }
+
+    fn commits(&self, from: &str, to: &str) -> Result<Vec<CommitInfo>> {
+        commit_log(&self.repo(), from, to)
+    }
+
+    fn merge_preview(&self, base: &str, source: MergeSource) -> Result<MergePreview> {
+        git::merge_preview(&workdir(&self.repo())?, base, source)
+    }
+
+    fn rev_parse(&self, rev: &str) -> Result<String> {
+        let repo = self.repo();
+        let id = repo
+            .rev_parse_single(rev)
+            .with_context(|| format!("Could not resolve '{rev}'"))?;
+        Ok(id.to_string())
+    }
+
+    fn status_hash(&self) -> Result<u64> {
+        let repo = self.repo();
+        let root = workdir(&repo)?;
+        let status = repo
+            .status(gix::progress::Discard)?
+            .untracked_files(gix::status::UntrackedFiles::Files)
+            .index_worktree_rewrites(None)
+            .into_iter(None)?;
+
+        // Items arrive in no particular order; sort them so the hash is stable.
+        let mut records: Vec<String> = Vec::new();
+        for item in status {
+            match item? {
+                gix::status::Item::TreeIndex(change) => {
+                    let (path, _, mode, id) = change.fields();
+                    records.push(format!("T {path} {mode:?} {id}"));
+                }
+                gix::status::Item::IndexWorktree(item) => {
+                    let Some(summary) = item.summary() else {
+                        continue;
+                    };
+                    let path = item.rela_path().to_string();
+                    let staged = match &item {
+                        gix::status::index_worktree::Item::Modification { entry, .. } => {
+                            Some(entry.id)
+                        }
+                        _ => None,
+                    };
+                    // The summary stays the same while a modified file is edited
+                    // further, so its size and mtime are part of the record.
+                    let stat = std::fs::symlink_metadata(root.join(&path))
+                        .ok()
+                        .map(|meta| (meta.len(), meta.modified().ok()));
+                    records.push(format!("W {path} {summary:?} {staged:?} {stat:?}"));
+                }
+            }
+        }
+        records.sort();
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        records.hash(&mut hasher);
+        Ok(hasher.finish())
+    }
+
+    fn list_branches(&self) -> Result<Vec<String>> {
+        let repo = self.repo();
+        let refs = repo.references()?;
+        let mut branches: Vec<String> = Vec::new();
+        for reference in refs.local_branches()?.chain(refs.remote_branches()?) {
+            let Ok(reference) = reference else {
+                continue;
+            };
+            let name = reference.name().shorten().to_string();
+            if !name.contains("HEAD") {
+                branches.push(name);
+            }
+        }
+        branches.sort();
+        branches.dedup();
+        Ok(branches)
+    }
+
+    fn remotes(&self) -> Result<Vec<String>> {
+        let repo = self.repo();
+        Ok(repo
+            .remote_names()
+            .into_iter()
+            .map(|name| name.as_bstr().to_str_lossy().into_owned())
+            .collect())
+    }
+
+    fn open_submodule(&self, path: &str) -> Result<Box<dyn GitBackend>> {
+        let dir = workdir(&self.repo())?.join(path);
+        let repo = gix::ThreadSafeRepository::open(&dir)
+            .with_context(|| format!("Failed to open submodule '{path}'"))?;
+        Ok(Box::new(Self { repo }))
+    }
+
+    // Writing the index is left to git, which knows how to lock it.
+    fn stage(&self, paths: &[String]) -> Result<()> {
+        git::stage_paths(&workdir(&self.repo())?, paths)
+    }
+
+    fn unstage(&self, paths: &[String]) -> Result<()> {
+        git::unstage_paths(&workdir(&self.repo())?, paths)
+    }
+
+    fn apply_patch(&self, patch: &str, to: ApplyTo, reverse: bool) -> Result<()> {
+        git::apply_patch(&workdir(&self.repo())?, patch, to, reverse)
+    }
+
+    fn git_dir(&self) -> Result<PathBuf> {
+        Ok(self.repo().git_dir().to_path_buf())
+    }
+
+    fn git_path(&self, name: &str) -> Result<PathBuf> {
+        let repo = self.repo();
+        // In a linked worktree only HEAD and the index are its own; refs
+        // and packed-refs are shared with the main one.
+        let dir = match name {
+            "HEAD" | "index" => repo.git_dir(),
+            _ => repo.common_dir(),
+        };
+        Ok(dir.join(name))
+    }
+
+    fn commit_message(&self, rev: &str) -> Result<String> {
+        let repo = self.repo();
+        let commit = repo
+            .rev_parse_single(rev)
+            .with_context(|| format!("Could not resolve '{rev}'"))?
+            .object()?
+            .try_into_commit()?;
+        Ok(commit.message_raw()?.to_str_lossy().trim_end().to_string())
+    }
+
+    fn head_branch(&self) -> Option<String> {
+        let name = self.repo().head_name().ok()??;
+        Some(name.shorten().to_string())
+    }
+
+    fn commit(&self, message_file: &Path, amend: bool) -> Result<()> {
+        git::commit(&workdir(&self.repo())?, message_file, amend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::line_stats;
+    use crate::model::{DiffOptions, Whitespace};
+
+    fn ignoring(whitespace: Whitespace) -> DiffOptions {
+        DiffOptions {
+            whitespace,
+            ..DiffOptions::default()
+        }
+    }
+
+    #[test]
+    fn whitespace_modes_hide_ignored_changes_from_stats() {
+        let old = b"fn main() {\n  call(a,  b);\n  x(a,b);\n}\n";
+        let new = b"fn main() {\n    call(a, b);  \n  x(a, b);\n}\n";
+        assert_eq!(line_stats(old, new, &DiffOptions::default()), (2, 2));
+        // Like `git diff --numstat`, adding the final newline changes the last line.
+        assert_eq!(
+            line_stats(b"a\nb", b"a\nb\n", &DiffOptions::default()),
+            (1, 1)
+        );
+        let amount = ignoring(Whitespace {
+            ignore_amount: true,
+            ..Whitespace::default()
+        });
+        assert_eq!(line_stats(old, new, &amount), (1, 1));
+
+        // The blank line far from the real change isn't counted.
+        let old = b"a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let new = b"a\n\nb\nc\nd\ne\nf\ng\nh\ni\nJ\n";
+        let blank = ignoring(Whitespace {
+            ignore_blank_lines: true,
+            ..Whitespace::default()
+        });
+        assert_eq!(line_stats(old, new, &blank), (1, 1));
+    }
+}
@@ -1,21 +1,19 @@
-use crate::model::HighlightedLine;
+use crate::full_file::{FileRow, Marker};
+use crate::model::{
+    DiffLine, DiffLineKind, FileConflict, FileDiff, FileEntry, HighlightedDiff, HighlightedLine,
+    SplitRow,
+};
+use crate::moves::FileMoves;
 use crate::theme::Theme;
+use crate::word_diff;
 use ratatui::prelude::Color;
+use std::ops::Range;
 use syntect::{
     easy::HighlightLines,
     highlighting::{Style as SyntectStyle, Theme as SyntectTheme, ThemeSet},
     parsing::SyntaxSet,
 };
 
-#[derive(Clone, Copy, PartialEq)]
-enum DiffLineType {
-    Header,
-    Hunk,
-    Added,
-    Removed,
-    Context,
-}
-
 pub struct Highlighter {
     syntax_set: SyntaxSet,
     syntect_theme: Option<SyntectTheme>,
@@ -44,7 +42,251 @@ impl Highlighter {
         }
     }
 
-    pub fn highlight_diff(&self, diff_lines: &[String], file_path: &str) -> Vec<HighlightedLine> {
+    /// Highlight a diff, drawing the lines in `moves` as moved rather than
+    /// added or removed.
+    pub fn highlight_diff(
+        &self,
+        diff: &FileDiff,
+        file_path: &str,
+        moves: Option<&FileMoves>,
+    ) -> Vec<HighlightedLine> {
+        let mut highlighter = self.line_highlighter(file_path);
+
+        let mut result = Vec::new();
+
+        // Header lines get plain, dimmed styling
+        for line in &diff.header {
+            result.push(HighlightedLine::plain(
+                strip_ansi(line),
+                Color::DarkGray,
+                Color::Reset,
+            ));
+        }
+
+        for (h, hunk) in diff.hunks.iter().enumerate() {
+            result.push(HighlightedLine::plain(
+                hunk.header(),
+                Color::Cyan,
+                self.theme.diff_hunk_bg,
+            ));
+
+            let codes: Vec<String> = hunk.lines.iter().map(|l| strip_ansi(&l.content)).collect();
+            let emphasis = paired_emphasis(&hunk.lines, &codes);
+
+            for (i, ((line, code), emphasized)) in
+                hunk.lines.iter().zip(codes).zip(emphasis).enumerate()
+            {
+                let (prefix, mut prefix_fg, mut bg_color, mut emph_bg) = match line.kind {
+                    DiffLineKind::Added => (
+                        "+",
+                        self.theme.diff_added_fg,
+                        self.theme.diff_added_bg,
+                        self.theme.diff_added_word_bg,
+                    ),
+                    DiffLineKind::Removed => (
+                        "-",
+                        self.theme.diff_removed_fg,
+                        self.theme.diff_removed_bg,
+                        self.theme.diff_removed_word_bg,
+                    ),
+                    DiffLineKind::Context => (" ", Color::DarkGray, Color::Reset, Color::Reset),
+                    DiffLineKind::NoNewline => {
+                        result.push(HighlightedLine::plain(
+                            format!("\\{code}"),
+                            Color::DarkGray,
+                            Color::Reset,
+                        ));
+                        continue;
+                    }
+                };
+
+                let moved = moves.and_then(|moves| moves.get(&(h, i)));
+                if moved.is_some() {
+                    prefix_fg = self.theme.diff_moved_fg;
+                    bg_color = self.theme.diff_moved_bg;
+                    emph_bg = bg_color;
+                }
+
+                let pieces = self.highlight_code(highlighter.as_mut(), code);
+                let mut spans = vec![(prefix.to_string(), prefix_fg, bg_color)];
+                push_emphasized(&mut spans, pieces, &emphasized, bg_color, emph_bg);
+                if let Some(moved) = moved.filter(|m| m.block_start) {
+                    let direction = if line.kind == DiffLineKind::Added {
+                        "from"
+                    } else {
+                        "to"
+                    };
+                    spans.push((
+                        format!("  moved {direction} {}:{}", moved.path, moved.line),
+                        self.theme.diff_moved_fg,
+                        Color::Reset,
+                    ));
+                }
+
+                result.push(HighlightedLine {
+                    spans,
+                    old_lineno: line.old_lineno,
+                    new_lineno: line.new_lineno,
+                });
+            }
+        }
+
+        result
+    }
+
+    /// Highlight the whole new version of a file as laid out by
+    /// [`full_file::file_rows`], with a gutter marker on changed lines and
+    /// inline removed lines taken from `diff`.
+    pub fn highlight_file(
+        &self,
+        lines: &[&str],
+        diff: &FileDiff,
+        rows: &[FileRow],
+        file_path: &str,
+    ) -> Vec<HighlightedLine> {
+        // The file and its removed lines are separate streams of code.
+        let mut highlighter = self.line_highlighter(file_path);
+        let mut removed_highlighter = self.line_highlighter(file_path);
+
+        let mut result = Vec::with_capacity(rows.len());
+        for row in rows {
+            match *row {
+                FileRow::Line {
+                    index,
+                    old_lineno,
+                    marker,
+                } => {
+                    let text = lines[index].strip_suffix('\n').unwrap_or(lines[index]);
+                    let code = strip_ansi(text.strip_suffix('\r').unwrap_or(text));
+                    let fg = match marker {
+                        Some(Marker::Added) => self.theme.diff_added_fg,
+                        Some(Marker::Modified) => self.theme.diff_modified_fg,
+                        Some(Marker::DeletedAbove | Marker::DeletedBelow) => {
+                            self.theme.diff_removed_fg
+                        }
+                        None => Color::Reset,
+                    };
+                    let symbol = marker.map_or(" ", Marker::symbol);
+                    let mut spans = vec![(symbol.to_string(), fg, Color::Reset)];
+                    let pieces = self.highlight_code(highlighter.as_mut(), code);
+                    spans.extend(
+                        pieces
+                            .into_iter()
+                            .map(|(text, fg)| (text, fg, Color::Reset)),
+                    );
+                    result.push(HighlightedLine {
+                        spans,
+                        old_lineno,
+                        new_lineno: Some(index as u32 + 1),
+                    });
+                }
+                FileRow::Removed { hunk, line } => {
+                    let line = &diff.hunks[hunk].lines[line];
+                    let bg = self.theme.diff_removed_bg;
+                    let mut spans = vec![("-".to_string(), self.theme.diff_removed_fg, bg)];
+                    let pieces = self
+                        .highlight_code(removed_highlighter.as_mut(), strip_ansi(&line.content));
+                    spans.extend(pieces.into_iter().map(|(text, fg)| (text, fg, bg)));
+                    result.push(HighlightedLine {
+                        spans,
+                        old_lineno: line.old_lineno,
+                        new_lineno: None,
+                    });
+                }
+            }
+        }
+        result
+    }
+
+    /// Each conflict of a file that would not merge cleanly into `base`: the
+    /// base's lines next to the branch's, like the old and new side of a diff.
+    pub fn highlight_conflict(&self, conflict: &FileConflict, base: &str) -> HighlightedDiff {
+        let mut base_highlighter = self.line_highlighter(&conflict.path);
+        let mut branch_highlighter = self.line_highlighter(&conflict.path);
+        let summary = format!("Merging into {base}: {}", conflict.kind.describe());
+        let mut lines = vec![HighlightedLine::plain(
+            summary,
+            Color::DarkGray,
+            Color::Reset,
+        )];
+        let mut split_rows = vec![SplitRow::Full(0)];
+        let mut changes = Vec::new();
+        if conflict.regions.is_empty() {
+            lines.push(HighlightedLine::plain(
+                "No conflicting lines to show.".to_string(),
+                Color::DarkGray,
+                Color::Reset,
+            ));
+            split_rows.push(SplitRow::Full(1));
+        }
+
+        let count = conflict.regions.len();
+        for (i, region) in conflict.regions.iter().enumerate() {
+            changes.push(lines.len());
+            split_rows.push(SplitRow::Full(lines.len()));
+            lines.push(HighlightedLine::plain(
+                format!(
+                    "@@ conflict {}/{count}: {base} line {}, this branch line {} @@",
+                    i + 1,
+                    region.base_start,
+                    region.branch_start
+                ),
+                Color::Cyan,
+                self.theme.diff_hunk_bg,
+            ));
+
+            let mut side = |label: &str, code: &[String], start: u32, base_side: bool| {
+                let (prefix, fg, bg, highlighter) = if base_side {
+                    (
+                        "<",
+                        self.theme.diff_removed_fg,
+                        self.theme.diff_removed_bg,
+                        &mut base_highlighter,
+                    )
+                } else {
+                    (
+                        ">",
+                        self.theme.diff_added_fg,
+                        self.theme.diff_added_bg,
+                        &mut branch_highlighter,
+                    )
+                };
+                let mut indices = vec![lines.len()];
+                lines.push(HighlightedLine::plain(label.to_string(), fg, Color::Reset));
+                for (n, code) in code.iter().enumerate() {
+                    let lineno = Some(start + n as u32);
+                    let mut spans = vec![(prefix.to_string(), fg, bg)];
+                    let pieces = self.highlight_code(highlighter.as_mut(), strip_ansi(code));
+                    spans.extend(pieces.into_iter().map(|(text, fg)| (text, fg, bg)));
+                    indices.push(lines.len());
+                    lines.push(HighlightedLine {
+                        spans,
+                        old_lineno: lineno.filter(|_| base_side),
+                        new_lineno: lineno.filter(|_| !base_side),
+                    });
+                }
+                indices
+            };
+            let left = side(base, &region.base, region.base_start, true);
+            let right = side("this branch", &region.branch, region.branch_start, false);
+            for row in 0..left.len().max(right.len()) {
+                split_rows.push(SplitRow::Pair(
+                    left.get(row).copied(),
+                    right.get(row).copied(),
+                ));
+            }
+        }
+
+        HighlightedDiff {
+            lines,
+            split_rows,
+            changes,
+            full_file: false,
+        }
+    }
+
+    /// Stateful highlighter for the syntax of `file_path`, if a theme loaded.
+    fn line_highlighter(&self, file_path: &str) -> Option<HighlightLines<'_>> {
         let extension = file_path.rsplit('.').next().unwrap_or("");
         // Map common extensions that syntect doesn't recognize directly
         let mapped_ext = match extension {
@@ -58,128 +300,210 @@ impl Highlighter {
             .find_syntax_by_extension(mapped_ext)
             .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text());
 
-        let mut highlighter = self
-            .syntect_theme
+        self.syntect_theme
             .as_ref()
-            .map(|theme| HighlightLines::new(syntax, theme));
+            .map(|theme| HighlightLines::new(syntax, theme))
+    }
 
-        let mut result = Vec::new();
+    /// Split one line of code into pieces with their syntax colors.
+    fn highlight_code(
+        &self,
+        highlighter: Option<&mut HighlightLines>,
+        code: String,
+    ) -> Vec<(String, Color)> {
+        let code_with_newline = format!("{code}\n");
+        let highlighted =
+            highlighter.and_then(|hl| hl.highlight_line(&code_with_newline, &self.syntax_set).ok());
+        let Some(highlighted) = highlighted else {
+            return vec![(code, Color::White)];
+        };
+        highlighted
+            .into_iter()
+            .map(|(style, text)| (text.trim_end_matches('\n'), style))
+            .filter(|(text, _)| !text.is_empty())
+            .map(|(text, style)| (text.to_string(), syntect_to_ratatui_color(style)))
+            .collect()
+    }
+}
 
-        for line in diff_lines {
-            let stripped = strip_ansi(line);
-            let line_type = classify_diff_line(&stripped);
+/// Pair each run of removed lines with the added lines that follow it (the
+/// same pairing the split view uses) and word-diff every pair. Returns the
+/// changed byte ranges of each line's code; unpaired lines get none.
+fn paired_emphasis(lines: &[DiffLine], codes: &[String]) -> Vec<Vec<Range<usize>>> {
+    let mut emphasis = vec![Vec::new(); lines.len()];
+    let kind_at = |i: usize| lines[i].kind;
 
-            let (bg_color, code_to_highlight) = match line_type {
-                DiffLineType::Added => (
-                    self.theme.diff_added_bg,
-                    stripped.get(1..).unwrap_or("").to_string(),
-                ),
-                DiffLineType::Removed => (
-                    self.theme.diff_removed_bg,
-                    stripped.get(1..).unwrap_or("").to_string(),
-                ),
-                DiffLineType::Hunk => (self.theme.diff_hunk_bg, stripped.clone()),
-                DiffLineType::Header => (Color::Reset, stripped.clone()),
-                DiffLineType::Context => {
-                    if stripped.starts_with(' ') {
-                        (Color::Reset, stripped.get(1..).unwrap_or("").to_string())
-                    } else {
-                        (Color::Reset, stripped.clone())
-                    }
+    let mut i = 0;
+    while i < lines.len() {
+        if kind_at(i) != DiffLineKind::Removed {
+            i += 1;
+            continue;
+        }
+        let mut removed = Vec::new();
+        while i < lines.len()
+            && matches!(kind_at(i), DiffLineKind::Removed | DiffLineKind::NoNewline)
+        {
+            if kind_at(i) == DiffLineKind::Removed {
+                removed.push(i);
+            }
+            i += 1;
+        }
+        let mut added = Vec::new();
+        while i < lines.len() && matches!(kind_at(i), DiffLineKind::Added | DiffLineKind::NoNewline)
+        {
+            if kind_at(i) == DiffLineKind::Added {
+                added.push(i);
+            }
+            i += 1;
+        }
+        for (&old, &new) in removed.iter().zip(&added) {
+            if let Some((old_ranges, new_ranges)) =
+                word_diff::changed_ranges(&codes[old], &codes[new])
+            {
+                emphasis[old] = old_ranges;
+                emphasis[new] = new_ranges;
+            }
+        }
+    }
+    emphasis
+}
+
+/// Append highlighted pieces as spans, switching to `emph_bg` inside the
+/// changed byte ranges while keeping each piece's syntax foreground.
+fn push_emphasized(
+    spans: &mut Vec<(String, Color, Color)>,
+    pieces: Vec<(String, Color)>,
+    ranges: &[Range<usize>],
+    bg: Color,
+    emph_bg: Color,
+) {
+    let mut offset = 0;
+    for (text, fg) in pieces {
+        let end = offset + text.len();
+        let mut pos = offset;
+        while pos < end {
+            let (next, piece_bg) = match ranges.iter().find(|r| r.start <= pos && pos < r.end) {
+                Some(r) => (r.end.min(end), emph_bg),
+                None => {
+                    let next_start = ranges.iter().map(|r| r.start).filter(|&s| s > pos).min();
+                    (next_start.unwrap_or(end).min(end), bg)
                 }
             };
+            spans.push((text[pos - offset..next - offset].to_string(), fg, piece_bg));
+            pos = next;
+        }
+        offset = end;
+    }
+}
 
-            // For header/hunk lines, just use plain styling
-            if matches!(line_type, DiffLineType::Header | DiffLineType::Hunk) {
-                let fg = match line_type {
-                    DiffLineType::Hunk => Color::Cyan,
-                    _ => Color::DarkGray,
-                };
-                result.push(HighlightedLine {
-                    spans: vec![(stripped.clone(), fg, bg_color)],
-                });
-                continue;
+/// Stand-in for the diff of a generated, vendored or `viewed` file: just
+/// its stats.
+pub fn collapsed(file: &FileEntry, viewed: bool) -> HighlightedDiff {
+    let kind = match (viewed, file.attributes.generated) {
+        (true, _) => "Viewed",
+        (false, true) => "Generated",
+        (false, false) => "Vendored",
+    };
+    let summary = format!(
+        "{kind} file, +{}/-{}. Press Space to show the diff.",
+        file.additions, file.deletions
+    );
+    HighlightedDiff {
+        lines: vec![HighlightedLine::plain(
+            summary,
+            Color::DarkGray,
+            Color::Reset,
+        )],
+        split_rows: vec![SplitRow::Full(0)],
+        changes: Vec::new(),
+        full_file: false,
+    }
+}
+
+/// Indices into the lines produced by `Highlighter::highlight_diff` where a
+/// run of added or removed lines starts.
+pub fn change_starts(diff: &FileDiff) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut idx = diff.header.len();
+    for hunk in &diff.hunks {
+        idx += 1;
+        let mut in_change = false;
+        for line in &hunk.lines {
+            // A "no newline" marker belongs to the line before it.
+            if line.kind != DiffLineKind::NoNewline {
+                let change = matches!(line.kind, DiffLineKind::Added | DiffLineKind::Removed);
+                if change && !in_change {
+                    starts.push(idx);
+                }
+                in_change = change;
             }
+            idx += 1;
+        }
+    }
+    starts
+}
 
-            // For code lines, apply syntax highlighting (if theme exists).
-            let prefix = match line_type {
-                DiffLineType::Added => "+",
-                DiffLineType::Removed => "-",
-                DiffLineType::Context if stripped.starts_with(' ') => " ",
-                _ => "",
-            };
+/// Lay out the lines produced by `Highlighter::highlight_diff` side by side.
+/// Within each hunk, runs of removed lines are paired with the added lines that
+/// follow them; the shorter side is padded with filler rows.
+pub fn split_rows(diff: &FileDiff) -> Vec<SplitRow> {
+    fn flush(rows: &mut Vec<SplitRow>, removed: &mut Vec<usize>, added: &mut Vec<usize>) {
+        for i in 0..removed.len().max(added.len()) {
+            rows.push(SplitRow::Pair(
+                removed.get(i).copied(),
+                added.get(i).copied(),
+            ));
+        }
+        removed.clear();
+        added.clear();
+    }
 
-            let mut spans = Vec::new();
+    let mut rows = Vec::new();
+    let mut idx = 0;
+    for _ in &diff.header {
+        rows.push(SplitRow::Full(idx));
+        idx += 1;
+    }
 
-            // Add the prefix with appropriate color from theme
-            let prefix_fg = match line_type {
-                DiffLineType::Added => self.theme.diff_added_fg,
-                DiffLineType::Removed => self.theme.diff_removed_fg,
-                _ => Color::DarkGray,
-            };
-            if !prefix.is_empty() {
-                spans.push((prefix.to_string(), prefix_fg, bg_color));
-            }
+    for hunk in &diff.hunks {
+        rows.push(SplitRow::Full(idx));
+        idx += 1;
 
-            // Highlight the code
-            if let Some(ref mut hl) = highlighter {
-                let code_with_newline = format!("{code_to_highlight}\n");
-                if let Ok(highlighted) = hl.highlight_line(&code_with_newline, &self.syntax_set) {
-                    for (style, text) in highlighted {
-                        let fg = syntect_to_ratatui_color(style);
-                        let clean_text = text.trim_end_matches('\n').to_string();
-                        if !clean_text.is_empty() {
-                            spans.push((clean_text, fg, bg_color));
-                        }
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        let mut last_kind = DiffLineKind::Context;
+        for line in &hunk.lines {
+            // A "no newline" marker stays on the side of the line it annotates.
+            let kind = match line.kind {
+                DiffLineKind::NoNewline => last_kind,
+                kind => kind,
+            };
+            match kind {
+                DiffLineKind::Removed => {
+                    if !added.is_empty() {
+                        flush(&mut rows, &mut removed, &mut added);
                     }
-                } else {
-                    spans.push((code_to_highlight.to_string(), Color::White, bg_color));
+                    removed.push(idx);
+                }
+                DiffLineKind::Added => added.push(idx),
+                _ => {
+                    flush(&mut rows, &mut removed, &mut added);
+                    rows.push(SplitRow::Pair(Some(idx), Some(idx)));
                 }
-            } else {
-                spans.push((code_to_highlight.to_string(), Color::White, bg_color));
             }
-
-            result.push(HighlightedLine { spans });
+            last_kind = kind;
+            idx += 1;
         }
-
-        result
+        flush(&mut rows, &mut removed, &mut added);
     }
+
+    rows
 }
 
 fn syntect_to_ratatui_color(style: SyntectStyle) -> Color {
     Color::Rgb(style.foreground.r, style.foreground.g, style.foreground.b)
 }
 
-fn classify_diff_line(line: &str) -> DiffLineType {
-    if line.starts_with("@@") {
-        DiffLineType::Hunk
-    } else if line.starts_with('+') && !line.starts_with("+++") {
-        DiffLineType::Added
-    } else if line.starts_with('-') && !line.starts_with("---") {
-        DiffLineType::Removed
-    } else if line.starts_with("diff ")
-        || line.starts_with("index ")
-        || line.starts_with("+++")
-        || line.starts_with("---")
-        || line.starts_with("new file mode")
-        || line.starts_with("deleted file mode")
-        || line.starts_with("old mode")
-        || line.starts_with("new mode")
-        || line.starts_with("similarity index")
-        || line.starts_with("dissimilarity index")
-        || line.starts_with("rename from")
-        || line.starts_with("rename to")
-        || line.starts_with("copy from")
-        || line.starts_with("copy to")
-        || line.starts_with("Binary files ")
-        || line.starts_with("\\ No newline at end of file")
-    {
-        DiffLineType::Header
-    } else {
-        DiffLineType::Context
-    }
-}
-
 fn strip_ansi(s: &str) -> String {
     let mut result = String::new();
     let mut in_escape = false;
@@ -196,3 +520,30 @@ fn strip_ansi(s: &str) -> String {
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::split_rows;
+    use crate::git::parse_diff;
+    use crate::model::SplitRow;
+
+    #[test]
+    fn split_rows_pairs_changes_and_pads_the_short_side() {
+        let diff = parse_diff(
+            "--- a/f\n+++ b/f\n@@ -1,5 +1,3 @@\n ctx\n-old1\n-old2\n+new1\n ctx\n-gone\n",
+        );
+        assert_eq!(
+            split_rows(&diff),
+            [
+                SplitRow::Full(0),
+                SplitRow::Full(1),
+                SplitRow::Full(2),
+                SplitRow::Pair(Some(3), Some(3)),
+                SplitRow::Pair(Some(4), Some(6)),
+                SplitRow::Pair(Some(5), None),
+                SplitRow::Pair(Some(7), Some(7)),
+                SplitRow::Pair(Some(8), None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use crate::binary;
+use crate::git;
+use crate::line_diff;
+use crate::model::{DiffOptions, FileDiff};
+use std::path::{Path, PathBuf};
+
+/// Pointer files are small; git-lfs never writes one larger than this.
+pub const MAX_POINTER_SIZE: usize = 1024;
+
+const POINTER_VERSION: &str = "version https://git-lfs.github.com/spec/v1";
+
+/// A Git LFS pointer: the small text file committed in place of a large one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pointer {
+    /// SHA-256 of the object, in hex
+    pub oid: String,
+    pub size: usize,
+}
+
+pub fn parse_pointer(bytes: &[u8]) -> Option<Pointer> {
+    if bytes.len() > MAX_POINTER_SIZE {
+        return None;
+    }
+    let text = std::str::from_utf8(bytes).ok()?;
+    let mut lines = text.lines();
+    if lines.next()? != POINTER_VERSION {
+        return None;
+    }
+    let (mut oid, mut size) = (None, None);
+    for line in lines {
+        if let Some(hex) = line.strip_prefix("oid sha256:") {
+            oid = Some(hex.to_string())
+                .filter(|h| h.len() == 64 && h.bytes().all(|b| b.is_ascii_hexdigit()));
+        } else if let Some(n) = line.strip_prefix("size ") {
+            size = n.parse().ok();
+        }
+    }
+    Some(Pointer {
+        oid: oid?,
+        size: size?,
+    })
+}
+
+/// The local object store of the repository whose common git dir is `git_dir`.
+pub fn store(git_dir: &Path) -> PathBuf {
+    git_dir.join("lfs").join("objects")
+}
+
+/// The object behind `pointer`, if it was fetched into `store`.
+fn read_object(store: &Path, pointer: &Pointer) -> Option<Vec<u8>> {
+    let path = store
+        .join(&pointer.oid[..2])
+        .join(&pointer.oid[2..4])
+        .join(&pointer.oid);
+    std::fs::read(path)
+        .ok()
+        .filter(|bytes| bytes.len() == pointer.size)
+}
+
+/// `bytes`, or the object it points at when it is an LFS pointer whose
+/// object is in `store`.
+pub fn smudge(bytes: Option<Vec<u8>>, store: &Path) -> Option<Vec<u8>> {
+    let pointer = bytes.as_deref().and_then(parse_pointer);
+    pointer.and_then(|p| read_object(store, &p)).or(bytes)
+}
+
+/// Whether a text diff rewrites the `oid` line of an LFS pointer, which is
+/// all `git diff` shows of a changed LFS file.
+pub fn changes_pointer(diff: &FileDiff) -> bool {
+    diff.hunks
+        .iter()
+        .flat_map(|h| &h.lines)
+        .any(|l| l.content.starts_with("oid sha256:"))
+}
+
+/// If either side is an LFS pointer, replace the pointer diff with a
+/// description of the objects: size and oid of each side. When every
+/// pointed-at object is in `store`, their contents are diffed instead, as
+/// text or as binary. `new_in_worktree` says the new side was read from
+/// the working tree, where an LFS file is checked out as its contents.
+/// Returns `false` when neither side is a pointer.
+pub fn attach(
+    diff: &mut FileDiff,
+    old: Option<&[u8]>,
+    new: Option<&[u8]>,
+    new_in_worktree: bool,
+    store: &Path,
+    options: &DiffOptions,
+) -> bool {
+    let old_pointer = old.and_then(parse_pointer);
+    let new_pointer = new.and_then(parse_pointer);
+    if old_pointer.is_none() && new_pointer.is_none() {
+        return false;
+    }
+
+    let change = match (old, new) {
+        (None, Some(_)) => "added",
+        (Some(_), None) => "deleted",
+        _ => "changed",
+    };
+    diff.header.push(format!("LFS object {change}"));
+    let describe = |bytes: &[u8], pointer: &Option<Pointer>, note: &str| match pointer {
+        Some(p) => (
+            p.size,
+            format!("{}, sha256:{}", binary::format_size(p.size), &p.oid[..7]),
+        ),
+        None => (
+            bytes.len(),
+            format!("{}, {note}", binary::format_size(bytes.len())),
+        ),
+    };
+    let old_size = old.map(|bytes| {
+        let (size, text) = describe(bytes, &old_pointer, "not stored in LFS");
+        diff.header.push(format!("  old: {text}"));
+        size
+    });
+    if let Some(bytes) = new {
+        let note = if new_in_worktree {
+            "checked out in the working tree"
+        } else {
+            "not stored in LFS"
+        };
+        let (size, mut text) = describe(bytes, &new_pointer, note);
+        if let Some(old_size) = old_size {
+            text.push_str(&format!(" ({})", binary::format_delta(old_size, size)));
+        }
+        diff.header.push(format!("  new: {text}"));
+    }
+
+    // A side that isn't a pointer is already the content.
+    let content = |bytes: Option<&[u8]>, pointer: &Option<Pointer>| match (bytes, pointer) {
+        (Some(_), Some(p)) => read_object(store, p).map(Some),
+        (bytes, _) => Some(bytes.map(<[u8]>::to_vec)),
+    };
+    diff.hunks.clear();
+    diff.binary = true;
+    let (Some(old), Some(new)) = (content(old, &old_pointer), content(new, &new_pointer)) else {
+        diff.header.push(
+            "  contents are not in the local LFS store; `git lfs fetch` downloads them".to_string(),
+        );
+        return true;
+    };
+    let (old_bytes, new_bytes) = (
+        old.as_deref().unwrap_or_default(),
+        new.as_deref().unwrap_or_default(),
+    );
+    if git::is_binary(old_bytes) || git::is_binary(new_bytes) {
+        binary::attach(diff, old.as_deref(), new.as_deref(), options);
+    } else {
+        diff.binary = false;
+        diff.hunks = line_diff::diff_hunks(old_bytes, new_bytes, options);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{attach, parse_pointer, store};
+    use crate::model::{DiffOptions, FileDiff};
+
+    #[test]
+    fn pointers_are_described_and_resolved_from_the_store() {
+        let old = format!(
+            "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 4\n",
+            "a".repeat(64)
+        );
+        let new = format!(
+            "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 6\n",
+            "b".repeat(64)
+        );
+        assert_eq!(parse_pointer(old.as_bytes()).unwrap().size, 4);
+        assert!(parse_pointer(b"version https://git-lfs.github.com/spec/v1\nsize 4\n").is_none());
+
+        let dir = std::env::temp_dir().join(format!("prdiff-lfs-{}", std::process::id()));
+        let store = store(&dir);
+        let mut diff = FileDiff::default();
+        assert!(attach(
+            &mut diff,
+            Some(old.as_bytes()),
+            Some(new.as_bytes()),
+            false,
+            &store,
+            &DiffOptions::default()
+        ));
+        assert_eq!(
+            diff.header,
+            [
+                "LFS object changed",
+                "  old: 4 bytes, sha256:aaaaaaa",
+                "  new: 6 bytes, sha256:bbbbbbb (+2 bytes)",
+                "  contents are not in the local LFS store; `git lfs fetch` downloads them",
+            ]
+        );
+
+        // With both objects fetched, their text is diffed.
+        for (oid, content) in [("a", "one\n"), ("b", "two\n2\n")] {
+            let oid = oid.repeat(64);
+            let path = store.join(&oid[..2]).join(&oid[2..4]);
+            std::fs::create_dir_all(&path).unwrap();
+            std::fs::write(path.join(&oid), content).unwrap();
+        }
+        let mut diff = FileDiff::default();
+        attach(
+            &mut diff,
+            Some(old.as_bytes()),
+            Some(new.as_bytes()),
+            false,
+            &store,
+            &DiffOptions::default(),
+        );
+        assert!(!diff.binary);
+        assert_eq!(diff.hunks[0].header(), "@@ -1,1 +1,2 @@");
+
+        // A file checked out in the working tree is its object's contents.
+        let mut diff = FileDiff::default();
+        attach(
+            &mut diff,
+            Some(old.as_bytes()),
+            Some(b"one\n1\n"),
+            true,
+            &store,
+            &DiffOptions::default(),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            diff.header[2],
+            "  new: 6 bytes, checked out in the working tree (+2 bytes)"
+        );
+        assert_eq!(diff.hunks[0].header(), "@@ -1,1 +1,2 @@");
+        assert!(!attach(
+            &mut diff,
+            Some(b"plain"),
+            None,
+            false,
+            &store,
+            &DiffOptions::default()
+        ));
+    }
+}
@@ -1,13 +1,27 @@
 mod app;
 mod args;
+mod backend;
+mod binary;
+mod comments;
+mod conflict;
+mod expand;
+mod full_file;
 mod git;
+mod gix_backend;
 mod highlight;
+mod lfs;
+mod line_diff;
 mod logging;
 mod model;
+mod moves;
+mod patch;
+mod submodule;
 mod theme;
 mod tree;
 mod ui;
+mod viewed;
 mod watcher;
+mod word_diff;
 
 use anyhow::Result;
 
@@ -23,7 +37,16 @@ pub fn run() -> Result<()> {
 
     let args = args::parse_args()?;
     let theme = theme::Theme::from_config(args.theme);
-    let mut app = app::App::new(args.base_branch, theme)?;
+    let backend = backend::open(backend::BackendKind::from_config(args.backend))?;
+    let diff_options = model::DiffOptions::from_config(
+        args.rename_threshold,
+        args.recurse_submodules,
+        args.diff_algorithm,
+        args.context_lines,
+    );
+    let interactive = args.interactive_from_config();
+    let mut app = app::App::new(backend, args.base_branch, args.range, theme, diff_options)?;
+    app.interactive = interactive;
 
     let mut guard = ui::TerminalGuard::new()?;
     let mut terminal = ui::new_terminal()?;
@@ -0,0 +1,279 @@
+use crate::model::{DiffAlgorithm, DiffLine, DiffLineKind, DiffOptions, Hunk, Whitespace};
+use gix::diff::blob::intern::InternedInput;
+use gix::diff::blob::sources::byte_lines_with_terminator;
+use gix::diff::blob::Algorithm;
+use std::borrow::Cow;
+use std::ops::Range;
+
+/// A changed block: the old lines it removes and the new lines it adds.
+type Change = (Range<u32>, Range<u32>);
+
+/// Split a text into lines, keeping terminators so a missing final newline
+/// shows up as a change.
+pub fn split_lines(text: &[u8]) -> Vec<&[u8]> {
+    byte_lines_with_terminator(text).collect()
+}
+
+/// The part of `line` that takes part in comparisons under `whitespace`.
+fn normalize_line(line: &[u8], whitespace: Whitespace) -> Cow<'_, [u8]> {
+    if whitespace.ignore_all {
+        return Cow::Owned(
+            line.iter()
+                .copied()
+                .filter(|b| !b.is_ascii_whitespace())
+                .collect(),
+        );
+    }
+    if whitespace.ignore_amount {
+        let mut out = Vec::with_capacity(line.len());
+        let mut in_space = false;
+        for &b in line.trim_ascii_end() {
+            if b.is_ascii_whitespace() {
+                in_space = true;
+                continue;
+            }
+            if std::mem::take(&mut in_space) {
+                out.push(b' ');
+            }
+            out.push(b);
+        }
+        return Cow::Owned(out);
+    }
+    if whitespace.ignore_cr_at_eol {
+        // git compares these without the line terminator altogether.
+        let body = line.strip_suffix(b"\n").unwrap_or(line);
+        return Cow::Borrowed(body.strip_suffix(b"\r").unwrap_or(body));
+    }
+    Cow::Borrowed(line)
+}
+
+/// gitoxide's closest match for `algorithm`. It has no patience diff, so
+/// that uses histogram, git's faster extension of it.
+fn algorithm(algorithm: DiffAlgorithm) -> Algorithm {
+    match algorithm {
+        DiffAlgorithm::Myers => Algorithm::Myers,
+        DiffAlgorithm::Minimal => Algorithm::MyersMinimal,
+        DiffAlgorithm::Patience | DiffAlgorithm::Histogram => Algorithm::Histogram,
+    }
+}
+
+/// Changed blocks between two texts, with the whitespace differences
+/// `options` asks to ignore left out.
+pub fn line_changes(old: &[&[u8]], new: &[&[u8]], options: &DiffOptions) -> Vec<Change> {
+    let whitespace = options.whitespace;
+    let mut input = InternedInput::default();
+    input.update_before(old.iter().map(|line| normalize_line(line, whitespace)));
+    input.update_after(new.iter().map(|line| normalize_line(line, whitespace)));
+    let mut changes = Vec::new();
+    gix::diff::blob::diff(
+        algorithm(options.algorithm),
+        &input,
+        |before: Range<u32>, after: Range<u32>| changes.push((before, after)),
+    );
+    if whitespace.ignore_blank_lines {
+        changes = drop_blank_changes(changes, old, new, options);
+    }
+    changes
+}
+
+/// Whether a change only adds or removes blank lines. Like git, lines of
+/// spaces only count as blank when other whitespace is ignored too.
+fn is_blank_change(
+    (before, after): &Change,
+    old: &[&[u8]],
+    new: &[&[u8]],
+    whitespace: Whitespace,
+) -> bool {
+    let spaces_are_blank =
+        whitespace.ignore_all || whitespace.ignore_amount || whitespace.ignore_cr_at_eol;
+    let removed = &old[before.start as usize..before.end as usize];
+    let added = &new[after.start as usize..after.end as usize];
+    removed.iter().chain(added).all(|line| {
+        if spaces_are_blank {
+            line.iter().all(u8::is_ascii_whitespace)
+        } else {
+            *line == b"\n"
+        }
+    })
+}
+
+/// Split changes into the groups that end up in one hunk with `context`
+/// lines around each change.
+fn group_changes(changes: &[Change], context: u32) -> Vec<&[Change]> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    for i in 1..=changes.len() {
+        if i == changes.len() || changes[i].0.start - changes[i - 1].0.end > 2 * context {
+            groups.push(&changes[start..i]);
+            start = i;
+        }
+    }
+    groups
+}
+
+/// Like `git diff --ignore-blank-lines`: drop blank-line changes unless they
+/// share a hunk with a real change and sit within its context.
+fn drop_blank_changes(
+    changes: Vec<Change>,
+    old: &[&[u8]],
+    new: &[&[u8]],
+    options: &DiffOptions,
+) -> Vec<Change> {
+    let (whitespace, context) = (options.whitespace, options.context_lines);
+    let mut kept = Vec::new();
+    for group in group_changes(&changes, context) {
+        let real: Vec<usize> = (0..group.len())
+            .filter(|&i| !is_blank_change(&group[i], old, new, whitespace))
+            .collect();
+        let (Some(&first), Some(&last)) = (real.first(), real.last()) else {
+            continue;
+        };
+        let leading = group[..first]
+            .iter()
+            .position(|c| group[first].0.start - c.0.end < context)
+            .unwrap_or(first);
+        let trailing = group[last + 1..]
+            .iter()
+            .rposition(|c| c.0.start - group[last].0.end < context)
+            .map_or(last, |i| last + 1 + i);
+        kept.extend(group[leading..=trailing].iter().cloned());
+    }
+    kept
+}
+
+/// Hunks around `changes`, built like git's. Context lines are taken from
+/// the new side, as they may differ from the old one in ignored whitespace.
+fn build_hunks(old: &[&[u8]], new: &[&[u8]], changes: &[Change], context_lines: u32) -> Vec<Hunk> {
+    let line = |kind, bytes: &[u8], old_lineno, new_lineno| {
+        let text = String::from_utf8_lossy(bytes);
+        let content = text.strip_suffix('\n').unwrap_or(&text);
+        let content = content.strip_suffix('\r').unwrap_or(content);
+        let mut lines = vec![DiffLine {
+            kind,
+            content: content.to_string(),
+            old_lineno,
+            new_lineno,
+        }];
+        if !bytes.ends_with(b"\n") {
+            lines.push(DiffLine {
+                kind: DiffLineKind::NoNewline,
+                content: " No newline at end of file".to_string(),
+                old_lineno: None,
+                new_lineno: None,
+            });
+        }
+        lines
+    };
+    let (old_len, new_len) = (old.len() as u32, new.len() as u32);
+    let mut hunks = Vec::new();
+    for group in group_changes(changes, context_lines) {
+        let (first, last) = (&group[0], &group[group.len() - 1]);
+        let before = context_lines.min(first.0.start).min(first.1.start);
+        let after = context_lines
+            .min(old_len - last.0.end)
+            .min(new_len - last.1.end);
+        let (old_begin, new_begin) = (first.0.start - before, first.1.start - before);
+        let mut lines = Vec::new();
+        let (mut o, mut n) = (old_begin, new_begin);
+        let context = |o: &mut u32, n: &mut u32, lines: &mut Vec<DiffLine>| {
+            lines.extend(line(
+                DiffLineKind::Context,
+                new[*n as usize],
+                Some(*o + 1),
+                Some(*n + 1),
+            ));
+            *o += 1;
+            *n += 1;
+        };
+        for (removed, added) in group {
+            while o < removed.start {
+                context(&mut o, &mut n, &mut lines);
+            }
+            for i in removed.clone() {
+                lines.extend(line(
+                    DiffLineKind::Removed,
+                    old[i as usize],
+                    Some(i + 1),
+                    None,
+                ));
+            }
+            for i in added.clone() {
+                lines.extend(line(
+                    DiffLineKind::Added,
+                    new[i as usize],
+                    None,
+                    Some(i + 1),
+                ));
+            }
+            (o, n) = (removed.end, added.end);
+        }
+        for _ in 0..after {
+            context(&mut o, &mut n, &mut lines);
+        }
+        // git reports an empty range as starting at the line before it.
+        let start = |begin: u32, count: u32| if count == 0 { begin } else { begin + 1 };
+        hunks.push(Hunk {
+            old_start: start(old_begin, o - old_begin),
+            old_count: o - old_begin,
+            new_start: start(new_begin, n - new_begin),
+            new_count: n - new_begin,
+            section: String::new(),
+            lines,
+        });
+    }
+    hunks
+}
+
+/// Line diff of two texts as hunks with `options.context_lines` of context.
+pub fn diff_hunks(old: &[u8], new: &[u8], options: &DiffOptions) -> Vec<Hunk> {
+    let (old, new) = (split_lines(old), split_lines(new));
+    build_hunks(
+        &old,
+        &new,
+        &line_changes(&old, &new, options),
+        options.context_lines,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::diff_hunks;
+    use crate::model::{DiffLineKind, DiffOptions, Whitespace};
+
+    fn ignoring(whitespace: Whitespace) -> DiffOptions {
+        DiffOptions {
+            whitespace,
+            ..DiffOptions::default()
+        }
+    }
+
+    #[test]
+    fn whitespace_modes_hide_ignored_changes_from_hunks() {
+        let old = b"fn main() {\n  call(a,  b);\n  x(a,b);\n}\n";
+        let new = b"fn main() {\n    call(a, b);  \n  x(a, b);\n}\n";
+        let all = ignoring(Whitespace {
+            ignore_all: true,
+            ..Whitespace::default()
+        });
+        assert!(diff_hunks(old, new, &all).is_empty());
+
+        // A blank line far from the real change is dropped, one next to it stays.
+        let old = b"a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let new = b"a\n\nb\nc\nd\ne\nf\ng\nh\ni\nJ\n";
+        let blank = ignoring(Whitespace {
+            ignore_blank_lines: true,
+            ..Whitespace::default()
+        });
+        let hunks = diff_hunks(old, new, &blank);
+        assert_eq!(hunks.len(), 1);
+        assert_eq!((hunks[0].old_start, hunks[0].old_count), (7, 4));
+        assert_eq!((hunks[0].new_start, hunks[0].new_count), (8, 4));
+        let near = b"a\nb\nc\nd\ne\nf\ng\n\nh\ni\nJ\n";
+        let hunk = &diff_hunks(old, near, &blank)[0];
+        let added = hunk
+            .lines
+            .iter()
+            .filter(|l| matches!(l.kind, DiffLineKind::Added));
+        assert_eq!(added.count(), 2);
+    }
+}
@@ -1,4 +1,5 @@
 use ratatui::prelude::Color;
+use std::env;
 
 #[derive(Clone, Debug)]
 pub struct FileEntry {
@@ -6,6 +7,165 @@ pub struct FileEntry {
     pub status: FileStatus,
     pub additions: i32,
     pub deletions: i32,
+    /// Source path of a rename or copy
+    pub old_path: Option<String>,
+    /// Similarity to `old_path` in percent, as reported by rename detection
+    pub similarity: Option<u8>,
+    /// Git file modes (`0o100644`, `0o120000`, ...) of each side; `None`
+    /// where the side doesn't exist or the mode isn't known
+    pub old_mode: Option<u32>,
+    pub new_mode: Option<u32>,
+    /// Set for files listed from inside a changed submodule
+    pub submodule: Option<Box<SubmoduleScope>>,
+    /// Every change in the file is whitespace the current mode ignores, so
+    /// its diff is empty
+    pub whitespace_only: bool,
+    /// What `.gitattributes` says about the path
+    pub attributes: Attributes,
+    /// Either side is a Git LFS pointer
+    pub lfs: bool,
+}
+
+/// Where a file inside an expanded submodule comes from. The entry's own
+/// path is prefixed with the submodule path; `file` is the entry as the
+/// submodule's backend listed it.
+#[derive(Clone, Debug)]
+pub struct SubmoduleScope {
+    pub path: String,
+    /// Range to diff within the submodule
+    pub range: DiffRange,
+    pub file: FileEntry,
+}
+
+/// The `.gitattributes` that change how prdiff shows a file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Attributes {
+    /// `linguist-generated`: written by a tool, not worth reviewing line by line
+    pub generated: bool,
+    /// `linguist-vendored`: third-party code checked into the repository
+    pub vendored: bool,
+    /// `-diff` or `binary`: never shown as text
+    pub no_diff: bool,
+}
+
+impl Attributes {
+    /// The attributes to ask git for.
+    pub const NAMES: [&'static str; 4] =
+        ["linguist-generated", "linguist-vendored", "diff", "binary"];
+
+    /// Record one attribute as `git check-attr` prints it: `set`, `unset`,
+    /// `unspecified` or a value.
+    pub fn assign(&mut self, name: &str, value: &str) {
+        let set = matches!(value, "set" | "true");
+        match name {
+            "linguist-generated" => self.generated = set,
+            "linguist-vendored" => self.vendored = set,
+            "diff" => self.no_diff |= value == "unset",
+            "binary" => self.no_diff |= set,
+            _ => {}
+        }
+    }
+
+    /// Tree badge for files that are collapsed and kept out of the totals.
+    pub fn badge(self) -> Option<&'static str> {
+        if self.generated {
+            Some("generated")
+        } else if self.vendored {
+            Some("vendored")
+        } else {
+            None
+        }
+    }
+}
+
+pub const MODE_FILE: u32 = 0o100644;
+pub const MODE_EXECUTABLE: u32 = 0o100755;
+pub const MODE_SYMLINK: u32 = 0o120000;
+pub const MODE_GITLINK: u32 = 0o160000;
+
+impl FileEntry {
+    pub fn new(
+        path: impl Into<String>,
+        status: FileStatus,
+        additions: i32,
+        deletions: i32,
+    ) -> Self {
+        Self {
+            path: path.into(),
+            status,
+            additions,
+            deletions,
+            old_path: None,
+            similarity: None,
+            old_mode: None,
+            new_mode: None,
+            submodule: None,
+            whitespace_only: false,
+            attributes: Attributes::default(),
+            lfs: false,
+        }
+    }
+
+    /// Generated or vendored, so collapsed and left out of the totals.
+    pub fn is_generated(&self) -> bool {
+        self.attributes.badge().is_some()
+    }
+
+    /// Apply the file's attributes; a `-diff` file has no line stats, like
+    /// a binary one.
+    pub fn set_attributes(&mut self, attributes: Attributes) {
+        self.attributes = attributes;
+        if attributes.no_diff {
+            self.clear_stats();
+        }
+    }
+
+    /// Mark a file with an LFS pointer on either side, whose line stats
+    /// would count pointer lines.
+    pub fn mark_lfs(&mut self) {
+        self.lfs = true;
+        self.clear_stats();
+    }
+
+    fn clear_stats(&mut self) {
+        self.additions = 0;
+        self.deletions = 0;
+        self.whitespace_only = false;
+    }
+
+    /// Whether either side is a submodule commit rather than a file.
+    pub fn is_submodule(&self) -> bool {
+        self.old_mode == Some(MODE_GITLINK) || self.new_mode == Some(MODE_GITLINK)
+    }
+
+    /// Short tree badge for changes the status symbol doesn't spell out:
+    /// `+x`/`-x` for the executable bit, symlinks and submodules.
+    pub fn mode_badge(&self) -> Option<String> {
+        let kind = |mode: u32| match mode {
+            MODE_SYMLINK => "symlink",
+            MODE_GITLINK => "submodule",
+            _ => "file",
+        };
+        match (self.old_mode, self.new_mode) {
+            _ if self.is_submodule() => Some("submodule".to_string()),
+            (Some(old), Some(new)) if kind(old) != kind(new) => {
+                Some(format!("{}→{}", kind(old), kind(new)))
+            }
+            (Some(old), Some(new)) if old != new => {
+                Some(if new == MODE_EXECUTABLE { "+x" } else { "-x" }.to_string())
+            }
+            (None, Some(MODE_SYMLINK)) | (Some(MODE_SYMLINK), None) => Some("symlink".to_string()),
+            _ => None,
+        }
+    }
+
+    /// `old → new` for renames and copies, otherwise just the path.
+    pub fn display_path(&self) -> String {
+        match &self.old_path {
+            Some(old) => format!("{old} → {}", self.path),
+            None => self.path.clone(),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -14,6 +174,13 @@ pub enum FileStatus {
     Modified,
     Deleted,
     Renamed,
+    Copied,
+    /// Only the file mode changed, e.g. the executable bit
+    ModeChanged,
+    /// A regular file became a symlink or the other way round
+    TypeChanged,
+    /// A submodule now points at a different commit
+    Submodule,
     Unknown,
 }
 
@@ -24,6 +191,10 @@ impl FileStatus {
             Self::Modified => "~",
             Self::Deleted => "-",
             Self::Renamed => "→",
+            Self::Copied => "⇉",
+            Self::ModeChanged => "*",
+            Self::TypeChanged => "⇄",
+            Self::Submodule => "@",
             Self::Unknown => "?",
         }
     }
@@ -34,6 +205,10 @@ impl FileStatus {
             Self::Modified => Color::Yellow,
             Self::Deleted => Color::Red,
             Self::Renamed => Color::Cyan,
+            Self::Copied => Color::Magenta,
+            Self::ModeChanged => Color::LightYellow,
+            Self::TypeChanged => Color::LightMagenta,
+            Self::Submodule => Color::LightBlue,
             Self::Unknown => Color::Gray,
         }
     }
@@ -57,10 +232,130 @@ impl TreeNode {
     }
 }
 
+/// Parsed unified diff for a single file, produced once by `git::parse_diff`.
+#[derive(Clone, Debug, Default)]
+pub struct FileDiff {
+    /// Extended header lines before the first hunk (`diff --git`, `index`, `---`/`+++`, ...)
+    pub header: Vec<String>,
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    pub rename_from: Option<String>,
+    pub rename_to: Option<String>,
+    pub old_mode: Option<String>,
+    pub new_mode: Option<String>,
+    pub binary: bool,
+    /// Sizes and formats of both sides when `binary` is set
+    pub binary_info: Option<BinaryInfo>,
+    pub hunks: Vec<Hunk>,
+}
+
+/// What prdiff could tell about the two sides of a binary change.
+#[derive(Clone, Debug, Default)]
+pub struct BinaryInfo {
+    pub old: Option<BinarySide>,
+    pub new: Option<BinarySide>,
+}
+
+#[derive(Clone, Debug)]
+pub struct BinarySide {
+    pub size: usize,
+    pub format: &'static str,
+    pub mime: &'static str,
+    /// Width and height for recognized image formats
+    pub dimensions: Option<(u32, u32)>,
+    /// First `DiffOptions::hex_dump_kb` KB, for the hex dump view
+    pub head: Vec<u8>,
+}
+
+/// Old and new contents of a file; `None` for a side where it doesn't exist.
+pub type FileSides = (Option<Vec<u8>>, Option<Vec<u8>>);
+
+#[derive(Clone, Debug)]
+pub struct Hunk {
+    pub old_start: u32,
+    pub old_count: u32,
+    pub new_start: u32,
+    pub new_count: u32,
+    /// Text after the closing `@@`, usually the enclosing function signature.
+    pub section: String,
+    pub lines: Vec<DiffLine>,
+}
+
+impl Hunk {
+    pub fn header(&self) -> String {
+        let mut header = format!(
+            "@@ -{},{} +{},{} @@",
+            self.old_start, self.old_count, self.new_start, self.new_count
+        );
+        if !self.section.is_empty() {
+            header.push(' ');
+            header.push_str(&self.section);
+        }
+        header
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DiffLine {
+    pub kind: DiffLineKind,
+    /// Line content without the leading `+`/`-`/space marker.
+    pub content: String,
+    pub old_lineno: Option<u32>,
+    pub new_lineno: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffLineKind {
+    Context,
+    Added,
+    Removed,
+    /// `\ No newline at end of file` marker for the preceding line.
+    NoNewline,
+}
+
 /// Pre-rendered diff line with syntax highlighting.
 #[derive(Clone)]
 pub struct HighlightedLine {
     pub spans: Vec<(String, Color, Color)>, // (text, fg, bg)
+    /// Line numbers for the gutter; `None` for headers, hunk headers and the missing side.
+    pub old_lineno: Option<u32>,
+    pub new_lineno: Option<u32>,
+}
+
+impl HighlightedLine {
+    /// A line with no gutter numbers (file header, hunk header, markers).
+    pub fn plain(text: String, fg: Color, bg: Color) -> Self {
+        Self {
+            spans: vec![(text, fg, bg)],
+            old_lineno: None,
+            new_lineno: None,
+        }
+    }
+}
+
+/// Highlighted unified lines plus the side-by-side layout built from them.
+pub struct HighlightedDiff {
+    pub lines: Vec<HighlightedLine>,
+    pub split_rows: Vec<SplitRow>,
+    /// Indices into `lines` where a block of changes starts
+    pub changes: Vec<usize>,
+    /// `lines` is the whole file rather than its diff; there are no split rows
+    pub full_file: bool,
+}
+
+/// One row of the side-by-side view, as indices into `HighlightedDiff::lines`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplitRow {
+    /// File header or hunk header spanning both halves
+    Full(usize),
+    /// Old line on the left, new line on the right; `None` renders a filler
+    Pair(Option<usize>, Option<usize>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffViewMode {
+    Unified,
+    Split,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -68,4 +363,372 @@ pub enum DiffSource {
     Worktree,
     Index,
     Untracked,
+    /// Diff between two commits of a fixed range
+    Commit,
+}
+
+/// A commit in the reviewed range, as shown in the commit panel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitInfo {
+    pub id: String,
+    pub subject: String,
+    pub author: String,
+    /// Committer time, seconds since the Unix epoch
+    pub time: i64,
+}
+
+/// The two sides being compared.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffRange {
+    pub from: String,
+    /// New side when it is a commit; `None` means uncommitted state, as
+    /// selected by `work`
+    pub to: Option<String>,
+    pub work: WorkState,
+    /// Include untracked files when the new side is the working tree
+    pub untracked: bool,
+}
+
+/// Which uncommitted state the new side of a `DiffRange` is when `to` is `None`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkState {
+    /// The working tree, falling back to staged-only changes
+    Worktree,
+    /// The index: what is staged relative to `from`
+    Index,
+    /// The working tree relative to the index; `from` is not used
+    Unstaged,
+}
+
+impl DiffRange {
+    pub fn worktree(from: impl Into<String>) -> Self {
+        Self {
+            from: from.into(),
+            to: None,
+            work: WorkState::Worktree,
+            untracked: true,
+        }
+    }
+
+    pub fn commits(from: impl Into<String>, to: impl Into<String>) -> Self {
+        Self {
+            from: from.into(),
+            to: Some(to.into()),
+            work: WorkState::Worktree,
+            untracked: false,
+        }
+    }
+}
+
+/// Settings that change how diffs are computed, shared by the app and the
+/// watcher so both see the same file list.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffOptions {
+    /// Minimum similarity in percent for a deleted/added pair to be reported
+    /// as a rename or copy; 0 turns rename detection off
+    pub rename_threshold: u8,
+    /// How much of each side of a binary file the hex dump covers, in KB
+    pub hex_dump_kb: usize,
+    /// List the files changed inside submodules instead of their pointer change
+    pub recurse_submodules: bool,
+    /// Whitespace differences to leave out of stats and diffs
+    pub whitespace: Whitespace,
+    pub algorithm: DiffAlgorithm,
+    /// Unchanged lines shown around each change, like `git diff -U<n>`
+    pub context_lines: u32,
+}
+
+/// Line diff algorithm, as in `git diff --diff-algorithm`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DiffAlgorithm {
+    #[default]
+    Myers,
+    /// Myers, spending extra time to find the smallest diff
+    Minimal,
+    Patience,
+    Histogram,
+}
+
+impl DiffAlgorithm {
+    /// Parse an algorithm name (case-insensitive)
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "myers" | "default" => Some(Self::Myers),
+            "minimal" => Some(Self::Minimal),
+            "patience" => Some(Self::Patience),
+            "histogram" => Some(Self::Histogram),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Myers => "myers",
+            Self::Minimal => "minimal",
+            Self::Patience => "patience",
+            Self::Histogram => "histogram",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            Self::Myers => Self::Minimal,
+            Self::Minimal => Self::Patience,
+            Self::Patience => Self::Histogram,
+            Self::Histogram => Self::Myers,
+        }
+    }
+
+    /// The matching `git diff` flag.
+    pub fn git_arg(self) -> String {
+        format!("--diff-algorithm={}", self.name())
+    }
+}
+
+/// Whitespace differences that stats and diffs ignore, like `git diff -w`,
+/// `-b`, `--ignore-blank-lines` and `--ignore-cr-at-eol`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Whitespace {
+    pub ignore_all: bool,
+    pub ignore_amount: bool,
+    pub ignore_blank_lines: bool,
+    pub ignore_cr_at_eol: bool,
+}
+
+impl Whitespace {
+    /// The matching `git diff` flags.
+    pub fn git_args(self) -> Vec<&'static str> {
+        let flags = [
+            (self.ignore_all, "--ignore-all-space"),
+            (self.ignore_amount, "--ignore-space-change"),
+            (self.ignore_blank_lines, "--ignore-blank-lines"),
+            (self.ignore_cr_at_eol, "--ignore-cr-at-eol"),
+        ];
+        flags
+            .iter()
+            .filter(|(on, _)| *on)
+            .map(|(_, flag)| *flag)
+            .collect()
+    }
+
+    /// Short description for the tree title, e.g. `ignoring all whitespace, blank lines`.
+    pub fn label(self) -> Option<String> {
+        let names = [
+            (self.ignore_all, "all whitespace"),
+            (self.ignore_amount, "whitespace amount"),
+            (self.ignore_blank_lines, "blank lines"),
+            (self.ignore_cr_at_eol, "CR at EOL"),
+        ];
+        let active: Vec<&str> = names
+            .iter()
+            .filter(|(on, _)| *on)
+            .map(|(_, name)| *name)
+            .collect();
+        (!active.is_empty()).then(|| format!("ignoring {}", active.join(", ")))
+    }
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            rename_threshold: 50,
+            hex_dump_kb: 4,
+            recurse_submodules: false,
+            whitespace: Whitespace::default(),
+            algorithm: DiffAlgorithm::Myers,
+            context_lines: 3,
+        }
+    }
+}
+
+impl DiffOptions {
+    /// Parse a rename threshold like `50` or `50%`
+    pub fn parse_threshold(s: &str) -> Option<u8> {
+        let value: u8 = s.trim().trim_end_matches('%').parse().ok()?;
+        (value <= 100).then_some(value)
+    }
+
+    /// Parse an on/off setting like `1`, `true`, `yes` or `0`, `false`, `no`
+    pub fn parse_switch(s: &str) -> Option<bool> {
+        match s.trim().to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Some(true),
+            "0" | "false" | "no" | "off" => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Build options from environment variables and/or CLI arguments
+    /// Priority: PRDIFF_RENAME_THRESHOLD env var > CLI arg > default (50),
+    /// and likewise PRDIFF_RECURSE_SUBMODULES > --recurse-submodules > off,
+    /// PRDIFF_DIFF_ALGORITHM > --diff-algorithm > myers and
+    /// PRDIFF_CONTEXT > --unified > 3.
+    /// The hex dump size only comes from PRDIFF_HEX_KB (default 4).
+    pub fn from_config(
+        cli_rename_threshold: Option<u8>,
+        cli_recurse_submodules: bool,
+        cli_algorithm: Option<DiffAlgorithm>,
+        cli_context_lines: Option<u32>,
+    ) -> Self {
+        let env_threshold = env::var("PRDIFF_RENAME_THRESHOLD")
+            .ok()
+            .and_then(|s| Self::parse_threshold(&s));
+        let mut options = Self::default();
+        if let Some(threshold) = env_threshold.or(cli_rename_threshold) {
+            options.rename_threshold = threshold;
+        }
+        if let Some(kb) = env::var("PRDIFF_HEX_KB").ok().and_then(|s| s.parse().ok()) {
+            options.hex_dump_kb = kb;
+        }
+        options.recurse_submodules = env::var("PRDIFF_RECURSE_SUBMODULES")
+            .ok()
+            .and_then(|s| Self::parse_switch(&s))
+            .unwrap_or(cli_recurse_submodules);
+        let env_algorithm = env::var("PRDIFF_DIFF_ALGORITHM")
+            .ok()
+            .and_then(|s| DiffAlgorithm::from_str(&s));
+        if let Some(algorithm) = env_algorithm.or(cli_algorithm) {
+            options.algorithm = algorithm;
+        }
+        let env_context = env::var("PRDIFF_CONTEXT")
+            .ok()
+            .and_then(|s| s.trim().parse().ok());
+        if let Some(lines) = env_context.or(cli_context_lines) {
+            options.context_lines = lines;
+        }
+        options
+    }
+}
+
+/// Parts of the branch diff that can be shown on their own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffLayer {
+    /// Everything from the merge-base to the working tree
+    All,
+    /// Only commits: merge-base to `HEAD`
+    Committed,
+    /// Only staged changes: `HEAD` to the index
+    Staged,
+    /// Only unstaged changes: the index to the working tree
+    Unstaged,
+}
+
+impl DiffLayer {
+    pub fn next(self) -> Self {
+        match self {
+            Self::All => Self::Committed,
+            Self::Committed => Self::Staged,
+            Self::Staged => Self::Unstaged,
+            Self::Unstaged => Self::All,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::All => "all",
+            Self::Committed => "committed",
+            Self::Staged => "staged",
+            Self::Unstaged => "unstaged",
+        }
+    }
+}
+
+/// Which layers of the branch diff touch a file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileLayers {
+    pub committed: bool,
+    pub staged: bool,
+    pub unstaged: bool,
+    pub untracked: bool,
+}
+
+impl FileLayers {
+    /// Compact badge like `cs` for a file with committed and staged changes.
+    pub fn badge(&self) -> String {
+        [
+            (self.committed, 'c'),
+            (self.staged, 's'),
+            (self.unstaged, 'u'),
+            (self.untracked, '?'),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, c)| *c)
+        .collect()
+    }
+
+    /// Whether the file's uncommitted changes are staged: `●` all of them,
+    /// `◐` some, `○` none. Blank for a file with committed changes only.
+    pub fn stage_marker(&self) -> &'static str {
+        match (self.staged, self.unstaged || self.untracked) {
+            (true, false) => "●",
+            (true, true) => "◐",
+            (false, true) => "○",
+            (false, false) => " ",
+        }
+    }
+}
+
+/// Where `git apply` writes a patch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApplyTo {
+    Index,
+    Worktree,
+}
+
+/// What the merge preview merges into the base branch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeSource {
+    /// The branch as committed
+    Head,
+    /// HEAD plus staged, unstaged and untracked changes
+    Worktree,
+}
+
+/// Outcome of merging the branch into its base without touching the
+/// working tree or index.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MergePreview {
+    /// Tree of the merge result, with conflict markers in conflicting files
+    pub tree: String,
+    pub conflicts: Vec<FileConflict>,
+}
+
+/// A file that would conflict when the branch is merged into its base.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileConflict {
+    pub path: String,
+    pub kind: ConflictKind,
+    /// Conflicting lines of each side; empty when git left no conflict
+    /// markers, as for deleted or binary files
+    pub regions: Vec<ConflictRegion>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// Both sides changed the same lines
+    Content,
+    AddedOnBoth,
+    DeletedOnBase,
+    DeletedOnBranch,
+}
+
+impl ConflictKind {
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::Content => "changed on both sides",
+            Self::AddedOnBoth => "added on both sides",
+            Self::DeletedOnBase => "deleted on the base, changed on this branch",
+            Self::DeletedOnBranch => "changed on the base, deleted on this branch",
+        }
+    }
+}
+
+/// One conflict in a file. Line numbers count lines of the merge result
+/// as if every conflict were resolved to that side.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConflictRegion {
+    pub base_start: u32,
+    pub base: Vec<String>,
+    pub branch_start: u32,
+    pub branch: Vec<String>,
 }
@@ -0,0 +1,182 @@
+use crate::model::{DiffLineKind, FileDiff};
+use std::collections::{HashMap, HashSet};
+
+/// Blocks shorter than this are usually braces or boilerplate that happen
+/// to repeat, not moved code.
+const MIN_MOVED_LINES: usize = 3;
+
+/// A moved block also needs this many alphanumeric characters, like git's
+/// `--color-moved`.
+const MIN_MOVED_ALNUM: usize = 20;
+
+/// Lines removed in more places than this are too common to start a block
+/// and would make the search quadratic.
+const MAX_CANDIDATES: usize = 64;
+
+/// The other end of a moved line: where an added line was removed, or
+/// where a removed line was added back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MovedLine {
+    pub path: String,
+    pub line: u32,
+    /// First line of its block, which carries the annotation
+    pub block_start: bool,
+}
+
+/// Moved lines of one file, keyed by hunk index and line index in the hunk.
+pub type FileMoves = HashMap<(usize, usize), MovedLine>;
+
+/// A removed or added line with its position in the diff.
+struct ChangedLine<'a> {
+    hunk: usize,
+    index: usize,
+    lineno: u32,
+    /// Content with surrounding whitespace trimmed, so re-indented code matches
+    key: &'a str,
+}
+
+/// Runs of consecutive lines of `kind` in `diff`.
+fn runs(diff: &FileDiff, kind: DiffLineKind) -> Vec<Vec<ChangedLine<'_>>> {
+    let mut runs = Vec::new();
+    for (h, hunk) in diff.hunks.iter().enumerate() {
+        let mut run = Vec::new();
+        for (index, line) in hunk.lines.iter().enumerate() {
+            if line.kind == kind {
+                let lineno = match kind {
+                    DiffLineKind::Added => line.new_lineno,
+                    _ => line.old_lineno,
+                };
+                run.push(ChangedLine {
+                    hunk: h,
+                    index,
+                    lineno: lineno.unwrap_or_default(),
+                    key: line.content.trim(),
+                });
+            } else if line.kind != DiffLineKind::NoNewline && !run.is_empty() {
+                runs.push(std::mem::take(&mut run));
+            }
+        }
+        if !run.is_empty() {
+            runs.push(run);
+        }
+    }
+    runs
+}
+
+fn is_block(lines: &[ChangedLine]) -> bool {
+    let alnum: usize = lines
+        .iter()
+        .map(|line| line.key.chars().filter(|c| c.is_alphanumeric()).count())
+        .sum();
+    lines.len() >= MIN_MOVED_LINES && alnum >= MIN_MOVED_ALNUM
+}
+
+/// Find blocks of removed lines that were added back elsewhere, in the same
+/// file or in another one, and annotate both ends of each.
+pub fn detect_moves(diffs: &[(&str, &FileDiff)]) -> HashMap<String, FileMoves> {
+    let removed: Vec<(&str, Vec<ChangedLine>)> = diffs
+        .iter()
+        .flat_map(|(path, diff)| {
+            runs(diff, DiffLineKind::Removed)
+                .into_iter()
+                .map(move |run| (*path, run))
+        })
+        .collect();
+    let mut starts: HashMap<&str, Vec<(usize, usize)>> = HashMap::new();
+    for (r, (_, run)) in removed.iter().enumerate() {
+        for (i, line) in run.iter().enumerate() {
+            if !line.key.is_empty() {
+                starts.entry(line.key).or_default().push((r, i));
+            }
+        }
+    }
+
+    let mut used: HashSet<(usize, usize)> = HashSet::new();
+    let mut moves: HashMap<String, FileMoves> = HashMap::new();
+    for (path, diff) in diffs {
+        for run in runs(diff, DiffLineKind::Added) {
+            let mut i = 0;
+            while i < run.len() {
+                let candidates = starts.get(run[i].key).filter(|c| c.len() <= MAX_CANDIDATES);
+                let longest = candidates
+                    .into_iter()
+                    .flatten()
+                    .map(|&(r, j)| {
+                        let source = &removed[r].1;
+                        let len = (0..)
+                            .take_while(|&k| {
+                                i + k < run.len()
+                                    && j + k < source.len()
+                                    && run[i + k].key == source[j + k].key
+                                    && !used.contains(&(r, j + k))
+                            })
+                            .count();
+                        (len, r, j)
+                    })
+                    .max_by_key(|&(len, r, j)| (len, std::cmp::Reverse((r, j))));
+                let Some((len, r, j)) = longest.filter(|&(len, _, _)| is_block(&run[i..i + len]))
+                else {
+                    i += 1;
+                    continue;
+                };
+                let (source_path, source) = &removed[r];
+                for k in 0..len {
+                    used.insert((r, j + k));
+                    let (added, gone) = (&run[i + k], &source[j + k]);
+                    let block_start = k == 0;
+                    moves.entry(path.to_string()).or_default().insert(
+                        (added.hunk, added.index),
+                        MovedLine {
+                            path: source_path.to_string(),
+                            line: gone.lineno,
+                            block_start,
+                        },
+                    );
+                    moves.entry(source_path.to_string()).or_default().insert(
+                        (gone.hunk, gone.index),
+                        MovedLine {
+                            path: path.to_string(),
+                            line: added.lineno,
+                            block_start,
+                        },
+                    );
+                }
+                i += len;
+            }
+        }
+    }
+    moves
+}
+
+#[cfg(test)]
+mod tests {
+    use super::detect_moves;
+    use crate::git::parse_diff;
+
+    #[test]
+    fn detect_moves_links_blocks_across_files() {
+        let old = parse_diff(
+            "--- a/a.rs\n+++ b/a.rs\n@@ -1,6 +1,2 @@\n fn keep() {}\n-fn helper(input: &str) -> usize {\n-    input.len()\n-}\n-}\n }\n",
+        );
+        let new = parse_diff(
+            "--- a/b.rs\n+++ b/b.rs\n@@ -4,0 +5,4 @@\n+fn helper(input: &str) -> usize {\n+        input.len()\n+}\n+}\n",
+        );
+        let moves = detect_moves(&[("a.rs", &old), ("b.rs", &new)]);
+
+        let to = &moves["a.rs"][&(0, 1)];
+        assert_eq!(
+            (to.path.as_str(), to.line, to.block_start),
+            ("b.rs", 5, true)
+        );
+        let from = &moves["b.rs"][&(0, 1)];
+        assert_eq!(
+            (from.path.as_str(), from.line, from.block_start),
+            ("a.rs", 3, false)
+        );
+        assert_eq!(moves["b.rs"].len(), 4);
+
+        // A lone closing brace is not a block.
+        let short = parse_diff("--- a/c.rs\n+++ b/c.rs\n@@ -1,0 +1,1 @@\n+}\n");
+        assert!(!detect_moves(&[("a.rs", &old), ("c.rs", &short)]).contains_key("c.rs"));
+    }
+}
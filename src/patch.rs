@@ -0,0 +1,158 @@
+use crate::model::{DiffLineKind, FileDiff};
+
+/// The hunk and line a row of the unified diff shows; `None` for the file
+/// header and hunk headers.
+pub fn row_line(diff: &FileDiff, row: usize) -> Option<(usize, usize)> {
+    let mut start = diff.header.len();
+    for (h, hunk) in diff.hunks.iter().enumerate() {
+        // Row `start` is the hunk header.
+        if row > start && row <= start + hunk.lines.len() {
+            return Some((h, row - start - 1));
+        }
+        start += 1 + hunk.lines.len();
+    }
+    None
+}
+
+/// Rows of the unified diff: the file header, then each hunk's header and lines.
+pub fn row_count(diff: &FileDiff) -> usize {
+    diff.header.len() + diff.hunks.iter().map(|h| 1 + h.lines.len()).sum::<usize>()
+}
+
+/// Whether a row of the unified diff is an added or removed line.
+pub fn is_change_row(diff: &FileDiff, row: usize) -> bool {
+    row_line(diff, row).is_some_and(|(h, i)| {
+        matches!(
+            diff.hunks[h].lines[i].kind,
+            DiffLineKind::Added | DiffLineKind::Removed
+        )
+    })
+}
+
+/// Start line of a hunk side: the first line, or the line before an empty side.
+fn start(position: i64, count: u32) -> i64 {
+    if count == 0 {
+        position - 1
+    } else {
+        position
+    }
+}
+
+/// A patch of just the `selected` lines of `diff`, given by hunk index and
+/// line index in the hunk, to `git apply` to the old side. Unselected
+/// removed lines become context and unselected added lines are left out.
+///
+/// With `reverse` the patch is meant for `git apply -R` on the new side
+/// instead: unselected added lines become context and unselected removed
+/// lines are left out. Returns `None` when no change is selected.
+pub fn build_patch(
+    path: &str,
+    diff: &FileDiff,
+    selected: impl Fn(usize, usize) -> bool,
+    reverse: bool,
+) -> Option<String> {
+    let mut body = String::new();
+    // Lines the patch adds minus lines it removes, in the hunks so far.
+    let mut delta = 0i64;
+    for (h, hunk) in diff.hunks.iter().enumerate() {
+        let mut lines = Vec::new();
+        let (mut old_count, mut new_count) = (0u32, 0u32);
+        let mut changes = false;
+        // Whether the line a `\ No newline` marker belongs to was kept.
+        let mut kept = false;
+        for (i, line) in hunk.lines.iter().enumerate() {
+            let picked = selected(h, i);
+            let marker = match (line.kind, picked, reverse) {
+                (DiffLineKind::Context, _, _) => Some(' '),
+                (DiffLineKind::Added, true, _) => Some('+'),
+                (DiffLineKind::Removed, true, _) => Some('-'),
+                (DiffLineKind::Added, false, true) | (DiffLineKind::Removed, false, false) => {
+                    Some(' ')
+                }
+                (DiffLineKind::Added | DiffLineKind::Removed, false, _) => None,
+                (DiffLineKind::NoNewline, _, _) => {
+                    if kept {
+                        lines.push(format!("\\{}", line.content));
+                    }
+                    continue;
+                }
+            };
+            kept = marker.is_some();
+            let Some(marker) = marker else {
+                continue;
+            };
+            match marker {
+                '+' => new_count += 1,
+                '-' => old_count += 1,
+                _ => {
+                    old_count += 1;
+                    new_count += 1;
+                }
+            }
+            changes |= marker != ' ';
+            lines.push(format!("{marker}{}", line.content));
+        }
+        if !changes {
+            continue;
+        }
+
+        // The side the patch is applied to keeps its lines, and so its place.
+        let (old_start, new_start) = if reverse {
+            let position = hunk.new_start as i64 + i64::from(hunk.new_count == 0);
+            (start(position - delta, old_count), hunk.new_start as i64)
+        } else {
+            let position = hunk.old_start as i64 + i64::from(hunk.old_count == 0);
+            (hunk.old_start as i64, start(position + delta, new_count))
+        };
+        delta += new_count as i64 - old_count as i64;
+        body.push_str(&format!(
+            "@@ -{old_start},{old_count} +{new_start},{new_count} @@\n"
+        ));
+        for line in lines {
+            body.push_str(&line);
+            body.push('\n');
+        }
+    }
+    if body.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "diff --git a/{path} b/{path}\n--- a/{path}\n+++ b/{path}\n{body}"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_patch, is_change_row, row_line};
+    use crate::git::parse_diff;
+
+    #[test]
+    fn patches_keep_only_the_selected_lines() {
+        // Old: a b c d; new: a B c x d (b changed, x added).
+        let diff = parse_diff(
+            "--- a/f\n+++ b/f\n@@ -1,4 +1,5 @@\n a\n-b\n+B\n c\n+x\n d\n@@ -10,1 +10,0 @@\n-gone\n",
+        );
+        assert_eq!(row_line(&diff, 4), Some((0, 1)));
+        assert_eq!(row_line(&diff, 9), None);
+        assert!(is_change_row(&diff, 10) && !is_change_row(&diff, 3));
+
+        // Staging only `+x` keeps `b` as context.
+        let patch = build_patch("f", &diff, |h, i| h == 0 && i == 4, false).unwrap();
+        assert_eq!(
+            patch,
+            "diff --git a/f b/f\n--- a/f\n+++ b/f\n@@ -1,4 +1,5 @@\n a\n b\n c\n+x\n d\n"
+        );
+
+        // Staging the whole first hunk shifts the second one.
+        let patch = build_patch("f", &diff, |_, _| true, false).unwrap();
+        assert!(patch.ends_with("@@ -10,1 +10,0 @@\n-gone\n"), "{patch}");
+
+        // Discarding `-b` alone from the new side leaves `+B` as context.
+        let patch = build_patch("f", &diff, |h, i| h == 0 && i == 1, true).unwrap();
+        assert!(
+            patch.ends_with("@@ -1,6 +1,5 @@\n a\n-b\n B\n c\n x\n d\n"),
+            "{patch}"
+        );
+        assert!(build_patch("f", &diff, |h, i| h == 0 && i == 0, false).is_none());
+    }
+}
@@ -0,0 +1,268 @@
+use crate::backend::GitBackend;
+use crate::model::{
+    DiffOptions, DiffRange, DiffSource, FileDiff, FileEntry, FileSides, FileStatus, SubmoduleScope,
+    WorkState,
+};
+use anyhow::Result;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+
+/// Replace each changed submodule in `files` with the files changed inside
+/// it when `recurse_submodules` is on. A submodule that isn't checked out,
+/// or whose pointers can't be resolved, keeps its pointer entry.
+pub fn expand(
+    backend: &dyn GitBackend,
+    range: &DiffRange,
+    files: Vec<FileEntry>,
+    options: &DiffOptions,
+) -> Vec<FileEntry> {
+    if !options.recurse_submodules {
+        return files;
+    }
+    let mut expanded = Vec::with_capacity(files.len());
+    for file in files {
+        if matches!(file.status, FileStatus::Submodule) {
+            match inner_files(backend, range, &file.path, options) {
+                Ok(inner) if !inner.is_empty() => {
+                    expanded.extend(inner);
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        expanded.push(file);
+    }
+    expanded
+}
+
+/// The range inside the submodule at `path` that matches `range` in the
+/// superproject: between the recorded pointers, or from the old pointer to
+/// the submodule's working tree.
+fn inner_range(backend: &dyn GitBackend, range: &DiffRange, path: &str) -> Result<DiffRange> {
+    let pointer = |rev: &str| backend.rev_parse(&format!("{rev}:{path}"));
+    let worktree = |from: String| DiffRange {
+        untracked: range.untracked,
+        ..DiffRange::worktree(from)
+    };
+    Ok(match (&range.to, range.work) {
+        (Some(to), _) => DiffRange::commits(pointer(&range.from)?, pointer(to)?),
+        (None, WorkState::Index) => DiffRange::commits(pointer(&range.from)?, pointer("")?),
+        (None, WorkState::Unstaged) => worktree(pointer("")?),
+        (None, WorkState::Worktree) => worktree(pointer(&range.from)?),
+    })
+}
+
+fn inner_files(
+    backend: &dyn GitBackend,
+    range: &DiffRange,
+    path: &str,
+    options: &DiffOptions,
+) -> Result<Vec<FileEntry>> {
+    let inner_range = inner_range(backend, range, path)?;
+    let files = backend
+        .open_submodule(path)?
+        .changed_files(&inner_range, options)?;
+    Ok(files
+        .into_iter()
+        .map(|file| {
+            let scope = SubmoduleScope {
+                path: path.to_string(),
+                range: inner_range.clone(),
+                file: file.clone(),
+            };
+            FileEntry {
+                path: format!("{path}/{}", file.path),
+                old_path: file.old_path.map(|old| format!("{path}/{old}")),
+                submodule: Some(Box::new(scope)),
+                ..file
+            }
+        })
+        .collect())
+}
+
+/// Diff of a file listed by [`expand`], with its paths prefixed by the
+/// submodule path like the file list.
+pub fn file_diff(
+    backend: &dyn GitBackend,
+    scope: &SubmoduleScope,
+    options: &DiffOptions,
+) -> (DiffSource, FileDiff) {
+    match backend.open_submodule(&scope.path) {
+        Ok(inner) => {
+            let (source, diff) = inner.file_diff(&scope.range, &scope.file, options);
+            (source, prefix_paths(diff, &scope.path))
+        }
+        Err(err) => {
+            let diff = FileDiff {
+                header: vec![format!("Error getting diff: {err}")],
+                ..FileDiff::default()
+            };
+            (DiffSource::Worktree, diff)
+        }
+    }
+}
+
+/// [`GitBackend::file_sides`] of a file listed by [`expand`].
+pub fn file_sides(
+    backend: &dyn GitBackend,
+    scope: &SubmoduleScope,
+    source: DiffSource,
+) -> Result<FileSides> {
+    backend
+        .open_submodule(&scope.path)?
+        .file_sides(&scope.range, &scope.file, source)
+}
+
+/// [`GitBackend::status_hash`] combined with that of every expanded
+/// submodule in `files`, whose status the superproject only sums up as dirty.
+pub fn status_hash(
+    backend: &dyn GitBackend,
+    files: &[FileEntry],
+    options: &DiffOptions,
+) -> Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    backend.status_hash()?.hash(&mut hasher);
+    if options.recurse_submodules {
+        let mut scopes: BTreeMap<&str, Vec<FileEntry>> = BTreeMap::new();
+        for scope in files.iter().filter_map(|f| f.submodule.as_ref()) {
+            scopes
+                .entry(&scope.path)
+                .or_default()
+                .push(scope.file.clone());
+        }
+        for (path, inner) in scopes {
+            let sub = backend.open_submodule(path)?;
+            status_hash(sub.as_ref(), &inner, options)?.hash(&mut hasher);
+        }
+    }
+    Ok(hasher.finish())
+}
+
+/// Rewrite the paths in a diff from the submodule's root to the superproject's.
+fn prefix_paths(mut diff: FileDiff, prefix: &str) -> FileDiff {
+    let join = |path: &str| format!("{prefix}/{path}");
+    for line in &mut diff.header {
+        let rewritten = if let Some(rest) = line.strip_prefix("diff --git a/") {
+            rest.split_once(" b/")
+                .map(|(old, new)| format!("diff --git a/{} b/{}", join(old), join(new)))
+        } else if line.starts_with("Binary files ") {
+            Some(line.replacen(" a/", &format!(" a/{prefix}/"), 1).replacen(
+                " b/",
+                &format!(" b/{prefix}/"),
+                1,
+            ))
+        } else {
+            [
+                "--- a/",
+                "+++ b/",
+                "rename from ",
+                "rename to ",
+                "copy from ",
+                "copy to ",
+                "Submodule ",
+            ]
+            .iter()
+            .find_map(|tag| {
+                line.strip_prefix(tag)
+                    .map(|rest| format!("{tag}{}", join(rest)))
+            })
+        };
+        if let Some(rewritten) = rewritten {
+            *line = rewritten;
+        }
+    }
+    let paths = [
+        &mut diff.old_path,
+        &mut diff.new_path,
+        &mut diff.rename_from,
+        &mut diff.rename_to,
+    ];
+    for path in paths.into_iter().flatten() {
+        *path = join(path);
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand, prefix_paths};
+    use crate::backend::fake::FakeBackend;
+    use crate::backend::GitBackend;
+    use crate::model::{DiffOptions, DiffRange, FileDiff, FileEntry, FileStatus, MODE_GITLINK};
+
+    #[test]
+    fn expand_replaces_submodule_pointer_with_its_files() {
+        let mut pointer = FileEntry::new("vendor/lib", FileStatus::Submodule, 0, 0);
+        pointer.old_mode = Some(MODE_GITLINK);
+        pointer.new_mode = Some(MODE_GITLINK);
+        let mut backend = FakeBackend {
+            branches: vec!["main".to_string(), "topic".to_string()],
+            ..FakeBackend::default()
+        };
+        backend.submodules.insert(
+            "vendor/lib".to_string(),
+            vec![FileEntry::new("src/parse.rs", FileStatus::Modified, 4, 1)],
+        );
+        let range = DiffRange::commits("main", "topic");
+        let options = DiffOptions {
+            recurse_submodules: true,
+            ..DiffOptions::default()
+        };
+
+        let files = expand(&backend, &range, vec![pointer.clone()], &options);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "vendor/lib/src/parse.rs");
+        let scope = files[0].submodule.as_ref().unwrap();
+        assert_eq!(scope.path, "vendor/lib");
+        assert_eq!(scope.file.path, "src/parse.rs");
+        assert_eq!(
+            scope.range,
+            DiffRange::commits("main:vendor/lib-oid", "topic:vendor/lib-oid")
+        );
+
+        // Off by default, and a submodule that can't be opened stays a pointer.
+        let files = expand(
+            &backend,
+            &range,
+            vec![pointer.clone()],
+            &DiffOptions::default(),
+        );
+        assert_eq!(files[0].path, "vendor/lib");
+        pointer.path = "missing".to_string();
+        let files = expand(&backend, &range, vec![pointer], &options);
+        assert_eq!(files[0].path, "missing");
+        assert!(backend.open_submodule("missing").is_err());
+    }
+
+    #[test]
+    fn prefix_paths_rewrites_headers_and_paths() {
+        let diff = FileDiff {
+            header: vec![
+                "diff --git a/old.rs b/new.rs".to_string(),
+                "similarity index 90%".to_string(),
+                "rename from old.rs".to_string(),
+                "rename to new.rs".to_string(),
+                "--- a/old.rs".to_string(),
+                "+++ b/new.rs".to_string(),
+            ],
+            old_path: Some("old.rs".to_string()),
+            new_path: Some("new.rs".to_string()),
+            ..FileDiff::default()
+        };
+        let diff = prefix_paths(diff, "sub");
+        assert_eq!(
+            diff.header,
+            [
+                "diff --git a/sub/old.rs b/sub/new.rs",
+                "similarity index 90%",
+                "rename from sub/old.rs",
+                "rename to sub/new.rs",
+                "--- a/sub/old.rs",
+                "+++ b/sub/new.rs",
+            ]
+        );
+        assert_eq!(diff.old_path.as_deref(), Some("sub/old.rs"));
+        assert_eq!(diff.new_path.as_deref(), Some("sub/new.rs"));
+    }
+}
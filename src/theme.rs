@@ -24,14 +24,30 @@ pub struct Theme {
     pub diff_added_bg: Color,
     pub diff_removed_bg: Color,
     pub diff_hunk_bg: Color,
+    /// Stronger backgrounds for the changed words within a paired line
+    pub diff_added_word_bg: Color,
+    pub diff_removed_word_bg: Color,
+    /// Background of padding rows in the side-by-side view
+    pub diff_filler_bg: Color,
+    /// Lines that moved elsewhere instead of being added or removed
+    pub diff_moved_bg: Color,
+    pub diff_moved_fg: Color,
 
     // Diff prefix colors
     pub diff_added_fg: Color,
     pub diff_removed_fg: Color,
+    /// Gutter marker of changed lines in the full-file view
+    pub diff_modified_fg: Color,
+
+    // Line number gutter
+    pub line_number_fg: Color,
+    pub line_number_bg: Color,
 
     // UI selection
     pub selected_bg: Color,
     pub selected_fg: Color,
+    /// Rows inside a range selection that aren't under the cursor
+    pub marked_bg: Color,
 
     // Syntect theme name
     syntect_theme_name: &'static str,
@@ -41,13 +57,22 @@ impl Theme {
     /// Dark theme (Monokai-adjacent) - default
     pub fn dark() -> Self {
         Self {
-            diff_added_bg: Color::Rgb(45, 74, 45),    // #2d4a2d - muted dark green
-            diff_removed_bg: Color::Rgb(74, 45, 45),  // #4a2d2d - muted dark red
-            diff_hunk_bg: Color::Rgb(45, 45, 74),     // #2d2d4a - muted dark blue
+            diff_added_bg: Color::Rgb(45, 74, 45), // #2d4a2d - muted dark green
+            diff_removed_bg: Color::Rgb(74, 45, 45), // #4a2d2d - muted dark red
+            diff_hunk_bg: Color::Rgb(45, 45, 74),  // #2d2d4a - muted dark blue
+            diff_added_word_bg: Color::Rgb(46, 120, 46), // #2e782e - brighter green
+            diff_removed_word_bg: Color::Rgb(130, 50, 50), // #823232 - brighter red
+            diff_filler_bg: Color::Rgb(32, 32, 32), // #202020 - near-black
+            diff_moved_bg: Color::Rgb(35, 65, 80), // #234150 - muted teal
+            diff_moved_fg: Color::Cyan,
             diff_added_fg: Color::Green,
             diff_removed_fg: Color::Red,
+            diff_modified_fg: Color::Yellow,
+            line_number_fg: Color::Rgb(110, 110, 110), // #6e6e6e - dim gray
+            line_number_bg: Color::Reset,
             selected_bg: Color::Rgb(60, 60, 120),     // #3c3c78 - current selection color
             selected_fg: Color::White,
+            marked_bg: Color::Rgb(40, 40, 70), // #282846 - dim selection
             syntect_theme_name: "base16-mocha.dark",
         }
     }
@@ -58,10 +83,19 @@ impl Theme {
             diff_added_bg: Color::Rgb(200, 255, 200),
             diff_removed_bg: Color::Rgb(255, 220, 220),
             diff_hunk_bg: Color::Rgb(220, 220, 255),
+            diff_added_word_bg: Color::Rgb(140, 230, 140),
+            diff_removed_word_bg: Color::Rgb(255, 170, 170),
+            diff_filler_bg: Color::Rgb(235, 235, 235),
+            diff_moved_bg: Color::Rgb(205, 235, 250),
+            diff_moved_fg: Color::Blue,
             diff_added_fg: Color::Green,
             diff_removed_fg: Color::Red,
+            diff_modified_fg: Color::Rgb(170, 120, 0),
+            line_number_fg: Color::Rgb(140, 140, 140),
+            line_number_bg: Color::Rgb(240, 240, 240),
             selected_bg: Color::Rgb(60, 60, 120),
             selected_fg: Color::White,
+            marked_bg: Color::Rgb(210, 210, 240),
             syntect_theme_name: "base16-ocean.light",
         }
     }
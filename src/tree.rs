@@ -1,12 +1,33 @@
 use crate::model::{FileEntry, TreeNode};
 use std::collections::HashSet;
 
-pub fn build_tree(files: &[FileEntry]) -> Vec<TreeNode> {
+/// Virtual folder at the end of the tree that gathers generated and vendored
+/// files. Repository paths are relative, so the leading slash keeps it apart
+/// from a real `Generated` directory; the tree shows it without.
+pub const GENERATED_DIR: &str = "/Generated";
+
+/// Build the file tree, moving generated and vendored files under
+/// [`GENERATED_DIR`] when `group_generated` is set.
+pub fn build_tree(files: &[FileEntry], group_generated: bool) -> Vec<TreeNode> {
+    let (generated, files): (Vec<&FileEntry>, Vec<&FileEntry>) = files
+        .iter()
+        .partition(|f| group_generated && f.is_generated());
+    let mut root = build_nodes(&files);
+    if !generated.is_empty() {
+        root.push(TreeNode::Directory {
+            name: GENERATED_DIR.to_string(),
+            children: build_nodes(&generated),
+        });
+    }
+    root
+}
+
+fn build_nodes(files: &[&FileEntry]) -> Vec<TreeNode> {
     let mut root: Vec<TreeNode> = Vec::new();
 
     for file in files {
         let parts: Vec<&str> = file.path.split('/').collect();
-        insert_into_tree(&mut root, &parts, file.clone());
+        insert_into_tree(&mut root, &parts, (*file).clone());
     }
 
     sort_tree(&mut root);
@@ -106,6 +127,27 @@ pub fn expand_all_dirs(nodes: &[TreeNode], prefix: &str, expanded: &mut HashSet<
     }
 }
 
+/// Directories to expand when they first appear: all of them except the
+/// generated group, which starts collapsed.
+pub fn default_expanded(nodes: &[TreeNode]) -> HashSet<String> {
+    let mut expanded = HashSet::new();
+    expand_all_dirs(nodes, "", &mut expanded);
+    expanded.remove(GENERATED_DIR);
+    expanded
+}
+
+/// Every file in `node`, itself if it is a file.
+pub fn files_under<'a>(node: &'a TreeNode, out: &mut Vec<&'a FileEntry>) {
+    match node {
+        TreeNode::Directory { children, .. } => {
+            for child in children {
+                files_under(child, out);
+            }
+        }
+        TreeNode::File(f) => out.push(f),
+    }
+}
+
 pub fn collect_visible<'a>(
     nodes: &'a [TreeNode],
     prefix: &str,
@@ -136,27 +178,17 @@ pub fn collect_visible<'a>(
 
 #[cfg(test)]
 mod tests {
-    use super::{build_tree, compact_tree};
+    use super::{build_tree, compact_tree, default_expanded, GENERATED_DIR};
     use crate::model::{FileEntry, FileStatus, TreeNode};
 
     #[test]
     fn compact_tree_does_not_merge_branching_directories() {
         let files = vec![
-            FileEntry {
-                path: "a/b/c/file1.txt".to_string(),
-                status: FileStatus::Modified,
-                additions: 1,
-                deletions: 0,
-            },
-            FileEntry {
-                path: "a/b/d/file2.txt".to_string(),
-                status: FileStatus::Modified,
-                additions: 1,
-                deletions: 0,
-            },
+            FileEntry::new("a/b/c/file1.txt", FileStatus::Modified, 1, 0),
+            FileEntry::new("a/b/d/file2.txt", FileStatus::Modified, 1, 0),
         ];
 
-        let tree = build_tree(&files);
+        let tree = build_tree(&files, true);
         // "a/b" should exist as a directory because it branches into c and d.
         let root_dir = tree
             .iter()
@@ -164,6 +196,27 @@ mod tests {
         assert!(root_dir.is_some());
     }
 
+    #[test]
+    fn generated_files_are_grouped_at_the_end() {
+        let mut generated = FileEntry::new("src/api.pb.rs", FileStatus::Modified, 900, 0);
+        generated.attributes.generated = true;
+        let files = vec![
+            generated,
+            FileEntry::new("src/main.rs", FileStatus::Modified, 1, 0),
+            FileEntry::new("Generated/notes.md", FileStatus::Modified, 1, 0),
+        ];
+
+        let tree = build_tree(&files, true);
+        let names: Vec<&str> = tree.iter().map(|n| n.name()).collect();
+        assert_eq!(names, ["Generated", "src", GENERATED_DIR]);
+        let expanded = default_expanded(&tree);
+        assert!(!expanded.contains(GENERATED_DIR) && expanded.contains("Generated"));
+        assert!(expanded.contains(&format!("{GENERATED_DIR}/src")));
+
+        let tree = build_tree(&files, false);
+        assert_eq!(tree.len(), 2);
+    }
+
     #[test]
     fn compact_tree_merges_pure_chains() {
         let mut nodes = vec![TreeNode::Directory {
@@ -172,12 +225,12 @@ mod tests {
                 name: "b".to_string(),
                 children: vec![TreeNode::Directory {
                     name: "c".to_string(),
-                    children: vec![TreeNode::File(FileEntry {
-                        path: "a/b/c/file.txt".to_string(),
-                        status: FileStatus::Modified,
-                        additions: 0,
-                        deletions: 0,
-                    })],
+                    children: vec![TreeNode::File(FileEntry::new(
+                        "a/b/c/file.txt",
+                        FileStatus::Modified,
+                        0,
+                        0,
+                    ))],
                 }],
             }],
         }];
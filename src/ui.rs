@@ -1,7 +1,13 @@
 use crate::app::App;
+use crate::comments::{Comment, ReviewComments, Side};
+use crate::expand::Expand;
 use crate::logging;
-use crate::model::{DiffSource, FileEntry, HighlightedLine, TreeNode};
+use crate::model::{
+    DiffSource, DiffViewMode, FileConflict, FileDiff, FileEntry, FileLayers, HighlightedLine,
+    SplitRow, TreeNode,
+};
 use crate::theme::Theme;
+use crate::viewed::ViewedMarks;
 use anyhow::Result;
 use crossterm::{
     event::{
@@ -14,10 +20,14 @@ use crossterm::{
 };
 use ratatui::{
     prelude::*,
-    widgets::{Block, Borders, Clear, Paragraph, Scrollbar, ScrollbarOrientation, ScrollbarState},
+    widgets::{
+        Block, Borders, Clear, Paragraph, Scrollbar, ScrollbarOrientation, ScrollbarState, Wrap,
+    },
 };
+use std::collections::HashMap;
 use std::io::{stdout, Write, Stdout};
-use std::process::Command;
+use std::path::Path;
+use std::process::{Command, ExitStatus};
 use std::time::Duration;
 
 pub fn new_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
@@ -26,6 +36,8 @@ pub fn new_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
 
 #[derive(Clone, Copy)]
 struct UiLayout {
+    /// Commit panel above the file tree; zero height when closed
+    commit_area: Rect,
     tree_area: Rect,
     diff_area: Rect,
     tree_inner: Rect,
@@ -33,7 +45,7 @@ struct UiLayout {
     help_area: Rect,
 }
 
-fn compute_layout(area: Rect, split_percent: u16) -> UiLayout {
+fn compute_layout(area: Rect, split_percent: u16, commit_rows: u16) -> UiLayout {
     // Reserve the bottom row for the help footer before splitting panels
     let main_height = area.height.saturating_sub(1);
     let main_area = Rect::new(area.x, area.y, area.width, main_height);
@@ -44,9 +56,20 @@ fn compute_layout(area: Rect, split_percent: u16) -> UiLayout {
     let chunks =
         Layout::horizontal([Constraint::Percentage(tree_pct), Constraint::Percentage(diff_pct)])
             .split(main_area);
-    let tree_area = chunks[0];
+    let left = chunks[0];
     let diff_area = chunks[1];
 
+    // The commit panel takes what it needs from the top of the left column,
+    // up to half of it.
+    let commit_height = commit_rows.min(left.height / 2);
+    let commit_area = Rect::new(left.x, left.y, left.width, commit_height);
+    let tree_area = Rect::new(
+        left.x,
+        left.y.saturating_add(commit_height),
+        left.width,
+        left.height.saturating_sub(commit_height),
+    );
+
     let tree_inner = Rect::new(
         tree_area.x.saturating_add(1),
         tree_area.y.saturating_add(1),
@@ -61,6 +84,7 @@ fn compute_layout(area: Rect, split_percent: u16) -> UiLayout {
     );
 
     UiLayout {
+        commit_area,
         tree_area,
         diff_area,
         tree_inner,
@@ -69,6 +93,7 @@ fn compute_layout(area: Rect, split_percent: u16) -> UiLayout {
     }
 }
 
+#[allow(clippy::collapsible_match)]
 pub fn run_app(app: &mut App, terminal: &mut Terminal<impl Backend>, guard: &mut TerminalGuard) -> Result<()> {
     let mut needs_redraw = true;
 
@@ -97,16 +122,37 @@ pub fn run_app(app: &mut App, terminal: &mut Terminal<impl Backend>, guard: &mut
                     // NOTE: Esc is NOT used as exit key because some terminals/escape sequences
                     // can be misinterpreted as Esc, causing unexpected exits. Use 'q' or Ctrl+C.
 
-                    if app.branch_modal.is_some() {
+                    if app.error_pane.is_some() {
+                        if matches!(key.code, KeyCode::Esc | KeyCode::Enter | KeyCode::Char('q')) {
+                            app.error_pane = None;
+                        }
+                        needs_redraw = true;
+                    } else if app.branch_modal.is_some() {
                         handle_modal_key(app, key.code, key.modifiers);
                         needs_redraw = true;
+                    } else if app.commit_panel.is_some() {
+                        if let KeyAction::Quit = handle_commit_panel_key(app, key.code) {
+                            return Ok(());
+                        }
+                        needs_redraw = true;
+                    } else if app.whitespace_menu {
+                        handle_whitespace_key(app, key.code);
+                        needs_redraw = true;
                     } else {
                         // Get layout for key handling
                         let term_size = terminal.size()?;
-                        let layout =
-                            compute_layout(Rect::new(0, 0, term_size.width, term_size.height), app.split_percent);
-
-                        match handle_key(app, key.code, &layout, &cached_visible) {
+                        let layout = compute_layout(
+                            Rect::new(0, 0, term_size.width, term_size.height),
+                            app.split_percent,
+                            commit_panel_rows(app),
+                        );
+
+                        let action = if app.line_selection.is_some() {
+                            handle_line_selection_key(app, key.code, &layout)
+                        } else {
+                            handle_key(app, key.code, &layout, &cached_visible)
+                        };
+                        match action {
                             KeyAction::Quit => return Ok(()),
                             KeyAction::OpenEditor => {
                                 if let Some((editor, path)) = app.editor_command() {
@@ -116,6 +162,32 @@ pub fn run_app(app: &mut App, terminal: &mut Terminal<impl Backend>, guard: &mut
                                     terminal.clear()?;
                                 }
                             }
+                            KeyAction::Commit { amend } => {
+                                if let Some(path) = app.prepare_commit(amend) {
+                                    guard.restore();
+                                    let status = run_git_editor(app, &path);
+                                    guard.enter()?;
+                                    terminal.clear()?;
+                                    match status {
+                                        Ok(status) if status.success() => {
+                                            app.finish_commit(&path, amend)
+                                        }
+                                        _ => app.show_toast("Commit aborted: the editor failed"),
+                                    }
+                                }
+                            }
+                            KeyAction::Comment => {
+                                if let Some(path) = app.prepare_comment() {
+                                    guard.restore();
+                                    let status = run_git_editor(app, &path);
+                                    guard.enter()?;
+                                    terminal.clear()?;
+                                    match status {
+                                        Ok(status) if status.success() => app.finish_comment(&path),
+                                        _ => app.show_toast("Comment discarded: the editor failed"),
+                                    }
+                                }
+                            }
                             KeyAction::Continue => {}
                         }
                         needs_redraw = true;
@@ -124,8 +196,11 @@ pub fn run_app(app: &mut App, terminal: &mut Terminal<impl Backend>, guard: &mut
                 Event::Mouse(mouse) => {
                     if app.branch_modal.is_none() {
                         let term_size = terminal.size()?;
-                        let layout =
-                            compute_layout(Rect::new(0, 0, term_size.width, term_size.height), app.split_percent);
+                        let layout = compute_layout(
+                            Rect::new(0, 0, term_size.width, term_size.height),
+                            app.split_percent,
+                            commit_panel_rows(app),
+                        );
                         handle_mouse(app, &layout, &mouse, cached_visible.len());
                         needs_redraw = true;
                     }
@@ -138,7 +213,11 @@ pub fn run_app(app: &mut App, terminal: &mut Terminal<impl Backend>, guard: &mut
         }
 
         // === PHASE 2: Check for file changes (throttled internally) ===
+        let toast = app.toast.as_ref().map(|(_, shown)| *shown);
         app.check_for_changes();
+        if app.toast.as_ref().map(|(_, shown)| *shown) != toast || app.expire_toast() {
+            needs_redraw = true;
+        }
 
         // === PHASE 3: Rebuild visible items cache if tree changed ===
         let tree_version = app.tree_version();
@@ -174,43 +253,79 @@ pub fn run_app(app: &mut App, terminal: &mut Terminal<impl Backend>, guard: &mut
 
         // === PHASE 4: Render (only if needed) ===
         if needs_redraw {
-            let selected_file_path = cached_visible.get(app.cursor).and_then(|(_, _, is_dir, file)| {
-                if !is_dir {
-                    file.as_ref().map(|f| f.path.clone())
-                } else {
-                    None
-                }
-            });
+            let selected_file =
+                cached_visible.get(app.cursor).and_then(
+                    |(_, _, is_dir, file)| {
+                        if !is_dir {
+                            file.clone()
+                        } else {
+                            None
+                        }
+                    },
+                );
+            let selected_file_path = selected_file.as_ref().map(|f| f.path.clone());
 
             if let Some(ref path) = selected_file_path {
                 app.ensure_highlighted(path);
-                app.diff_line_count = app.get_highlighted(path).len();
+                app.diff_line_count = app.diff_row_count(path);
+                app.diff_comment_rows = app.comment_rows(path);
             } else {
                 app.diff_line_count = 0;
+                app.diff_comment_rows.clear();
             }
 
             let highlighted_lines: &[HighlightedLine] = selected_file_path
                 .as_ref()
                 .map(|p| app.get_highlighted(p))
                 .unwrap_or(&[]);
+            let split_rows: &[SplitRow] = selected_file_path
+                .as_ref()
+                .map(|p| app.get_split_rows(p))
+                .unwrap_or(&[]);
+            let selected_diff = selected_file_path.as_ref().and_then(|p| app.get_diff(p));
+            let file_comments = selected_file_path
+                .as_ref()
+                .map(|p| app.file_comments(p))
+                .unwrap_or_default();
+            let placed_comments = selected_file_path
+                .as_ref()
+                .map(|p| app.placed_comments(p))
+                .unwrap_or_default();
             let selected_diff_source = selected_file_path
                 .as_ref()
                 .and_then(|p| app.get_diff_source(p))
                 .unwrap_or(DiffSource::Worktree);
-            let selected_file_path_ref = selected_file_path.as_deref();
             let theme = &app.theme;
             let split_percent = app.split_percent;
+            let commit_rows = commit_panel_rows(app);
+            let show_line_numbers = app.show_line_numbers;
+            let diff_view = selected_file_path
+                .as_ref()
+                .map_or(app.diff_view, |p| app.view_mode(p));
+            let full_file = selected_file_path
+                .as_ref()
+                .is_some_and(|p| app.showing_full_file(p));
+            let conflict = selected_file_path
+                .as_ref()
+                .and_then(|p| app.showing_conflict(p));
+            let line_selection = app
+                .line_selection
+                .as_ref()
+                .filter(|s| selected_file_path.as_ref() == Some(&s.path))
+                .map(|s| (s.rows(), s.cursor));
             let base_branch = app.base_branch.as_str();
-            let merge_base_short: String = app.merge_base.chars().take(7).collect();
+            let tree_title = app.tree_title();
             let expanded = &app.expanded;
 
             // Compute layout inside draw to use the authoritative frame area,
             // and clamp scroll values against that same layout.
             let mut draw_layout = None;
             let branch_modal = &app.branch_modal;
-            let has_modal = branch_modal.is_some();
+            let error_pane = &app.error_pane;
+            let help = help_text(app);
+            let toast = app.toast.as_ref().map(|(text, _)| text.as_str());
             terminal.draw(|f| {
-                let layout = compute_layout(f.area(), split_percent);
+                let layout = compute_layout(f.area(), split_percent, commit_rows);
                 draw_layout = Some(layout);
                 draw_ui(
                     f,
@@ -220,22 +335,44 @@ pub fn run_app(app: &mut App, terminal: &mut Terminal<impl Backend>, guard: &mut
                     app.scroll_offset,
                     app.diff_scroll,
                     expanded,
-                    base_branch,
-                    &merge_base_short,
-                    selected_file_path_ref,
+                    &app.file_layers,
+                    app.interactive,
+                    &app.viewed,
+                    &app.comments,
+                    &app.conflicts,
+                    &tree_title,
+                    selected_file.as_ref(),
                     selected_diff_source,
+                    selected_diff,
                     highlighted_lines,
+                    split_rows,
+                    diff_view,
+                    full_file,
+                    conflict,
+                    line_selection,
+                    (&file_comments, &placed_comments),
+                    show_line_numbers,
                     theme,
-                    has_modal,
+                    help,
                 );
+                if let Some(panel) = &app.commit_panel {
+                    draw_commit_panel(f, layout.commit_area, app, panel, theme);
+                }
+                if let Some(text) = toast {
+                    draw_toast(f, layout.help_area, text, theme);
+                }
                 if let Some(modal) = branch_modal {
                     draw_branch_modal(f, modal, base_branch, theme);
                 }
+                if let Some((title, text)) = error_pane {
+                    draw_error_pane(f, title, text);
+                }
             })?;
 
             if let Some(layout) = draw_layout {
                 clamp_scroll(app, &layout);
                 adjust_tree_scroll(app, &layout);
+                adjust_commit_scroll(app, &layout);
             }
             needs_redraw = false;
         }
@@ -248,6 +385,25 @@ pub fn run_app(app: &mut App, terminal: &mut Terminal<impl Backend>, guard: &mut
     }
 }
 
+/// Key hints for the footer, depending on what has focus.
+fn help_text(app: &App) -> &'static str {
+    if app.error_pane.is_some() {
+        " Esc/Enter/q:close "
+    } else if app.branch_modal.is_some() {
+        " ↑/↓:nav | Enter:select | Esc:cancel | type to filter "
+    } else if app.commit_panel.is_some() {
+        " j/k:nav | Space:mark range | Enter:show | a:all commits | Esc/c:close | q:quit "
+    } else if app.whitespace_menu {
+        " ignore whitespace: w:all | b:amount | l:blank lines | r:CR at EOL | other:cancel "
+    } else if app.line_selection.is_some() {
+        " j/k:extend selection | J/K:scroll | i:comment | S:stage/unstage lines | D:discard lines | V/Esc:cancel "
+    } else if app.interactive {
+        " j/k:nav | h/l/Space:expand | Enter:open | d:viewed | V:pick lines | i:comment | s/u:stage/unstage | S/D:stage/discard hunk | Z:undo discard | C/A:commit/amend | J/K:scroll | </>:resize | n:line# | v:split | f:file | r:removed | {/}:change | x:hex | X:conflicts | M:merge uncommitted | R:rebased | w:whitespace | a:algorithm | m:moves | [/]/e:expand | +/-:context | c:commits | L:layer | U:untracked | G:group generated | b:branch | q:quit "
+    } else {
+        " j/k:nav | h/l/Space:expand | Enter:open | d:viewed | V:pick lines | i:comment | J/K:scroll | </>:resize | n:line# | v:split | f:file | r:removed | {/}:change | x:hex | X:conflicts | M:merge uncommitted | R:rebased | w:whitespace | a:algorithm | m:moves | [/]/e:expand | +/-:context | c:commits | L:layer | U:untracked | G:group generated | b:branch | q:quit "
+    }
+}
+
 fn clamp_scroll(app: &mut App, layout: &UiLayout) {
     let max_tree_visible = layout.tree_inner.height as usize;
     if max_tree_visible == 0 {
@@ -255,7 +411,11 @@ fn clamp_scroll(app: &mut App, layout: &UiLayout) {
     }
 
     let max_diff_visible = layout.diff_inner.height as usize;
-    let max_scroll = app.diff_line_count.saturating_sub(max_diff_visible);
+    let max_scroll = max_diff_scroll(
+        app.diff_line_count,
+        |row| comment_rows_under(app, row),
+        max_diff_visible,
+    );
     app.diff_scroll = app.diff_scroll.min(max_scroll);
 }
 
@@ -278,12 +438,55 @@ fn adjust_tree_scroll(app: &mut App, layout: &UiLayout) {
     }
 }
 
+/// Rows the commit panel wants, including its border; 0 when closed.
+fn commit_panel_rows(app: &App) -> u16 {
+    match app.commit_panel {
+        Some(_) => (app.commit_entry_count().max(1) + 2).min(u16::MAX as usize) as u16,
+        None => 0,
+    }
+}
+
+fn adjust_commit_scroll(app: &mut App, layout: &UiLayout) {
+    let max_visible = layout.commit_area.height.saturating_sub(2) as usize;
+    let Some(panel) = &mut app.commit_panel else {
+        return;
+    };
+    if max_visible == 0 {
+        panel.scroll_offset = 0;
+        return;
+    }
+    if panel.cursor >= panel.scroll_offset.saturating_add(max_visible) {
+        panel.scroll_offset = panel.cursor.saturating_add(1).saturating_sub(max_visible);
+    }
+    if panel.cursor < panel.scroll_offset {
+        panel.scroll_offset = panel.cursor;
+    }
+}
+
 enum KeyAction {
     Continue,
     Quit,
     OpenEditor,
+    /// Write a commit message in the editor, then commit or amend
+    Commit {
+        amend: bool,
+    },
+    /// Write a review comment in the editor
+    Comment,
 }
 
+/// Run git's editor on `path` and wait for it.
+fn run_git_editor(app: &App, path: &Path) -> std::io::Result<ExitStatus> {
+    // GIT_EDITOR may carry arguments, so git runs it through sh.
+    Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", app.commit_editor()))
+        .arg("editor")
+        .arg(path)
+        .status()
+}
+
+#[allow(clippy::collapsible_match)]
 fn handle_key(
     app: &mut App,
     code: KeyCode,
@@ -306,9 +509,11 @@ fn handle_key(
             }
         }
         KeyCode::Char('J') => {
-            let max_scroll = app
-                .diff_line_count
-                .saturating_sub(layout.diff_inner.height as usize);
+            let max_scroll = max_diff_scroll(
+                app.diff_line_count,
+                |row| comment_rows_under(app, row),
+                layout.diff_inner.height as usize,
+            );
             app.diff_scroll = app.diff_scroll.saturating_add(3).min(max_scroll);
         }
         KeyCode::Char('K') => {
@@ -332,6 +537,8 @@ fn handle_key(
         KeyCode::Char(' ') => {
             if matches!(visible.get(app.cursor), Some((_, _, true, _))) {
                 app.toggle_expand();
+            } else {
+                app.toggle_revealed();
             }
         }
         KeyCode::Char('<') => {
@@ -343,11 +550,227 @@ fn handle_key(
         KeyCode::Char('b') => {
             app.open_branch_modal();
         }
+        KeyCode::Char('c') => {
+            app.toggle_commit_panel();
+        }
+        KeyCode::Char('L') => {
+            app.cycle_layer();
+        }
+        KeyCode::Char('G') => {
+            app.toggle_group_generated();
+        }
+        KeyCode::Char('U') => {
+            app.toggle_untracked();
+        }
+        KeyCode::Char('n') => {
+            app.show_line_numbers = !app.show_line_numbers;
+        }
+        KeyCode::Char('v') => {
+            app.toggle_diff_view();
+        }
+        KeyCode::Char('x') => {
+            app.toggle_hex();
+        }
+        KeyCode::Char('X') => {
+            app.toggle_conflict_view();
+        }
+        KeyCode::Char('M') => {
+            app.toggle_merge_worktree();
+        }
+        KeyCode::Char('R') => {
+            app.toggle_rebased_preview();
+        }
+        KeyCode::Char('d') => {
+            app.toggle_viewed();
+        }
+        KeyCode::Char('s') => {
+            app.stage_selected(true);
+        }
+        KeyCode::Char('u') => {
+            app.stage_selected(false);
+        }
+        KeyCode::Char('S') => {
+            app.stage_lines();
+        }
+        KeyCode::Char('D') => {
+            app.discard_lines();
+        }
+        KeyCode::Char('Z') => {
+            app.undo_discard();
+        }
+        KeyCode::Char('V') => {
+            app.start_line_selection();
+            keep_line_cursor_visible(app, layout);
+        }
+        KeyCode::Char('i') => return KeyAction::Comment,
+        KeyCode::Char('C') => return KeyAction::Commit { amend: false },
+        KeyCode::Char('A') => return KeyAction::Commit { amend: true },
+        KeyCode::Char('w') => {
+            app.whitespace_menu = true;
+        }
+        KeyCode::Char('a') => {
+            app.cycle_algorithm();
+        }
+        KeyCode::Char('m') => {
+            app.toggle_moves();
+        }
+        KeyCode::Char('f') => {
+            app.toggle_full_file();
+        }
+        KeyCode::Char('r') => {
+            app.toggle_removed();
+        }
+        KeyCode::Char('}') => {
+            app.jump_to_change(true);
+        }
+        KeyCode::Char('{') => {
+            app.jump_to_change(false);
+        }
+        KeyCode::Char('[') => {
+            app.expand_context(Expand::Up);
+        }
+        KeyCode::Char(']') => {
+            app.expand_context(Expand::Down);
+        }
+        KeyCode::Char('e') => {
+            app.expand_context(Expand::Gap);
+        }
+        KeyCode::Char('+') | KeyCode::Char('=') => {
+            app.set_context_lines(app.diff_options.context_lines + 1);
+        }
+        KeyCode::Char('-') => {
+            app.set_context_lines(app.diff_options.context_lines.saturating_sub(1));
+        }
+        _ => {}
+    }
+    KeyAction::Continue
+}
+
+/// Second key after `w`: toggle one kind of ignored whitespace. Any other
+/// key just closes the menu.
+fn handle_whitespace_key(app: &mut App, code: KeyCode) {
+    app.whitespace_menu = false;
+    let mut whitespace = app.diff_options.whitespace;
+    let flag = match code {
+        KeyCode::Char('w') => &mut whitespace.ignore_all,
+        KeyCode::Char('b') => &mut whitespace.ignore_amount,
+        KeyCode::Char('l') => &mut whitespace.ignore_blank_lines,
+        KeyCode::Char('r') => &mut whitespace.ignore_cr_at_eol,
+        _ => return,
+    };
+    *flag = !*flag;
+    app.set_whitespace(whitespace);
+}
+
+/// Keys while picking lines in the diff pane: j/k move the selection's end.
+fn handle_line_selection_key(app: &mut App, code: KeyCode, layout: &UiLayout) -> KeyAction {
+    match code {
+        KeyCode::Char('j') | KeyCode::Down => app.move_line_cursor(true),
+        KeyCode::Char('k') | KeyCode::Up => app.move_line_cursor(false),
+        KeyCode::Char('J') => {
+            let height = layout.diff_inner.height as usize;
+            let max_scroll = max_diff_scroll(
+                app.diff_line_count,
+                |row| comment_rows_under(app, row),
+                height,
+            );
+            app.diff_scroll = app.diff_scroll.saturating_add(3).min(max_scroll);
+            return KeyAction::Continue;
+        }
+        KeyCode::Char('K') => {
+            app.diff_scroll = app.diff_scroll.saturating_sub(3);
+            return KeyAction::Continue;
+        }
+        KeyCode::Char('S') => app.stage_lines(),
+        KeyCode::Char('D') => app.discard_lines(),
+        KeyCode::Char('i') => return KeyAction::Comment,
+        KeyCode::Char('V') | KeyCode::Esc => app.line_selection = None,
+        _ => {}
+    }
+    keep_line_cursor_visible(app, layout);
+    KeyAction::Continue
+}
+
+/// Scroll the diff pane so the end of the line selection stays in view.
+fn keep_line_cursor_visible(app: &mut App, layout: &UiLayout) {
+    let Some(selection) = &app.line_selection else {
+        return;
+    };
+    let cursor = selection.cursor;
+    let height = (layout.diff_inner.height as usize).max(1);
+    if cursor < app.diff_scroll {
+        app.diff_scroll = cursor;
+        return;
+    }
+    // Comments under the rows above the cursor push it down the pane.
+    let mut shown: usize = (app.diff_scroll..cursor)
+        .map(|row| 1 + comment_rows_under(app, row))
+        .sum::<usize>()
+        + 1;
+    while shown > height && app.diff_scroll < cursor {
+        shown -= 1 + comment_rows_under(app, app.diff_scroll);
+        app.diff_scroll += 1;
+    }
+}
+
+/// Rows of comment text under diff row `row`.
+fn comment_rows_under(app: &App, row: usize) -> usize {
+    app.diff_comment_rows.get(&row).copied().unwrap_or(0)
+}
+
+/// The furthest the diff pane scrolls, in diff rows: the last row from
+/// which the rest of the `row_count` rows, with the `comment_rows` under
+/// each, still fill `height` rows.
+fn max_diff_scroll(
+    row_count: usize,
+    comment_rows: impl Fn(usize) -> usize,
+    height: usize,
+) -> usize {
+    let mut below = 0;
+    for row in (0..row_count).rev() {
+        below += 1 + comment_rows(row);
+        if below >= height {
+            return row;
+        }
+    }
+    0
+}
+
+fn handle_commit_panel_key(app: &mut App, code: KeyCode) -> KeyAction {
+    let entry_count = app.commit_entry_count();
+    let Some(panel) = &mut app.commit_panel else {
+        return KeyAction::Continue;
+    };
+
+    match code {
+        KeyCode::Char('q') => return KeyAction::Quit,
+        KeyCode::Esc | KeyCode::Char('c') => {
+            app.commit_panel = None;
+        }
+        KeyCode::Enter => {
+            app.apply_commit_selection();
+        }
+        KeyCode::Char('a') => {
+            app.clear_commit_filter();
+        }
+        KeyCode::Char(' ') => {
+            panel.anchor = match panel.anchor {
+                Some(_) => None,
+                None => Some(panel.cursor),
+            };
+        }
+        KeyCode::Char('k') | KeyCode::Up => {
+            panel.cursor = panel.cursor.saturating_sub(1);
+        }
+        KeyCode::Char('j') | KeyCode::Down => {
+            panel.cursor = (panel.cursor + 1).min(entry_count.saturating_sub(1));
+        }
         _ => {}
     }
     KeyAction::Continue
 }
 
+#[allow(clippy::collapsible_match)]
 fn handle_modal_key(app: &mut App, code: KeyCode, modifiers: KeyModifiers) {
     let Some(modal) = &mut app.branch_modal else {
         return;
@@ -398,6 +821,7 @@ fn handle_modal_key(app: &mut App, code: KeyCode, modifiers: KeyModifiers) {
     }
 }
 
+#[allow(clippy::collapsible_match)]
 fn handle_mouse(app: &mut App, layout: &UiLayout, mouse: &MouseEvent, visible_count: usize) {
     let x = mouse.column;
     let y = mouse.row;
@@ -427,9 +851,11 @@ fn handle_mouse(app: &mut App, layout: &UiLayout, mouse: &MouseEvent, visible_co
         }
         MouseEventKind::ScrollDown => {
             if in_diff_panel {
-                let max_scroll = app
-                    .diff_line_count
-                    .saturating_sub(layout.diff_inner.height as usize);
+                let max_scroll = max_diff_scroll(
+                    app.diff_line_count,
+                    |row| comment_rows_under(app, row),
+                    layout.diff_inner.height as usize,
+                );
                 app.diff_scroll = app.diff_scroll.saturating_add(3).min(max_scroll);
             }
         }
@@ -442,6 +868,22 @@ fn handle_mouse(app: &mut App, layout: &UiLayout, mouse: &MouseEvent, visible_co
     }
 }
 
+/// File name shown in the tree. Renames and copies also show where they came
+/// from: just the old name within the same directory, the full path otherwise.
+fn tree_file_name(f: &FileEntry) -> String {
+    let (dir, name) = f.path.rsplit_once('/').unwrap_or(("", &f.path));
+    let Some(old) = &f.old_path else {
+        return name.to_string();
+    };
+    let (old_dir, old_name) = old.rsplit_once('/').unwrap_or(("", old));
+    let source = if old_dir == dir {
+        old_name
+    } else {
+        old.as_str()
+    };
+    format!("{source} → {name}")
+}
+
 #[allow(clippy::too_many_arguments)]
 fn draw_ui(
     f: &mut Frame,
@@ -451,20 +893,28 @@ fn draw_ui(
     scroll_offset: usize,
     diff_scroll: usize,
     expanded: &std::collections::HashSet<String>,
-    base_branch: &str,
-    merge_base_short: &str,
-    selected_file_path: Option<&str>,
+    file_layers: &std::collections::HashMap<String, FileLayers>,
+    interactive: bool,
+    viewed: &ViewedMarks,
+    comments: &ReviewComments,
+    conflicts: &std::collections::HashMap<String, FileConflict>,
+    tree_title: &str,
+    selected_file: Option<&FileEntry>,
     selected_diff_source: DiffSource,
+    selected_diff: Option<&FileDiff>,
     highlighted_lines: &[HighlightedLine],
+    split_rows: &[SplitRow],
+    diff_view: DiffViewMode,
+    full_file: bool,
+    conflict: Option<&FileConflict>,
+    line_selection: Option<((usize, usize), usize)>,
+    comments_shown: (&[&Comment], &HashMap<usize, Vec<&Comment>>),
+    show_line_numbers: bool,
     theme: &Theme,
-    has_modal: bool,
+    help: &str,
 ) {
     // File tree
-    let tree_block = Block::default()
-        .title(format!(
-            " prdiff vs {base_branch} (merge-base {merge_base_short}) "
-        ))
-        .borders(Borders::ALL);
+    let tree_block = Block::default().title(tree_title).borders(Borders::ALL);
     let tree_inner = tree_block.inner(layout.tree_area);
     f.render_widget(tree_block, layout.tree_area);
 
@@ -486,6 +936,7 @@ fn draw_ui(
         let indent = "  ".repeat(*depth);
         let is_selected = i == cursor;
 
+        let mut badge = String::new();
         let (prefix, name, style) = if *is_dir {
             let is_exp = expanded.contains(path);
             let arrow = if is_exp { "▼ " } else { "▶ " };
@@ -496,12 +947,51 @@ fn draw_ui(
                 Style::default().fg(Color::Blue).bold(),
             )
         } else if let Some(f) = file {
-            let fname = f.path.rsplit('/').next().unwrap_or(&f.path);
-            let stats = format!(" +{}/-{}", f.additions, f.deletions);
+            let fname = tree_file_name(f);
+            let stats = if f.is_submodule() {
+                String::new()
+            } else {
+                format!(" +{}/-{}", f.additions, f.deletions)
+            };
+            if let Some(mode) = f.mode_badge() {
+                badge = format!(" [{mode}]");
+            }
+            if let Some(kind) = f.attributes.badge() {
+                badge.push_str(&format!(" [{kind}]"));
+            }
+            if f.lfs {
+                badge.push_str(" [LFS]");
+            }
+            if let Some(layers) = file_layers.get(&f.path) {
+                badge.push_str(&format!(" {}", layers.badge()));
+            }
+            if conflicts.contains_key(&f.path) {
+                badge.push_str(" [conflict]");
+            }
+            match comments.for_path(&f.path).count() {
+                0 => {}
+                1 => badge.push_str(" [1 comment]"),
+                n => badge.push_str(&format!(" [{n} comments]")),
+            }
+            // Files whose changes are all ignored whitespace, generated or
+            // vendored ones, and those already viewed fade out.
+            let is_viewed = viewed.contains(&f.path);
+            let color = if f.whitespace_only || f.is_generated() || is_viewed {
+                Color::DarkGray
+            } else {
+                f.status.color()
+            };
+            // Staged, partly staged or unstaged, for the s/u keys.
+            let staging = match file_layers.get(&f.path) {
+                Some(layers) if interactive => format!("{} ", layers.stage_marker()),
+                _ if interactive => "  ".to_string(),
+                _ => String::new(),
+            };
+            let check = if is_viewed { "✓ " } else { "" };
             (
-                format!("{} ", f.status.symbol()),
+                format!("{staging}{check}{} ", f.status.symbol()),
                 format!("{fname}{stats}"),
-                Style::default().fg(f.status.color()),
+                Style::default().fg(color),
             )
         } else {
             continue;
@@ -516,10 +1006,16 @@ fn draw_ui(
             style
         };
 
+        let badge_style = if is_selected {
+            line_style
+        } else {
+            Style::default().fg(Color::DarkGray)
+        };
         lines.push(Line::from(vec![
             Span::styled(indent, line_style),
             Span::styled(prefix, line_style),
             Span::styled(name, line_style),
+            Span::styled(badge, badge_style),
         ]));
     }
 
@@ -535,43 +1031,120 @@ fn draw_ui(
     }
 
     // Diff preview
-    let diff_title = match selected_diff_source {
-        DiffSource::Worktree => " Diff (worktree) ",
-        DiffSource::Index => " Diff (staged) ",
-        DiffSource::Untracked => " Diff (untracked) ",
+    let source_label = match selected_diff_source {
+        DiffSource::Worktree => "worktree",
+        DiffSource::Index => "staged",
+        DiffSource::Untracked => "untracked",
+        DiffSource::Commit => "commit",
     };
+    let mut diff_title = match selected_diff.map(|d| d.hunks.len()) {
+        _ if conflict.is_some() => match conflict.map_or(0, |c| c.regions.len()) {
+            1 => " Merge conflict (1 region) ".to_string(),
+            n => format!(" Merge conflict ({n} regions) "),
+        },
+        _ if full_file => format!(" File ({source_label}) "),
+        Some(1) => format!(" Diff ({source_label}, 1 hunk) "),
+        Some(n) if n > 0 => format!(" Diff ({source_label}, {n} hunks) "),
+        _ => format!(" Diff ({source_label}) "),
+    };
+    if let Some(file) = selected_file.filter(|f| f.old_path.is_some()) {
+        diff_title.push_str(&file.display_path());
+        if let Some(similarity) = file.similarity {
+            diff_title.push_str(&format!(" ({similarity}%)"));
+        }
+        diff_title.push(' ');
+    }
     let diff_block = Block::default().title(diff_title).borders(Borders::ALL);
     let diff_inner = diff_block.inner(layout.diff_area);
     f.render_widget(diff_block, layout.diff_area);
 
-    if selected_file_path.is_some() {
+    if selected_file.is_some() {
+        let (file_comments, placed) = comments_shown;
+        let row_count = match diff_view {
+            DiffViewMode::Unified => highlighted_lines.len(),
+            DiffViewMode::Split => split_rows.len(),
+        };
         let max_diff_visible = diff_inner.height as usize;
-        let clamped_scroll =
-            diff_scroll.min(highlighted_lines.len().saturating_sub(max_diff_visible));
-
-        let visible_end = (clamped_scroll + max_diff_visible).min(highlighted_lines.len());
-        let visible_lines = &highlighted_lines[clamped_scroll..visible_end];
-
-        let diff_text: Vec<Line> = visible_lines
-            .iter()
-            .map(|hl| {
-                let spans: Vec<Span> = hl
-                    .spans
-                    .iter()
-                    .map(|(text, fg, bg)| {
-                        Span::styled(text.clone(), Style::default().fg(*fg).bg(*bg))
-                    })
-                    .collect();
+        // Comments add their text below the rows, so the end scrolls into view.
+        let comment_rows = |row| {
+            placed
+                .get(&row)
+                .map_or(0, |c| c.iter().map(|c| comment_lines(c).len()).sum())
+        };
+        let max_scroll = max_diff_scroll(row_count, comment_rows, max_diff_visible);
+        let clamped_scroll = diff_scroll.min(max_scroll);
+
+        // Size the gutter for the largest line number in the whole file so it
+        // doesn't change width while scrolling.
+        let gutter_width = if show_line_numbers {
+            highlighted_lines
+                .iter()
+                .flat_map(|hl| [hl.old_lineno, hl.new_lineno])
+                .flatten()
+                .max()
+                .map(|n| n.to_string().len())
+        } else {
+            None
+        };
 
-                Line::from(spans)
-            })
-            .collect();
+        match diff_view {
+            DiffViewMode::Unified => {
+                let visible_end = (clamped_scroll + max_diff_visible).min(highlighted_lines.len());
+                let visible_lines = &highlighted_lines[clamped_scroll..visible_end];
+
+                let mut diff_text: Vec<Line> = Vec::new();
+                for (i, hl) in visible_lines.iter().enumerate() {
+                    let row = clamped_scroll + i;
+                    // Picked lines show in the gutter, or across the
+                    // line when there is none.
+                    let picked = line_selection.and_then(|((first, last), cursor)| {
+                        let bg = if row == cursor {
+                            theme.selected_bg
+                        } else {
+                            theme.marked_bg
+                        };
+                        (first..=last).contains(&row).then_some(bg)
+                    });
+                    let mut spans: Vec<Span> = Vec::new();
+                    if !file_comments.is_empty() {
+                        spans.push(comment_marker(hl.old_lineno, hl.new_lineno, file_comments));
+                    }
+                    if let Some(width) = gutter_width {
+                        let style =
+                            picked.map_or(gutter_style(theme), |bg| gutter_style(theme).bg(bg));
+                        spans.push(Span::styled(gutter_text(hl, width), style));
+                        spans.extend(highlighted_spans(hl));
+                    } else {
+                        spans.extend(highlighted_spans(hl).into_iter().map(|span| match picked {
+                            Some(bg) => span.bg(bg),
+                            None => span,
+                        }));
+                    }
+                    diff_text.push(Line::from(spans));
+                    for comment in placed.get(&row).into_iter().flatten() {
+                        diff_text.extend(comment_lines(comment));
+                    }
+                }
+                diff_text.truncate(max_diff_visible);
 
-        f.render_widget(Paragraph::new(diff_text), diff_inner);
+                f.render_widget(Paragraph::new(diff_text), diff_inner);
+            }
+            DiffViewMode::Split => {
+                let visible_end = (clamped_scroll + max_diff_visible).min(split_rows.len());
+                draw_split_rows(
+                    f,
+                    diff_inner,
+                    &split_rows[clamped_scroll..visible_end],
+                    highlighted_lines,
+                    gutter_width,
+                    (clamped_scroll, placed, file_comments),
+                    theme,
+                );
+            }
+        }
 
-        if highlighted_lines.len() > max_diff_visible {
-            let mut scrollbar_state =
-                ScrollbarState::new(highlighted_lines.len()).position(clamped_scroll);
+        if max_scroll > 0 {
+            let mut scrollbar_state = ScrollbarState::new(row_count).position(clamped_scroll);
             f.render_stateful_widget(
                 Scrollbar::new(ScrollbarOrientation::VerticalRight),
                 layout.diff_area,
@@ -585,11 +1158,6 @@ fn draw_ui(
 
     // Help footer (skip if terminal is too small).
     if f.area().height > 0 {
-        let help = if has_modal {
-            " ↑/↓:nav | Enter:select | Esc:cancel | type to filter "
-        } else {
-            " j/k:nav | h/l/Space:expand | Enter:open | J/K:scroll | </>:resize | b:branch | q:quit "
-        };
         f.render_widget(
             Paragraph::new(help).style(Style::default().bg(Color::DarkGray)),
             layout.help_area,
@@ -597,6 +1165,292 @@ fn draw_ui(
     }
 }
 
+/// A notice over the right end of the footer.
+fn draw_toast(f: &mut Frame, help_area: Rect, text: &str, theme: &Theme) {
+    let text = format!(" {text} ");
+    let width = (text.chars().count() as u16).min(help_area.width);
+    let area = Rect::new(
+        help_area.right() - width,
+        help_area.y,
+        width,
+        help_area.height,
+    );
+    f.render_widget(Clear, area);
+    f.render_widget(
+        Paragraph::new(text).style(
+            Style::default()
+                .bg(theme.selected_bg)
+                .fg(theme.selected_fg)
+                .bold(),
+        ),
+        area,
+    );
+}
+
+/// Render the old and new files next to each other, separated by a vertical rule.
+/// `comments` are the index of the first row, the comments to show under
+/// each row and all comments on the file.
+fn draw_split_rows(
+    f: &mut Frame,
+    area: Rect,
+    rows: &[SplitRow],
+    lines: &[HighlightedLine],
+    gutter_width: Option<usize>,
+    comments: (usize, &HashMap<usize, Vec<&Comment>>, &[&Comment]),
+    theme: &Theme,
+) {
+    let (first_row, placed, file_comments) = comments;
+    let halves = Layout::horizontal([
+        Constraint::Fill(1),
+        Constraint::Length(1),
+        Constraint::Fill(1),
+    ])
+    .split(area);
+
+    let side = |idx: Option<usize>, old_side: bool| -> Line {
+        let Some(hl) = idx.and_then(|i| lines.get(i)) else {
+            return Line::default().style(Style::default().bg(theme.diff_filler_bg));
+        };
+        let mut spans: Vec<Span> = Vec::new();
+        if !file_comments.is_empty() {
+            let marker = if old_side {
+                comment_marker(hl.old_lineno, None, file_comments)
+            } else {
+                comment_marker(None, hl.new_lineno, file_comments)
+            };
+            spans.push(marker);
+        }
+        if let Some(width) = gutter_width {
+            let num = if old_side {
+                hl.old_lineno
+            } else {
+                hl.new_lineno
+            };
+            let num = num.map(|n| n.to_string()).unwrap_or_default();
+            spans.push(Span::styled(format!("{num:>width$} "), gutter_style(theme)));
+        }
+        spans.extend(highlighted_spans(hl));
+        Line::from(spans)
+    };
+
+    let mut left: Vec<Line> = Vec::new();
+    let mut right: Vec<Line> = Vec::new();
+    for (i, row) in rows.iter().enumerate() {
+        match *row {
+            SplitRow::Full(idx) => {
+                let Some(hl) = lines.get(idx) else {
+                    continue;
+                };
+                // Headers start on the left; carry the background across the right half.
+                let bg = hl
+                    .spans
+                    .last()
+                    .map(|(_, _, bg)| *bg)
+                    .unwrap_or(Color::Reset);
+                left.push(Line::from(highlighted_spans(hl)).style(Style::default().bg(bg)));
+                right.push(Line::default().style(Style::default().bg(bg)));
+            }
+            SplitRow::Pair(old, new) => {
+                left.push(side(old, true));
+                right.push(side(new, false));
+            }
+        }
+        // A comment goes on the side of its lines, across from a blank.
+        for comment in placed.get(&(first_row + i)).into_iter().flatten() {
+            let text = comment_lines(comment);
+            let blank = vec![Line::default(); text.len()];
+            match comment.side {
+                Side::Old => {
+                    left.extend(text);
+                    right.extend(blank);
+                }
+                Side::New => {
+                    left.extend(blank);
+                    right.extend(text);
+                }
+            }
+        }
+    }
+
+    let separator: Vec<Line> = left
+        .iter()
+        .map(|_| Line::styled("│", Style::default().fg(Color::DarkGray)))
+        .collect();
+
+    f.render_widget(Paragraph::new(left), halves[0]);
+    f.render_widget(Paragraph::new(separator), halves[1]);
+    f.render_widget(Paragraph::new(right), halves[2]);
+}
+
+/// The mark in front of a row on commented lines; dim when all the
+/// comments there are outdated.
+fn comment_marker(old: Option<u32>, new: Option<u32>, comments: &[&Comment]) -> Span<'static> {
+    let outdated = comments
+        .iter()
+        .filter(|c| {
+            old.is_some_and(|n| c.covers(Side::Old, n))
+                || new.is_some_and(|n| c.covers(Side::New, n))
+        })
+        .map(|c| c.outdated)
+        .min();
+    match outdated {
+        Some(false) => Span::styled("▍", Style::default().fg(Color::Yellow)),
+        Some(true) => Span::styled("▍", Style::default().fg(Color::DarkGray)),
+        None => Span::raw(" "),
+    }
+}
+
+/// A comment's text as shown under its lines.
+fn comment_lines(comment: &Comment) -> Vec<Line<'static>> {
+    let style = if comment.outdated {
+        Style::default().fg(Color::DarkGray).italic()
+    } else {
+        Style::default().fg(Color::Yellow).italic()
+    };
+    comment
+        .text
+        .lines()
+        .enumerate()
+        .map(|(i, text)| {
+            let lead = if i == 0 && comment.outdated {
+                "  ▍ (outdated) "
+            } else {
+                "  ▍ "
+            };
+            Line::styled(format!("{lead}{text}"), style)
+        })
+        .collect()
+}
+
+fn highlighted_spans(line: &HighlightedLine) -> Vec<Span<'static>> {
+    line.spans
+        .iter()
+        .map(|(text, fg, bg)| Span::styled(text.clone(), Style::default().fg(*fg).bg(*bg)))
+        .collect()
+}
+
+fn gutter_style(theme: &Theme) -> Style {
+    Style::default()
+        .fg(theme.line_number_fg)
+        .bg(theme.line_number_bg)
+}
+
+/// Render the `old new ` gutter columns for one diff line.
+fn gutter_text(line: &HighlightedLine, width: usize) -> String {
+    let format_num = |n: Option<u32>| n.map(|n| n.to_string()).unwrap_or_default();
+    format!(
+        "{:>width$} {:>width$} ",
+        format_num(line.old_lineno),
+        format_num(line.new_lineno),
+    )
+}
+
+fn draw_commit_panel(
+    f: &mut Frame,
+    area: Rect,
+    app: &App,
+    panel: &crate::app::CommitPanel,
+    theme: &Theme,
+) {
+    let block = Block::default().title(" Commits ").borders(Borders::ALL);
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let (sel_newest, sel_oldest) = panel.selection();
+    let active = app.commit_filter_entries();
+
+    let mut lines: Vec<Line> = Vec::new();
+    for entry in (0..app.commit_entry_count())
+        .skip(panel.scroll_offset)
+        .take(inner.height as usize)
+    {
+        let in_filter = active.is_some_and(|(newest, oldest)| (newest..=oldest).contains(&entry));
+        let marker = if in_filter { "● " } else { "  " };
+
+        let mut spans = match app.commit_entry(entry) {
+            Some(commit) => {
+                let short: String = commit.id.chars().take(7).collect();
+                vec![
+                    Span::styled(
+                        format!("{marker}{short} "),
+                        Style::default().fg(Color::Yellow),
+                    ),
+                    Span::raw(commit.subject.clone()),
+                    Span::styled(
+                        format!("  {}, {}", commit.author, relative_time(now - commit.time)),
+                        Style::default().fg(Color::DarkGray),
+                    ),
+                ]
+            }
+            None => vec![Span::styled(
+                format!("{marker}Uncommitted changes"),
+                Style::default().fg(Color::Green),
+            )],
+        };
+
+        if entry == panel.cursor {
+            let style = Style::default()
+                .bg(theme.selected_bg)
+                .fg(theme.selected_fg)
+                .bold();
+            spans = spans.into_iter().map(|s| s.patch_style(style)).collect();
+        } else if panel.anchor.is_some() && (sel_newest..=sel_oldest).contains(&entry) {
+            let style = Style::default().bg(theme.marked_bg);
+            spans = spans.into_iter().map(|s| s.patch_style(style)).collect();
+        }
+        lines.push(Line::from(spans));
+    }
+
+    f.render_widget(Paragraph::new(lines), inner);
+}
+
+/// Short human-readable age, like `git log --date=relative`.
+fn relative_time(seconds_ago: i64) -> String {
+    let plural = |n: i64, unit: &str| {
+        if n == 1 {
+            format!("1 {unit} ago")
+        } else {
+            format!("{n} {unit}s ago")
+        }
+    };
+    let s = seconds_ago.max(0);
+    match s {
+        0..=59 => "just now".to_string(),
+        60..=3599 => plural(s / 60, "minute"),
+        3600..=86_399 => plural(s / 3600, "hour"),
+        86_400..=1_209_599 => plural(s / 86_400, "day"),
+        1_209_600..=5_183_999 => plural(s / 604_800, "week"),
+        5_184_000..=31_535_999 => plural(s / 2_592_000, "month"),
+        _ => plural(s / 31_536_000, "year"),
+    }
+}
+
+/// A failed operation's output, like a rejected commit's hook messages.
+fn draw_error_pane(f: &mut Frame, title: &str, text: &str) {
+    let area = f.area();
+    let width = 80.min(area.width.saturating_sub(4));
+    let height = (text.lines().count() as u16 + 2).clamp(3, area.height.saturating_sub(2).max(3));
+    let x = (area.width.saturating_sub(width)) / 2;
+    let y = (area.height.saturating_sub(height)) / 2;
+    let pane_area = Rect::new(x, y, width, height.min(area.height));
+
+    f.render_widget(Clear, pane_area);
+    let block = Block::default()
+        .title(format!(" {title} "))
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(Color::Red));
+    f.render_widget(
+        Paragraph::new(text.to_string())
+            .block(block)
+            .wrap(Wrap { trim: false }),
+        pane_area,
+    );
+}
+
 fn draw_branch_modal(
     f: &mut Frame,
     modal: &crate::app::BranchModal,
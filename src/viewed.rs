@@ -0,0 +1,145 @@
+use crate::model::{DiffLineKind, FileDiff};
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Files marked as viewed, each with the hash of the diff it was viewed
+/// at, kept in `file` as one `hash<TAB>path` line per mark.
+#[derive(Default)]
+pub struct ViewedMarks {
+    /// `None` when there is nowhere to keep them, like outside a repository
+    file: Option<PathBuf>,
+    marks: BTreeMap<String, u64>,
+}
+
+impl ViewedMarks {
+    /// The marks saved in `file`; none when it is missing or unreadable.
+    pub fn load(file: Option<PathBuf>) -> Self {
+        let text = file
+            .as_ref()
+            .and_then(|f| std::fs::read_to_string(f).ok())
+            .unwrap_or_default();
+        let marks = text
+            .lines()
+            .filter_map(|line| {
+                let (hash, path) = line.split_once('\t')?;
+                Some((path.to_string(), u64::from_str_radix(hash, 16).ok()?))
+            })
+            .collect();
+        Self { file, marks }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        if self.marks.is_empty() {
+            return match std::fs::remove_file(file) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let text: String = self
+            .marks
+            .iter()
+            .map(|(path, hash)| format!("{hash:016x}\t{path}\n"))
+            .collect();
+        std::fs::write(file, text).with_context(|| format!("Could not write {}", file.display()))
+    }
+
+    /// Where the marks are kept.
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.marks.contains_key(path)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.marks.is_empty()
+    }
+
+    /// Hash of the diff `path` was marked at.
+    pub fn hash(&self, path: &str) -> Option<u64> {
+        self.marks.get(path).copied()
+    }
+
+    pub fn paths(&self) -> Vec<String> {
+        self.marks.keys().cloned().collect()
+    }
+
+    pub fn mark(&mut self, path: &str, hash: u64) {
+        self.marks.insert(path.to_string(), hash);
+    }
+
+    pub fn unmark(&mut self, path: &str) -> bool {
+        self.marks.remove(path).is_some()
+    }
+}
+
+/// Hash of what a diff shows, to tell whether a file changed since it was
+/// marked. FNV-1a rather than `DefaultHasher`, whose output may change
+/// between Rust releases and would reset every saved mark.
+pub fn diff_hash(diff: &FileDiff) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut write = |bytes: &[u8]| {
+        for &b in bytes {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+    for line in &diff.header {
+        write(line.as_bytes());
+        write(b"\n");
+    }
+    for hunk in &diff.hunks {
+        write(hunk.header().as_bytes());
+        write(b"\n");
+        for line in &hunk.lines {
+            let marker = match line.kind {
+                DiffLineKind::Context => b" ",
+                DiffLineKind::Added => b"+",
+                DiffLineKind::Removed => b"-",
+                DiffLineKind::NoNewline => b"\\",
+            };
+            write(marker);
+            write(line.content.as_bytes());
+            write(b"\n");
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_hash, ViewedMarks};
+    use crate::git::parse_diff;
+
+    #[test]
+    fn marks_are_saved_and_diffs_hashed_by_content() {
+        let dir = std::env::temp_dir().join(format!("prdiff-viewed-{}", std::process::id()));
+        let file = dir.join("prdiff").join("viewed").join("topic");
+        let mut marks = ViewedMarks::load(Some(file.clone()));
+        assert!(marks.is_empty());
+        marks.mark("src/a b.rs", 0xabc);
+        marks.mark("README.md", 1);
+        marks.save().unwrap();
+
+        let mut loaded = ViewedMarks::load(Some(file.clone()));
+        assert_eq!(loaded.hash("src/a b.rs"), Some(0xabc));
+        assert_eq!(loaded.paths(), ["README.md", "src/a b.rs"]);
+        assert!(loaded.unmark("README.md") && loaded.unmark("src/a b.rs"));
+        loaded.save().unwrap();
+        assert!(!file.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let one = parse_diff("--- a/f\n+++ b/f\n@@ -1,1 +1,1 @@\n-a\n+b\n");
+        let two = parse_diff("--- a/f\n+++ b/f\n@@ -1,1 +1,1 @@\n-a\n+c\n");
+        assert_eq!(diff_hash(&one), diff_hash(&one.clone()));
+        assert_ne!(diff_hash(&one), diff_hash(&two));
+    }
+}
@@ -1,7 +1,11 @@
+use crate::backend::GitBackend;
 use crate::git;
-use crate::model::FileEntry;
+use crate::model::{DiffOptions, DiffRange, FileEntry, FileLayers, MergePreview, MergeSource};
+use crate::submodule;
 use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
@@ -10,10 +14,15 @@ pub enum WatcherMessage {
     /// Files have changed - here's the new state
     FilesChanged {
         files: Vec<FileEntry>,
+        file_layers: HashMap<String, FileLayers>,
         merge_base: String,
         invalidate_all: bool,
         invalidate_paths: HashSet<String>,
     },
+    /// The merge preview was rerun against the base branch
+    MergeChecked { preview: MergePreview },
+    /// The base branch moved from one commit to another
+    BaseMoved { from: String, to: String },
 }
 
 /// Handle to the background watcher thread
@@ -23,12 +32,30 @@ pub struct GitWatcher {
 }
 
 impl GitWatcher {
-    /// Spawn a background thread that watches for git changes
-    pub fn spawn(base_branch: String, initial_merge_base: String, initial_files: Vec<FileEntry>) -> Self {
+    /// Spawn a background thread that watches for git changes. With a
+    /// `merge_source`, it runs the merge preview as it starts, then again
+    /// when HEAD or the base moves, and on every change when the preview
+    /// includes the worktree.
+    pub fn spawn(
+        backend: Arc<dyn GitBackend>,
+        base_branch: String,
+        initial_merge_base: String,
+        initial_files: Vec<FileEntry>,
+        options: DiffOptions,
+        merge_source: Option<MergeSource>,
+    ) -> Self {
         let (sender, receiver) = mpsc::channel();
 
         let handle = thread::spawn(move || {
-            watcher_loop(sender, base_branch, initial_merge_base, initial_files);
+            watcher_loop(
+                sender,
+                backend.as_ref(),
+                base_branch,
+                initial_merge_base,
+                initial_files,
+                &options,
+                merge_source,
+            );
         });
 
         Self {
@@ -45,27 +72,54 @@ impl GitWatcher {
 
 fn watcher_loop(
     sender: Sender<WatcherMessage>,
+    backend: &dyn GitBackend,
     base_branch: String,
     mut merge_base: String,
     mut files: Vec<FileEntry>,
+    options: &DiffOptions,
+    merge_source: Option<MergeSource>,
 ) {
-    let mut last_head_oid = git::git_rev_parse("HEAD").unwrap_or_default();
-    let mut last_base_oid = git::git_rev_parse(&base_branch).unwrap_or_default();
-    let mut last_status_hash = git::git_status_hash().unwrap_or(0);
-    let git_index_path = git::git_git_path("index").unwrap_or_default();
-    let git_head_path = git::git_git_path("HEAD").unwrap_or_default();
+    // The first merge preview runs here too, to keep it off the UI thread.
+    if let Some(source) = merge_source {
+        if let Ok(preview) = backend.merge_preview(&base_branch, source) {
+            if sender
+                .send(WatcherMessage::MergeChecked { preview })
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+    let mut last_head_oid = backend.rev_parse("HEAD").unwrap_or_default();
+    let mut last_base_oid = backend.rev_parse(&base_branch).unwrap_or_default();
+    let mut last_status_hash = submodule::status_hash(backend, &files, options).unwrap_or(0);
+    let git_index_path = backend.git_path("index").unwrap_or_default();
+    let git_head_path = backend.git_path("HEAD").unwrap_or_default();
     // Resolve the base branch ref path for cheap mtime checks.
     // For remote refs like "origin/main", this resolves to e.g. ".git/refs/remotes/origin/main"
     // or packed-refs. We also watch the packed-refs file for repacks.
-    let git_refs_heads_path = git::git_git_path(&format!("refs/heads/{base_branch}")).unwrap_or_default();
-    let git_refs_remotes_path = git::git_git_path(&format!("refs/remotes/{base_branch}")).unwrap_or_default();
-    let git_packed_refs_path = git::git_git_path("packed-refs").unwrap_or_default();
+    let git_refs_heads_path = backend
+        .git_path(&format!("refs/heads/{base_branch}"))
+        .unwrap_or_default();
+    let git_refs_remotes_path = backend
+        .git_path(&format!("refs/remotes/{base_branch}"))
+        .unwrap_or_default();
+    let git_packed_refs_path = backend.git_path("packed-refs").unwrap_or_default();
+    // A commit moves the branch HEAD points at, not HEAD itself.
+    let branch_ref_path = || {
+        let branch = backend.head_branch();
+        branch
+            .and_then(|b| backend.git_path(&format!("refs/heads/{b}")).ok())
+            .unwrap_or_default()
+    };
+    let mut git_branch_path = branch_ref_path();
 
     let mut last_index_mtime = git::file_mtime_ns(&git_index_path);
     let mut last_head_mtime = git::file_mtime_ns(&git_head_path);
     let mut last_refs_heads_mtime = git::file_mtime_ns(&git_refs_heads_path);
     let mut last_refs_remotes_mtime = git::file_mtime_ns(&git_refs_remotes_path);
     let mut last_packed_refs_mtime = git::file_mtime_ns(&git_packed_refs_path);
+    let mut last_branch_mtime = git::file_mtime_ns(&git_branch_path);
     let mut file_mtimes = get_file_mtimes(&files);
 
     loop {
@@ -74,6 +128,8 @@ fn watcher_loop(
         let mut invalidate_all_caches = false;
         let mut invalidate_paths: HashSet<String> = HashSet::new();
         let mut needs_refresh = false;
+        let mut refs_moved = false;
+        let mut base_moved = None;
 
         // Cheap mtime checks on git internal files to avoid spawning processes
         let index_mtime = git::file_mtime_ns(&git_index_path);
@@ -81,12 +137,17 @@ fn watcher_loop(
         let refs_heads_mtime = git::file_mtime_ns(&git_refs_heads_path);
         let refs_remotes_mtime = git::file_mtime_ns(&git_refs_remotes_path);
         let packed_refs_mtime = git::file_mtime_ns(&git_packed_refs_path);
+        if head_mtime != last_head_mtime {
+            git_branch_path = branch_ref_path();
+        }
+        let branch_mtime = git::file_mtime_ns(&git_branch_path);
 
         let git_dir_changed = index_mtime != last_index_mtime
             || head_mtime != last_head_mtime
             || refs_heads_mtime != last_refs_heads_mtime
             || refs_remotes_mtime != last_refs_remotes_mtime
-            || packed_refs_mtime != last_packed_refs_mtime;
+            || packed_refs_mtime != last_packed_refs_mtime
+            || branch_mtime != last_branch_mtime;
 
         if git_dir_changed {
             // Something in .git changed - check what exactly
@@ -100,29 +161,35 @@ fn watcher_loop(
                 || refs_heads_mtime != last_refs_heads_mtime
                 || refs_remotes_mtime != last_refs_remotes_mtime
                 || packed_refs_mtime != last_packed_refs_mtime
+                || branch_mtime != last_branch_mtime
             {
                 last_head_mtime = head_mtime;
+                last_branch_mtime = branch_mtime;
                 last_refs_heads_mtime = refs_heads_mtime;
                 last_refs_remotes_mtime = refs_remotes_mtime;
                 last_packed_refs_mtime = packed_refs_mtime;
 
                 // Only spawn git processes when ref files actually changed
-                let head_oid = match git::git_rev_parse("HEAD") {
+                let head_oid = match backend.rev_parse("HEAD") {
                     Ok(v) => v,
                     Err(_) => continue,
                 };
-                let base_oid = match git::git_rev_parse(&base_branch) {
+                let base_oid = match backend.rev_parse(&base_branch) {
                     Ok(v) => v,
                     Err(_) => continue,
                 };
 
                 if head_oid != last_head_oid || base_oid != last_base_oid {
                     invalidate_all_caches = true;
-                    if let Ok(new_merge_base) = git::get_merge_base(&base_branch) {
+                    if let Ok(new_merge_base) = backend.merge_base("HEAD", &base_branch) {
+                        if base_oid != last_base_oid && !last_base_oid.is_empty() {
+                            base_moved = Some((last_base_oid.clone(), base_oid.clone()));
+                        }
                         merge_base = new_merge_base;
                         last_head_oid = head_oid;
                         last_base_oid = base_oid;
                         needs_refresh = true;
+                        refs_moved = true;
                     }
                 }
             }
@@ -141,7 +208,7 @@ fn watcher_loop(
 
         // Always check git status to detect new untracked files and staging changes.
         // This is a single process spawn per poll cycle — cheap enough to run unconditionally.
-        if let Ok(status_hash) = git::git_status_hash() {
+        if let Ok(status_hash) = submodule::status_hash(backend, &files, options) {
             if status_hash != last_status_hash {
                 last_status_hash = status_hash;
                 needs_refresh = true;
@@ -152,17 +219,40 @@ fn watcher_loop(
             continue;
         }
 
+        if let Some((from, to)) = base_moved {
+            if sender.send(WatcherMessage::BaseMoved { from, to }).is_err() {
+                break;
+            }
+        }
+
+        if let Some(source) = merge_source {
+            if refs_moved || source == MergeSource::Worktree {
+                if let Ok(preview) = backend.merge_preview(&base_branch, source) {
+                    if sender
+                        .send(WatcherMessage::MergeChecked { preview })
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+
         // Fetch new file list
-        let new_files = match git::get_changed_files(&merge_base) {
-            Ok(f) => f,
-            Err(_) => continue,
-        };
+        let new_files =
+            match backend.changed_files(&DiffRange::worktree(merge_base.as_str()), options) {
+                Ok(f) => f,
+                Err(_) => continue,
+            };
 
         file_mtimes = get_file_mtimes(&new_files);
 
         // Send update to main thread
         let msg = WatcherMessage::FilesChanged {
             files: new_files.clone(),
+            file_layers: backend
+                .file_layers(&merge_base, options)
+                .unwrap_or_default(),
             merge_base: merge_base.clone(),
             invalidate_all: invalidate_all_caches,
             invalidate_paths,
@@ -180,7 +270,7 @@ fn watcher_loop(
 fn get_file_mtimes(files: &[FileEntry]) -> HashMap<String, u128> {
     let mut mtimes = HashMap::new();
     for file in files {
-        if let Some(mtime) = git::file_mtime_ns(&file.path) {
+        if let Some(mtime) = git::file_mtime_ns(Path::new(&file.path)) {
             mtimes.insert(file.path.clone(), mtime);
         }
     }
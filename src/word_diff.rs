@@ -0,0 +1,134 @@
+use std::ops::Range;
+
+/// Lines longer than this many tokens are not word-diffed; the LCS table
+/// would get expensive and the result is rarely useful anyway.
+const MAX_TOKENS: usize = 400;
+
+/// Skip emphasis when less than this fraction of the line is unchanged,
+/// otherwise a rewritten line lights up almost entirely.
+const MIN_SHARED_RATIO: f64 = 0.3;
+
+/// Changed byte ranges of the old line and of the new line.
+pub type ChangedRanges = (Vec<Range<usize>>, Vec<Range<usize>>);
+
+/// Split a line into words (alphanumerics and `_`), whitespace runs and
+/// single punctuation characters, returned as byte ranges.
+fn tokenize(line: &str) -> Vec<Range<usize>> {
+    #[derive(PartialEq)]
+    enum Class {
+        Word,
+        Space,
+        Punct,
+    }
+    let class = |c: char| {
+        if c.is_alphanumeric() || c == '_' {
+            Class::Word
+        } else if c.is_whitespace() {
+            Class::Space
+        } else {
+            Class::Punct
+        }
+    };
+
+    let mut tokens: Vec<Range<usize>> = Vec::new();
+    let mut prev: Option<Class> = None;
+    for (i, c) in line.char_indices() {
+        let cls = class(c);
+        let extends = cls != Class::Punct && prev.as_ref() == Some(&cls);
+        match tokens.last_mut() {
+            Some(last) if extends => last.end = i + c.len_utf8(),
+            _ => tokens.push(i..i + c.len_utf8()),
+        }
+        prev = Some(cls);
+    }
+    tokens
+}
+
+/// Merge adjacent or overlapping byte ranges.
+fn push_range(ranges: &mut Vec<Range<usize>>, range: Range<usize>) {
+    match ranges.last_mut() {
+        Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+        _ => ranges.push(range),
+    }
+}
+
+/// Compute the byte ranges that differ between a removed line and the added
+/// line it was paired with, using a token-level longest common subsequence.
+/// Returns `None` when the lines are too long or too different to be worth
+/// emphasizing.
+pub fn changed_ranges(old: &str, new: &str) -> Option<ChangedRanges> {
+    let old_tokens = tokenize(old);
+    let new_tokens = tokenize(new);
+    if old_tokens.len() > MAX_TOKENS || new_tokens.len() > MAX_TOKENS {
+        return None;
+    }
+
+    let (n, m) = (old_tokens.len(), new_tokens.len());
+    let tok_old = |i: usize| &old[old_tokens[i].clone()];
+    let tok_new = |j: usize| &new[new_tokens[j].clone()];
+
+    // lcs[i][j] = LCS length of old_tokens[i..] and new_tokens[j..]
+    let mut lcs = vec![vec![0u16; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if tok_old(i) == tok_new(j) {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut old_changed = Vec::new();
+    let mut new_changed = Vec::new();
+    let mut shared_bytes = 0;
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && tok_old(i) == tok_new(j) {
+            shared_bytes += old_tokens[i].len();
+            i += 1;
+            j += 1;
+        } else if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            push_range(&mut new_changed, new_tokens[j].clone());
+            j += 1;
+        } else {
+            push_range(&mut old_changed, old_tokens[i].clone());
+            i += 1;
+        }
+    }
+
+    let longest = old.len().max(new.len());
+    if longest > 0 && (shared_bytes as f64) / (longest as f64) < MIN_SHARED_RATIO {
+        return None;
+    }
+    Some((old_changed, new_changed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::changed_ranges;
+
+    #[test]
+    fn changed_ranges_marks_only_the_edited_token() {
+        let old = "let total = count + 1;";
+        let new = "let total = count - 1;";
+        let (old_ranges, new_ranges) = changed_ranges(old, new).unwrap();
+        assert_eq!(old_ranges.len(), 1);
+        assert_eq!(new_ranges.len(), 1);
+        assert_eq!(&old[old_ranges[0].clone()], "+");
+        assert_eq!(&new[new_ranges[0].clone()], "-");
+    }
+
+    #[test]
+    fn changed_ranges_handles_renamed_identifiers() {
+        let (old_ranges, new_ranges) =
+            changed_ranges("fn parse(input: &str)", "fn parse(text: &str)").unwrap();
+        assert_eq!(&"fn parse(input: &str)"[old_ranges[0].clone()], "input");
+        assert_eq!(&"fn parse(text: &str)"[new_ranges[0].clone()], "text");
+    }
+
+    #[test]
+    fn changed_ranges_skips_rewritten_lines() {
+        assert!(changed_ranges("return a.len();", "panic!(\"unreachable\")").is_none());
+    }
+}